@@ -0,0 +1,901 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use sha256::digest;
+
+use crate::{
+    commands::Command,
+    common::{constant_time_eq, current_time_ms, PatternMatcher},
+    resp::RespValue,
+};
+
+const ACL_LOG_MAX_LEN: usize = 128;
+/// Repeated failures within this window update one log entry.
+const ACL_LOG_GROUPING_MS: u128 = 60_000;
+
+/// ACL categories of every command, keyed by the command name used in rules.
+/// Subcommands are named `container|subcommand`.
+const COMMAND_CATEGORIES: [(&str, &[&str]); 104] = [
+    ("ping", &["fast", "connection"]),
+    ("echo", &["fast", "connection"]),
+    ("auth", &["fast", "connection"]),
+    ("set", &["write", "string", "slow"]),
+    ("get", &["read", "string", "fast"]),
+    ("incr", &["write", "string", "fast"]),
+    ("rpush", &["write", "list", "fast"]),
+    ("lpush", &["write", "list", "fast"]),
+    ("lrange", &["read", "list", "slow"]),
+    ("llen", &["read", "list", "fast"]),
+    ("lpop", &["write", "list", "fast"]),
+    ("rpop", &["write", "list", "fast"]),
+    ("blpop", &["write", "list", "slow", "blocking"]),
+    ("brpop", &["write", "list", "slow", "blocking"]),
+    ("type", &["keyspace", "read", "fast"]),
+    ("del", &["keyspace", "write", "slow"]),
+    ("object|freq", &["keyspace", "read", "slow"]),
+    ("object|idletime", &["keyspace", "read", "slow"]),
+    ("keys", &["keyspace", "read", "slow", "dangerous"]),
+    ("flushall", &["keyspace", "write", "slow", "dangerous"]),
+    ("flushdb", &["keyspace", "write", "slow", "dangerous"]),
+    ("select", &["fast", "connection"]),
+    ("move", &["keyspace", "write", "fast"]),
+    ("swapdb", &["keyspace", "write", "fast", "dangerous"]),
+    ("dbsize", &["keyspace", "read", "fast"]),
+    ("xadd", &["write", "stream", "fast"]),
+    ("xrange", &["read", "stream", "slow"]),
+    ("xread", &["read", "stream", "slow", "blocking"]),
+    ("zadd", &["write", "sortedset", "fast"]),
+    ("zrank", &["read", "sortedset", "fast"]),
+    ("zrange", &["read", "sortedset", "slow"]),
+    ("zcard", &["read", "sortedset", "fast"]),
+    ("zscore", &["read", "sortedset", "fast"]),
+    ("zrem", &["write", "sortedset", "fast"]),
+    ("geoadd", &["write", "geo", "slow"]),
+    ("geopos", &["read", "geo", "slow"]),
+    ("geodist", &["read", "geo", "slow"]),
+    ("geosearch", &["read", "geo", "slow"]),
+    ("multi", &["fast", "transaction"]),
+    ("exec", &["slow", "transaction"]),
+    ("discard", &["fast", "transaction"]),
+    ("watch", &["fast", "transaction"]),
+    ("unwatch", &["fast", "transaction"]),
+    ("subscribe", &["pubsub", "slow"]),
+    ("unsubscribe", &["pubsub", "slow"]),
+    ("publish", &["pubsub", "fast"]),
+    ("psubscribe", &["pubsub", "slow"]),
+    ("punsubscribe", &["pubsub", "slow"]),
+    ("ssubscribe", &["pubsub", "slow"]),
+    ("sunsubscribe", &["pubsub", "slow"]),
+    ("spublish", &["pubsub", "fast"]),
+    ("pubsub|channels", &["pubsub", "slow"]),
+    ("pubsub|numsub", &["pubsub", "slow"]),
+    ("pubsub|numpat", &["pubsub", "slow"]),
+    ("pubsub|shardchannels", &["pubsub", "slow"]),
+    ("pubsub|shardnumsub", &["pubsub", "slow"]),
+    ("quit", &["fast", "connection"]),
+    ("reset", &["fast", "connection"]),
+    ("client|id", &["slow", "connection"]),
+    ("client|tracking", &["slow", "connection"]),
+    ("client|caching", &["slow", "connection"]),
+    ("client|list", &["admin", "slow", "dangerous", "connection"]),
+    ("client|info", &["slow", "connection"]),
+    ("client|setname", &["slow", "connection"]),
+    ("client|getname", &["slow", "connection"]),
+    ("client|kill", &["admin", "slow", "dangerous", "connection"]),
+    (
+        "client|pause",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    (
+        "client|unpause",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    (
+        "client|no-evict",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    ("info", &["slow", "dangerous"]),
+    ("wait", &["slow", "connection"]),
+    ("save", &["admin", "slow", "dangerous"]),
+    ("replconf", &["admin", "slow", "dangerous"]),
+    ("psync", &["admin", "slow", "dangerous"]),
+    ("config|get", &["admin", "slow", "dangerous"]),
+    ("config|set", &["admin", "slow", "dangerous"]),
+    ("config|resetstat", &["admin", "slow", "dangerous"]),
+    ("config|rewrite", &["admin", "slow", "dangerous"]),
+    ("acl|whoami", &["slow"]),
+    ("acl|cat", &["slow"]),
+    ("acl|genpass", &["slow"]),
+    ("acl|getuser", &["admin", "slow", "dangerous"]),
+    ("acl|setuser", &["admin", "slow", "dangerous"]),
+    ("acl|deluser", &["admin", "slow", "dangerous"]),
+    ("acl|list", &["admin", "slow", "dangerous"]),
+    ("acl|users", &["admin", "slow", "dangerous"]),
+    ("acl|dryrun", &["admin", "slow", "dangerous"]),
+    ("acl|load", &["admin", "slow", "dangerous"]),
+    ("acl|save", &["admin", "slow", "dangerous"]),
+    ("acl|log", &["admin", "slow", "dangerous"]),
+    ("eval", &["slow", "scripting"]),
+    ("evalsha", &["slow", "scripting"]),
+    ("fcall", &["slow", "scripting"]),
+    ("fcall_ro", &["slow", "scripting"]),
+    ("script|load", &["slow", "scripting"]),
+    ("script|exists", &["slow", "scripting"]),
+    ("script|flush", &["slow", "scripting"]),
+    ("script|kill", &["slow", "scripting"]),
+    ("function|load", &["write", "slow", "scripting"]),
+    ("function|delete", &["write", "slow", "scripting"]),
+    ("function|flush", &["write", "slow", "scripting"]),
+    ("function|list", &["slow", "scripting"]),
+    ("function|dump", &["slow", "scripting"]),
+    ("function|restore", &["write", "slow", "scripting"]),
+];
+
+const CATEGORIES: [&str; 18] = [
+    "keyspace",
+    "read",
+    "write",
+    "string",
+    "list",
+    "sortedset",
+    "stream",
+    "geo",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+    "all",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum KeyAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl KeyAccess {
+    fn covers(&self, needed: KeyAccess) -> bool {
+        *self == KeyAccess::ReadWrite || *self == needed
+    }
+}
+
+/// Reason a command was refused by [`User::check`].
+#[derive(Debug, PartialEq)]
+pub(crate) enum AclDenial {
+    Command(String /* Command name */),
+    Key(String),
+    Channel(String),
+}
+
+impl AclDenial {
+    /// The error returned to the client running the command.
+    pub(crate) fn to_error(&self, user: &str) -> String {
+        match self {
+            AclDenial::Command(name) => format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user, name
+            ),
+            AclDenial::Key(_) => "NOPERM No permissions to access a key".to_string(),
+            AclDenial::Channel(_) => "NOPERM No permissions to access a channel".to_string(),
+        }
+    }
+
+    /// The reason and the object of the ACL LOG entry.
+    pub(crate) fn log_reason_and_object(&self) -> (&str, &str) {
+        match self {
+            AclDenial::Command(name) => ("command", name),
+            AclDenial::Key(key) => ("key", key),
+            AclDenial::Channel(channel) => ("channel", channel),
+        }
+    }
+
+    /// The reason reported by ACL DRYRUN.
+    pub(crate) fn to_dryrun_reply(&self) -> String {
+        match self {
+            AclDenial::Command(name) => {
+                format!("This user has no permissions to run the '{}' command", name)
+            }
+            AclDenial::Key(key) => {
+                format!("This user has no permissions to access the '{}' key", key)
+            }
+            AclDenial::Channel(channel) => format!(
+                "This user has no permissions to access the '{}' channel",
+                channel
+            ),
+        }
+    }
+}
+
+/// Categories of a command, `None` for commands unknown to the ACL table.
+fn command_categories(name: &str) -> Option<&'static [&'static str]> {
+    COMMAND_CATEGORIES
+        .iter()
+        .find(|(command, _)| *command == name)
+        .map(|(_, categories)| *categories)
+}
+
+/// Rule targets are a full command name, or a container like `acl` covering
+/// all of its subcommands.
+fn is_command_name(name: &str) -> bool {
+    COMMAND_CATEGORIES.iter().any(|(command, _)| {
+        *command == name
+            || command
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with('|'))
+    })
+}
+
+/// All category names, as listed by ACL CAT.
+pub(crate) fn categories() -> Vec<String> {
+    CATEGORIES
+        .iter()
+        .map(|category| category.to_string())
+        .collect()
+}
+
+/// Commands in a category, as listed by `ACL CAT category`.
+pub(crate) fn commands_in_category(category: &str) -> Option<Vec<String>> {
+    if !CATEGORIES.contains(&category) {
+        return None;
+    }
+
+    Some(
+        COMMAND_CATEGORIES
+            .iter()
+            .filter(|(_, categories)| category == "all" || categories.contains(&category))
+            .map(|(command, _)| command.to_string())
+            .collect(),
+    )
+}
+
+/// A user of the ACL system. Command rules are kept in the order given and the
+/// last matching rule decides, so `+@all -keys` allows all but KEYS. A new
+/// user is disabled and has no passwords or permissions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct User {
+    enabled: bool,
+    nopass: bool,
+    /// Only SHA-256 hashes of the passwords are kept.
+    password_hashes: Vec<String>,
+    command_rules: Vec<(
+        bool,   /* Allowed */
+        String, /* Command or @category */
+    )>,
+    key_patterns: Vec<(String, KeyAccess)>,
+    channel_patterns: Vec<String>,
+}
+
+impl User {
+    /// The default user, able to run everything. Without a password it is a
+    /// `nopass` user.
+    pub(crate) fn new_default(password: Option<&str>) -> Self {
+        let mut user = Self::default();
+        let password_rule = match password {
+            Some(password) => format!(">{}", password),
+            None => "nopass".to_string(),
+        };
+        for rule in [
+            "on",
+            &password_rule,
+            "allkeys",
+            "allchannels",
+            "allcommands",
+        ] {
+            user.apply_rule(rule).expect("valid-default-user-rule");
+        }
+        user
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn is_nopass(&self) -> bool {
+        self.nopass
+    }
+
+    /// Applies one rule of the ACL SETUSER grammar. The error is the reason
+    /// the rule was refused.
+    pub(crate) fn apply_rule(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.password_hashes.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.password_hashes.clear();
+            }
+            "allkeys" => {
+                self.key_patterns = vec![("*".to_string(), KeyAccess::ReadWrite)];
+            }
+            "resetkeys" => self.key_patterns.clear(),
+            "allchannels" => self.channel_patterns = vec!["*".to_string()],
+            "resetchannels" => self.channel_patterns.clear(),
+            "allcommands" => self.apply_rule("+@all")?,
+            "nocommands" => self.apply_rule("-@all")?,
+            "reset" => {
+                for rule in [
+                    "resetpass",
+                    "resetkeys",
+                    "resetchannels",
+                    "nocommands",
+                    "off",
+                ] {
+                    self.apply_rule(rule)?;
+                }
+            }
+            _ => return self.apply_pattern_rule(rule),
+        }
+
+        Ok(())
+    }
+
+    fn apply_pattern_rule(&mut self, rule: &str) -> Result<(), String> {
+        if let Some(password) = rule.strip_prefix('>') {
+            self.add_password_hash(digest(password));
+        } else if let Some(password) = rule.strip_prefix('<') {
+            self.remove_password_hash(&digest(password))?;
+        } else if let Some(hash) = rule.strip_prefix('#') {
+            if hash.len() != 64
+                || !hash
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+            {
+                return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+            }
+            self.add_password_hash(hash.to_string());
+        } else if let Some(hash) = rule.strip_prefix('!') {
+            self.remove_password_hash(hash)?;
+        } else if let Some(pattern) = rule.strip_prefix('~') {
+            self.add_key_pattern(pattern, KeyAccess::ReadWrite);
+        } else if let Some((access, pattern)) =
+            rule.strip_prefix('%').and_then(|r| r.split_once('~'))
+        {
+            let access = match access.to_uppercase().as_str() {
+                "R" => KeyAccess::Read,
+                "W" => KeyAccess::Write,
+                "RW" | "WR" => KeyAccess::ReadWrite,
+                _ => return Err("Syntax error".to_string()),
+            };
+            self.add_key_pattern(pattern, access);
+        } else if let Some(pattern) = rule.strip_prefix('&') {
+            if !self.channel_patterns.iter().any(|p| p == pattern) {
+                self.channel_patterns.push(pattern.to_string());
+            }
+        } else if let Some(target) = rule.strip_prefix('+') {
+            self.add_command_rule(true, target)?;
+        } else if let Some(target) = rule.strip_prefix('-') {
+            self.add_command_rule(false, target)?;
+        } else {
+            return Err("Syntax error".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn add_password_hash(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.password_hashes.contains(&hash) {
+            self.password_hashes.push(hash);
+        }
+    }
+
+    fn remove_password_hash(&mut self, hash: &str) -> Result<(), String> {
+        let len_before = self.password_hashes.len();
+        self.password_hashes.retain(|stored| stored != hash);
+        if self.password_hashes.len() == len_before {
+            return Err(
+                "The password you are trying to remove from the user does not exist".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, access: KeyAccess) {
+        self.key_patterns
+            .retain(|(stored, stored_access)| stored != pattern || *stored_access != access);
+        self.key_patterns.push((pattern.to_string(), access));
+    }
+
+    fn add_command_rule(&mut self, allowed: bool, target: &str) -> Result<(), String> {
+        let target = target.to_lowercase();
+
+        let is_known = match target.strip_prefix('@') {
+            Some(category) => CATEGORIES.contains(&category),
+            None => is_command_name(&target),
+        };
+        if !is_known {
+            return Err("Unknown command or category name in ACL".to_string());
+        }
+
+        if target == "@all" {
+            // Everything before is overridden, so the rules start over.
+            self.command_rules.clear();
+            if allowed {
+                self.command_rules.push((true, target));
+            }
+        } else {
+            self.command_rules.retain(|(_, stored)| *stored != target);
+            self.command_rules.push((allowed, target));
+        }
+
+        Ok(())
+    }
+
+    /// Checks every stored hash without stopping early, so the time taken does
+    /// not tell which hash or byte did not match.
+    pub(crate) fn check_password(&self, password: &str) -> bool {
+        let password_hash = digest(password);
+        self.password_hashes
+            .iter()
+            .fold(self.nopass, |matched, hash| {
+                matched | constant_time_eq(hash.as_bytes(), password_hash.as_bytes())
+            })
+    }
+
+    fn is_command_allowed(&self, name: &str) -> bool {
+        let categories = command_categories(name).unwrap_or(&[]);
+
+        self.command_rules
+            .iter()
+            .rev()
+            .find(|(_, target)| match target.strip_prefix('@') {
+                Some("all") => true,
+                Some(category) => categories.contains(&category),
+                None => {
+                    name == target
+                        || name
+                            .strip_prefix(target.as_str())
+                            .is_some_and(|rest| rest.starts_with('|'))
+                }
+            })
+            .map(|(allowed, _)| *allowed)
+            .unwrap_or(false)
+    }
+
+    fn is_key_allowed(&self, key: &str, access: KeyAccess) -> bool {
+        self.key_patterns.iter().any(|(pattern, pattern_access)| {
+            pattern_access.covers(access) && PatternMatcher::new(pattern).is_match(key)
+        })
+    }
+
+    fn is_channel_allowed(&self, channel: &str) -> bool {
+        self.channel_patterns
+            .iter()
+            .any(|pattern| PatternMatcher::new(pattern).is_match(channel))
+    }
+
+    /// A subscription pattern is only allowed when granted as is, so it
+    /// can't reach channels the user has no access to.
+    fn is_channel_pattern_allowed(&self, pattern: &str) -> bool {
+        self.channel_patterns
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == pattern)
+    }
+
+    /// Checks the command itself, then the keys and channels it touches.
+    pub(crate) fn check(&self, command: &Command) -> Result<(), AclDenial> {
+        let name = command.acl_name();
+        if !self.is_command_allowed(&name) {
+            return Err(AclDenial::Command(name));
+        }
+
+        for (key, access) in command.acl_keys() {
+            if !self.is_key_allowed(key, access) {
+                return Err(AclDenial::Key(key.to_string()));
+            }
+        }
+
+        for channel in command.acl_channels() {
+            if !self.is_channel_allowed(channel) {
+                return Err(AclDenial::Channel(channel.to_string()));
+            }
+        }
+
+        for pattern in command.acl_channel_patterns() {
+            if !self.is_channel_pattern_allowed(pattern) {
+                return Err(AclDenial::Channel(pattern.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn flags(&self) -> Vec<String> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            flags.push("nopass".to_string());
+        }
+        flags
+    }
+
+    fn commands_description(&self) -> String {
+        let mut rules = self
+            .command_rules
+            .iter()
+            .map(|(allowed, target)| format!("{}{}", if *allowed { '+' } else { '-' }, target))
+            .collect::<Vec<_>>();
+        if rules.first().map(|rule| rule != "+@all").unwrap_or(true) {
+            rules.insert(0, "-@all".to_string());
+        }
+        rules.join(" ")
+    }
+
+    fn keys_description(&self) -> String {
+        self.key_patterns
+            .iter()
+            .map(|(pattern, access)| match access {
+                KeyAccess::ReadWrite => format!("~{}", pattern),
+                KeyAccess::Read => format!("%R~{}", pattern),
+                KeyAccess::Write => format!("%W~{}", pattern),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn channels_description(&self) -> String {
+        self.channel_patterns
+            .iter()
+            .map(|pattern| format!("&{}", pattern))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The user as a list of rules recreating it, used by ACL LIST.
+    pub(crate) fn describe(&self, name: &str) -> String {
+        let mut parts = vec![format!("user {}", name)];
+        parts.append(&mut self.flags());
+        parts.extend(self.password_hashes.iter().map(|hash| format!("#{}", hash)));
+        if !self.key_patterns.is_empty() {
+            parts.push(self.keys_description());
+        }
+        if self.channel_patterns.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.channels_description());
+        }
+        parts.push(self.commands_description());
+        parts.join(" ")
+    }
+
+    /// The reply of ACL GETUSER.
+    pub(crate) fn to_resp(&self) -> RespValue {
+        RespValue::Array(vec![
+            RespValue::BulkString("flags".into()),
+            RespValue::Array(
+                self.flags()
+                    .into_iter()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+            RespValue::BulkString("passwords".into()),
+            RespValue::Array(
+                self.password_hashes
+                    .iter()
+                    .cloned()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+            RespValue::BulkString("commands".into()),
+            RespValue::BulkString(self.commands_description()),
+            RespValue::BulkString("keys".into()),
+            RespValue::BulkString(self.keys_description()),
+            RespValue::BulkString("channels".into()),
+            RespValue::BulkString(self.channels_description()),
+        ])
+    }
+}
+
+/// Parses users in the ACL file format, one `user <name> <rules...>` per line.
+pub(crate) fn parse_acl_file(path: &str, content: &str) -> Result<BTreeMap<String, User>, String> {
+    let mut users = BTreeMap::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line_error = |reason: String| format!("ERR {}:{}: {}", path, index + 1, reason);
+
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        match parts.as_slice() {
+            [] => continue,
+            ["user", name, rules @ ..] => {
+                if users.contains_key(*name) {
+                    return Err(line_error(format!("Duplicate user '{}' found", name)));
+                }
+
+                let mut user = User::default();
+                for rule in rules {
+                    user.apply_rule(rule).map_err(|reason| {
+                        line_error(format!(
+                            "Error in applying operation '{}': {}",
+                            rule, reason
+                        ))
+                    })?;
+                }
+                users.insert(name.to_string(), user);
+            }
+            _ => {
+                return Err(line_error(
+                    "line should start with user keyword".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(users)
+}
+
+/// Writes users in the ACL file format, as read by [`parse_acl_file`].
+pub(crate) fn format_acl_file(users: &BTreeMap<String, User>) -> String {
+    users
+        .iter()
+        .map(|(name, user)| format!("{}\n", user.describe(name)))
+        .collect()
+}
+
+struct AclLogEntry {
+    count: u64,
+    reason: String,
+    context: String,
+    object: String,
+    username: String,
+    client_info: String,
+    entry_id: u64,
+    created_ms: u128,
+    updated_ms: u128,
+}
+
+impl AclLogEntry {
+    fn to_resp(&self, now_ms: u128) -> RespValue {
+        let age_secs = now_ms.saturating_sub(self.updated_ms) as f64 / 1000.0;
+
+        RespValue::Array(vec![
+            RespValue::BulkString("count".into()),
+            RespValue::Integer(self.count as i64),
+            RespValue::BulkString("reason".into()),
+            RespValue::BulkString(self.reason.clone()),
+            RespValue::BulkString("context".into()),
+            RespValue::BulkString(self.context.clone()),
+            RespValue::BulkString("object".into()),
+            RespValue::BulkString(self.object.clone()),
+            RespValue::BulkString("username".into()),
+            RespValue::BulkString(self.username.clone()),
+            RespValue::BulkString("age-seconds".into()),
+            RespValue::BulkString(format!("{:.3}", age_secs)),
+            RespValue::BulkString("client-info".into()),
+            RespValue::BulkString(self.client_info.clone()),
+            RespValue::BulkString("entry-id".into()),
+            RespValue::Integer(self.entry_id as i64),
+            RespValue::BulkString("timestamp-created".into()),
+            RespValue::Integer(self.created_ms as i64),
+            RespValue::BulkString("timestamp-last-updated".into()),
+            RespValue::Integer(self.updated_ms as i64),
+        ])
+    }
+}
+
+/// Recent auth failures and denied commands, newest first.
+#[derive(Default)]
+pub(crate) struct AclLog {
+    entries: VecDeque<AclLogEntry>,
+    next_entry_id: u64,
+}
+
+impl AclLog {
+    /// Records a failure. A failure matching a recent entry only bumps its
+    /// count, so a client retrying in a loop does not flush the log.
+    pub(crate) fn add(
+        &mut self,
+        reason: &str,
+        context: &str,
+        object: &str,
+        username: &str,
+        client_info: &str,
+    ) {
+        let now_ms = current_time_ms();
+
+        let recent_entry = self.entries.iter_mut().find(|entry| {
+            entry.reason == reason
+                && entry.context == context
+                && entry.object == object
+                && entry.username == username
+                && entry.client_info == client_info
+                && now_ms - entry.updated_ms < ACL_LOG_GROUPING_MS
+        });
+        if let Some(entry) = recent_entry {
+            entry.count += 1;
+            entry.updated_ms = now_ms;
+            return;
+        }
+
+        self.entries.push_front(AclLogEntry {
+            count: 1,
+            reason: reason.to_string(),
+            context: context.to_string(),
+            object: object.to_string(),
+            username: username.to_string(),
+            client_info: client_info.to_string(),
+            entry_id: self.next_entry_id,
+            created_ms: now_ms,
+            updated_ms: now_ms,
+        });
+        self.next_entry_id += 1;
+        self.entries.truncate(ACL_LOG_MAX_LEN);
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn to_resp(&self, count: Option<usize>) -> RespValue {
+        let now_ms = current_time_ms();
+        RespValue::Array(
+            self.entries
+                .iter()
+                .take(count.unwrap_or(usize::MAX))
+                .map(|entry| entry.to_resp(now_ms))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user_with(rules: &[&str]) -> User {
+        let mut user = User::default();
+        for rule in rules {
+            user.apply_rule(rule).unwrap();
+        }
+        user
+    }
+
+    #[test]
+    fn test_default_user() {
+        let user = User::new_default(None);
+        assert_eq!(vec!["on", "nopass"], user.flags());
+        assert!(user.check_password("anything"));
+        assert_eq!(
+            "user default on nopass ~* &* +@all",
+            user.describe("default")
+        );
+
+        let user = User::new_default(Some("secret"));
+        assert!(user.check_password("secret"));
+        assert!(!user.check_password("other"));
+    }
+
+    #[test]
+    fn test_passwords() {
+        let mut user = user_with(&["on", ">one", ">two"]);
+        assert!(user.check_password("one"));
+        assert!(user.check_password("two"));
+
+        user.apply_rule("<one").unwrap();
+        assert!(!user.check_password("one"));
+        assert!(user.apply_rule("<one").is_err());
+
+        user.apply_rule(&format!("#{}", digest("three"))).unwrap();
+        assert!(user.check_password("three"));
+        assert!(user.apply_rule("#nothex").is_err());
+
+        user.apply_rule("resetpass").unwrap();
+        assert!(!user.check_password("two"));
+        assert!(!user.check_password(""));
+    }
+
+    #[test]
+    fn test_command_rules() {
+        let user = user_with(&["+@all", "-keys", "-acl|setuser"]);
+        assert!(user.is_command_allowed("get"));
+        assert!(!user.is_command_allowed("keys"));
+        assert!(!user.is_command_allowed("acl|setuser"));
+        assert!(user.is_command_allowed("acl|whoami"));
+
+        let user = user_with(&["+@read", "-@list", "+llen", "+acl"]);
+        assert!(user.is_command_allowed("get"));
+        assert!(!user.is_command_allowed("lrange"));
+        assert!(user.is_command_allowed("llen"));
+        assert!(!user.is_command_allowed("set"));
+        assert!(user.is_command_allowed("acl|getuser"));
+        assert_eq!(
+            "-@all +@read -@list +llen +acl",
+            user.commands_description()
+        );
+
+        assert!(User::default().apply_rule("+nosuchcommand").is_err());
+        assert!(User::default().apply_rule("+@nosuchcategory").is_err());
+        assert!(User::default().apply_rule("bogus").is_err());
+    }
+
+    #[test]
+    fn test_key_and_channel_rules() {
+        let user = user_with(&["+@all", "~app:*", "%R~shared:*", "&news.*"]);
+        assert!(user.check(&Command::Get("app:1".into())).is_ok());
+        assert!(user.check(&Command::Get("shared:1".into())).is_ok());
+        assert_eq!(
+            Err(AclDenial::Key("shared:1".into())),
+            user.check(&Command::Set("shared:1".into(), "v".into(), None))
+        );
+        assert_eq!(
+            Err(AclDenial::Key("other".into())),
+            user.check(&Command::Get("other".into()))
+        );
+        assert!(user
+            .check(&Command::Publish("news.tech".into(), "hi".into()))
+            .is_ok());
+        assert_eq!(
+            Err(AclDenial::Channel("sports".into())),
+            user.check(&Command::Publish("sports".into(), "hi".into()))
+        );
+        assert_eq!(
+            "user u off ~app:* %R~shared:* &news.* +@all",
+            user.describe("u")
+        );
+    }
+
+    #[test]
+    fn test_acl_file_round_trip() {
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), User::new_default(None));
+        users.insert(
+            "alice".to_string(),
+            user_with(&["on", ">pw", "%R~app:*", "&news", "+@read", "-keys"]),
+        );
+
+        let content = format_acl_file(&users);
+        let parsed = parse_acl_file("users.acl", &content).unwrap();
+        assert_eq!(content, format_acl_file(&parsed));
+        assert!(parsed["alice"].check_password("pw"));
+
+        assert_eq!(
+            Err("ERR users.acl:2: line should start with user keyword".to_string()),
+            parse_acl_file("users.acl", "user a on\nbogus\n")
+        );
+        assert_eq!(
+            Err("ERR users.acl:1: Error in applying operation '+nope': Unknown command or category name in ACL".to_string()),
+            parse_acl_file("users.acl", "user a on +nope\n")
+        );
+        assert_eq!(
+            Err("ERR users.acl:2: Duplicate user 'a' found".to_string()),
+            parse_acl_file("users.acl", "user a on\nuser a off\n")
+        );
+    }
+
+    #[test]
+    fn test_acl_log_groups_entries() {
+        let mut log = AclLog::default();
+        log.add("auth", "toplevel", "AUTH", "alice", "id=1");
+        log.add("auth", "toplevel", "AUTH", "alice", "id=1");
+        log.add("key", "multi", "secret", "bob", "id=2");
+        assert_eq!(2, log.entries.len());
+        assert_eq!(1, log.entries[0].entry_id);
+        assert_eq!(2, log.entries[1].count);
+
+        for i in 0..ACL_LOG_MAX_LEN {
+            log.add("command", "toplevel", &i.to_string(), "bob", "id=2");
+        }
+        assert_eq!(ACL_LOG_MAX_LEN, log.entries.len());
+
+        log.reset();
+        assert_eq!(RespValue::Array(vec![]), log.to_resp(None));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut user = user_with(&["on", "nopass", "allkeys", "allchannels", "allcommands"]);
+        user.apply_rule("reset").unwrap();
+        assert_eq!("user u off resetchannels -@all", user.describe("u"));
+        assert_eq!(
+            Err(AclDenial::Command("ping".into())),
+            user.check(&Command::Ping)
+        );
+    }
+}
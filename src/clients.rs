@@ -0,0 +1,394 @@
+use std::{collections::BTreeMap, fmt};
+
+use tokio::{sync::watch, time::Instant};
+
+/// Connection type, as CLIENT LIST and CLIENT KILL filter it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ClientType {
+    Normal,
+    Master,
+    Replica,
+    Pubsub,
+}
+
+impl ClientType {
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        match raw.to_lowercase().as_str() {
+            "normal" => Ok(Self::Normal),
+            "master" => Ok(Self::Master),
+            "replica" | "slave" => Ok(Self::Replica),
+            "pubsub" => Ok(Self::Pubsub),
+            _ => Err(format!("ERR Unknown client type '{}'", raw)),
+        }
+    }
+}
+
+/// What CLIENT PAUSE holds back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PauseMode {
+    /// Commands that may write, publish or replicate.
+    Write,
+    All,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Pause {
+    pub(crate) until: Instant,
+    pub(crate) mode: PauseMode,
+}
+
+impl Pause {
+    /// Whether a command is held back now. `may_write` tells if it may write.
+    pub(crate) fn holds(&self, may_write: bool) -> bool {
+        self.until > Instant::now() && (self.mode == PauseMode::All || may_write)
+    }
+}
+
+/// Filters of CLIENT KILL, all of which must match.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ClientKillFilter {
+    pub(crate) id: Option<u64>,
+    pub(crate) addr: Option<String>,
+    pub(crate) laddr: Option<String>,
+    pub(crate) user: Option<String>,
+    pub(crate) client_type: Option<ClientType>,
+    /// Spare the client sending the command.
+    pub(crate) skipme: bool,
+    /// Only clients connected for longer, in seconds.
+    pub(crate) max_age: Option<u64>,
+    /// `CLIENT KILL addr:port`, which replies OK or an error instead of a count.
+    pub(crate) legacy: bool,
+}
+
+impl Default for ClientKillFilter {
+    fn default() -> Self {
+        Self {
+            id: None,
+            addr: None,
+            laddr: None,
+            user: None,
+            client_type: None,
+            skipme: true,
+            max_age: None,
+            legacy: false,
+        }
+    }
+}
+
+impl ClientKillFilter {
+    pub(crate) fn matches(&self, client: &ClientView, current_id: u64) -> bool {
+        self.id.is_none_or(|id| client.id == id)
+            && self.addr.as_ref().is_none_or(|addr| client.addr == *addr)
+            && self
+                .laddr
+                .as_ref()
+                .is_none_or(|laddr| client.laddr == *laddr)
+            && self.user.as_ref().is_none_or(|user| client.user == *user)
+            && self
+                .client_type
+                .is_none_or(|client_type| client.client_type() == client_type)
+            && !(self.skipme && client.id == current_id)
+            && self.max_age.is_none_or(|max_age| client.age > max_age)
+    }
+}
+
+/// Client names are shown in CLIENT LIST, so they can't break its format.
+pub(crate) fn validate_client_name(name: &str) -> Result<(), String> {
+    if name.bytes().any(|byte| !(b'!'..=b'~').contains(&byte)) {
+        return Err(
+            "ERR Client names cannot contain spaces, newlines or special characters.".into(),
+        );
+    }
+    Ok(())
+}
+
+/// A connected client, updated as it runs commands.
+pub(crate) struct Client {
+    addr: String,
+    laddr: String,
+    pub(crate) name: String,
+    pub(crate) user: String,
+    pub(crate) db: usize,
+    created: Instant,
+    last_interaction: Instant,
+    /// Name of the last command, as ACL rules name it.
+    last_command: Option<String>,
+    /// Set once the connection turned into a replication stream.
+    pub(crate) replica: bool,
+    /// Set while a blocking command waits.
+    pub(crate) blocked: bool,
+    pub(crate) no_evict: bool,
+    pub(crate) query_buffer: usize,
+    pub(crate) query_buffer_free: usize,
+    pub(crate) output_buffer: usize,
+    kill: watch::Sender<bool>,
+}
+
+impl Client {
+    /// Records the command the client sent.
+    pub(crate) fn interact(&mut self, command_name: String) {
+        self.last_interaction = Instant::now();
+        self.last_command = Some(command_name);
+    }
+}
+
+/// The connected clients by id, for the CLIENT command.
+#[derive(Default)]
+pub(crate) struct ClientRegistry {
+    clients: BTreeMap<u64, Client>,
+}
+
+impl ClientRegistry {
+    /// Adds a client, giving the receiver the connection watches for CLIENT
+    /// KILL.
+    pub(crate) fn register(
+        &mut self,
+        id: u64,
+        addr: String,
+        laddr: String,
+        user: String,
+    ) -> watch::Receiver<bool> {
+        let (kill, killed) = watch::channel(false);
+        let now = Instant::now();
+        self.clients.insert(
+            id,
+            Client {
+                addr,
+                laddr,
+                name: String::new(),
+                user,
+                db: 0,
+                created: now,
+                last_interaction: now,
+                last_command: None,
+                replica: false,
+                blocked: false,
+                no_evict: false,
+                query_buffer: 0,
+                query_buffer_free: 0,
+                output_buffer: 0,
+                kill,
+            },
+        );
+
+        killed
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.clients.contains_key(&id)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: u64) -> Option<&mut Client> {
+        self.clients.get_mut(&id)
+    }
+
+    /// Asks the connection to close. It does so before reading its next
+    /// command, or right away while it waits.
+    pub(crate) fn kill(&self, id: u64) {
+        if let Some(client) = self.clients.get(&id) {
+            client.kill.send_replace(true);
+        }
+    }
+
+    /// Snapshot of the clients, in id order. State kept elsewhere is left at
+    /// its defaults for the caller to fill in.
+    pub(crate) fn views(&self) -> Vec<ClientView> {
+        self.clients
+            .iter()
+            .map(|(id, client)| ClientView {
+                id: *id,
+                addr: client.addr.clone(),
+                laddr: client.laddr.clone(),
+                name: client.name.clone(),
+                age: client.created.elapsed().as_secs(),
+                idle: client.last_interaction.elapsed().as_secs(),
+                db: client.db,
+                user: client.user.clone(),
+                last_command: client.last_command.clone(),
+                replica: client.replica,
+                blocked: client.blocked,
+                no_evict: client.no_evict,
+                query_buffer: client.query_buffer,
+                query_buffer_free: client.query_buffer_free,
+                output_buffer: client.output_buffer,
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+/// A client as CLIENT LIST and CLIENT INFO show it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientView {
+    pub(crate) id: u64,
+    pub(crate) addr: String,
+    pub(crate) laddr: String,
+    pub(crate) name: String,
+    /// Seconds since the connection was opened.
+    pub(crate) age: u64,
+    /// Seconds since the last command.
+    pub(crate) idle: u64,
+    pub(crate) db: usize,
+    pub(crate) user: String,
+    pub(crate) last_command: Option<String>,
+    pub(crate) replica: bool,
+    pub(crate) blocked: bool,
+    pub(crate) no_evict: bool,
+    pub(crate) subscriptions: usize,
+    pub(crate) pattern_subscriptions: usize,
+    pub(crate) shard_subscriptions: usize,
+    /// Commands queued since MULTI, if a transaction is open.
+    pub(crate) multi: Option<usize>,
+    /// Set when tracking is on, with the redirect target if any.
+    pub(crate) tracking: Option<Option<u64>>,
+    pub(crate) query_buffer: usize,
+    pub(crate) query_buffer_free: usize,
+    pub(crate) output_buffer: usize,
+    /// Pub/sub messages queued for the client, in bytes.
+    pub(crate) output_memory: usize,
+}
+
+impl ClientView {
+    pub(crate) fn client_type(&self) -> ClientType {
+        if self.replica {
+            ClientType::Replica
+        } else if self.subscriptions + self.pattern_subscriptions + self.shard_subscriptions > 0 {
+            ClientType::Pubsub
+        } else {
+            ClientType::Normal
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        if self.replica {
+            flags.push('S');
+        }
+        if self.client_type() == ClientType::Pubsub {
+            flags.push('P');
+        }
+        if self.multi.is_some() {
+            flags.push('x');
+        }
+        if self.blocked {
+            flags.push('b');
+        }
+        if self.tracking.is_some() {
+            flags.push('t');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+}
+
+impl fmt::Display for ClientView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub={} psub={} ssub={} multi={} qbuf={} qbuf-free={} obl={} oll=0 omem={} cmd={} user={} redir={} resp=2",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name,
+            self.age,
+            self.idle,
+            self.flags(),
+            self.db,
+            self.subscriptions,
+            self.pattern_subscriptions,
+            self.shard_subscriptions,
+            self.multi.map_or(-1, |count| count as i64),
+            self.query_buffer,
+            self.query_buffer_free,
+            self.output_buffer,
+            self.output_memory,
+            self.last_command.as_deref().unwrap_or("NULL"),
+            self.user,
+            self.tracking.flatten().map_or(-1, |redirect| redirect as i64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clients::{
+        validate_client_name, ClientKillFilter, ClientRegistry, ClientType, ClientView,
+    };
+
+    #[test]
+    fn test_client_line() {
+        let mut registry = ClientRegistry::default();
+        registry.register(
+            7,
+            "127.0.0.1:5000".into(),
+            "127.0.0.1:6379".into(),
+            "default".into(),
+        );
+        let client = registry.get_mut(7).unwrap();
+        client.name = "worker".into();
+        client.interact("client|list".into());
+
+        let view = ClientView {
+            multi: Some(2),
+            tracking: Some(Some(3)),
+            subscriptions: 1,
+            ..registry.views().remove(0)
+        };
+        assert_eq!(ClientType::Pubsub, view.client_type());
+        assert_eq!(
+            "id=7 addr=127.0.0.1:5000 laddr=127.0.0.1:6379 name=worker age=0 idle=0 flags=Pxt db=0 sub=1 psub=0 ssub=0 multi=2 qbuf=0 qbuf-free=0 obl=0 oll=0 omem=0 cmd=client|list user=default redir=3 resp=2",
+            view.to_string()
+        );
+    }
+
+    #[test]
+    fn test_kill_filter() {
+        let view = ClientView {
+            id: 2,
+            addr: "127.0.0.1:5000".into(),
+            user: "alice".into(),
+            age: 10,
+            ..Default::default()
+        };
+        let filter = |filter: ClientKillFilter| filter.matches(&view, 1);
+
+        assert!(filter(ClientKillFilter::default()));
+        assert!(filter(ClientKillFilter {
+            user: Some("alice".into()),
+            client_type: Some(ClientType::Normal),
+            max_age: Some(5),
+            ..Default::default()
+        }));
+        assert!(!filter(ClientKillFilter {
+            addr: Some("127.0.0.1:5001".into()),
+            ..Default::default()
+        }));
+        assert!(!filter(ClientKillFilter {
+            max_age: Some(10),
+            ..Default::default()
+        }));
+        assert!(!ClientKillFilter::default().matches(&view, 2));
+        assert!(ClientKillFilter {
+            skipme: false,
+            ..Default::default()
+        }
+        .matches(&view, 2));
+    }
+
+    #[test]
+    fn test_client_names() {
+        assert!(validate_client_name("worker-1").is_ok());
+        assert!(validate_client_name("").is_ok());
+        assert!(validate_client_name("two words").is_err());
+        assert!(validate_client_name("line\n").is_err());
+    }
+}
@@ -2,9 +2,12 @@ use core::f64;
 use std::{u128, usize, vec};
 
 use crate::{
+    clients::{ClientKillFilter, ClientType, PauseMode},
     commands::Command,
     common::{CompleteStreamEntryID, RangeStreamEntryID, StreamEntryID},
     resp::RespValue,
+    scripting::FunctionRestorePolicy,
+    tracking::TrackingOptions,
 };
 
 macro_rules! to_number {
@@ -43,6 +46,9 @@ impl CommandParser {
                     }
 
                     if name.to_lowercase() == "set" {
+                        if items.len() < 3 {
+                            return Err("ERR wrong number of arguments for 'set' command".into());
+                        }
                         let Some(key) = items[1].as_string() else {
                             return Err("ERR wrong number of arguments for 'set' command".into());
                         };
@@ -158,14 +164,7 @@ impl CommandParser {
                         }
                         let items_len = items.len();
                         let mut str_items = Self::get_strings_exact(items, items_len, "blpop")?;
-                        let timeout_str = str_items.pop().unwrap();
-                        let mut timeout_secs: f64 = timeout_str
-                            .parse()
-                            .map_err(|_| format!("ERR wrong expiry value for 'blpop' command"))?;
-
-                        if timeout_secs == 0.0 {
-                            timeout_secs = 60.0 * 60.0 * 24.0; // 1 day.
-                        }
+                        let timeout_secs = Self::get_blocking_timeout(&str_items.pop().unwrap())?;
 
                         let keys = str_items.into_iter().skip(1).collect::<Vec<String>>();
                         return Ok(Command::Blpop(keys, timeout_secs));
@@ -177,14 +176,7 @@ impl CommandParser {
                         }
                         let items_len = items.len();
                         let mut str_items = Self::get_strings_exact(items, items_len, "brpop")?;
-                        let timeout_str = str_items.pop().unwrap();
-                        let mut timeout_secs: f64 = timeout_str
-                            .parse()
-                            .map_err(|_| format!("ERR wrong expiry value for 'brpop' command"))?;
-
-                        if timeout_secs == 0.0 {
-                            timeout_secs = 60.0 * 60.0 * 24.0; // 1 day.
-                        }
+                        let timeout_secs = Self::get_blocking_timeout(&str_items.pop().unwrap())?;
 
                         let keys = str_items.into_iter().skip(1).collect::<Vec<String>>();
                         return Ok(Command::Brpop(keys, timeout_secs));
@@ -195,6 +187,39 @@ impl CommandParser {
                         return Ok(Command::Type(str_items.remove(1)));
                     }
 
+                    if name.to_lowercase() == "del" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'del' command".into());
+                        }
+                        let items_len = items.len();
+                        let mut str_items = Self::get_strings_exact(items, items_len, "del")?;
+                        str_items.remove(0); // Word del.
+                        return Ok(Command::Del(str_items));
+                    }
+
+                    if name.to_lowercase() == "object" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'object' command".into());
+                        }
+                        let items_len = items.len();
+                        let mut str_items = Self::get_strings_exact(items, items_len, "object")?;
+                        let sub_command = str_items[1].to_lowercase();
+                        let mut args = str_items.split_off(2);
+
+                        return match (sub_command.as_str(), args.len()) {
+                            ("freq", 1) => Ok(Command::ObjectFreq(args.remove(0))),
+                            ("idletime", 1) => Ok(Command::ObjectIdletime(args.remove(0))),
+                            ("freq" | "idletime", _) => Err(format!(
+                                "ERR wrong number of arguments for 'object|{}' command",
+                                sub_command
+                            )),
+                            _ => Err(format!(
+                                "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+                                str_items[1]
+                            )),
+                        };
+                    }
+
                     if name.to_lowercase() == "xadd" {
                         if items.len() < 5 {
                             return Err("ERR wrong number of arguments for 'xadd' command".into());
@@ -265,7 +290,8 @@ impl CommandParser {
                         let mut count = usize::MAX;
                         let mut blocking_ttl = None;
 
-                        while str_items.len() >= 2 && str_items[0] != "streams" {
+                        while str_items.len() >= 2 && !str_items[0].eq_ignore_ascii_case("streams")
+                        {
                             let setting_name = str_items.remove(0);
 
                             if setting_name.to_lowercase() == "count" {
@@ -273,14 +299,8 @@ impl CommandParser {
                                 count = to_number!(usize, &count_raw, "xread");
                             } else if setting_name.to_lowercase() == "block" {
                                 let blocking_ttl_raw = str_items.remove(0);
-                                let mut blocking_ttl_value =
-                                    to_number!(u128, &blocking_ttl_raw, "xread");
-
-                                if blocking_ttl_value == 0 {
-                                    blocking_ttl_value = 1000 * 60 * 60 * 24; // 1 day;
-                                }
-
-                                blocking_ttl = Some(blocking_ttl_value);
+                                // Zero means blocking forever.
+                                blocking_ttl = Some(to_number!(u128, &blocking_ttl_raw, "xread"));
                             } else {
                                 return Err("ERR invalid setting for 'xread' command".into());
                             }
@@ -338,6 +358,72 @@ impl CommandParser {
                         return Ok(Command::Discard);
                     }
 
+                    if name.to_lowercase() == "watch" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'watch' command".into());
+                        }
+                        let items_len = items.len();
+                        let mut str_items = Self::get_strings_exact(items, items_len, "watch")?;
+                        str_items.remove(0); // Word watch.
+                        return Ok(Command::Watch(str_items));
+                    }
+
+                    if name.to_lowercase() == "unwatch" {
+                        if items.len() != 1 {
+                            return Err(
+                                "ERR wrong number of arguments for 'unwatch' command".into()
+                            );
+                        }
+                        return Ok(Command::Unwatch);
+                    }
+
+                    if name.to_lowercase() == "flushall" || name.to_lowercase() == "flushdb" {
+                        let command_name = name.to_lowercase();
+                        let items_len = items.len();
+                        let str_items = Self::get_strings_exact(items, items_len, &command_name)?;
+                        let lazy = match str_items.len() {
+                            1 => false,
+                            2 if str_items[1].eq_ignore_ascii_case("sync") => false,
+                            2 if str_items[1].eq_ignore_ascii_case("async") => true,
+                            _ => return Err("ERR syntax error".into()),
+                        };
+                        return Ok(if command_name == "flushall" {
+                            Command::Flushall(lazy)
+                        } else {
+                            Command::Flushdb(lazy)
+                        });
+                    }
+
+                    if name.to_lowercase() == "select" {
+                        let str_items = Self::get_strings_exact(items, 2, "select")?;
+                        return Ok(Command::Select(Self::get_db_index(
+                            &str_items[1],
+                            "ERR value is not an integer or out of range",
+                        )?));
+                    }
+
+                    if name.to_lowercase() == "move" {
+                        let mut str_items = Self::get_strings_exact(items, 3, "move")?;
+                        let db = Self::get_db_index(
+                            &str_items[2],
+                            "ERR value is not an integer or out of range",
+                        )?;
+                        return Ok(Command::Move(str_items.remove(1), db));
+                    }
+
+                    if name.to_lowercase() == "swapdb" {
+                        let str_items = Self::get_strings_exact(items, 3, "swapdb")?;
+                        return Ok(Command::Swapdb(
+                            Self::get_db_index(&str_items[1], "ERR invalid first DB index")?,
+                            Self::get_db_index(&str_items[2], "ERR invalid second DB index")?,
+                        ));
+                    }
+
+                    if name.to_lowercase() == "dbsize" {
+                        Self::get_strings_exact(items, 1, "dbsize")?;
+                        return Ok(Command::Dbsize);
+                    }
+
                     if name.to_lowercase() == "info" {
                         let items_len = items.len();
                         let mut str_items = Self::get_strings_exact(items, items_len, "info")?;
@@ -370,19 +456,36 @@ impl CommandParser {
                     }
 
                     if name.to_lowercase() == "config" {
-                        let items_len = items.len();
-                        if items.len() < 3 {
+                        if items.len() < 2 {
                             return Err("ERR wrong number of arguments for 'config' command".into());
                         }
-
-                        let mut str_items = Self::get_strings_exact(items, items_len, "config")?;
-                        str_items.remove(0); // Word config.
-                        let kind = str_items.remove(0);
-                        if kind.to_lowercase() == "get" {
-                            return Ok(Command::GetConfig(str_items));
+                        let mut str_items = vec![];
+                        for item in &items {
+                            str_items.push(Self::get_string(item, "config")?);
                         }
-
-                        return Err("ERR wrong get/set kind for 'config' command".into());
+                        let sub_command = str_items[1].to_lowercase();
+                        let args = str_items.split_off(2);
+
+                        return match sub_command.as_str() {
+                            "get" if !args.is_empty() => Ok(Command::GetConfig(args)),
+                            "set" if !args.is_empty() && args.len() % 2 == 0 => {
+                                Ok(Command::SetConfig(
+                                    args.chunks(2)
+                                        .map(|pair| (pair[0].clone(), pair[1].clone()))
+                                        .collect(),
+                                ))
+                            }
+                            "resetstat" if args.is_empty() => Ok(Command::ResetStatConfig),
+                            "rewrite" if args.is_empty() => Ok(Command::RewriteConfig),
+                            "get" | "set" | "resetstat" | "rewrite" => Err(format!(
+                                "ERR wrong number of arguments for 'config|{}' command",
+                                sub_command
+                            )),
+                            _ => Err(format!(
+                                "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+                                str_items[1]
+                            )),
+                        };
                     }
 
                     if name.to_lowercase() == "keys" {
@@ -403,16 +506,191 @@ impl CommandParser {
                     }
 
                     if name.to_lowercase() == "unsubscribe" {
+                        // Without channels it unsubscribes from all of them.
+                        let items_len = items.len();
+                        let mut str_items =
+                            Self::get_strings_exact(items, items_len, "unsubscribe")?;
+                        str_items.remove(0); // Word unsubscribe.
+                        return Ok(Command::Unsubscribe(str_items));
+                    }
+
+                    if name.to_lowercase() == "psubscribe" {
                         let items_len = items.len();
                         if items_len < 2 {
                             return Err(
-                                "ERR wrong number of arguments for 'unsubscribe' command".into()
+                                "ERR wrong number of arguments for 'psubscribe' command".into()
                             );
                         }
                         let mut str_items =
-                            Self::get_strings_exact(items, items_len, "unsubscribe")?;
-                        str_items.remove(0); // Word unsubscribe.
-                        return Ok(Command::Unsubscribe(str_items));
+                            Self::get_strings_exact(items, items_len, "psubscribe")?;
+                        str_items.remove(0); // Word psubscribe.
+                        return Ok(Command::Psubscribe(str_items));
+                    }
+
+                    if name.to_lowercase() == "punsubscribe" {
+                        let items_len = items.len();
+                        let mut str_items =
+                            Self::get_strings_exact(items, items_len, "punsubscribe")?;
+                        str_items.remove(0); // Word punsubscribe.
+                        return Ok(Command::Punsubscribe(str_items));
+                    }
+
+                    if name.to_lowercase() == "ssubscribe" {
+                        let items_len = items.len();
+                        if items_len < 2 {
+                            return Err(
+                                "ERR wrong number of arguments for 'ssubscribe' command".into()
+                            );
+                        }
+                        let mut str_items =
+                            Self::get_strings_exact(items, items_len, "ssubscribe")?;
+                        str_items.remove(0); // Word ssubscribe.
+                        return Ok(Command::Ssubscribe(str_items));
+                    }
+
+                    if name.to_lowercase() == "sunsubscribe" {
+                        let items_len = items.len();
+                        let mut str_items =
+                            Self::get_strings_exact(items, items_len, "sunsubscribe")?;
+                        str_items.remove(0); // Word sunsubscribe.
+                        return Ok(Command::Sunsubscribe(str_items));
+                    }
+
+                    if name.to_lowercase() == "spublish" {
+                        let mut str_items = Self::get_strings_exact(items, 3, "spublish")?;
+                        str_items.remove(0); // Word spublish.
+                        let channel = str_items.remove(0);
+                        let message = str_items.remove(0);
+                        return Ok(Command::Spublish(channel, message));
+                    }
+
+                    if name.to_lowercase() == "quit" {
+                        return Ok(Command::Quit);
+                    }
+
+                    if name.to_lowercase() == "pubsub" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'pubsub' command".into());
+                        }
+                        let mut str_items = vec![];
+                        for item in &items {
+                            str_items.push(Self::get_string(item, "pubsub")?);
+                        }
+                        let sub_command = str_items[1].to_lowercase();
+                        let mut args = str_items.split_off(2);
+
+                        return match sub_command.as_str() {
+                            "channels" | "shardchannels" if args.len() <= 1 => {
+                                let pattern = args.pop();
+                                if sub_command == "channels" {
+                                    Ok(Command::PubsubChannels(pattern))
+                                } else {
+                                    Ok(Command::PubsubShardchannels(pattern))
+                                }
+                            }
+                            "numsub" => Ok(Command::PubsubNumsub(args)),
+                            "shardnumsub" => Ok(Command::PubsubShardnumsub(args)),
+                            "numpat" if args.is_empty() => Ok(Command::PubsubNumpat),
+                            "channels" | "shardchannels" | "numpat" => Err(format!(
+                                "ERR wrong number of arguments for 'pubsub {}' command",
+                                sub_command
+                            )),
+                            _ => Err(format!(
+                                "ERR unknown subcommand '{}' for 'pubsub' command",
+                                str_items[1]
+                            )),
+                        };
+                    }
+
+                    if name.to_lowercase() == "client" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'client' command".into());
+                        }
+                        let mut str_items = vec![];
+                        for item in &items {
+                            str_items.push(Self::get_string(item, "client")?);
+                        }
+                        let sub_command = str_items[1].to_lowercase();
+                        let args = str_items.split_off(2);
+
+                        return match (sub_command.as_str(), args.len()) {
+                            ("id", 0) => Ok(Command::ClientId),
+                            ("info", 0) => Ok(Command::ClientInfo),
+                            ("getname", 0) => Ok(Command::ClientGetname),
+                            ("unpause", 0) => Ok(Command::ClientUnpause),
+                            ("setname", 1) => Ok(Command::ClientSetname(args[0].clone())),
+                            ("list", 0) => Ok(Command::ClientList(None, vec![])),
+                            ("list", 2) if args[0].eq_ignore_ascii_case("type") => Ok(
+                                Command::ClientList(Some(ClientType::parse(&args[1])?), vec![]),
+                            ),
+                            ("list", 2..) if args[0].eq_ignore_ascii_case("id") => {
+                                let mut ids = vec![];
+                                for raw in &args[1..] {
+                                    ids.push(
+                                        raw.parse::<u64>()
+                                            .map_err(|_| "ERR Invalid client ID".to_string())?,
+                                    );
+                                }
+                                Ok(Command::ClientList(None, ids))
+                            }
+                            ("list", _) => Err("ERR syntax error".into()),
+                            ("kill", 1..) => Ok(Command::ClientKill(Self::get_kill_filter(&args)?)),
+                            ("pause", 1 | 2) => {
+                                let timeout_ms = args[0].parse::<i64>().map_err(|_| {
+                                    "ERR timeout is not an integer or out of range".to_string()
+                                })?;
+                                if timeout_ms < 0 {
+                                    return Err("ERR timeout is negative".into());
+                                }
+                                let mode = match args.get(1).map(|mode| mode.to_lowercase()) {
+                                    None => PauseMode::All,
+                                    Some(mode) if mode == "all" => PauseMode::All,
+                                    Some(mode) if mode == "write" => PauseMode::Write,
+                                    Some(_) => return Err("ERR syntax error".into()),
+                                };
+                                Ok(Command::ClientPause(timeout_ms as u64, mode))
+                            }
+                            ("no-evict", 1) => match args[0].to_lowercase().as_str() {
+                                "on" => Ok(Command::ClientNoEvict(true)),
+                                "off" => Ok(Command::ClientNoEvict(false)),
+                                _ => Err("ERR syntax error".into()),
+                            },
+                            ("tracking", 1..) => {
+                                let on = match args[0].to_lowercase().as_str() {
+                                    "on" => true,
+                                    "off" => false,
+                                    _ => return Err("ERR syntax error".into()),
+                                };
+                                Ok(Command::ClientTracking(
+                                    on,
+                                    Self::get_tracking_options(&args[1..])?,
+                                ))
+                            }
+                            ("caching", 1) => match args[0].to_lowercase().as_str() {
+                                "yes" => Ok(Command::ClientCaching(true)),
+                                "no" => Ok(Command::ClientCaching(false)),
+                                _ => Err("ERR syntax error".into()),
+                            },
+                            (
+                                "id" | "info" | "getname" | "unpause" | "setname" | "kill"
+                                | "pause" | "no-evict" | "tracking" | "caching",
+                                _,
+                            ) => Err(format!(
+                                "ERR wrong number of arguments for 'client {}' command",
+                                sub_command
+                            )),
+                            _ => Err(format!(
+                                "ERR unknown subcommand '{}' for 'client' command",
+                                str_items[1]
+                            )),
+                        };
+                    }
+
+                    if name.to_lowercase() == "reset" {
+                        if items.len() != 1 {
+                            return Err("ERR wrong number of arguments for 'reset' command".into());
+                        }
+                        return Ok(Command::Reset);
                     }
 
                     if name.to_lowercase() == "publish" {
@@ -573,34 +851,328 @@ impl CommandParser {
                         return Ok(Command::Geosearch(key, (lon, lat), radius));
                     }
 
-                    if name.to_ascii_lowercase() == "acl" {
-                        if items.len() > 1 {
-                            if let Some(sub_command) = items[1].as_string().cloned() {
-                                if sub_command.to_lowercase() == "whoami" {
-                                    return Ok(Command::AclWhoami);
+                    if name.to_lowercase() == "eval" || name.to_lowercase() == "evalsha" {
+                        let (script, keys, args) = Self::get_script_call(items, &name)?;
+
+                        if name.to_lowercase() == "eval" {
+                            return Ok(Command::Eval(script, keys, args));
+                        } else {
+                            return Ok(Command::Evalsha(script.to_lowercase(), keys, args));
+                        }
+                    }
+
+                    if name.to_lowercase() == "script" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'script' command".into());
+                        }
+                        let sub_command = Self::get_string(&items[1], "script")?;
+
+                        if sub_command.to_lowercase() == "load" {
+                            let mut str_items = Self::get_strings_exact(items, 3, "script load")?;
+                            return Ok(Command::ScriptLoad(str_items.remove(2)));
+                        }
+
+                        if sub_command.to_lowercase() == "exists" {
+                            if items.len() < 3 {
+                                return Err(
+                                    "ERR wrong number of arguments for 'script exists' command"
+                                        .into(),
+                                );
+                            }
+                            let mut shas = vec![];
+                            for item in &items[2..] {
+                                shas.push(Self::get_string(item, "script exists")?.to_lowercase());
+                            }
+                            return Ok(Command::ScriptExists(shas));
+                        }
+
+                        if sub_command.to_lowercase() == "flush" {
+                            // The flush is always synchronous, so the mode is only validated.
+                            if items.len() > 3 {
+                                return Err(
+                                    "ERR wrong number of arguments for 'script flush' command"
+                                        .into(),
+                                );
+                            }
+                            if items.len() == 3 {
+                                let mode = Self::get_string(&items[2], "script flush")?;
+                                if mode.to_lowercase() != "sync" && mode.to_lowercase() != "async" {
+                                    return Err("ERR syntax error".into());
                                 }
+                            }
+                            return Ok(Command::ScriptFlush);
+                        }
+
+                        if sub_command.to_lowercase() == "kill" {
+                            Self::get_strings_exact(items, 2, "script kill")?;
+                            return Ok(Command::ScriptKill);
+                        }
+
+                        return Err(format!(
+                            "ERR unknown subcommand '{}' for 'script' command",
+                            sub_command
+                        ));
+                    }
 
-                                if sub_command.to_lowercase() == "getuser" {
-                                    return Ok(Command::AclGetuser(Self::get_string(
-                                        &items[2],
-                                        "acl getuser",
-                                    )?));
+                    if name.to_lowercase() == "fcall" || name.to_lowercase() == "fcall_ro" {
+                        let (function, keys, args) = Self::get_script_call(items, &name)?;
+
+                        if name.to_lowercase() == "fcall" {
+                            return Ok(Command::Fcall(function, keys, args));
+                        } else {
+                            return Ok(Command::FcallRo(function, keys, args));
+                        }
+                    }
+
+                    if name.to_lowercase() == "function" {
+                        if items.len() < 2 {
+                            return Err(
+                                "ERR wrong number of arguments for 'function' command".into()
+                            );
+                        }
+                        let mut str_items = vec![];
+                        for item in &items {
+                            str_items.push(Self::get_string(item, "function")?);
+                        }
+                        let sub_command = str_items[1].to_lowercase();
+                        let options = str_items[2..]
+                            .iter()
+                            .map(|option| option.to_lowercase())
+                            .collect::<Vec<_>>();
+
+                        if sub_command == "load" {
+                            return match options.as_slice() {
+                                [_] => Ok(Command::FunctionLoad(str_items.remove(2), false)),
+                                [replace, _] if replace == "replace" => {
+                                    Ok(Command::FunctionLoad(str_items.remove(3), true))
                                 }
+                                _ => Err("ERR syntax error".into()),
+                            };
+                        }
+
+                        if sub_command == "delete" {
+                            let mut str_items =
+                                Self::get_strings_exact(items, 3, "function delete")?;
+                            return Ok(Command::FunctionDelete(str_items.remove(2)));
+                        }
 
-                                if sub_command.to_lowercase() == "setuser" {
-                                    let mut str_items =
-                                        Self::get_strings_exact(items, 4, "acl setuser")?;
-                                    let user = str_items.remove(2);
-                                    let password_part = str_items.remove(2);
-                                    if &password_part[0..=0] == ">" {
-                                        return Ok(Command::AclSetuser(
-                                            user,
-                                            password_part[1..].to_string(),
-                                        ));
+                        if sub_command == "flush" {
+                            // The flush is always synchronous, so the mode is only validated.
+                            return match options.as_slice() {
+                                [] => Ok(Command::FunctionFlush),
+                                [mode] if mode == "sync" || mode == "async" => {
+                                    Ok(Command::FunctionFlush)
+                                }
+                                _ => Err("ERR syntax error".into()),
+                            };
+                        }
+
+                        if sub_command == "list" {
+                            let mut pattern = None;
+                            let mut with_code = false;
+                            let mut i = 2;
+                            while i < str_items.len() {
+                                match str_items[i].to_lowercase().as_str() {
+                                    "withcode" => with_code = true,
+                                    "libraryname" if i + 1 < str_items.len() => {
+                                        pattern = Some(str_items[i + 1].clone());
+                                        i += 1;
                                     }
+                                    _ => return Err("ERR syntax error".into()),
                                 }
+                                i += 1;
+                            }
+                            return Ok(Command::FunctionList(pattern, with_code));
+                        }
+
+                        if sub_command == "dump" {
+                            Self::get_strings_exact(items, 2, "function dump")?;
+                            return Ok(Command::FunctionDump);
+                        }
+
+                        if sub_command == "restore" {
+                            let policy = match options.get(1).map(|option| option.as_str()) {
+                                None | Some("append") => FunctionRestorePolicy::Append,
+                                Some("replace") => FunctionRestorePolicy::Replace,
+                                Some("flush") => FunctionRestorePolicy::Flush,
+                                Some(_) => return Err("ERR syntax error".into()),
+                            };
+                            if options.is_empty() || options.len() > 2 {
+                                return Err(
+                                    "ERR wrong number of arguments for 'function restore' command"
+                                        .into(),
+                                );
                             }
+                            return Ok(Command::FunctionRestore(str_items.remove(2), policy));
                         }
+
+                        return Err(format!(
+                            "ERR unknown subcommand '{}' for 'function' command",
+                            str_items[1]
+                        ));
+                    }
+
+                    if name.to_lowercase() == "auth" {
+                        let items_len = items.len();
+                        let mut str_items = match items_len {
+                            2 | 3 => Self::get_strings_exact(items, items_len, "auth")?,
+                            _ => {
+                                return Err(
+                                    "ERR wrong number of arguments for 'auth' command".into()
+                                )
+                            }
+                        };
+                        let password = str_items.pop().unwrap();
+                        let user = if str_items.len() == 2 {
+                            str_items.pop()
+                        } else {
+                            None
+                        };
+                        return Ok(Command::Auth(user, password));
+                    }
+
+                    if name.to_lowercase() == "save" {
+                        Self::get_strings_exact(items, 1, "save")?;
+                        return Ok(Command::Save);
+                    }
+
+                    if name.to_ascii_lowercase() == "acl" {
+                        if items.len() < 2 {
+                            return Err("ERR wrong number of arguments for 'acl' command".into());
+                        }
+                        let sub_command = Self::get_string(&items[1], "acl")?.to_lowercase();
+
+                        if sub_command == "whoami" {
+                            Self::get_strings_exact(items, 2, "acl whoami")?;
+                            return Ok(Command::AclWhoami);
+                        }
+
+                        if sub_command == "getuser" {
+                            let mut str_items = Self::get_strings_exact(items, 3, "acl getuser")?;
+                            return Ok(Command::AclGetuser(str_items.remove(2)));
+                        }
+
+                        if sub_command == "setuser" {
+                            if items.len() < 3 {
+                                return Err(
+                                    "ERR wrong number of arguments for 'acl setuser' command"
+                                        .into(),
+                                );
+                            }
+                            let mut str_items = vec![];
+                            for item in &items[2..] {
+                                str_items.push(Self::get_string(item, "acl setuser")?);
+                            }
+                            let user = str_items.remove(0);
+                            return Ok(Command::AclSetuser(user, str_items));
+                        }
+
+                        if sub_command == "deluser" {
+                            if items.len() < 3 {
+                                return Err(
+                                    "ERR wrong number of arguments for 'acl deluser' command"
+                                        .into(),
+                                );
+                            }
+                            let mut users = vec![];
+                            for item in &items[2..] {
+                                users.push(Self::get_string(item, "acl deluser")?);
+                            }
+                            return Ok(Command::AclDeluser(users));
+                        }
+
+                        if sub_command == "list" {
+                            Self::get_strings_exact(items, 2, "acl list")?;
+                            return Ok(Command::AclList);
+                        }
+
+                        if sub_command == "users" {
+                            Self::get_strings_exact(items, 2, "acl users")?;
+                            return Ok(Command::AclUsers);
+                        }
+
+                        if sub_command == "cat" {
+                            return match items.len() {
+                                2 => Ok(Command::AclCat(None)),
+                                3 => Ok(Command::AclCat(Some(
+                                    Self::get_string(&items[2], "acl cat")?.to_lowercase(),
+                                ))),
+                                _ => {
+                                    Err("ERR wrong number of arguments for 'acl cat' command"
+                                        .into())
+                                }
+                            };
+                        }
+
+                        if sub_command == "dryrun" {
+                            if items.len() < 4 {
+                                return Err(
+                                    "ERR wrong number of arguments for 'acl dryrun' command".into(),
+                                );
+                            }
+                            let mut items = items;
+                            let command_items = items.split_off(3);
+                            let user = Self::get_string(&items[2], "acl dryrun")?;
+                            return match Self::parse(RespValue::Array(command_items))? {
+                                Command::Unknown(command_name) => {
+                                    Err(format!("ERR Command '{}' not found", command_name))
+                                }
+                                command => Ok(Command::AclDryrun(user, Box::new(command))),
+                            };
+                        }
+
+                        if sub_command == "genpass" {
+                            return match items.len() {
+                                2 => Ok(Command::AclGenpass(None)),
+                                3 => {
+                                    let bits = Self::get_string(&items[2], "acl genpass")?
+                                        .parse::<usize>()
+                                        .ok()
+                                        .filter(|bits| (1..=4096).contains(bits))
+                                        .ok_or("ERR ACL GENPASS argument must be the number of bits for the output password, a positive number up to 4096".to_string())?;
+                                    Ok(Command::AclGenpass(Some(bits)))
+                                }
+                                _ => Err("ERR wrong number of arguments for 'acl genpass' command"
+                                    .into()),
+                            };
+                        }
+
+                        if sub_command == "load" {
+                            Self::get_strings_exact(items, 2, "acl load")?;
+                            return Ok(Command::AclLoad);
+                        }
+
+                        if sub_command == "save" {
+                            Self::get_strings_exact(items, 2, "acl save")?;
+                            return Ok(Command::AclSave);
+                        }
+
+                        if sub_command == "log" {
+                            return match items.len() {
+                                2 => Ok(Command::AclLog(None)),
+                                3 => {
+                                    let arg = Self::get_string(&items[2], "acl log")?;
+                                    if arg.to_lowercase() == "reset" {
+                                        Ok(Command::AclLogReset)
+                                    } else {
+                                        let count = arg.parse::<usize>().map_err(|_| {
+                                            "ERR value is out of range, must be positive"
+                                                .to_string()
+                                        })?;
+                                        Ok(Command::AclLog(Some(count)))
+                                    }
+                                }
+                                _ => {
+                                    Err("ERR wrong number of arguments for 'acl log' command"
+                                        .into())
+                                }
+                            };
+                        }
+
+                        return Err(format!(
+                            "ERR unknown subcommand '{}' for 'acl' command",
+                            sub_command
+                        ));
                     }
 
                     return Ok(Command::Unknown(name.to_lowercase()));
@@ -619,6 +1191,110 @@ impl CommandParser {
         ))
     }
 
+    /// Seconds a blocking command may wait, 0 for ever.
+    fn get_blocking_timeout(raw: &str) -> Result<f64, String> {
+        let secs = raw
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && secs * 1000.0 <= i64::MAX as f64)
+            .ok_or("ERR timeout is not a float or out of range")?;
+        if secs < 0.0 {
+            return Err("ERR timeout is negative".into());
+        }
+
+        Ok(secs)
+    }
+
+    /// Database index argument. Whether it exists is checked on execution.
+    fn get_db_index(raw: &str, not_integer_error: &str) -> Result<usize, String> {
+        match raw.parse::<i64>() {
+            Ok(index) if index < 0 => Err("ERR DB index is out of range".into()),
+            Ok(index) => Ok(index as usize),
+            Err(_) => Err(not_integer_error.into()),
+        }
+    }
+
+    /// Filters of `CLIENT KILL`, or the address of its older form.
+    fn get_kill_filter(args: &[String]) -> Result<ClientKillFilter, String> {
+        if let [addr] = args {
+            return Ok(ClientKillFilter {
+                addr: Some(addr.clone()),
+                skipme: false,
+                legacy: true,
+                ..Default::default()
+            });
+        }
+
+        let mut filter = ClientKillFilter::default();
+        for pair in args.chunks(2) {
+            let [name, value] = pair else {
+                return Err("ERR syntax error".into());
+            };
+            match name.to_lowercase().as_str() {
+                "id" => match value.parse::<u64>() {
+                    Ok(id) if id > 0 => filter.id = Some(id),
+                    _ => return Err("ERR client-id should be greater than 0".into()),
+                },
+                "addr" => filter.addr = Some(value.clone()),
+                "laddr" => filter.laddr = Some(value.clone()),
+                "user" => filter.user = Some(value.clone()),
+                "type" => filter.client_type = Some(ClientType::parse(value)?),
+                "skipme" => match value.to_lowercase().as_str() {
+                    "yes" => filter.skipme = true,
+                    "no" => filter.skipme = false,
+                    _ => return Err("ERR syntax error".into()),
+                },
+                "maxage" => {
+                    filter.max_age =
+                        Some(value.parse::<u64>().map_err(|_| {
+                            "ERR value is not an integer or out of range".to_string()
+                        })?)
+                }
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Options of `CLIENT TRACKING on|off`. Whether the redirect target exists
+    /// is checked on execution.
+    fn get_tracking_options(args: &[String]) -> Result<TrackingOptions, String> {
+        let mut options = TrackingOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.to_lowercase().as_str() {
+                "redirect" => {
+                    let Some(raw) = args.next() else {
+                        return Err("ERR syntax error".into());
+                    };
+                    if options.redirect.is_some() {
+                        return Err(
+                            "ERR A client can only redirect to a single other client".into()
+                        );
+                    }
+                    options.redirect =
+                        Some(raw.parse::<u64>().map_err(|_| {
+                            "ERR value is not an integer or out of range".to_string()
+                        })?);
+                }
+                "prefix" => {
+                    let Some(prefix) = args.next() else {
+                        return Err("ERR syntax error".into());
+                    };
+                    options.prefixes.push(prefix.clone());
+                }
+                "bcast" => options.bcast = true,
+                "optin" => options.optin = true,
+                "optout" => options.optout = true,
+                "noloop" => options.noloop = true,
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+
+        Ok(options)
+    }
+
     fn get_strings_exact(
         values: Vec<RespValue>,
         n: usize,
@@ -645,6 +1321,37 @@ impl CommandParser {
         Ok(out)
     }
 
+    /// Splits `EVAL script numkeys key [key ...] arg [arg ...]` into its parts.
+    fn get_script_call(
+        values: Vec<RespValue>,
+        command_name: &str,
+    ) -> Result<(String, Vec<String>, Vec<String>), String> {
+        if values.len() < 3 {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                command_name.to_lowercase()
+            ));
+        }
+
+        let mut str_items = vec![];
+        for value in values {
+            str_items.push(Self::get_string(&value, command_name)?);
+        }
+
+        let numkeys = str_items[2]
+            .parse::<usize>()
+            .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+        if numkeys > str_items.len() - 3 {
+            return Err("ERR Number of keys can't be greater than number of args".into());
+        }
+
+        let args = str_items.split_off(3 + numkeys);
+        let keys = str_items.split_off(3);
+        let script = str_items.remove(1);
+
+        Ok((script, keys, args))
+    }
+
     fn stream_entry_id_from_raw(raw: &str) -> Result<StreamEntryID, String> {
         if raw == "*" {
             return Ok(StreamEntryID::Wildcard);
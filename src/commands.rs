@@ -1,6 +1,10 @@
 use crate::{
+    acl::KeyAccess,
+    clients::{ClientKillFilter, ClientType, PauseMode},
     common::{KeyValuePair, RangeStreamEntryID, StreamEntryID},
     resp::RespValue,
+    scripting::FunctionRestorePolicy,
+    tracking::TrackingOptions,
 };
 
 #[derive(Debug, Clone)]
@@ -17,16 +21,37 @@ pub(crate) enum Command {
     Rpop(String),
     Lpopn(String, usize),
     Rpopn(String, usize),
-    Blpop(Vec<String>, f64),
-    Brpop(Vec<String>, f64),
+    Blpop(
+        Vec<String>,
+        f64, /* Timeout secs, zero blocks forever */
+    ),
+    Brpop(
+        Vec<String>,
+        f64, /* Timeout secs, zero blocks forever */
+    ),
     Type(String),
+    Del(Vec<String> /* Keys */),
+    ObjectFreq(String /* Key */),
+    ObjectIdletime(String /* Key */),
     Xadd(String, StreamEntryID, Vec<KeyValuePair>),
     Xrange(String, RangeStreamEntryID, RangeStreamEntryID, usize),
-    Xread(Vec<(String, RangeStreamEntryID)>, usize, Option<u128>),
+    Xread(
+        Vec<(String, RangeStreamEntryID)>,
+        usize,        /* Count */
+        Option<u128>, /* Block ms, zero blocks forever */
+    ),
     Incr(String),
     Multi,
     Exec,
     Discard,
+    Watch(Vec<String> /* Keys */),
+    Unwatch,
+    Flushall(bool /* Async */),
+    Flushdb(bool /* Async */),
+    Select(usize),
+    Move(String, usize /* Destination db */),
+    Swapdb(usize, usize),
+    Dbsize,
     Info(Vec<String>),
     Replconf(Vec<String>),
     Psync(String, i64),
@@ -35,10 +60,39 @@ pub(crate) enum Command {
         u128,  /* timeout ms */
     ),
     GetConfig(Vec<String> /* Arguments */),
+    SetConfig(Vec<(String /* Parameter */, String /* Value */)>),
+    ResetStatConfig,
+    RewriteConfig,
     Keys(String /* Pattern */),
     Subscribe(Vec<String> /* Channels */),
     Unsubscribe(Vec<String> /* Channels */),
     Publish(String /* Channel */, String /* Message */),
+    Psubscribe(Vec<String> /* Patterns */),
+    Punsubscribe(Vec<String> /* Patterns */),
+    Ssubscribe(Vec<String> /* Shard channels */),
+    Sunsubscribe(Vec<String> /* Shard channels */),
+    Spublish(String /* Shard channel */, String /* Message */),
+    Quit,
+    Reset,
+    PubsubChannels(Option<String> /* Pattern */),
+    PubsubNumsub(Vec<String> /* Channels */),
+    PubsubNumpat,
+    PubsubShardchannels(Option<String> /* Pattern */),
+    PubsubShardnumsub(Vec<String> /* Shard channels */),
+    ClientId,
+    ClientTracking(bool /* On */, TrackingOptions),
+    ClientCaching(bool /* Yes */),
+    ClientList(
+        Option<ClientType>,
+        Vec<u64>, /* Ids, all clients when empty */
+    ),
+    ClientInfo,
+    ClientSetname(String),
+    ClientGetname,
+    ClientKill(ClientKillFilter),
+    ClientPause(u64 /* Timeout ms */, PauseMode),
+    ClientUnpause,
+    ClientNoEvict(bool /* On */),
     Zadd(
         String, /* Key */
         Vec<(f64 /* Score */, String /* Member */)>,
@@ -69,7 +123,52 @@ pub(crate) enum Command {
     ),
     AclWhoami,
     AclGetuser(String /* User */),
-    AclSetuser(String /* User */, String /* Password */),
+    AclSetuser(String /* User */, Vec<String> /* Rules */),
+    AclDeluser(Vec<String> /* Users */),
+    AclList,
+    AclUsers,
+    AclCat(Option<String> /* Category */),
+    AclDryrun(String /* User */, Box<Command>),
+    AclGenpass(Option<usize> /* Bits */),
+    AclLoad,
+    AclSave,
+    AclLog(Option<usize> /* Count */),
+    AclLogReset,
+    Auth(Option<String> /* User */, String /* Password */),
+    Eval(
+        String,      /* Script */
+        Vec<String>, /* Keys */
+        Vec<String>, /* Args */
+    ),
+    Evalsha(
+        String,      /* Sha1 */
+        Vec<String>, /* Keys */
+        Vec<String>, /* Args */
+    ),
+    ScriptLoad(String /* Script */),
+    ScriptExists(Vec<String> /* Sha1s */),
+    ScriptFlush,
+    ScriptKill,
+    FunctionLoad(String /* Code */, bool /* Replace */),
+    FunctionDelete(String /* Library */),
+    FunctionFlush,
+    FunctionList(
+        Option<String>, /* Library name pattern */
+        bool,           /* With code */
+    ),
+    FunctionDump,
+    FunctionRestore(String /* Hex payload */, FunctionRestorePolicy),
+    Fcall(
+        String,      /* Function */
+        Vec<String>, /* Keys */
+        Vec<String>, /* Args */
+    ),
+    FcallRo(
+        String,      /* Function */
+        Vec<String>, /* Keys */
+        Vec<String>, /* Args */
+    ),
+    Save,
     // ---
     Unknown(String),
 }
@@ -110,13 +209,101 @@ impl Command {
         }
     }
 
+    pub(crate) fn is_auth(&self) -> bool {
+        matches!(self, Command::Auth(_, _))
+    }
+
+    /// QUIT and RESET run right away, even before authentication or inside
+    /// MULTI.
+    pub(crate) fn is_connection_control(&self) -> bool {
+        matches!(self, Command::Quit | Command::Reset)
+    }
+
+    /// CLIENT subcommands, which work on the state of the connection.
+    pub(crate) fn is_client(&self) -> bool {
+        matches!(
+            self,
+            Command::ClientId
+                | Command::ClientTracking(_, _)
+                | Command::ClientCaching(_)
+                | Command::ClientList(_, _)
+                | Command::ClientInfo
+                | Command::ClientSetname(_)
+                | Command::ClientGetname
+                | Command::ClientKill(_)
+                | Command::ClientPause(_, _)
+                | Command::ClientUnpause
+                | Command::ClientNoEvict(_)
+        )
+    }
+
+    /// Commands CLIENT PAUSE WRITE holds back: writes, and commands that may
+    /// write or publish.
+    pub(crate) fn may_write(&self) -> bool {
+        self.for_replication()
+            || self.is_script()
+            || matches!(self, Command::Publish(_, _) | Command::Spublish(_, _))
+    }
+
+    /// Commands that may grow the keyspace, refused while used memory is over
+    /// `maxmemory` and nothing is left to evict. Scripts count as they may
+    /// write.
+    pub(crate) fn is_denyoom(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(_, _, _)
+                | Command::Rpush(_, _)
+                | Command::Lpush(_, _)
+                | Command::Xadd(_, _, _)
+                | Command::Incr(_)
+                | Command::Zadd(_, _)
+                | Command::Geoadd(_, _)
+                | Command::FunctionLoad(_, _)
+                | Command::FunctionRestore(_, _)
+                | Command::Eval(_, _, _)
+                | Command::Evalsha(_, _, _)
+                | Command::Fcall(_, _, _)
+        )
+    }
+
+    /// Commands that may wait on other clients before replying.
+    pub(crate) fn is_blocking(&self) -> bool {
+        matches!(
+            self,
+            Command::Blpop(_, _)
+                | Command::Brpop(_, _)
+                | Command::Xread(_, _, Some(_))
+                | Command::Wait(_, _)
+        )
+    }
+
+    /// Keyspace commands working on other databases than the selected one,
+    /// or on the selection itself.
+    pub(crate) fn spans_databases(&self) -> bool {
+        matches!(
+            self,
+            Command::Flushall(_) | Command::Select(_) | Command::Move(_, _) | Command::Swapdb(_, _)
+        )
+    }
+
     pub(crate) fn is_subscribe(&self) -> bool {
         match self {
-            Command::Subscribe(_) => true,
+            Command::Subscribe(_) | Command::Psubscribe(_) | Command::Ssubscribe(_) => true,
             _ => false,
         }
     }
 
+    /// Commands running Lua, replicated by the writes they make.
+    pub(crate) fn is_script(&self) -> bool {
+        matches!(
+            self,
+            Command::Eval(_, _, _)
+                | Command::Evalsha(_, _, _)
+                | Command::Fcall(_, _, _)
+                | Command::FcallRo(_, _, _)
+        )
+    }
+
     pub(crate) fn for_replication(&self) -> bool {
         match self {
             Command::Set(_, _, _) => true,
@@ -128,8 +315,17 @@ impl Command {
             Command::Rpopn(_, _) => true,
             Command::Xadd(_, _, _) => true,
             Command::Incr(_) => true,
+            Command::Flushall(_) => true,
+            Command::Flushdb(_) => true,
+            Command::Move(_, _) => true,
+            Command::Swapdb(_, _) => true,
             Command::Zadd(_, _) => true,
             Command::Geoadd(_, _) => true,
+            Command::FunctionLoad(_, _) => true,
+            Command::FunctionDelete(_) => true,
+            Command::FunctionFlush => true,
+            Command::FunctionRestore(_, _) => true,
+            Command::Del(_) => true,
             // ---
             Command::Blpop(_, _) => false,
             Command::Brpop(_, _) => false,
@@ -139,21 +335,54 @@ impl Command {
             Command::Lrange(_, _, _) => false,
             Command::Llen(_) => false,
             Command::Type(_) => false,
+            Command::ObjectFreq(_) => false,
+            Command::ObjectIdletime(_) => false,
             Command::Xrange(_, _, _, _) => false,
             Command::Xread(_, _, _) => false,
             Command::Multi => false,
             Command::Exec => false,
             Command::Discard => false,
+            Command::Watch(_) => false,
+            Command::Unwatch => false,
             Command::Info(_) => false,
             Command::Replconf(_) => false,
+            // Replicas are switched by the writer when a write is replicated.
+            Command::Select(_) => false,
+            Command::Dbsize => false,
             Command::Psync(_, _) => false,
             Command::Unknown(_) => false,
             Command::Wait(_, _) => false,
             Command::GetConfig(_) => false,
+            Command::SetConfig(_) => false,
+            Command::ResetStatConfig => false,
+            Command::RewriteConfig => false,
             Command::Keys(_) => false,
             Command::Subscribe(_) => false,
             Command::Unsubscribe(_) => false,
             Command::Publish(_, _) => false,
+            Command::Psubscribe(_) => false,
+            Command::Punsubscribe(_) => false,
+            Command::Ssubscribe(_) => false,
+            Command::Sunsubscribe(_) => false,
+            Command::Spublish(_, _) => false,
+            Command::Quit => false,
+            Command::Reset => false,
+            Command::ClientId => false,
+            Command::ClientTracking(_, _) => false,
+            Command::ClientCaching(_) => false,
+            Command::ClientList(_, _) => false,
+            Command::ClientInfo => false,
+            Command::ClientSetname(_) => false,
+            Command::ClientGetname => false,
+            Command::ClientKill(_) => false,
+            Command::ClientPause(_, _) => false,
+            Command::ClientUnpause => false,
+            Command::ClientNoEvict(_) => false,
+            Command::PubsubChannels(_) => false,
+            Command::PubsubNumsub(_) => false,
+            Command::PubsubNumpat => false,
+            Command::PubsubShardchannels(_) => false,
+            Command::PubsubShardnumsub(_) => false,
             Command::Zrank(_, _) => false,
             Command::Zrange(_, _, _) => false,
             Command::Zcard(_) => false,
@@ -165,6 +394,143 @@ impl Command {
             Command::AclWhoami => false,
             Command::AclGetuser(_) => false,
             Command::AclSetuser(_, _) => false,
+            Command::AclDeluser(_) => false,
+            Command::AclList => false,
+            Command::AclUsers => false,
+            Command::AclCat(_) => false,
+            Command::AclDryrun(_, _) => false,
+            Command::AclGenpass(_) => false,
+            Command::AclLoad => false,
+            Command::AclSave => false,
+            Command::AclLog(_) => false,
+            Command::AclLogReset => false,
+            Command::Auth(_, _) => false,
+            Command::Eval(_, _, _) => false,
+            Command::Evalsha(_, _, _) => false,
+            Command::ScriptLoad(_) => false,
+            Command::ScriptExists(_) => false,
+            Command::ScriptFlush => false,
+            Command::ScriptKill => false,
+            Command::FunctionList(_, _) => false,
+            Command::FunctionDump => false,
+            Command::Fcall(_, _, _) => false,
+            Command::FcallRo(_, _, _) => false,
+            Command::Save => false,
+        }
+    }
+
+    /// Commands reading or writing the keyspace, run against a locked `Database`.
+    pub(crate) fn is_keyspace(&self) -> bool {
+        match self {
+            Command::Set(_, _, _) => true,
+            Command::Rpush(_, _) => true,
+            Command::Lpush(_, _) => true,
+            Command::Lpop(_) => true,
+            Command::Rpop(_) => true,
+            Command::Lpopn(_, _) => true,
+            Command::Rpopn(_, _) => true,
+            Command::Xadd(_, _, _) => true,
+            Command::Incr(_) => true,
+            Command::Flushall(_) => true,
+            Command::Flushdb(_) => true,
+            Command::Select(_) => true,
+            Command::Move(_, _) => true,
+            Command::Swapdb(_, _) => true,
+            Command::Dbsize => true,
+            Command::Zadd(_, _) => true,
+            Command::Geoadd(_, _) => true,
+            Command::Blpop(_, _) => true,
+            Command::Brpop(_, _) => true,
+            Command::Get(_) => true,
+            Command::Lrange(_, _, _) => true,
+            Command::Llen(_) => true,
+            Command::Type(_) => true,
+            Command::Del(_) => true,
+            Command::ObjectFreq(_) => true,
+            Command::ObjectIdletime(_) => true,
+            Command::Xrange(_, _, _, _) => true,
+            Command::Xread(_, _, _) => true,
+            Command::Keys(_) => true,
+            Command::Zrank(_, _) => true,
+            Command::Zrange(_, _, _) => true,
+            Command::Zcard(_) => true,
+            Command::Zscore(_, _) => true,
+            Command::Zrem(_, _) => true,
+            Command::Geopos(_, _) => true,
+            Command::Geodist(_, _, _) => true,
+            Command::Geosearch(_, _, _) => true,
+            // ---
+            Command::Ping => false,
+            Command::Echo(_) => false,
+            Command::Multi => false,
+            Command::Exec => false,
+            Command::Discard => false,
+            Command::Watch(_) => false,
+            Command::Unwatch => false,
+            Command::Info(_) => false,
+            Command::Replconf(_) => false,
+            Command::Psync(_, _) => false,
+            Command::Unknown(_) => false,
+            Command::Wait(_, _) => false,
+            Command::GetConfig(_) => false,
+            Command::SetConfig(_) => false,
+            Command::ResetStatConfig => false,
+            Command::RewriteConfig => false,
+            Command::Subscribe(_) => false,
+            Command::Unsubscribe(_) => false,
+            Command::Publish(_, _) => false,
+            Command::Psubscribe(_) => false,
+            Command::Punsubscribe(_) => false,
+            Command::Ssubscribe(_) => false,
+            Command::Sunsubscribe(_) => false,
+            Command::Spublish(_, _) => false,
+            Command::Quit => false,
+            Command::Reset => false,
+            Command::ClientId => false,
+            Command::ClientTracking(_, _) => false,
+            Command::ClientCaching(_) => false,
+            Command::ClientList(_, _) => false,
+            Command::ClientInfo => false,
+            Command::ClientSetname(_) => false,
+            Command::ClientGetname => false,
+            Command::ClientKill(_) => false,
+            Command::ClientPause(_, _) => false,
+            Command::ClientUnpause => false,
+            Command::ClientNoEvict(_) => false,
+            Command::PubsubChannels(_) => false,
+            Command::PubsubNumsub(_) => false,
+            Command::PubsubNumpat => false,
+            Command::PubsubShardchannels(_) => false,
+            Command::PubsubShardnumsub(_) => false,
+            Command::AclWhoami => false,
+            Command::AclGetuser(_) => false,
+            Command::AclSetuser(_, _) => false,
+            Command::AclDeluser(_) => false,
+            Command::AclList => false,
+            Command::AclUsers => false,
+            Command::AclCat(_) => false,
+            Command::AclDryrun(_, _) => false,
+            Command::AclGenpass(_) => false,
+            Command::AclLoad => false,
+            Command::AclSave => false,
+            Command::AclLog(_) => false,
+            Command::AclLogReset => false,
+            Command::Auth(_, _) => false,
+            Command::Eval(_, _, _) => false,
+            Command::Evalsha(_, _, _) => false,
+            Command::ScriptLoad(_) => false,
+            Command::ScriptExists(_) => false,
+            Command::ScriptFlush => false,
+            Command::ScriptKill => false,
+            Command::FunctionLoad(_, _) => false,
+            Command::FunctionDelete(_) => false,
+            Command::FunctionFlush => false,
+            Command::FunctionList(_, _) => false,
+            Command::FunctionDump => false,
+            Command::FunctionRestore(_, _) => false,
+            Command::Fcall(_, _, _) => false,
+            Command::FcallRo(_, _, _) => false,
+            Command::Save => false,
         }
     }
 
@@ -187,21 +553,58 @@ impl Command {
             Command::Lrange(_, _, _) => "lrange",
             Command::Llen(_) => "llen",
             Command::Type(_) => "type",
+            Command::Del(_) => "del",
+            Command::ObjectFreq(_) => "object freq",
+            Command::ObjectIdletime(_) => "object idletime",
             Command::Xrange(_, _, _, _) => "xrange",
             Command::Xread(_, _, _) => "xread",
             Command::Multi => "multi",
             Command::Exec => "exec",
             Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Flushall(_) => "flushall",
+            Command::Flushdb(_) => "flushdb",
+            Command::Select(_) => "select",
+            Command::Move(_, _) => "move",
+            Command::Swapdb(_, _) => "swapdb",
+            Command::Dbsize => "dbsize",
             Command::Info(_) => "info",
             Command::Replconf(_) => "replconf",
             Command::Psync(_, _) => "psync",
             Command::Unknown(_) => "unknown",
             Command::Wait(_, _) => "wait",
             Command::GetConfig(_) => "getconfig",
+            Command::SetConfig(_) => "config set",
+            Command::ResetStatConfig => "config resetstat",
+            Command::RewriteConfig => "config rewrite",
             Command::Keys(_) => "keys",
             Command::Subscribe(_) => "subscribe",
             Command::Unsubscribe(_) => "unsubscribe",
             Command::Publish(_, _) => "publish",
+            Command::Psubscribe(_) => "psubscribe",
+            Command::Punsubscribe(_) => "punsubscribe",
+            Command::Ssubscribe(_) => "ssubscribe",
+            Command::Sunsubscribe(_) => "sunsubscribe",
+            Command::Spublish(_, _) => "spublish",
+            Command::Quit => "quit",
+            Command::Reset => "reset",
+            Command::ClientId => "client id",
+            Command::ClientTracking(_, _) => "client tracking",
+            Command::ClientCaching(_) => "client caching",
+            Command::ClientList(_, _) => "client list",
+            Command::ClientInfo => "client info",
+            Command::ClientSetname(_) => "client setname",
+            Command::ClientGetname => "client getname",
+            Command::ClientKill(_) => "client kill",
+            Command::ClientPause(_, _) => "client pause",
+            Command::ClientUnpause => "client unpause",
+            Command::ClientNoEvict(_) => "client no-evict",
+            Command::PubsubChannels(_) => "pubsub channels",
+            Command::PubsubNumsub(_) => "pubsub numsub",
+            Command::PubsubNumpat => "pubsub numpat",
+            Command::PubsubShardchannels(_) => "pubsub shardchannels",
+            Command::PubsubShardnumsub(_) => "pubsub shardnumsub",
             Command::Zadd(_, _) => "zadd",
             Command::Zrank(_, _) => "zrank",
             Command::Zrange(_, _, _) => "zrange",
@@ -215,6 +618,206 @@ impl Command {
             Command::AclWhoami => "acl whoami",
             Command::AclGetuser(_) => "acl getuser",
             Command::AclSetuser(_, _) => "acl setuser",
+            Command::AclDeluser(_) => "acl deluser",
+            Command::AclList => "acl list",
+            Command::AclUsers => "acl users",
+            Command::AclCat(_) => "acl cat",
+            Command::AclDryrun(_, _) => "acl dryrun",
+            Command::AclGenpass(_) => "acl genpass",
+            Command::AclLoad => "acl load",
+            Command::AclSave => "acl save",
+            Command::AclLog(_) => "acl log",
+            Command::AclLogReset => "acl log",
+            Command::Auth(_, _) => "auth",
+            Command::Eval(_, _, _) => "eval",
+            Command::Evalsha(_, _, _) => "evalsha",
+            Command::ScriptLoad(_) => "script load",
+            Command::ScriptExists(_) => "script exists",
+            Command::ScriptFlush => "script flush",
+            Command::ScriptKill => "script kill",
+            Command::FunctionLoad(_, _) => "function load",
+            Command::FunctionDelete(_) => "function delete",
+            Command::FunctionFlush => "function flush",
+            Command::FunctionList(_, _) => "function list",
+            Command::FunctionDump => "function dump",
+            Command::FunctionRestore(_, _) => "function restore",
+            Command::Fcall(_, _, _) => "fcall",
+            Command::FcallRo(_, _, _) => "fcall_ro",
+            Command::Save => "save",
+        }
+    }
+
+    /// Name of the command in ACL rules, with subcommands as `container|subcommand`.
+    pub(crate) fn acl_name(&self) -> String {
+        match self {
+            Command::Lpopn(_, _) => "lpop".to_string(),
+            Command::Rpopn(_, _) => "rpop".to_string(),
+            Command::GetConfig(_) => "config|get".to_string(),
+            other => other.short_name().replace(' ', "|"),
+        }
+    }
+
+    /// Keys the command touches and the access it needs to them, checked
+    /// against the key patterns of the ACL user.
+    pub(crate) fn acl_keys(&self) -> Vec<(&str, KeyAccess)> {
+        match self {
+            Command::Set(key, _, _) => vec![(key, KeyAccess::Write)],
+            Command::Get(key) => vec![(key, KeyAccess::Read)],
+            Command::Rpush(key, _) => vec![(key, KeyAccess::Write)],
+            Command::Lpush(key, _) => vec![(key, KeyAccess::Write)],
+            Command::Lrange(key, _, _) => vec![(key, KeyAccess::Read)],
+            Command::Llen(key) => vec![(key, KeyAccess::Read)],
+            Command::Lpop(key) => vec![(key, KeyAccess::ReadWrite)],
+            Command::Rpop(key) => vec![(key, KeyAccess::ReadWrite)],
+            Command::Lpopn(key, _) => vec![(key, KeyAccess::ReadWrite)],
+            Command::Rpopn(key, _) => vec![(key, KeyAccess::ReadWrite)],
+            Command::Blpop(keys, _) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::ReadWrite))
+                .collect(),
+            Command::Brpop(keys, _) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::ReadWrite))
+                .collect(),
+            Command::Type(key) => vec![(key, KeyAccess::Read)],
+            Command::Del(keys) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::Write))
+                .collect(),
+            Command::ObjectFreq(key) => vec![(key, KeyAccess::Read)],
+            Command::ObjectIdletime(key) => vec![(key, KeyAccess::Read)],
+            Command::Xadd(key, _, _) => vec![(key, KeyAccess::Write)],
+            Command::Xrange(key, _, _, _) => vec![(key, KeyAccess::Read)],
+            Command::Xread(streams, _, _) => streams
+                .iter()
+                .map(|(key, _)| (key.as_str(), KeyAccess::Read))
+                .collect(),
+            Command::Incr(key) => vec![(key, KeyAccess::ReadWrite)],
+            Command::Watch(keys) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::Read))
+                .collect(),
+            Command::Zadd(key, _) => vec![(key, KeyAccess::Write)],
+            Command::Zrank(key, _) => vec![(key, KeyAccess::Read)],
+            Command::Zrange(key, _, _) => vec![(key, KeyAccess::Read)],
+            Command::Zcard(key) => vec![(key, KeyAccess::Read)],
+            Command::Zscore(key, _) => vec![(key, KeyAccess::Read)],
+            Command::Zrem(key, _) => vec![(key, KeyAccess::Write)],
+            Command::Geoadd(key, _) => vec![(key, KeyAccess::Write)],
+            Command::Geopos(key, _) => vec![(key, KeyAccess::Read)],
+            Command::Geodist(key, _, _) => vec![(key, KeyAccess::Read)],
+            Command::Geosearch(key, _, _) => vec![(key, KeyAccess::Read)],
+            Command::Eval(_, keys, _) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::ReadWrite))
+                .collect(),
+            Command::Evalsha(_, keys, _) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::ReadWrite))
+                .collect(),
+            Command::Fcall(_, keys, _) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::ReadWrite))
+                .collect(),
+            Command::FcallRo(_, keys, _) => keys
+                .iter()
+                .map(|key| (key.as_str(), KeyAccess::Read))
+                .collect(),
+            // ---
+            Command::Ping => vec![],
+            Command::Echo(_) => vec![],
+            Command::Multi => vec![],
+            Command::Exec => vec![],
+            Command::Discard => vec![],
+            Command::Unwatch => vec![],
+            Command::Move(key, _) => vec![(key.as_str(), KeyAccess::ReadWrite)],
+            Command::Flushall(_) => vec![],
+            Command::Flushdb(_) => vec![],
+            Command::Select(_) => vec![],
+            Command::Swapdb(_, _) => vec![],
+            Command::Dbsize => vec![],
+            Command::Info(_) => vec![],
+            Command::Replconf(_) => vec![],
+            Command::Psync(_, _) => vec![],
+            Command::Wait(_, _) => vec![],
+            Command::GetConfig(_) => vec![],
+            Command::SetConfig(_) => vec![],
+            Command::ResetStatConfig => vec![],
+            Command::RewriteConfig => vec![],
+            Command::Keys(_) => vec![],
+            Command::Subscribe(_) => vec![],
+            Command::Unsubscribe(_) => vec![],
+            Command::Publish(_, _) => vec![],
+            Command::Psubscribe(_) => vec![],
+            Command::Punsubscribe(_) => vec![],
+            Command::Ssubscribe(_) => vec![],
+            Command::Sunsubscribe(_) => vec![],
+            Command::Spublish(_, _) => vec![],
+            Command::Quit => vec![],
+            Command::Reset => vec![],
+            Command::ClientId => vec![],
+            Command::ClientTracking(_, _) => vec![],
+            Command::ClientCaching(_) => vec![],
+            Command::ClientList(_, _) => vec![],
+            Command::ClientInfo => vec![],
+            Command::ClientSetname(_) => vec![],
+            Command::ClientGetname => vec![],
+            Command::ClientKill(_) => vec![],
+            Command::ClientPause(_, _) => vec![],
+            Command::ClientUnpause => vec![],
+            Command::ClientNoEvict(_) => vec![],
+            Command::PubsubChannels(_) => vec![],
+            Command::PubsubNumsub(_) => vec![],
+            Command::PubsubNumpat => vec![],
+            Command::PubsubShardchannels(_) => vec![],
+            Command::PubsubShardnumsub(_) => vec![],
+            Command::AclWhoami => vec![],
+            Command::AclGetuser(_) => vec![],
+            Command::AclSetuser(_, _) => vec![],
+            Command::AclDeluser(_) => vec![],
+            Command::AclList => vec![],
+            Command::AclUsers => vec![],
+            Command::AclCat(_) => vec![],
+            Command::AclDryrun(_, _) => vec![],
+            Command::AclGenpass(_) => vec![],
+            Command::AclLoad => vec![],
+            Command::AclSave => vec![],
+            Command::AclLog(_) => vec![],
+            Command::AclLogReset => vec![],
+            Command::Auth(_, _) => vec![],
+            Command::ScriptLoad(_) => vec![],
+            Command::ScriptExists(_) => vec![],
+            Command::ScriptFlush => vec![],
+            Command::ScriptKill => vec![],
+            Command::FunctionLoad(_, _) => vec![],
+            Command::FunctionDelete(_) => vec![],
+            Command::FunctionFlush => vec![],
+            Command::FunctionList(_, _) => vec![],
+            Command::FunctionDump => vec![],
+            Command::FunctionRestore(_, _) => vec![],
+            Command::Save => vec![],
+            Command::Unknown(_) => vec![],
+        }
+    }
+
+    /// Pub/sub channels the command touches, checked against the channel
+    /// patterns of the ACL user.
+    pub(crate) fn acl_channels(&self) -> Vec<&str> {
+        match self {
+            Command::Subscribe(channels) | Command::Ssubscribe(channels) => {
+                channels.iter().map(|c| c.as_str()).collect()
+            }
+            Command::Publish(channel, _) | Command::Spublish(channel, _) => vec![channel],
+            _ => vec![],
+        }
+    }
+
+    /// Channel patterns the command subscribes to. Unlike channels they must
+    /// be granted literally by the ACL user.
+    pub(crate) fn acl_channel_patterns(&self) -> Vec<&str> {
+        match self {
+            Command::Psubscribe(patterns) => patterns.iter().map(|p| p.as_str()).collect(),
+            _ => vec![],
         }
     }
 
@@ -303,6 +906,42 @@ impl Command {
                 RespValue::BulkString(key.clone()),
             ]),
 
+            Command::Flushall(lazy) | Command::Flushdb(lazy) => {
+                let mut params = vec![RespValue::BulkString(
+                    if matches!(self, Command::Flushall(_)) {
+                        "FLUSHALL"
+                    } else {
+                        "FLUSHDB"
+                    }
+                    .into(),
+                )];
+                if *lazy {
+                    params.push(RespValue::BulkString("ASYNC".into()));
+                }
+                RespValue::Array(params)
+            }
+
+            Command::Select(index) => RespValue::Array(vec![
+                RespValue::BulkString("SELECT".into()),
+                RespValue::BulkString(index.to_string()),
+            ]),
+
+            Command::Move(key, db) => RespValue::Array(vec![
+                RespValue::BulkString("MOVE".into()),
+                RespValue::BulkString(key.clone()),
+                RespValue::BulkString(db.to_string()),
+            ]),
+
+            Command::Swapdb(first, second) => RespValue::Array(vec![
+                RespValue::BulkString("SWAPDB".into()),
+                RespValue::BulkString(first.to_string()),
+                RespValue::BulkString(second.to_string()),
+            ]),
+
+            Command::Multi => RespValue::Array(vec![RespValue::BulkString("MULTI".into())]),
+
+            Command::Exec => RespValue::Array(vec![RespValue::BulkString("EXEC".into())]),
+
             Command::Zadd(key, args) => {
                 let mut elems = vec![
                     RespValue::BulkString("ZADD".into()),
@@ -338,6 +977,52 @@ impl Command {
                 RespValue::Array(params)
             }
 
+            Command::FunctionLoad(code, replace) => {
+                let mut params = vec![
+                    RespValue::BulkString("FUNCTION".into()),
+                    RespValue::BulkString("LOAD".into()),
+                ];
+
+                if *replace {
+                    params.push(RespValue::BulkString("REPLACE".into()));
+                }
+                params.push(RespValue::BulkString(code.clone()));
+
+                RespValue::Array(params)
+            }
+
+            Command::FunctionDelete(library) => RespValue::Array(vec![
+                RespValue::BulkString("FUNCTION".into()),
+                RespValue::BulkString("DELETE".into()),
+                RespValue::BulkString(library.clone()),
+            ]),
+
+            Command::FunctionFlush => RespValue::Array(vec![
+                RespValue::BulkString("FUNCTION".into()),
+                RespValue::BulkString("FLUSH".into()),
+            ]),
+
+            Command::FunctionRestore(payload, policy) => RespValue::Array(vec![
+                RespValue::BulkString("FUNCTION".into()),
+                RespValue::BulkString("RESTORE".into()),
+                RespValue::BulkString(payload.clone()),
+                RespValue::BulkString(
+                    match policy {
+                        FunctionRestorePolicy::Append => "APPEND",
+                        FunctionRestorePolicy::Replace => "REPLACE",
+                        FunctionRestorePolicy::Flush => "FLUSH",
+                    }
+                    .into(),
+                ),
+            ]),
+
+            Command::Del(keys) => RespValue::Array(
+                std::iter::once("DEL".to_string())
+                    .chain(keys.iter().cloned())
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+
             _ => unimplemented!("Command resp-ization not implemented for {:?}", self),
         }
     }
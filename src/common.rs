@@ -1,12 +1,14 @@
 use crate::commands::Command;
 use rand::rng;
 use rand::RngCore;
-use regex::Regex;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
 use std::u128;
+use tokio::sync::Notify;
 
 pub(crate) const MIN_LAT: f64 = -85.05112878;
 pub(crate) const MAX_LAT: f64 = 85.05112878;
@@ -15,9 +17,18 @@ pub(crate) const MAX_LON: f64 = 180.0;
 
 pub(crate) type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// Version reported by INFO and written to snapshots.
+pub(crate) const REDIS_VERSION: &str = "7.2.0";
+
 pub(crate) struct ReaderRole {
     pub(crate) writer_host: String,
     pub(crate) writer_port: u16,
+    /// Set while the replication link to the writer is up.
+    pub(crate) link_up: bool,
+    /// When the writer last sent something.
+    pub(crate) last_io: Option<Instant>,
+    /// Bytes of the replication stream processed.
+    pub(crate) offset: usize,
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -47,6 +58,8 @@ pub(crate) struct ClientInfo {
     last_synced_command_index: i64,
     pub(crate) offset: usize,
     pub(crate) offset_update: ClientOffsetUpdate,
+    /// When the replica last acknowledged its offset, or connected.
+    pub(crate) last_ack: Instant,
 }
 
 impl ClientInfo {
@@ -57,6 +70,7 @@ impl ClientInfo {
             last_synced_command_index: -1,
             offset: 0,
             offset_update: ClientOffsetUpdate::Idle,
+            last_ack: Instant::now(),
         }
     }
 }
@@ -67,14 +81,30 @@ pub(crate) struct WriterRole {
     //                          vvv--request-count
     pub(crate) clients: HashMap<u64, ClientInfo>,
     pub(crate) write_queue: VecDeque<Command>,
+    /// Database the replicas have selected last, if known.
+    pub(crate) selected_db: Option<usize>,
 }
 
 impl WriterRole {
     pub(crate) fn push_write_command(&mut self, command: Command) {
         self.offset += command.into_resp().serialize().len();
+        if let Command::Select(db_index) = command {
+            self.selected_db = Some(db_index);
+        }
         self.write_queue.push_back(command);
     }
 
+    /// Registers a replica that got a snapshot of the current dataset, so it is
+    /// only sent the writes after it. The next write selects its database
+    /// again, as the replica has none selected yet.
+    pub(crate) fn add_synced_client(&mut self, request_count: u64) {
+        self.selected_db = None;
+        let mut client_info = ClientInfo::new();
+        client_info.last_synced_command_index = self.write_queue.len() as i64 - 1;
+        client_info.offset = self.offset;
+        self.clients.insert(request_count, client_info);
+    }
+
     pub(crate) fn pop_write_command(&mut self, request_count: u64) -> Vec<Command> {
         let client_info = self
             .clients
@@ -108,6 +138,7 @@ impl WriterRole {
             .expect("Missing client");
         client_info.offset = offset;
         client_info.offset_update = ClientOffsetUpdate::Idle;
+        client_info.last_ack = Instant::now();
     }
 
     pub(crate) fn reset_client_offset_state(&mut self, request_count: u64) {
@@ -154,9 +185,98 @@ impl ReplicationRole {
     }
 }
 
+/// Registry of clients blocked on keys (BLPOP/BRPOP, XREAD BLOCK). Every waiter
+/// owns its `Notify`, so a write wakes all clients watching that key and nobody else.
+#[derive(Default)]
+pub(crate) struct KeyWaiters {
+    next_waiter_id: u64,
+    waiters: HashMap<String, HashMap<u64, Arc<Notify>>>,
+}
+
+impl KeyWaiters {
+    pub(crate) fn register(&mut self, keys: &[String]) -> (u64, Arc<Notify>) {
+        let waiter_id = self.next_waiter_id;
+        self.next_waiter_id += 1;
+
+        let notify = Arc::new(Notify::new());
+        for key in keys {
+            self.waiters
+                .entry(key.clone())
+                .or_default()
+                .insert(waiter_id, notify.clone());
+        }
+
+        (waiter_id, notify)
+    }
+
+    pub(crate) fn unregister(&mut self, waiter_id: u64, keys: &[String]) {
+        for key in keys {
+            if let Some(key_waiters) = self.waiters.get_mut(key) {
+                key_waiters.remove(&waiter_id);
+                if key_waiters.is_empty() {
+                    self.waiters.remove(key);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn notify(&self, key: &str) {
+        if let Some(key_waiters) = self.waiters.get(key) {
+            for notify in key_waiters.values() {
+                // `notify_one` stores a permit, so a waiter that is between two
+                // checks of the database won't miss the wakeup.
+                notify.notify_one();
+            }
+        }
+    }
+
+    /// Wakes every waiter, for writes that may touch any key.
+    pub(crate) fn notify_all(&self) {
+        for key_waiters in self.waiters.values() {
+            for notify in key_waiters.values() {
+                notify.notify_one();
+            }
+        }
+    }
+}
+
+/// A waiter of `KeyWaiters`, unregistered when dropped, so that it goes away
+/// with a blocked command even if the command's future is dropped before it
+/// completes.
+pub(crate) struct KeyWait<'a> {
+    key_waiters: &'a std::sync::Mutex<KeyWaiters>,
+    waiter_id: u64,
+    keys: Vec<String>,
+    notify: Arc<Notify>,
+}
+
+impl<'a> KeyWait<'a> {
+    pub(crate) fn new(key_waiters: &'a std::sync::Mutex<KeyWaiters>, keys: &[String]) -> Self {
+        let (waiter_id, notify) = key_waiters.lock().unwrap().register(keys);
+        Self {
+            key_waiters,
+            waiter_id,
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    pub(crate) fn notify(&self) -> &Notify {
+        &self.notify
+    }
+}
+
+impl Drop for KeyWait<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut key_waiters) = self.key_waiters.lock() {
+            key_waiters.unregister(self.waiter_id, &self.keys);
+        }
+    }
+}
+
 pub(crate) type KeyValuePair = (String, String);
 
-#[derive(Debug, Clone, PartialEq, Eq, Ord)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Ord)]
 pub(crate) struct CompleteStreamEntryID(pub(crate) u128, pub(crate) usize);
 
 #[derive(Debug, Clone)]
@@ -208,11 +328,36 @@ pub(crate) fn current_time_ms() -> u128 {
         .as_millis()
 }
 
-pub(crate) fn current_time_secs_f64() -> f64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("system time before UNIX EPOCH")
-        .as_secs_f64()
+/// Elements whose memory is measured to estimate a whole collection.
+const MEMORY_USAGE_SAMPLES: usize = 5;
+
+/// Extrapolates the memory of a collection from its first elements, so
+/// estimating a large collection stays cheap.
+pub(crate) fn sampled_memory_usage<T>(
+    items: impl ExactSizeIterator<Item = T>,
+    usage: impl Fn(T) -> usize,
+) -> usize {
+    let len = items.len();
+    let sampled = items.take(MEMORY_USAGE_SAMPLES).collect::<Vec<_>>();
+    if sampled.is_empty() {
+        return 0;
+    }
+    let count = sampled.len();
+
+    sampled.into_iter().map(usage).sum::<usize>() * len / count
+}
+
+/// Compares without returning early at the first difference, so timing does
+/// not leak how much of a secret matched.
+pub(crate) fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
+    if lhs.len() != rhs.len() {
+        return false;
+    }
+
+    lhs.iter()
+        .zip(rhs.iter())
+        .fold(0u8, |diff, (l, r)| diff | (l ^ r))
+        == 0
 }
 
 pub(crate) fn new_master_replid() -> String {
@@ -222,20 +367,217 @@ pub(crate) fn new_master_replid() -> String {
     bytes.map(|b| format!("{:x}", b)).join("")
 }
 
+/// Random lowercase hex string of `len` characters.
+pub(crate) fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len.div_ceil(2)];
+    rng().fill_bytes(&mut bytes);
+    let mut hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    hex.truncate(len);
+    hex
+}
+
+/// Splits a line into whitespace separated arguments with the quoting rules of
+/// inline commands and redis.conf: double quotes support `\n`, `\r`, `\t`,
+/// `\b`, `\a`, `\\`, `\"` and `\xHH` escapes, single quotes only `\'`.
+pub(crate) fn split_args(line: &[u8]) -> Result<Vec<String>, String> {
+    const UNBALANCED: &str = "unbalanced quotes";
+    // The line ends at the first NUL byte, as it does for the C string Redis
+    // splits.
+    let line = &line[..line.iter().position(|c| *c == 0).unwrap_or(line.len())];
+    let mut args = vec![];
+    let mut i = 0;
+
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut current = vec![];
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+        loop {
+            let c = line.get(i).copied();
+            if in_double_quotes {
+                match c {
+                    None => return Err(UNBALANCED.into()),
+                    Some(b'\\')
+                        if line.get(i + 1) == Some(&b'x')
+                            && line.get(i + 2).is_some_and(u8::is_ascii_hexdigit)
+                            && line.get(i + 3).is_some_and(u8::is_ascii_hexdigit) =>
+                    {
+                        let hex = std::str::from_utf8(&line[i + 2..i + 4]).unwrap();
+                        current.push(u8::from_str_radix(hex, 16).unwrap());
+                        i += 3;
+                    }
+                    Some(b'\\') if i + 1 < line.len() => {
+                        i += 1;
+                        current.push(match line[i] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    Some(b'"') => {
+                        // The closing quote must be followed by a space.
+                        if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                            return Err(UNBALANCED.into());
+                        }
+                        i += 1;
+                        break;
+                    }
+                    Some(c) => current.push(c),
+                }
+            } else if in_single_quotes {
+                match c {
+                    None => return Err(UNBALANCED.into()),
+                    Some(b'\\') if line.get(i + 1) == Some(&b'\'') => {
+                        i += 1;
+                        current.push(b'\'');
+                    }
+                    Some(b'\'') => {
+                        if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                            return Err(UNBALANCED.into());
+                        }
+                        i += 1;
+                        break;
+                    }
+                    Some(c) => current.push(c),
+                }
+            } else {
+                match c {
+                    None => break,
+                    Some(c) if c.is_ascii_whitespace() => break,
+                    Some(b'"') => in_double_quotes = true,
+                    Some(b'\'') => in_single_quotes = true,
+                    Some(c) => current.push(c),
+                }
+            }
+            i += 1;
+        }
+
+        args.push(String::from_utf8(current).map_err(|_| "argument is not valid UTF-8")?);
+    }
+}
+
+/// Parses a memory amount as in redis.conf: `1k` is 1000 bytes, `1kb` 1024
+/// bytes, and likewise for m/mb and g/gb.
+pub(crate) fn parse_memory_size(raw: &str) -> Result<usize, String> {
+    let raw = raw.trim().to_lowercase();
+    let digits_end = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (number, unit) = raw.split_at(digits_end);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid memory size '{}'", raw)),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid memory size '{}'", raw))
+}
+
+/// Glob-style matcher with the rules of Redis: `*` matches any run of bytes,
+/// `?` a single byte, `[...]` one byte of a set with `^` negating it and `a-z`
+/// for ranges, and `\` escapes the next byte.
 pub(crate) struct PatternMatcher {
-    pattern: Regex,
+    pattern: Vec<u8>,
 }
 
 impl PatternMatcher {
     pub(crate) fn new(raw: &str) -> Self {
-        let transformed = format!("^{}$", raw.replace('*', ".*").replace('?', "."));
         Self {
-            pattern: Regex::new(&transformed).unwrap(),
+            pattern: raw.as_bytes().to_vec(),
         }
     }
 
     pub(crate) fn is_match(&self, other: &str) -> bool {
-        self.pattern.is_match(other)
+        let pattern = &self.pattern[..];
+        let string = other.as_bytes();
+        let (mut p, mut s) = (0, 0);
+        // Pattern and string positions right after the last `*`, to retry
+        // from when the rest doesn't match. Only the last star needs retrying
+        // since it can absorb whatever an earlier one would have.
+        let mut star: Option<(usize, usize)> = None;
+
+        while p < pattern.len() || s < string.len() {
+            if p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+                star = Some((p, s));
+                continue;
+            }
+
+            if p < pattern.len() && s < string.len() {
+                if let Some(next) = Self::match_byte(pattern, p, string[s]) {
+                    p = next;
+                    s += 1;
+                    continue;
+                }
+            }
+
+            match star {
+                Some((star_p, star_s)) if star_s < string.len() => {
+                    p = star_p;
+                    s = star_s + 1;
+                    star = Some((star_p, s));
+                }
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Matches one byte against the pattern element at `p`, giving where the
+    /// next element starts.
+    fn match_byte(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+        match pattern[p] {
+            b'?' => Some(p + 1),
+            b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+            b'[' => {
+                let mut i = p + 1;
+                let negated = pattern.get(i) == Some(&b'^');
+                if negated {
+                    i += 1;
+                }
+
+                let mut matched = false;
+                while i < pattern.len() && pattern[i] != b']' {
+                    if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                        matched |= pattern[i + 1] == byte;
+                        i += 2;
+                    } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+                        let (start, end) = (
+                            pattern[i].min(pattern[i + 2]),
+                            pattern[i].max(pattern[i + 2]),
+                        );
+                        matched |= (start..=end).contains(&byte);
+                        i += 3;
+                    } else {
+                        matched |= pattern[i] == byte;
+                        i += 1;
+                    }
+                }
+
+                // An unterminated set ends with the pattern.
+                (matched != negated).then_some((i + 1).min(pattern.len()))
+            }
+            literal => (literal == byte).then_some(p + 1),
+        }
     }
 }
 
@@ -288,6 +630,15 @@ pub(crate) struct SortedSet {
 }
 
 impl SortedSet {
+    /// Estimated memory used by the members and scores, in bytes.
+    pub(crate) fn memory_usage(&self) -> usize {
+        sampled_memory_usage(self.members.keys(), |member| {
+            2 * member.len()
+                + std::mem::size_of::<SortedSetElem>()
+                + std::mem::size_of::<(String, SortedSetData)>()
+        })
+    }
+
     pub(crate) fn remove(&mut self, member: String) -> bool {
         if !self.members.contains_key(&member) {
             return false;
@@ -360,6 +711,11 @@ impl SortedSet {
     pub(crate) fn members(&self) -> Vec<&String> {
         self.members.keys().collect()
     }
+
+    /// Members with their scores, lowest score first.
+    pub(crate) fn scored_members(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.ordering.iter().map(|elem| (&elem.member, elem.score))
+    }
 }
 
 fn spread_u32_to_u64(v: u32) -> u64 {
@@ -441,10 +797,20 @@ pub(crate) fn geohash_get_distance(lon1d: f64, lat1d: f64, lon2d: f64, lat2d: f6
 
 #[cfg(test)]
 mod test {
+    use crate::commands::Command;
     use crate::common::{
-        decode_geohash, encode_geohash, geohash_get_distance, PatternMatcher, SortedSetElem,
+        constant_time_eq, decode_geohash, encode_geohash, geohash_get_distance, parse_memory_size,
+        split_args, KeyWait, KeyWaiters, PatternMatcher, SortedSetElem, WriterRole,
     };
 
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+
     #[test]
     fn test_pattern_matcher() {
         assert!(PatternMatcher::new("*").is_match("anything"));
@@ -469,6 +835,104 @@ mod test {
         assert!(!PatternMatcher::new("a?c").is_match("abbc"));
     }
 
+    #[test]
+    fn test_pattern_matcher_glob_rules() {
+        let matches = |pattern: &str, string: &str| PatternMatcher::new(pattern).is_match(string);
+
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        assert!(matches("h[\\]]llo", "h]llo"));
+        assert!(matches("h[ab", "ha"));
+
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("a\\?", "a?"));
+        assert!(matches("a\\", "a\\"));
+
+        // No regex semantics leak through.
+        assert!(!matches("a.c", "abc"));
+        assert!(matches("a(b|c", "a(b|c"));
+        assert!(matches("a+", "a+"));
+
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b*", "xxbxxaxx"));
+        assert!(matches("**", ""));
+        assert!(!matches("?", ""));
+        assert!(matches(&"*a".repeat(20), &"a".repeat(40)));
+        assert!(!matches(&format!("{}b", "*a".repeat(20)), &"a".repeat(60)));
+    }
+
+    #[tokio::test]
+    async fn test_key_waiters_wake_every_waiter_of_key() {
+        let mut waiters = KeyWaiters::default();
+        let (first_id, first) = waiters.register(&["a".to_string()]);
+        let (_, second) = waiters.register(&["a".to_string(), "b".to_string()]);
+        let (_, other) = waiters.register(&["c".to_string()]);
+
+        waiters.notify("a");
+
+        first.notified().await;
+        second.notified().await;
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(10), other.notified())
+                .await
+                .is_err()
+        );
+
+        waiters.unregister(first_id, &["a".to_string()]);
+        waiters.notify("a");
+        second.notified().await;
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(10), first.notified())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_synced_client_only_gets_later_writes() {
+        let mut writer = WriterRole {
+            replid: "id".into(),
+            offset: 0,
+            clients: Default::default(),
+            write_queue: Default::default(),
+            selected_db: None,
+        };
+        writer.push_write_command(Command::Select(3));
+        writer.push_write_command(Command::Del(vec!["a".into()]));
+
+        writer.add_synced_client(1);
+        assert_eq!(writer.offset, writer.clients[&1].offset);
+        assert!(writer.pop_write_command(1).is_empty());
+        assert_eq!(None, writer.selected_db);
+
+        writer.push_write_command(Command::Del(vec!["b".into()]));
+        assert_eq!(1, writer.pop_write_command(1).len());
+    }
+
+    #[tokio::test]
+    async fn test_key_wait_unregisters_when_dropped() {
+        let key_waiters = std::sync::Mutex::new(KeyWaiters::default());
+        let keys = ["a".to_string(), "b".to_string()];
+
+        let blocked = async {
+            let wait = KeyWait::new(&key_waiters, &keys);
+            wait.notify().notified().await;
+        };
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(10), blocked)
+                .await
+                .is_err()
+        );
+
+        assert!(key_waiters.lock().unwrap().waiters.is_empty());
+    }
+
     #[test]
     fn test_sorted_set_elem_ordering() {
         assert!(SortedSetElem::new(1.23, "Foo".into()) == SortedSetElem::new(1.23, "Foo".into()));
@@ -531,4 +995,47 @@ mod test {
         dbg!(diff);
         assert!(diff < 0.0001);
     }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(Ok(100), parse_memory_size("100"));
+        assert_eq!(Ok(2000), parse_memory_size("2k"));
+        assert_eq!(Ok(512 * 1024 * 1024), parse_memory_size("512MB"));
+        assert!(parse_memory_size("12xb").is_err());
+        assert!(parse_memory_size("mb").is_err());
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            Ok(vec![
+                "SET".to_string(),
+                "key".into(),
+                "a b".into(),
+                "it's".into()
+            ]),
+            split_args(br#"  SET key "a b"  'it\'s'"#)
+        );
+        assert_eq!(
+            Ok(vec!["\n\t\"A".to_string(), "".into()]),
+            split_args(br#""\n\t\"\x41" """#)
+        );
+        assert_eq!(Ok(vec![]), split_args(b" \t "));
+        assert!(split_args(br#"GET "key"#).is_err());
+        assert!(split_args(br#"GET "key"x"#).is_err());
+        assert!(split_args(b"GET 'key").is_err());
+        assert_eq!(
+            Ok(vec!["SET".to_string(), "a".into()]),
+            split_args(b"SET a\0b c")
+        );
+        assert_eq!(
+            Ok(vec!["GET".to_string(), "k".into()]),
+            split_args(b"GET k\0\0")
+        );
+        assert_eq!(
+            Ok(vec!["GET".to_string(), "k".into()]),
+            split_args(b"GET \"k\"\0x")
+        );
+        assert!(split_args(b"GET \"k\0\"").is_err());
+    }
 }
@@ -0,0 +1,913 @@
+use std::{fmt, path::PathBuf};
+
+use crate::{
+    common::{parse_memory_size, split_args, Error, PatternMatcher},
+    eviction::EvictionPolicy,
+    pubsub::{KeyspaceEvents, OutputBufferLimit},
+    server::NetworkConfig,
+    tls::{TlsAuthClients, TlsConfig},
+};
+
+/// Settings of the server, from redis.conf, the command line and CONFIG SET.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Config {
+    /// File the configuration was loaded from, rewritten by CONFIG REWRITE.
+    pub(crate) file: Option<String>,
+    /// Port of the plain TCP listeners, 0 disables them.
+    pub(crate) port: u16,
+    /// Addresses to listen on. `*` and `::*` stand for all IPv4 and IPv6
+    /// interfaces, and a leading `-` marks an address that may be unavailable.
+    pub(crate) bind: Vec<String>,
+    pub(crate) unixsocket: Option<String>,
+    pub(crate) unixsocketperm: Option<u32>,
+    /// Refuse clients from other hosts while the default user has no password.
+    pub(crate) protected_mode: bool,
+    pub(crate) replicaof: Option<(String, u16)>,
+    pub(crate) dir: String,
+    pub(crate) dbfilename: String,
+    pub(crate) databases: usize,
+    pub(crate) requirepass: Option<String>,
+    pub(crate) aclfile: Option<String>,
+    /// Port of the TLS listeners, 0 disables them.
+    pub(crate) tls_port: u16,
+    pub(crate) tls_cert_file: Option<String>,
+    pub(crate) tls_key_file: Option<String>,
+    pub(crate) tls_ca_cert_file: Option<String>,
+    pub(crate) tls_auth_clients: TlsAuthClients,
+    /// Authenticates TLS clients as the ACL user named by their certificate CN.
+    pub(crate) tls_auth_clients_user_cn: bool,
+    /// Connects to the writer over TLS.
+    pub(crate) tls_replication: bool,
+    /// Longest bulk string accepted in requests.
+    pub(crate) proto_max_bulk_len: usize,
+    pub(crate) client_output_buffer_limit: OutputBufferLimit,
+    pub(crate) notify_keyspace_events: KeyspaceEvents,
+    /// Memory limit of the keyspace in bytes, 0 for none.
+    pub(crate) maxmemory: usize,
+    pub(crate) maxmemory_policy: EvictionPolicy,
+    /// Keys sampled to pick each key to evict.
+    pub(crate) maxmemory_samples: usize,
+    /// Seconds a client may stay idle before it is disconnected, 0 for ever.
+    pub(crate) timeout: u64,
+    /// Milliseconds a script runs before the other clients get BUSY.
+    pub(crate) busy_reply_threshold: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            file: None,
+            port: 6379,
+            bind: vec!["127.0.0.1".into(), "-::1".into()],
+            unixsocket: None,
+            unixsocketperm: None,
+            protected_mode: true,
+            replicaof: None,
+            dir: "/tmp/redis-files".into(),
+            dbfilename: "dump.rdb".into(),
+            databases: 16,
+            requirepass: None,
+            aclfile: None,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::Yes,
+            tls_auth_clients_user_cn: false,
+            tls_replication: false,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            client_output_buffer_limit: OutputBufferLimit::default(),
+            notify_keyspace_events: KeyspaceEvents::default(),
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            maxmemory_samples: 5,
+            timeout: 0,
+            busy_reply_threshold: 5000,
+        }
+    }
+}
+
+/// A setting as CONFIG GET, CONFIG SET and redis.conf name it.
+struct Parameter {
+    name: &'static str,
+    alias: Option<&'static str>,
+    /// Whether CONFIG SET may change it while the server runs.
+    mutable: bool,
+    /// Written as several arguments in redis.conf, without quoting.
+    multi_arg: bool,
+    get: fn(&Config) -> String,
+    set: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+const PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "port",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.port.to_string(),
+        set: |config, raw| parse_integer(raw, 0, u16::MAX as u64).map(|n| config.port = n as u16),
+    },
+    Parameter {
+        name: "bind",
+        alias: None,
+        mutable: false,
+        multi_arg: true,
+        get: |config| config.bind.join(" "),
+        set: |config, raw| {
+            config.bind = split_args(raw.as_bytes())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "unixsocket",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.unixsocket.clone().unwrap_or_default(),
+        set: |config, raw| {
+            config.unixsocket = optional(raw);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "unixsocketperm",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| format!("{:o}", config.unixsocketperm.unwrap_or(0)),
+        set: |config, raw| {
+            let perm = u32::from_str_radix(raw, 8)
+                .ok()
+                .filter(|perm| *perm <= 0o777)
+                .ok_or("argument must be an octal number between 0 and 777")?;
+            config.unixsocketperm = (perm != 0).then_some(perm);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "protected-mode",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| yes_no(config.protected_mode),
+        set: |config, raw| parse_bool(raw).map(|on| config.protected_mode = on),
+    },
+    Parameter {
+        name: "replicaof",
+        alias: Some("slaveof"),
+        mutable: false,
+        multi_arg: true,
+        get: |config| {
+            config
+                .replicaof
+                .as_ref()
+                .map(|(host, port)| format!("{} {}", host, port))
+                .unwrap_or_default()
+        },
+        set: |config, raw| {
+            let args = split_args(raw.as_bytes())?;
+            config.replicaof = match &args[..] {
+                [] => None,
+                [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => {
+                    None
+                }
+                [host, port] => Some((
+                    host.clone(),
+                    port.parse().map_err(|_| "Invalid master port")?,
+                )),
+                _ => return Err("wrong number of arguments".into()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "dir",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.dir.clone(),
+        set: |config, raw| {
+            config.dir = raw.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "dbfilename",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.dbfilename.clone(),
+        set: |config, raw| {
+            if raw.contains('/') {
+                return Err("dbfilename can't be a path, just a filename".into());
+            }
+            config.dbfilename = raw.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "databases",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.databases.to_string(),
+        set: |config, raw| {
+            parse_integer(raw, 1, i32::MAX as u64).map(|n| config.databases = n as usize)
+        },
+    },
+    Parameter {
+        name: "requirepass",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.requirepass.clone().unwrap_or_default(),
+        set: |config, raw| {
+            config.requirepass = optional(raw);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "aclfile",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.aclfile.clone().unwrap_or_default(),
+        set: |config, raw| {
+            config.aclfile = optional(raw);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-port",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.tls_port.to_string(),
+        set: |config, raw| {
+            parse_integer(raw, 0, u16::MAX as u64).map(|n| config.tls_port = n as u16)
+        },
+    },
+    Parameter {
+        name: "tls-cert-file",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.tls_cert_file.clone().unwrap_or_default(),
+        set: |config, raw| {
+            config.tls_cert_file = optional(raw);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-key-file",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.tls_key_file.clone().unwrap_or_default(),
+        set: |config, raw| {
+            config.tls_key_file = optional(raw);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-ca-cert-file",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.tls_ca_cert_file.clone().unwrap_or_default(),
+        set: |config, raw| {
+            config.tls_ca_cert_file = optional(raw);
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-auth-clients",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| config.tls_auth_clients.to_string(),
+        set: |config, raw| {
+            config.tls_auth_clients = TlsAuthClients::parse(raw)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-auth-clients-user",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| match config.tls_auth_clients_user_cn {
+            true => "CN".into(),
+            false => "off".into(),
+        },
+        set: |config, raw| {
+            config.tls_auth_clients_user_cn = match raw.to_lowercase().as_str() {
+                "cn" => true,
+                "off" => false,
+                _ => return Err("argument must be 'CN' or 'off'".into()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-replication",
+        alias: None,
+        mutable: false,
+        multi_arg: false,
+        get: |config| yes_no(config.tls_replication),
+        set: |config, raw| parse_bool(raw).map(|on| config.tls_replication = on),
+    },
+    Parameter {
+        name: "proto-max-bulk-len",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.proto_max_bulk_len.to_string(),
+        set: |config, raw| {
+            let size = parse_memory(raw)?;
+            if size < 1024 * 1024 {
+                return Err("argument must be a memory value of at least 1mb".into());
+            }
+            config.proto_max_bulk_len = size;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "client-output-buffer-limit",
+        alias: None,
+        mutable: true,
+        multi_arg: true,
+        get: |config| config.client_output_buffer_limit.to_string(),
+        set: |config, raw| {
+            config.client_output_buffer_limit =
+                OutputBufferLimit::parse_pubsub(raw, config.client_output_buffer_limit)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "notify-keyspace-events",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.notify_keyspace_events.to_string(),
+        set: |config, raw| {
+            config.notify_keyspace_events = KeyspaceEvents::parse(raw)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.maxmemory.to_string(),
+        set: |config, raw| parse_memory(raw).map(|size| config.maxmemory = size),
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.maxmemory_policy.to_string(),
+        set: |config, raw| {
+            EvictionPolicy::parse(raw).map(|policy| config.maxmemory_policy = policy)
+        },
+    },
+    Parameter {
+        name: "maxmemory-samples",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.maxmemory_samples.to_string(),
+        set: |config, raw| parse_integer(raw, 1, 64).map(|n| config.maxmemory_samples = n as usize),
+    },
+    Parameter {
+        name: "timeout",
+        alias: None,
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.timeout.to_string(),
+        set: |config, raw| parse_integer(raw, 0, i32::MAX as u64).map(|n| config.timeout = n),
+    },
+    Parameter {
+        name: "busy-reply-threshold",
+        alias: Some("lua-time-limit"),
+        mutable: true,
+        multi_arg: false,
+        get: |config| config.busy_reply_threshold.to_string(),
+        set: |config, raw| {
+            parse_integer(raw, 0, i64::MAX as u64).map(|n| config.busy_reply_threshold = n)
+        },
+    },
+];
+
+impl Config {
+    fn parameter(name: &str) -> Option<&'static Parameter> {
+        let name = name.to_lowercase();
+        PARAMETERS
+            .iter()
+            .find(|parameter| parameter.name == name || parameter.alias == Some(name.as_str()))
+    }
+
+    /// Names and values of the parameters matching any of the glob patterns,
+    /// as CONFIG GET replies. Aliases only match by their exact name.
+    pub(crate) fn get(&self, patterns: &[String]) -> Vec<(String, String)> {
+        let matchers = patterns
+            .iter()
+            .map(|pattern| PatternMatcher::new(&pattern.to_lowercase()))
+            .collect::<Vec<_>>();
+        let patterns = patterns
+            .iter()
+            .map(|pattern| pattern.to_lowercase())
+            .collect::<Vec<_>>();
+
+        let mut values = vec![];
+        for parameter in PARAMETERS {
+            if matchers
+                .iter()
+                .any(|matcher| matcher.is_match(parameter.name))
+            {
+                values.push((parameter.name.to_string(), (parameter.get)(self)));
+            }
+            if let Some(alias) = parameter.alias {
+                if patterns.iter().any(|pattern| pattern == alias) {
+                    values.push((alias.to_string(), (parameter.get)(self)));
+                }
+            }
+        }
+
+        values
+    }
+
+    /// Applies a directive of redis.conf or of the command line, also for the
+    /// parameters that can't change while the server runs. `include` loads
+    /// another file, `files` being the ones being loaded.
+    fn apply_directive(
+        &mut self,
+        name: &str,
+        values: &[String],
+        files: &mut Vec<PathBuf>,
+    ) -> Result<(), String> {
+        if name.eq_ignore_ascii_case("include") {
+            let [path] = values else {
+                return Err("Bad directive or wrong number of arguments".into());
+            };
+            return self.load_directives(path, files);
+        }
+
+        let parameter = Self::parameter(name)
+            .filter(|parameter| parameter.multi_arg || values.len() == 1)
+            .ok_or("Bad directive or wrong number of arguments")?;
+        // A single argument of a multi-argument parameter holds all of them,
+        // as in `--bind "* -::*"`.
+        let value = match values {
+            [value] => value.clone(),
+            values => values
+                .iter()
+                .map(|value| quote_arg(value))
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        (parameter.set)(self, &value)
+    }
+
+    /// Sets parameters as CONFIG SET does: all of them or none.
+    pub(crate) fn set_at_runtime(&mut self, pairs: &[(String, String)]) -> Result<(), String> {
+        let mut updated = self.clone();
+        let mut seen = vec![];
+
+        for (name, value) in pairs {
+            let Some(parameter) = Self::parameter(name) else {
+                return Err(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ));
+            };
+            let failed = |reason: &str| {
+                format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    name, reason
+                )
+            };
+            if seen.contains(&parameter.name) {
+                return Err(failed("duplicate parameter"));
+            }
+            seen.push(parameter.name);
+            if !parameter.mutable {
+                return Err(failed("can't set immutable config"));
+            }
+            (parameter.set)(&mut updated, value).map_err(|err| failed(&err))?;
+        }
+
+        *self = updated;
+        Ok(())
+    }
+
+    /// Applies the directives of a redis.conf file, which CONFIG REWRITE then
+    /// writes back to.
+    pub(crate) fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let absolute_path = std::fs::canonicalize(path)
+            .map_err(|err| format!("Fatal error, can't open config file '{}': {}", path, err))?;
+        self.load_directives(path, &mut vec![])?;
+        self.file = Some(absolute_path.display().to_string());
+        Ok(())
+    }
+
+    fn load_directives(&mut self, path: &str, files: &mut Vec<PathBuf>) -> Result<(), String> {
+        let open_error = |err: std::io::Error| {
+            format!("Fatal error, can't open config file '{}': {}", path, err)
+        };
+        let absolute_path = std::fs::canonicalize(path).map_err(open_error)?;
+        if files.contains(&absolute_path) {
+            return Err(format!(
+                "Fatal error, config file '{}' includes itself",
+                path
+            ));
+        }
+        let content = std::fs::read_to_string(&absolute_path).map_err(open_error)?;
+
+        files.push(absolute_path);
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            let args = split_args(line.as_bytes())
+                .map_err(|err| config_file_error(path, i + 1, line, &err))?;
+            let Some((name, values)) = args.split_first() else {
+                continue;
+            };
+            self.apply_directive(name, values, files).map_err(|err| {
+                match err.starts_with("*** FATAL CONFIG FILE ERROR") {
+                    // Already located in the included file.
+                    true => err,
+                    false => config_file_error(path, i + 1, line, &err),
+                }
+            })?;
+        }
+        files.pop();
+
+        Ok(())
+    }
+
+    /// Applies options of the command line, given as `--<directive>` followed
+    /// by its arguments. An option without arguments, as `--tls-replication`,
+    /// stands for `yes`.
+    pub(crate) fn load_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut directives: Vec<(String, Vec<String>)> = vec![];
+        for arg in args {
+            // Arguments may start with `--` too, as long as no letter follows.
+            let name = arg
+                .strip_prefix("--")
+                .filter(|name| name.starts_with(|c: char| c.is_ascii_alphabetic()));
+            match (name, directives.last_mut()) {
+                (Some(name), _) => directives.push((name.to_string(), vec![])),
+                (None, Some((_, values))) => values.push(arg.clone()),
+                (None, None) => return Err(format!("Invalid argument '{}'", arg)),
+            }
+        }
+
+        for (name, mut values) in directives {
+            if values.is_empty() {
+                values.push("yes".into());
+            }
+            self.apply_directive(&name, &values, &mut vec![])
+                .map_err(|err| {
+                    format!(
+                        "*** FATAL CONFIG ERROR ***\nReading the command line option '--{} {}'\n{}",
+                        name,
+                        values.join(" "),
+                        err
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current settings back to the config file. Lines of the
+    /// parameters are updated in place, comments and other lines are kept,
+    /// and changed parameters missing from the file are appended.
+    pub(crate) fn rewrite(&self) -> Result<(), String> {
+        let Some(path) = &self.file else {
+            return Err("ERR The server is running without a config file".into());
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(format!("ERR Rewriting config file: {}", err)),
+        };
+
+        let mut lines = vec![];
+        let mut written = vec![];
+        for line in content.lines() {
+            let parameter = split_args(line.trim().as_bytes())
+                .ok()
+                .and_then(|args| args.first().and_then(|name| Self::parameter(name)));
+            match parameter {
+                Some(parameter) if written.contains(&parameter.name) => {}
+                Some(parameter) => {
+                    written.push(parameter.name);
+                    lines.push(self.directive(parameter));
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+
+        let defaults = Config::default();
+        let mut generated = PARAMETERS
+            .iter()
+            .filter(|parameter| !written.contains(&parameter.name))
+            .filter(|parameter| (parameter.get)(self) != (parameter.get)(&defaults))
+            .map(|parameter| self.directive(parameter))
+            .peekable();
+        if generated.peek().is_some() {
+            lines.push("# Generated by CONFIG REWRITE".into());
+            lines.extend(generated);
+        }
+
+        // Written next to the target and renamed, so the file is never half written.
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, lines.join("\n") + "\n")
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|err| format!("ERR Rewriting config file: {}", err))
+    }
+
+    pub(crate) fn network_config(&self) -> Result<NetworkConfig, Error> {
+        Ok(NetworkConfig {
+            bind: self.bind.clone(),
+            port: self.port,
+            unixsocket: self.unixsocket.clone(),
+            unixsocket_perm: self.unixsocketperm,
+            tls: self.tls_config()?,
+        })
+    }
+
+    fn tls_config(&self) -> Result<Option<TlsConfig>, Error> {
+        if self.tls_port == 0 && !self.tls_replication {
+            return Ok(None);
+        }
+
+        let (Some(cert_file), Some(key_file)) = (&self.tls_cert_file, &self.tls_key_file) else {
+            return Err("TLS requires tls-cert-file and tls-key-file".into());
+        };
+
+        Ok(Some(TlsConfig {
+            port: (self.tls_port != 0).then_some(self.tls_port),
+            cert_file: cert_file.clone(),
+            key_file: key_file.clone(),
+            ca_cert_file: self.tls_ca_cert_file.clone(),
+            auth_clients: self.tls_auth_clients,
+            auth_clients_user_cn: self.tls_auth_clients_user_cn,
+            replication: self.tls_replication,
+        }))
+    }
+
+    /// Line of redis.conf setting the parameter to its current value.
+    fn directive(&self, parameter: &Parameter) -> String {
+        let value = (parameter.get)(self);
+        if parameter.multi_arg {
+            format!("{} {}", parameter.name, value)
+        } else {
+            format!("{} {}", parameter.name, quote_arg(&value))
+        }
+    }
+}
+
+impl fmt::Display for TlsAuthClients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsAuthClients::No => write!(f, "no"),
+            TlsAuthClients::Optional => write!(f, "optional"),
+            TlsAuthClients::Yes => write!(f, "yes"),
+        }
+    }
+}
+
+fn config_file_error(path: &str, line_number: usize, line: &str, reason: &str) -> String {
+    format!(
+        "*** FATAL CONFIG FILE ERROR ***\nReading the configuration file {}, at line {}\n>>> '{}'\n{}",
+        path,
+        line_number,
+        line.trim(),
+        reason
+    )
+}
+
+/// Quotes an argument for redis.conf when it is empty or would not read back
+/// as a single argument.
+fn quote_arg(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !matches!(byte, b'"' | b'\'' | b'\\'));
+    if plain {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for byte in arg.bytes() {
+        match byte {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            byte if byte.is_ascii_graphic() || byte == b' ' => quoted.push(byte as char),
+            byte => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn optional(raw: &str) -> Option<String> {
+    (!raw.is_empty()).then(|| raw.to_string())
+}
+
+fn yes_no(on: bool) -> String {
+    if on { "yes" } else { "no" }.to_string()
+}
+
+fn parse_bool(raw: &str) -> Result<bool, String> {
+    match raw.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".into()),
+    }
+}
+
+fn parse_integer(raw: &str, min: u64, max: u64) -> Result<u64, String> {
+    raw.parse::<u64>()
+        .ok()
+        .filter(|n| (min..=max).contains(n))
+        .ok_or_else(|| format!("argument must be between {} and {} inclusive", min, max))
+}
+
+fn parse_memory(raw: &str) -> Result<usize, String> {
+    parse_memory_size(raw).map_err(|_| "argument must be a memory value".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+
+    fn pairs(raw: &[(&str, &str)]) -> Vec<(String, String)> {
+        raw.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_get_with_patterns_and_aliases() {
+        let config = Config {
+            replicaof: Some(("10.0.0.1".into(), 6380)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            pairs(&[("dir", "/tmp/redis-files"), ("dbfilename", "dump.rdb")]),
+            config.get(&["d?r".into(), "DBFILENAME".into()])
+        );
+        assert_eq!(
+            pairs(&[("slaveof", "10.0.0.1 6380")]),
+            config.get(&["slaveof".into()])
+        );
+        assert_eq!(
+            vec![
+                "tls-port",
+                "tls-cert-file",
+                "tls-key-file",
+                "tls-ca-cert-file"
+            ],
+            config
+                .get(&["tls-*-file".into(), "tls-port".into()])
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_set_at_runtime_is_atomic() {
+        let mut config = Config::default();
+
+        config
+            .set_at_runtime(&pairs(&[("maxmemory", "1mb"), ("timeout", "30")]))
+            .unwrap();
+        assert_eq!((1024 * 1024, 30), (config.maxmemory, config.timeout));
+
+        assert_eq!(
+            Err("ERR CONFIG SET failed (possibly related to argument 'maxmemory') - argument must be a memory value".into()),
+            config.set_at_runtime(&pairs(&[("timeout", "0"), ("maxmemory", "lots")]))
+        );
+        assert_eq!(30, config.timeout);
+        assert_eq!(
+            Err("ERR CONFIG SET failed (possibly related to argument 'port') - can't set immutable config".into()),
+            config.set_at_runtime(&pairs(&[("port", "7000")]))
+        );
+        assert_eq!(
+            Err("ERR Unknown option or number of arguments for CONFIG SET - 'nosuch'".into()),
+            config.set_at_runtime(&pairs(&[("nosuch", "1")]))
+        );
+        assert_eq!(
+            Err("ERR CONFIG SET failed (possibly related to argument 'TIMEOUT') - duplicate parameter".into()),
+            config.set_at_runtime(&pairs(&[("timeout", "1"), ("TIMEOUT", "2")]))
+        );
+    }
+
+    #[test]
+    fn test_load_and_rewrite_file() {
+        let path = std::env::temp_dir().join(format!("config-test-{}.conf", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(
+            &path,
+            "# Cache node\nport 7000\nbind 0.0.0.0 \"-::1\"\n\nmaxmemory 100mb\nmaxmemory 200mb\n",
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config.load_file(&path).unwrap();
+        assert_eq!(Some(path.clone()), config.file);
+        assert_eq!(7000, config.port);
+        assert_eq!(vec!["0.0.0.0", "-::1"], config.bind);
+        assert_eq!(200 * 1024 * 1024, config.maxmemory);
+
+        config
+            .set_at_runtime(&[
+                ("maxmemory".into(), "1gb".into()),
+                ("requirepass".into(), "two words".into()),
+            ])
+            .unwrap();
+        config.rewrite().unwrap();
+        assert_eq!(
+            "# Cache node\nport 7000\nbind 0.0.0.0 -::1\n\nmaxmemory 1073741824\n# Generated by CONFIG REWRITE\nrequirepass \"two words\"\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        let mut reloaded = Config::default();
+        reloaded.load_file(&path).unwrap();
+        assert_eq!(config, reloaded);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_include_and_command_line() {
+        let dir = std::env::temp_dir();
+        let main_path = dir.join(format!("config-main-{}.conf", std::process::id()));
+        let main_path = main_path.to_str().unwrap().to_string();
+        let included_path = dir.join(format!("config-included-{}.conf", std::process::id()));
+        let included_path = included_path.to_str().unwrap().to_string();
+        std::fs::write(
+            &main_path,
+            format!("timeout 10\ninclude {}\nport 7000\n", included_path),
+        )
+        .unwrap();
+        std::fs::write(&included_path, "port 7001\nrequirepass 'it\\'s'\n").unwrap();
+
+        let mut config = Config::default();
+        config.load_file(&main_path).unwrap();
+        assert_eq!((7000, 10), (config.port, config.timeout));
+        assert_eq!(Some("it's".to_string()), config.requirepass);
+
+        let args = [
+            "--port",
+            "7002",
+            "--bind",
+            "* -::*",
+            "--tls-replication",
+            "--requirepass",
+            "--",
+        ];
+        config.load_args(&args.map(String::from)).unwrap();
+        assert_eq!(7002, config.port);
+        assert_eq!(vec!["*", "-::*"], config.bind);
+        assert!(config.tls_replication);
+        assert_eq!(Some("--".to_string()), config.requirepass);
+
+        std::fs::write(&included_path, format!("include {}\n", main_path)).unwrap();
+        assert!(Config::default()
+            .load_file(&main_path)
+            .unwrap_err()
+            .ends_with("includes itself"));
+        std::fs::write(&included_path, "maxmemory lots\n").unwrap();
+        assert_eq!(
+            format!(
+                "*** FATAL CONFIG FILE ERROR ***\nReading the configuration file {}, at line 1\n>>> 'maxmemory lots'\nargument must be a memory value",
+                included_path
+            ),
+            Config::default().load_file(&main_path).unwrap_err()
+        );
+        std::fs::remove_file(&main_path).unwrap();
+        std::fs::remove_file(&included_path).unwrap();
+
+        let load_args = |args: &[&str]| {
+            Config::default().load_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+        };
+        assert!(load_args(&["--maxmemory", "a lot"]).is_err());
+        assert!(load_args(&["--no-such-option", "1"]).is_err());
+        assert!(load_args(&["--port", "1", "2"]).is_err());
+        assert!(load_args(&["6379"]).is_err());
+    }
+}
@@ -1,9 +1,19 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ops::Bound,
+};
+
+use indexmap::IndexMap;
+use rand::Rng;
 
 use crate::common::{
-    current_time_ms, decode_geohash, encode_geohash, geohash_get_distance, CompleteStreamEntryID,
-    KeyValuePair, PatternMatcher, SortedSet, StreamEntryID, MAX_LAT, MAX_LON, MIN_LAT, MIN_LON,
+    current_time_ms, decode_geohash, encode_geohash, geohash_get_distance, sampled_memory_usage,
+    CompleteStreamEntryID, KeyValuePair, PatternMatcher, SortedSet, StreamEntryID, MAX_LAT,
+    MAX_LON, MIN_LAT, MIN_LON,
 };
+use crate::eviction::{EvictionPolicy, KeyUsage};
+use crate::pubsub::{KeyspaceEvent, KeyspaceEvents};
+use crate::rdb::RdbValue;
 
 fn resolve_start_index(start: i64, len: usize) -> usize {
     if start < 0 {
@@ -42,14 +52,135 @@ impl StreamValue {
 
 pub(crate) type StreamEntry = Vec<StreamValue>;
 
+/// Stream entries ordered by ID in a B-tree, so range seeks are O(log n). The
+/// top ID is kept aside to validate and generate new IDs in O(1).
+#[derive(Default)]
+pub(crate) struct Stream {
+    entries: BTreeMap<CompleteStreamEntryID, KeyValuePairList>,
+    last_id: CompleteStreamEntryID,
+}
+
+impl Stream {
+    fn last_id(&self) -> &CompleteStreamEntryID {
+        &self.last_id
+    }
+
+    fn push(
+        &mut self,
+        id: StreamEntryID,
+        kvpairs: KeyValuePairList,
+    ) -> Result<CompleteStreamEntryID, String> {
+        let id = self.next_id(id)?;
+
+        self.entries.insert(id.clone(), kvpairs);
+        self.last_id = id.clone();
+
+        Ok(id)
+    }
+
+    fn next_id(&self, id: StreamEntryID) -> Result<CompleteStreamEntryID, String> {
+        let last_id = &self.last_id;
+
+        let id = match id {
+            StreamEntryID::Full(id) => id,
+            StreamEntryID::MsOnly(ms) => Self::id_with_next_seq(ms, last_id)?,
+            StreamEntryID::Wildcard => {
+                Self::id_with_next_seq(current_time_ms().max(last_id.0), last_id)?
+            }
+        };
+
+        if id.0 == 0 && id.1 == 0 {
+            return Err("ERR The ID specified in XADD must be greater than 0-0".into());
+        }
+
+        if &id <= last_id {
+            return Err(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .into(),
+            );
+        }
+
+        Ok(id)
+    }
+
+    fn id_with_next_seq(
+        ms: u128,
+        last_id: &CompleteStreamEntryID,
+    ) -> Result<CompleteStreamEntryID, String> {
+        if ms != last_id.0 {
+            return Ok(CompleteStreamEntryID(ms, if ms == 0 { 1 } else { 0 }));
+        }
+
+        match last_id.1.checked_add(1) {
+            Some(seq) => Ok(CompleteStreamEntryID(ms, seq)),
+            None => Err(
+                "ERR The stream has exhausted the last possible ID, unable to add more items"
+                    .into(),
+            ),
+        }
+    }
+
+    fn range(
+        &self,
+        start: &CompleteStreamEntryID,
+        start_inclusive: bool,
+        end: &CompleteStreamEntryID,
+        end_inclusive: bool,
+        count: usize,
+    ) -> StreamEntry {
+        // `BTreeMap::range` panics on inverted or empty exclusive ranges.
+        if start > end || (start == end && !(start_inclusive && end_inclusive)) {
+            return vec![];
+        }
+
+        let start = if start_inclusive {
+            Bound::Included(start)
+        } else {
+            Bound::Excluded(start)
+        };
+        let end = if end_inclusive {
+            Bound::Included(end)
+        } else {
+            Bound::Excluded(end)
+        };
+
+        self.entries
+            .range((start, end))
+            .take(count)
+            .map(|(id, kvpairs)| StreamValue::new(id.clone(), kvpairs.clone()))
+            .collect()
+    }
+}
+
 enum Entry {
     Value(ValueEntry),
     Array(VecDeque<String>),
-    Stream(StreamEntry),
+    Stream(Stream),
     SortedSet(SortedSet),
 }
 
+/// Rough heap and bookkeeping cost of one allocation, for memory estimates.
+const ALLOCATION_OVERHEAD: usize = 16;
+
 impl Entry {
+    /// Estimated memory used by the value, in bytes.
+    fn memory_usage(&self) -> usize {
+        match self {
+            Entry::Value(value_entry) => value_entry.value.len() + ALLOCATION_OVERHEAD,
+            Entry::Array(items) => sampled_memory_usage(items.iter(), |item| {
+                item.len() + ALLOCATION_OVERHEAD + std::mem::size_of::<String>()
+            }),
+            Entry::Stream(stream) => sampled_memory_usage(stream.entries.values(), |kvpairs| {
+                std::mem::size_of::<CompleteStreamEntryID>()
+                    + kvpairs
+                        .iter()
+                        .map(|(field, value)| field.len() + value.len() + 2 * ALLOCATION_OVERHEAD)
+                        .sum::<usize>()
+            }),
+            Entry::SortedSet(set) => set.memory_usage(),
+        }
+    }
+
     fn is_value(&self) -> bool {
         match self {
             Entry::Value(_) => true,
@@ -88,19 +219,520 @@ impl Entry {
     }
 }
 
+/// Snapshot of a watched key taken by WATCH, compared against the key at EXEC.
+#[derive(Clone, Copy)]
+pub(crate) struct KeyWatch {
+    version: u64,
+    alive: bool,
+}
+
+struct WatchedKey {
+    version: u64,
+    watcher_count: usize,
+}
+
+/// Lookup and expiry counters of a database, for INFO stats.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DbStats {
+    pub(crate) keyspace_hits: u64,
+    pub(crate) keyspace_misses: u64,
+    pub(crate) expired_keys: u64,
+    pub(crate) evicted_keys: u64,
+}
+
+/// Access and memory bookkeeping of a key, for eviction.
+struct KeyMeta {
+    usage: KeyUsage,
+    // Estimated when the key was last written.
+    memory: usize,
+}
+
+/// Writes that clients tracking keys have to be told about.
+#[derive(Default)]
+pub(crate) struct Modifications {
+    pub(crate) keys: Vec<String>,
+    /// Set when the keys were replaced all at once, by a flush or SWAPDB.
+    pub(crate) flushed: bool,
+}
+
 pub(crate) struct Database {
     dict: HashMap<String, Entry>,
+    // Modification versions, only kept for keys that are being watched.
+    watched_keys: HashMap<String, WatchedKey>,
+    version_counter: u64,
+    // Keys with an expiry, ordered by deadline for the active expire cycle.
+    expires: BTreeSet<(u128, String)>,
+    keyspace_events: KeyspaceEvents,
+    // Events recorded since the last `take_events`.
+    events: Vec<KeyspaceEvent>,
+    // Only recorded while some client tracks keys.
+    modifications: Option<Modifications>,
+    stats: DbStats,
+    // Kept in an IndexMap so that eviction can sample keys at random.
+    key_meta: IndexMap<String, KeyMeta>,
+    // Sum of the memory of `key_meta`.
+    used_memory: usize,
+    // Keys written since their memory was last estimated.
+    memory_dirty: HashSet<String>,
+    eviction_policy: EvictionPolicy,
 }
 
 impl Database {
     pub(crate) fn new() -> Self {
         Self {
             dict: HashMap::new(),
+            watched_keys: HashMap::new(),
+            version_counter: 0,
+            expires: BTreeSet::new(),
+            keyspace_events: KeyspaceEvents::default(),
+            events: vec![],
+            modifications: None,
+            stats: DbStats::default(),
+            key_meta: IndexMap::new(),
+            used_memory: 0,
+            memory_dirty: HashSet::new(),
+            eviction_policy: EvictionPolicy::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> DbStats {
+        self.stats
+    }
+
+    pub(crate) fn reset_stats(&mut self) {
+        self.stats = DbStats::default();
+    }
+
+    /// Counts a read of the key as a hit or a miss.
+    pub(crate) fn record_lookup(&mut self, key: &str) {
+        if self.is_alive(key) {
+            self.stats.keyspace_hits += 1;
+        } else {
+            self.stats.keyspace_misses += 1;
+        }
+    }
+
+    /// Keys, keys with an expiry and their average time to live in
+    /// milliseconds, as INFO keyspace shows them.
+    pub(crate) fn keyspace_info(&self) -> (usize, usize, u128) {
+        let now = current_time_ms();
+        let ttls = self
+            .expires
+            .iter()
+            .filter(|(expiry_ms, _)| *expiry_ms >= now)
+            .map(|(expiry_ms, _)| expiry_ms - now)
+            .collect::<Vec<_>>();
+        let avg_ttl = match ttls.len() {
+            0 => 0,
+            len => ttls.iter().sum::<u128>() / len as u128,
+        };
+
+        (self.key_count(), ttls.len(), avg_ttl)
+    }
+
+    /// Estimated memory used by the keys and values, in bytes. Only the keys
+    /// written since the last call are estimated again.
+    pub(crate) fn used_memory(&mut self) -> usize {
+        for key in std::mem::take(&mut self.memory_dirty) {
+            let memory = self.dict.get(&key).map(|entry| {
+                key.len()
+                    + ALLOCATION_OVERHEAD
+                    + std::mem::size_of::<(String, Entry)>()
+                    + entry.memory_usage()
+            });
+            match (memory, self.key_meta.get_mut(&key)) {
+                (Some(memory), Some(meta)) => {
+                    self.used_memory = self.used_memory - meta.memory + memory;
+                    meta.memory = memory;
+                }
+                (Some(memory), None) => {
+                    self.used_memory += memory;
+                    let usage = KeyUsage::new();
+                    self.key_meta.insert(key, KeyMeta { usage, memory });
+                }
+                (None, Some(_)) => {
+                    let meta = self.key_meta.swap_remove(&key).expect("Key has metadata");
+                    self.used_memory -= meta.memory;
+                }
+                (None, None) => {}
+            }
+        }
+
+        self.used_memory
+    }
+
+    pub(crate) fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    /// Records a read or write of the key, for OBJECT FREQ and IDLETIME and
+    /// the LRU and LFU policies.
+    pub(crate) fn record_access(&mut self, key: &str) {
+        if let Some(meta) = self.key_meta.get_mut(key) {
+            meta.usage.touch();
+        }
+    }
+
+    /// The key the eviction policy would pick among `samples` random keys,
+    /// with its score: the higher, the better a candidate.
+    pub(crate) fn eviction_candidate(&mut self, samples: usize) -> Option<(String, u128)> {
+        self.used_memory();
+        let policy = self.eviction_policy;
+        if policy == EvictionPolicy::VolatileTtl {
+            let (expiry_ms, key) = self.expires.first()?;
+            return Some((key.clone(), u128::MAX - expiry_ms));
+        }
+        if self.key_meta.is_empty() || (policy.is_volatile() && self.expires.is_empty()) {
+            return None;
+        }
+
+        let mut rng = rand::rng();
+        let mut best: Option<(String, u128)> = None;
+        let mut found = 0;
+        // Volatile policies may have to draw more keys to find ones with an
+        // expiry.
+        for _ in 0..samples * 10 {
+            if found == samples {
+                break;
+            }
+            let index = rng.random_range(0..self.key_meta.len());
+            let (key, meta) = self.key_meta.get_index(index).expect("Index is in range");
+            let expiry_ms = self.expiry_of(key);
+            if policy.is_volatile() && expiry_ms.is_none() {
+                continue;
+            }
+            found += 1;
+            let score = policy.score(&meta.usage, expiry_ms)?;
+            if best
+                .as_ref()
+                .is_none_or(|(_, best_score)| score > *best_score)
+            {
+                best = Some((key.clone(), score));
+            }
+        }
+
+        best.or_else(|| {
+            let (_, key) = self.expires.first()?;
+            Some((key.clone(), 0))
+        })
+    }
+
+    /// Evicts the key to free memory. Returns how much was freed.
+    pub(crate) fn evict(&mut self, key: &str) -> usize {
+        let used_memory = self.used_memory();
+        if !self.remove_key(key) {
+            return 0;
+        }
+        self.notify(KeyspaceEvents::EVICTED, "evicted", key);
+        self.stats.evicted_keys += 1;
+
+        used_memory - self.used_memory()
+    }
+
+    /// Deletes the key, as DEL does. Returns whether it existed.
+    pub(crate) fn delete(&mut self, key: &str) -> bool {
+        if !self.is_alive(key) || !self.remove_key(key) {
+            return false;
+        }
+        self.notify(KeyspaceEvents::GENERIC, "del", key);
+        true
+    }
+
+    fn remove_key(&mut self, key: &str) -> bool {
+        if let Some(expiry_ms) = self.expiry_of(key) {
+            self.expires.remove(&(expiry_ms, key.to_string()));
+        }
+        if self.dict.remove(key).is_none() {
+            return false;
+        }
+        self.touch(key);
+        true
+    }
+
+    /// The logarithmic access counter of the key, for OBJECT FREQ.
+    pub(crate) fn object_freq(&self, key: &str) -> Result<Option<u8>, String> {
+        if !self.eviction_policy.is_lfu() {
+            return Err("ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.".into());
+        }
+        Ok(self
+            .key_usage(key)
+            .map(|usage| usage.frequency(current_time_ms())))
+    }
+
+    /// Seconds since the key was last accessed, for OBJECT IDLETIME.
+    pub(crate) fn object_idletime(&self, key: &str) -> Result<Option<u128>, String> {
+        if self.eviction_policy.is_lfu() {
+            return Err("ERR An LFU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.".into());
+        }
+        Ok(self
+            .key_usage(key)
+            .map(|usage| usage.idle_ms(current_time_ms()) / 1000))
+    }
+
+    /// Keys written since the last memory estimate have no metadata yet, so
+    /// they count as new.
+    fn key_usage(&self, key: &str) -> Option<KeyUsage> {
+        if !self.is_alive(key) {
+            return None;
+        }
+        Some(
+            self.key_meta
+                .get(key)
+                .map(|meta| meta.usage)
+                .unwrap_or_else(KeyUsage::new),
+        )
+    }
+
+    /// Starts or stops recording the modified keys for client tracking.
+    pub(crate) fn set_tracking(&mut self, enabled: bool) {
+        if !enabled {
+            self.modifications = None;
+        } else if self.modifications.is_none() {
+            self.modifications = Some(Modifications::default());
+        }
+    }
+
+    /// Writes recorded since the last call, if there were any.
+    pub(crate) fn take_modifications(&mut self) -> Option<Modifications> {
+        self.modifications
+            .as_mut()
+            .filter(|modifications| !modifications.keys.is_empty() || modifications.flushed)
+            .map(std::mem::take)
+    }
+
+    pub(crate) fn keyspace_events(&self) -> KeyspaceEvents {
+        self.keyspace_events
+    }
+
+    pub(crate) fn set_keyspace_events(&mut self, keyspace_events: KeyspaceEvents) {
+        self.keyspace_events = keyspace_events;
+    }
+
+    /// Records a keyspace event, if its class is enabled.
+    pub(crate) fn notify(&mut self, class: KeyspaceEvents, name: &'static str, key: &str) {
+        if self.keyspace_events.wants(class) {
+            self.events.push(KeyspaceEvent {
+                class,
+                name,
+                key: key.to_string(),
+            });
+        }
+    }
+
+    /// Events recorded since the last call, for the engine to publish.
+    pub(crate) fn take_events(&mut self) -> Vec<KeyspaceEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Removes the key if it has expired, as a lookup does in Redis. Returns
+    /// whether it was removed.
+    pub(crate) fn expire_if_needed(&mut self, key: &str) -> bool {
+        let Some(expiry_ms) = self.expiry_of(key) else {
+            return false;
+        };
+        if expiry_ms >= current_time_ms() {
+            return false;
+        }
+
+        self.dict.remove(key);
+        self.expires.remove(&(expiry_ms, key.to_string()));
+        self.touch(key);
+        self.notify(KeyspaceEvents::EXPIRED, "expired", key);
+        self.stats.expired_keys += 1;
+        true
+    }
+
+    /// Removes up to `limit` expired keys, soonest deadline first. Returns how
+    /// many were removed.
+    pub(crate) fn remove_expired_keys(&mut self, limit: usize) -> usize {
+        let now = current_time_ms();
+        let mut removed = 0;
+        while removed < limit {
+            match self.expires.first() {
+                Some((expiry_ms, _)) if *expiry_ms < now => {}
+                _ => break,
+            }
+            let (_, key) = self.expires.pop_first().expect("Index is not empty");
+            self.dict.remove(&key);
+            self.touch(&key);
+            self.notify(KeyspaceEvents::EXPIRED, "expired", &key);
+            removed += 1;
+        }
+        self.stats.expired_keys += removed as u64;
+
+        removed
+    }
+
+    fn expiry_of(&self, key: &str) -> Option<u128> {
+        match self.dict.get(key) {
+            Some(Entry::Value(value_entry)) => value_entry.expiry_timestamp_ms,
+            _ => None,
+        }
+    }
+
+    fn notify_if_new(&mut self, key: &str, existed: bool) {
+        if !existed {
+            self.notify(KeyspaceEvents::NEW, "new", key);
         }
     }
 
     pub(crate) fn clear(&mut self) {
-        self.dict.clear();
+        drop(self.take_entries());
+    }
+
+    /// Empties the database and frees the entries on a background thread, as
+    /// FLUSHDB ASYNC does.
+    pub(crate) fn clear_lazy(&mut self) {
+        let entries = self.take_entries();
+        tokio::task::spawn_blocking(move || drop(entries));
+    }
+
+    fn take_entries(&mut self) -> HashMap<String, Entry> {
+        let existing_watched_keys = self
+            .watched_keys
+            .keys()
+            .filter(|key| self.dict.contains_key(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in existing_watched_keys {
+            self.touch(&key);
+        }
+
+        self.expires.clear();
+        self.key_meta.clear();
+        self.used_memory = 0;
+        self.memory_dirty.clear();
+        if let Some(modifications) = &mut self.modifications {
+            modifications.flushed = true;
+        }
+        std::mem::take(&mut self.dict)
+    }
+
+    /// Number of live keys.
+    pub(crate) fn key_count(&self) -> usize {
+        self.dict.keys().filter(|key| self.is_alive(key)).count()
+    }
+
+    /// Moves the key into `dest`, unless it is missing here or already exists
+    /// there.
+    pub(crate) fn move_key(&mut self, key: &str, dest: &mut Database) -> bool {
+        dest.expire_if_needed(key);
+        if !self.is_alive(key) || dest.is_alive(key) {
+            return false;
+        }
+
+        if let Some(expiry_ms) = self.expiry_of(key) {
+            let indexed = (expiry_ms, key.to_string());
+            self.expires.remove(&indexed);
+            dest.expires.insert(indexed);
+        }
+        let entry = self.dict.remove(key).expect("Live key has an entry");
+        self.touch(key);
+        dest.touch(key);
+        dest.dict.insert(key.to_string(), entry);
+        if let Some(meta) = self.key_meta.swap_remove(key) {
+            self.used_memory -= meta.memory;
+            dest.used_memory += meta.memory;
+            dest.key_meta.insert(key.to_string(), meta);
+        }
+        self.notify(KeyspaceEvents::GENERIC, "move_from", key);
+        dest.notify(KeyspaceEvents::GENERIC, "move_to", key);
+        true
+    }
+
+    /// Swaps the keys with `other`, as SWAPDB does. Watches stay with their
+    /// database, so watched keys that exist on either side count as modified.
+    pub(crate) fn swap_entries(&mut self, other: &mut Database) {
+        std::mem::swap(&mut self.dict, &mut other.dict);
+        std::mem::swap(&mut self.expires, &mut other.expires);
+        std::mem::swap(&mut self.key_meta, &mut other.key_meta);
+        std::mem::swap(&mut self.used_memory, &mut other.used_memory);
+        std::mem::swap(&mut self.memory_dirty, &mut other.memory_dirty);
+        for db in [&mut *self, &mut *other] {
+            if let Some(modifications) = &mut db.modifications {
+                modifications.flushed = true;
+            }
+        }
+
+        let in_either =
+            |key: &&String| self.dict.contains_key(*key) || other.dict.contains_key(*key);
+        let swapped_keys = self
+            .watched_keys
+            .keys()
+            .filter(&in_either)
+            .cloned()
+            .collect::<Vec<_>>();
+        let other_swapped_keys = other
+            .watched_keys
+            .keys()
+            .filter(&in_either)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in swapped_keys {
+            self.touch(&key);
+        }
+        for key in other_swapped_keys {
+            other.touch(&key);
+        }
+    }
+
+    pub(crate) fn watch(&mut self, key: &str) -> KeyWatch {
+        let watched_key = self
+            .watched_keys
+            .entry(key.to_string())
+            .or_insert(WatchedKey {
+                version: self.version_counter,
+                watcher_count: 0,
+            });
+        watched_key.watcher_count += 1;
+
+        KeyWatch {
+            version: watched_key.version,
+            alive: self.is_alive(key),
+        }
+    }
+
+    pub(crate) fn unwatch(&mut self, key: &str) {
+        if let Some(watched_key) = self.watched_keys.get_mut(key) {
+            watched_key.watcher_count -= 1;
+            if watched_key.watcher_count == 0 {
+                self.watched_keys.remove(key);
+            }
+        }
+    }
+
+    /// A watched key counts as modified when it was written or when it expired
+    /// since WATCH, as in Redis.
+    pub(crate) fn is_modified_since(&self, key: &str, watch: &KeyWatch) -> bool {
+        let version = self
+            .watched_keys
+            .get(key)
+            .map(|watched_key| watched_key.version);
+
+        version != Some(watch.version) || (watch.alive && !self.is_alive(key))
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.memory_dirty.insert(key.to_string());
+        if let Some(modifications) = &mut self.modifications {
+            modifications.keys.push(key.to_string());
+        }
+        if let Some(watched_key) = self.watched_keys.get_mut(key) {
+            self.version_counter += 1;
+            watched_key.version = self.version_counter;
+        }
+    }
+
+    pub(crate) fn is_alive(&self, key: &str) -> bool {
+        match self.dict.get(key) {
+            Some(Entry::Value(value_entry)) => value_entry
+                .expiry_timestamp_ms
+                .map(|expiry_timestamp_ms| expiry_timestamp_ms >= current_time_ms())
+                .unwrap_or(true),
+            Some(_) => true,
+            None => false,
+        }
     }
 
     pub(crate) fn set(
@@ -110,6 +742,20 @@ impl Database {
         expiry_ms: Option<u128>, /* Absolute value. */
     ) -> Result<(), String> {
         self.assert_single_value(&key)?;
+        self.touch(&key);
+
+        let existed = self.dict.contains_key(&key);
+        if let Some(old_expiry_ms) = self.expiry_of(&key) {
+            self.expires.remove(&(old_expiry_ms, key.clone()));
+        }
+        if let Some(expiry_ms) = expiry_ms {
+            self.expires.insert((expiry_ms, key.clone()));
+        }
+        self.notify_if_new(&key, existed);
+        self.notify(KeyspaceEvents::STRING, "set", &key);
+        if expiry_ms.is_some() {
+            self.notify(KeyspaceEvents::GENERIC, "expire", &key);
+        }
 
         self.dict
             .entry(key)
@@ -155,6 +801,7 @@ impl Database {
     ) -> Result<usize, String> {
         self.assert_array(&key)?;
 
+        let existed = self.dict.contains_key(&key);
         let entry = self
             .dict
             .entry(key.clone())
@@ -166,8 +813,13 @@ impl Database {
         for value in values {
             array.push_back(value);
         }
+        let len = array.len();
 
-        Ok(array.len())
+        self.touch(&key);
+        self.notify_if_new(&key, existed);
+        self.notify(KeyspaceEvents::LIST, "rpush", &key);
+
+        Ok(len)
     }
 
     pub(crate) fn insert_to_array(
@@ -177,6 +829,7 @@ impl Database {
     ) -> Result<usize, String> {
         self.assert_array(&key)?;
 
+        let existed = self.dict.contains_key(&key);
         let entry = self
             .dict
             .entry(key.clone())
@@ -188,8 +841,13 @@ impl Database {
         for value in values {
             array.push_front(value);
         }
+        let len = array.len();
 
-        Ok(array.len())
+        self.touch(&key);
+        self.notify_if_new(&key, existed);
+        self.notify(KeyspaceEvents::LIST, "lpush", &key);
+
+        Ok(len)
     }
 
     pub(crate) fn get_list_lrange(
@@ -248,7 +906,11 @@ impl Database {
         };
 
         match array.pop_front() {
-            Some(elem) => Ok(Some(elem)),
+            Some(elem) => {
+                self.touch(key);
+                self.notify(KeyspaceEvents::LIST, "lpop", key);
+                Ok(Some(elem))
+            }
             _ => Ok(None),
         }
     }
@@ -265,7 +927,11 @@ impl Database {
         };
 
         match array.pop_back() {
-            Some(elem) => Ok(Some(elem)),
+            Some(elem) => {
+                self.touch(key);
+                self.notify(KeyspaceEvents::LIST, "rpop", key);
+                Ok(Some(elem))
+            }
             _ => Ok(None),
         }
     }
@@ -298,6 +964,9 @@ impl Database {
             out.push(array.pop_front().unwrap());
         }
 
+        self.touch(key);
+        self.notify(KeyspaceEvents::LIST, "lpop", key);
+
         Ok(Some(out))
     }
 
@@ -329,6 +998,9 @@ impl Database {
             out.push(array.pop_back().unwrap());
         }
 
+        self.touch(key);
+        self.notify(KeyspaceEvents::LIST, "rpop", key);
+
         Ok(Some(out))
     }
 
@@ -347,14 +1019,19 @@ impl Database {
     ) -> Result<CompleteStreamEntryID, String> {
         self.assert_stream(&key)?;
 
-        let stream = self.dict.entry(key).or_insert(Entry::Stream(Vec::new()));
+        let existed = self.dict.contains_key(&key);
+        let stream = self
+            .dict
+            .entry(key.clone())
+            .or_insert(Entry::Stream(Stream::default()));
         let Entry::Stream(stream) = stream else {
             unreachable!()
         };
 
-        let id = Self::resolve_stream_entry_id(id, stream)?;
-
-        stream.push(StreamValue::new(id.clone(), kvpairs));
+        let id = stream.push(id, kvpairs)?;
+        self.touch(&key);
+        self.notify_if_new(&key, existed);
+        self.notify(KeyspaceEvents::STREAM, "xadd", &key);
 
         Ok(id)
     }
@@ -408,16 +1085,13 @@ impl Database {
             unreachable!()
         };
 
-        if stream.is_empty() {
-            return Ok(CompleteStreamEntryID(0, 0));
-        }
-
-        Ok(stream.last().unwrap().id.clone())
+        Ok(stream.last_id().clone())
     }
 
     pub(crate) fn incr(&mut self, key: &str) -> Result<i64, String> {
         self.assert_single_value(key)?;
 
+        let existed = self.dict.contains_key(key);
         let Entry::Value(value_entry) =
             self.dict
                 .entry(key.to_string())
@@ -434,10 +1108,42 @@ impl Database {
             + 1;
 
         value_entry.value = num.to_string();
+        self.touch(key);
+        self.notify_if_new(key, existed);
+        self.notify(KeyspaceEvents::STRING, "incrby", key);
 
         Ok(num)
     }
 
+    /// The live keys with their absolute expiry and value, for SAVE and full
+    /// resyncs.
+    pub(crate) fn snapshot_entries(&self) -> Vec<(String, Option<u128>, RdbValue)> {
+        self.dict
+            .iter()
+            .filter(|(key, _)| self.is_alive(key))
+            .map(|(key, entry)| {
+                let value = match entry {
+                    Entry::Value(value_entry) => RdbValue::Str(value_entry.value.clone()),
+                    Entry::Array(items) => RdbValue::List(items.iter().cloned().collect()),
+                    Entry::SortedSet(set) => RdbValue::SortedSet(
+                        set.scored_members()
+                            .map(|(member, score)| (member.clone(), score))
+                            .collect(),
+                    ),
+                    Entry::Stream(stream) => RdbValue::Stream(
+                        stream
+                            .entries
+                            .iter()
+                            .map(|(id, kvpairs)| (id.clone(), kvpairs.clone()))
+                            .collect(),
+                        stream.last_id.clone(),
+                    ),
+                };
+                (key.clone(), self.expiry_of(key), value)
+            })
+            .collect()
+    }
+
     pub(crate) fn keys(&self, raw_pattern: &str) -> Vec<String> {
         let mut out = vec![];
         let matcher = PatternMatcher::new(raw_pattern);
@@ -458,6 +1164,7 @@ impl Database {
     ) -> Result<usize, String> {
         self.assert_set(key)?;
 
+        let existed = self.dict.contains_key(key);
         let Entry::SortedSet(entry) = self
             .dict
             .entry(key.clone())
@@ -473,6 +1180,10 @@ impl Database {
             }
         }
 
+        self.touch(key);
+        self.notify_if_new(key, existed);
+        self.notify(KeyspaceEvents::ZSET, "zadd", key);
+
         Ok(new_items)
     }
 
@@ -483,6 +1194,7 @@ impl Database {
     ) -> Result<usize, String> {
         self.assert_set(key)?;
 
+        let existed = self.dict.contains_key(key);
         let Entry::SortedSet(entry) = self
             .dict
             .entry(key.clone())
@@ -505,6 +1217,10 @@ impl Database {
             }
         }
 
+        self.touch(key);
+        self.notify_if_new(key, existed);
+        self.notify(KeyspaceEvents::ZSET, "zadd", key);
+
         Ok(new_items)
     }
 
@@ -654,6 +1370,11 @@ impl Database {
             }
         }
 
+        if total > 0 {
+            self.touch(key);
+            self.notify(KeyspaceEvents::ZSET, "zrem", key);
+        }
+
         Ok(total)
     }
 
@@ -704,65 +1425,8 @@ impl Database {
         let Entry::Stream(stream) = self.dict.get(key).unwrap() else {
             unreachable!()
         };
-        let mut out = vec![];
-
-        for elem in stream {
-            if out.len() >= count {
-                break;
-            }
-
-            if ((start_inclusive && &elem.id >= start) || (!start_inclusive && &elem.id > start))
-                && ((end_inclusive && &elem.id <= end) || (!end_inclusive && &elem.id < end))
-            {
-                out.push(elem.clone());
-            }
-        }
-
-        Ok(out)
-    }
-
-    fn resolve_stream_entry_id(
-        id: StreamEntryID,
-        stream: &StreamEntry,
-    ) -> Result<CompleteStreamEntryID, String> {
-        let ms = match &id {
-            StreamEntryID::Full(id) => id.0,
-            StreamEntryID::MsOnly(ms) => *ms,
-            StreamEntryID::Wildcard => current_time_ms(),
-        };
-
-        let seq = match id {
-            StreamEntryID::Full(id) => id.1,
-            StreamEntryID::MsOnly(_) | StreamEntryID::Wildcard => {
-                let mut max_available_idx = if ms == 0 { 1 } else { 0 };
-                for entry in stream {
-                    if entry.id.0 == ms && entry.id.1 >= max_available_idx {
-                        max_available_idx = entry.id.1 + 1;
-                    }
-                }
-                max_available_idx
-            }
-        };
-
-        if ms == 0 && seq == 0 {
-            return Err("ERR The ID specified in XADD must be greater than 0-0".into());
-        }
 
-        for entry in stream {
-            if entry.id.0 > ms {
-                return Err(
-                    "ERR The ID specified in XADD is equal or smaller than the target stream top item"
-                        .into(),
-                );
-            }
-            if entry.id.0 == ms {
-                if seq <= entry.id.1 {
-                    return Err("ERR The ID specified in XADD is equal or smaller than the target stream top item".into());
-                }
-            }
-        }
-
-        Ok(CompleteStreamEntryID(ms, seq))
+        Ok(stream.range(start, start_inclusive, end, end_inclusive, count))
     }
 
     fn assert_array(&self, key: &str) -> Result<(), String> {
@@ -813,3 +1477,310 @@ impl Database {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        common::{current_time_ms, CompleteStreamEntryID, StreamEntryID},
+        database::Database,
+        eviction::EvictionPolicy,
+        pubsub::KeyspaceEvents,
+    };
+
+    fn id(ms: u128, seq: usize) -> CompleteStreamEntryID {
+        CompleteStreamEntryID(ms, seq)
+    }
+
+    fn push(db: &mut Database, raw: StreamEntryID) -> Result<CompleteStreamEntryID, String> {
+        db.stream_push("s".into(), raw, vec![("k".into(), "v".into())])
+    }
+
+    fn range_ids(
+        db: &Database,
+        start: CompleteStreamEntryID,
+        end: CompleteStreamEntryID,
+        count: usize,
+    ) -> Vec<CompleteStreamEntryID> {
+        db.stream_get_range("s", &start, &end, count)
+            .unwrap()
+            .into_iter()
+            .map(|value| value.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_id_generation() {
+        let mut db = Database::new();
+
+        assert_eq!(Ok(id(0, 1)), push(&mut db, StreamEntryID::MsOnly(0)));
+        assert_eq!(Ok(id(0, 2)), push(&mut db, StreamEntryID::MsOnly(0)));
+        assert_eq!(Ok(id(5, 0)), push(&mut db, StreamEntryID::MsOnly(5)));
+        assert_eq!(Ok(id(5, 1)), push(&mut db, StreamEntryID::MsOnly(5)));
+        assert_eq!(Ok(id(7, 3)), push(&mut db, StreamEntryID::Full(id(7, 3))));
+        assert_eq!(Ok(id(7, 4)), push(&mut db, StreamEntryID::MsOnly(7)));
+
+        let wildcard = push(&mut db, StreamEntryID::Wildcard).unwrap();
+        assert!(wildcard > id(7, 4));
+        assert_eq!(wildcard, db.resolve_latest_stream_id("s").unwrap());
+    }
+
+    #[test]
+    fn test_stream_id_validation() {
+        let mut db = Database::new();
+
+        assert_eq!(
+            Err("ERR The ID specified in XADD must be greater than 0-0".to_string()),
+            push(&mut db, StreamEntryID::Full(id(0, 0)))
+        );
+
+        push(&mut db, StreamEntryID::Full(id(10, 5))).unwrap();
+
+        let top_item_err = Err(
+            "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                .to_string(),
+        );
+        assert_eq!(top_item_err, push(&mut db, StreamEntryID::Full(id(10, 5))));
+        assert_eq!(top_item_err, push(&mut db, StreamEntryID::Full(id(10, 4))));
+        assert_eq!(top_item_err, push(&mut db, StreamEntryID::Full(id(9, 9))));
+        assert_eq!(top_item_err, push(&mut db, StreamEntryID::MsOnly(9)));
+        assert_eq!(Ok(id(10, 6)), push(&mut db, StreamEntryID::MsOnly(10)));
+
+        push(&mut db, StreamEntryID::Full(id(11, usize::MAX))).unwrap();
+        assert!(push(&mut db, StreamEntryID::MsOnly(11)).is_err());
+    }
+
+    #[test]
+    fn test_stream_range_boundaries() {
+        let mut db = Database::new();
+        for ms in 1..=5 {
+            for seq in 0..2 {
+                push(&mut db, StreamEntryID::Full(id(ms, seq))).unwrap();
+            }
+        }
+
+        assert_eq!(
+            10,
+            range_ids(&db, id(0, 0), CompleteStreamEntryID::max(), usize::MAX).len()
+        );
+        assert_eq!(
+            vec![id(2, 1), id(3, 0), id(3, 1)],
+            range_ids(&db, id(2, 1), id(3, 1), usize::MAX)
+        );
+        assert_eq!(
+            vec![id(2, 0), id(2, 1)],
+            range_ids(&db, id(2, 0), id(2, usize::MAX), usize::MAX)
+        );
+        assert_eq!(
+            vec![id(4, 0)],
+            range_ids(&db, id(4, 0), id(4, 0), usize::MAX)
+        );
+        assert_eq!(
+            vec![id(1, 0), id(1, 1)],
+            range_ids(&db, id(0, 0), id(9, 9), 2)
+        );
+        assert!(range_ids(&db, id(4, 0), id(3, 0), usize::MAX).is_empty());
+        assert!(range_ids(&db, id(6, 0), id(9, 0), usize::MAX).is_empty());
+        assert!(range_ids(&db, id(1, 0), id(9, 0), 0).is_empty());
+    }
+
+    #[test]
+    fn test_watch_detects_writes() {
+        let mut db = Database::new();
+        db.set("a".into(), "1".into(), None).unwrap();
+
+        let watch_a = db.watch("a");
+        let watch_b = db.watch("b");
+        assert!(!db.is_modified_since("a", &watch_a));
+        assert!(!db.is_modified_since("b", &watch_b));
+
+        db.set("a".into(), "2".into(), None).unwrap();
+        assert!(db.is_modified_since("a", &watch_a));
+        assert!(!db.is_modified_since("b", &watch_b));
+
+        db.push_to_array("b".into(), vec!["x".into()]).unwrap();
+        assert!(db.is_modified_since("b", &watch_b));
+    }
+
+    #[test]
+    fn test_watch_ignores_noop_writes() {
+        let mut db = Database::new();
+        let watch = db.watch("list");
+
+        assert_eq!(Ok(None), db.list_pop_one_front("list"));
+        assert!(db
+            .stream_push("list".into(), StreamEntryID::Full(id(0, 0)), vec![])
+            .is_err());
+        assert!(!db.is_modified_since("list", &watch));
+    }
+
+    #[test]
+    fn test_watch_detects_expiry_and_flush() {
+        let mut db = Database::new();
+        db.set("volatile".into(), "v".into(), Some(current_time_ms() + 20))
+            .unwrap();
+        db.set("expired".into(), "v".into(), Some(current_time_ms() - 1))
+            .unwrap();
+        db.set("persistent".into(), "v".into(), None).unwrap();
+
+        let watch_volatile = db.watch("volatile");
+        let watch_expired = db.watch("expired");
+        let watch_persistent = db.watch("persistent");
+
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        assert!(db.is_modified_since("volatile", &watch_volatile));
+        assert!(!db.is_modified_since("expired", &watch_expired));
+        assert!(!db.is_modified_since("persistent", &watch_persistent));
+
+        db.clear();
+        assert!(db.is_modified_since("persistent", &watch_persistent));
+    }
+
+    #[test]
+    fn test_unwatch_forgets_versions() {
+        let mut db = Database::new();
+        let first = db.watch("a");
+        let second = db.watch("a");
+
+        db.unwatch("a");
+        db.set("a".into(), "1".into(), None).unwrap();
+        assert!(db.is_modified_since("a", &second));
+
+        db.unwatch("a");
+        assert!(db.is_modified_since("a", &first));
+        assert!(db.watched_keys.is_empty());
+    }
+
+    #[test]
+    fn test_stream_read_exclusive_start() {
+        let mut db = Database::new();
+        for seq in 1..=3 {
+            push(&mut db, StreamEntryID::Full(id(1, seq))).unwrap();
+        }
+
+        let read = |start: CompleteStreamEntryID| {
+            db.stream_read_multi_from_id_exclusive(&vec![("s".to_string(), start)], usize::MAX)
+                .unwrap()
+        };
+
+        let streams = read(id(1, 1));
+        assert_eq!(1, streams.len());
+        assert_eq!(
+            vec![id(1, 2), id(1, 3)],
+            streams[0]
+                .1
+                .iter()
+                .map(|value| value.id.clone())
+                .collect::<Vec<_>>()
+        );
+
+        assert!(read(id(1, 3)).is_empty());
+        assert!(read(CompleteStreamEntryID::max()).is_empty());
+    }
+
+    #[test]
+    fn test_move_and_swap_between_databases() {
+        let mut first = Database::new();
+        let mut second = Database::new();
+        first.set("a".into(), "1".into(), None).unwrap();
+        first.set("b".into(), "2".into(), None).unwrap();
+        second.set("b".into(), "3".into(), None).unwrap();
+
+        assert!(first.move_key("a", &mut second));
+        assert!(!first.move_key("a", &mut second));
+        assert!(!first.move_key("b", &mut second));
+        assert_eq!(1, first.key_count());
+        assert_eq!(Some(&"1".to_string()), second.get(&"a".into()).unwrap());
+
+        let watch = first.watch("a");
+        first.swap_entries(&mut second);
+        assert!(first.is_modified_since("a", &watch));
+        assert_eq!(2, first.key_count());
+        assert_eq!(Some(&"2".to_string()), second.get(&"b".into()).unwrap());
+    }
+
+    #[test]
+    fn test_expired_keys_are_removed_with_events() {
+        let mut db = Database::new();
+        db.set_keyspace_events(KeyspaceEvents::parse("KA").unwrap());
+        let past = current_time_ms() - 1;
+        db.set("gone".into(), "1".into(), Some(past)).unwrap();
+        db.set("lazy".into(), "2".into(), Some(past)).unwrap();
+        db.set("kept".into(), "3".into(), Some(past + 60_000))
+            .unwrap();
+        db.push_to_array("list".into(), vec!["x".into()]).unwrap();
+        let names = |db: &mut Database| {
+            db.take_events()
+                .into_iter()
+                .map(|event| format!("{} {}", event.name, event.key))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![
+                "set gone",
+                "expire gone",
+                "set lazy",
+                "expire lazy",
+                "set kept",
+                "expire kept",
+                "rpush list",
+            ],
+            names(&mut db)
+        );
+
+        assert!(db.expire_if_needed("lazy"));
+        assert!(!db.expire_if_needed("kept"));
+        assert_eq!(1, db.remove_expired_keys(10));
+        assert_eq!(0, db.remove_expired_keys(10));
+        assert_eq!(vec!["expired lazy", "expired gone"], names(&mut db));
+        assert_eq!(2, db.key_count());
+        assert_eq!(2, db.stats().expired_keys);
+        let (keys, expires, avg_ttl) = db.keyspace_info();
+        assert_eq!((2, 1), (keys, expires));
+        assert!(avg_ttl > 50_000 && avg_ttl <= 60_000);
+
+        db.set("kept".into(), "4".into(), None).unwrap();
+        assert_eq!(0, db.remove_expired_keys(10));
+        assert!(db.expires.is_empty());
+    }
+
+    #[test]
+    fn test_memory_accounting_and_eviction() {
+        let mut db = Database::new();
+        db.set("a".into(), "x".repeat(100), None).unwrap();
+        db.set("b".into(), "y".repeat(10), Some(current_time_ms() + 60_000))
+            .unwrap();
+        let used_memory = db.used_memory();
+        assert!(used_memory > 110, "{}", used_memory);
+
+        db.set("a".into(), "x".repeat(1000), None).unwrap();
+        assert_eq!(used_memory + 900, db.used_memory());
+
+        let mut other = Database::new();
+        assert!(db.move_key("a", &mut other));
+        assert_eq!(used_memory + 900, db.used_memory() + other.used_memory());
+
+        db.set_eviction_policy(EvictionPolicy::NoEviction);
+        assert_eq!(None, db.eviction_candidate(5));
+        db.set("c".into(), "z".into(), None).unwrap();
+        db.set_eviction_policy(EvictionPolicy::VolatileRandom);
+        assert_eq!(
+            Some("b".to_string()),
+            db.eviction_candidate(5).map(|(key, _)| key)
+        );
+
+        db.set_keyspace_events(KeyspaceEvents::parse("Ee").unwrap());
+        assert!(db.evict("b") > 10);
+        assert_eq!(1, db.stats().evicted_keys);
+        assert_eq!(1, db.take_events().len());
+        assert_eq!(None, db.eviction_candidate(5));
+
+        assert!(db.object_freq("c").is_err());
+        assert_eq!(Ok(Some(0)), db.object_idletime("c"));
+        assert_eq!(Ok(None), db.object_idletime("missing"));
+        assert!(db.delete("c"));
+        assert!(!db.delete("c"));
+        assert_eq!(0, db.used_memory());
+        other.clear();
+        assert_eq!(0, other.used_memory());
+    }
+}
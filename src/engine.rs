@@ -1,119 +1,282 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    sync::Arc,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 
 use anyhow::Context;
-use sha256::digest;
 use tokio::{
     io::AsyncWriteExt,
-    net::TcpSocket,
-    sync::{Mutex, Notify, RwLock},
-    time::timeout,
+    net::{TcpSocket, TcpStream},
+    sync::{watch, Mutex, Notify, RwLock, RwLockWriteGuard},
+    time::{sleep_until, timeout, timeout_at, Instant},
 };
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
 
 use crate::{
+    acl::{self, AclLog, KeyAccess, User},
+    clients::{validate_client_name, ClientRegistry, ClientType, ClientView, Pause},
     command_parser::CommandParser,
     commands::Command,
     common::*,
-    database::{Database, StreamEntry},
-    network::StreamReader,
-    rdb::{RdbFile, RdbValue},
-    resp::RespValue,
+    config::Config,
+    database::{Database, DbStats, KeyWatch, StreamEntry},
+    network::{Connection, StreamReader},
+    pubsub::{KeyspaceEvents, PubSub, SubscriptionKind},
+    rdb::{dump_functions, restore_functions, RdbContent, RdbFile, RdbValue},
+    resp::{RespParser, RespValue},
+    scripting::{
+        run_function, run_script, script_sha1, FunctionRegistry, ScriptControl, ScriptEnv,
+        ScriptRun,
+    },
+    stats::{bytes_to_human, cpu_time, resident_memory, unix_time, NetCounters, Stats},
+    tracking::{Tracking, TrackingOptions, INVALIDATE_CHANNEL},
 };
 
-const INFO_SECTIONS: [&'static str; 1] = ["replication"];
+/// Sections of INFO in the order they are shown. The last two are only
+/// shown when asked for, or with `all` and `everything`.
+const INFO_SECTIONS: [&str; 11] = [
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cpu",
+    "errorstats",
+    "keyspace",
+    "commandstats",
+    "latencystats",
+];
+const DEFAULT_INFO_SECTIONS: usize = 9;
+const DEFAULT_USER: &str = "default";
+/// Wait before a reader connects to its writer again.
+const REPLICATION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const NO_ACLFILE_ERROR: &str = "ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.";
+
+/// Lines of an INFO section, one `field:value` per line.
+fn info_lines<K: AsRef<str>>(fields: &[(K, String)]) -> String {
+    fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}\r\n", name.as_ref(), value))
+        .collect()
+}
 
 enum ArrayDirection {
     Front,
     Back,
 }
 
-struct User {
-    password: String,
+/// Commands queued between MULTI and EXEC. A queue-time error aborts the
+/// whole transaction, so EXEC replies with EXECABORT.
+#[derive(Default)]
+struct Transaction {
+    commands: Vec<Command>,
+    aborted: bool,
+}
+
+/// State of a client connection, owned by the task serving the connection.
+pub(crate) struct Session {
+    pub(crate) id: u64,
+    /// The authenticated user, `None` until the client authenticates.
+    pub(crate) user: Option<String>,
+    /// Address of the peer.
+    pub(crate) addr: String,
+    /// Index of the selected database.
+    pub(crate) db: usize,
+    /// Set by QUIT, the connection is closed once the reply is written.
+    pub(crate) closing: bool,
+    /// Whether CLIENT TRACKING is on.
+    pub(crate) tracking: bool,
+    /// Set by CLIENT CACHING for the next command, or the next transaction.
+    pub(crate) caching: Option<bool>,
+    /// Turns true when CLIENT KILL closes the connection.
+    pub(crate) killed: watch::Receiver<bool>,
 }
 
-impl User {
-    fn new(password: String) -> Self {
-        Self { password }
+impl Session {
+    /// Resolves once the connection is killed.
+    pub(crate) async fn killed(&self) {
+        Self::wait_killed(self.killed.clone()).await
+    }
+
+    pub(crate) async fn wait_killed(mut killed: watch::Receiver<bool>) {
+        // The sender is gone once the client left the registry, which only
+        // happens when the connection closes anyway.
+        if killed.wait_for(|killed| *killed).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    fn is_killed(&self) -> bool {
+        *self.killed.borrow()
     }
 
-    fn password_hash(&self) -> String {
-        digest(&self.password)
+    /// Client description used in the ACL log.
+    fn client_info(&self) -> String {
+        format!(
+            "id={} addr={} user={}",
+            self.id,
+            self.addr,
+            self.user.as_deref().unwrap_or("")
+        )
+    }
+}
+
+/// Runs Lua and other long synchronous work from a task. On the multi-thread
+/// runtime the other tasks of the worker move to another thread meanwhile, so
+/// a looping script still lets the other clients get BUSY and send SCRIPT
+/// KILL.
+fn run_blocking<T>(work: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::current().runtime_flavor() {
+        tokio::runtime::RuntimeFlavor::MultiThread => tokio::task::block_in_place(work),
+        _ => work(),
     }
 }
 
+/// Watches of a client, by database index and key.
+type WatchedKeys = HashMap<(usize, String), KeyWatch>;
+
 pub(crate) struct Engine {
-    db: RwLock<Database>,
-    dir: String,
-    dbfilename: String,
-    transaction_store: Mutex<HashMap<u64, Vec<Command>>>,
+    /// The logical databases, selected by index with SELECT.
+    dbs: RwLock<Vec<Database>>,
+    /// Settings, changed by CONFIG SET. Taken after the databases when both
+    /// are needed, as commands queued in a transaction read it while EXEC
+    /// holds the databases.
+    config: RwLock<Config>,
+    transaction_store: Mutex<HashMap<u64, Transaction>>,
+    watched_keys: Mutex<HashMap<u64, WatchedKeys>>,
     replication_role: RwLock<ReplicationRole>,
-    stream_notify: Arc<Notify>,
+    key_waiters: std::sync::Mutex<KeyWaiters>,
     wr_cmd_propagation_notify: Notify,
     wr_read_client_offset_notify: Arc<Notify>,
-    subscriptions: RwLock<HashMap<u64, HashMap<String, VecDeque<String>>>>,
-    subscription_notify: Notify,
-    users: RwLock<HashMap<String, User>>,
+    pubsub: PubSub,
+    tracking: Mutex<Tracking>,
+    clients: Mutex<ClientRegistry>,
+    /// Set by CLIENT PAUSE until the pause ends.
+    pause: watch::Sender<Option<Pause>>,
+    users: RwLock<BTreeMap<String, User>>,
+    acl_log: Mutex<AclLog>,
+    /// The running script, for BUSY replies and SCRIPT KILL.
+    script_control: Arc<ScriptControl>,
+    /// Set when the replication link to the writer uses TLS.
+    replication_tls: Option<TlsConnector>,
+    scripts: Mutex<HashMap<String /* Sha1 */, String /* Script */>>,
+    functions: RwLock<FunctionRegistry>,
+    stats: Mutex<Stats>,
+    /// Bytes moved by client connections, counted as they are read and
+    /// written.
+    net: Arc<NetCounters>,
 }
 
 impl Engine {
-    pub(crate) fn new(replica_of: Option<(String, u16)>, dir: String, dbfilename: String) -> Self {
-        let replication_role = match replica_of {
+    pub(crate) fn new(config: Config, replication_tls: Option<TlsConnector>) -> Self {
+        let replication_role = match &config.replicaof {
             Some((host, port)) => ReplicationRole::Reader(ReaderRole {
-                writer_host: host,
-                writer_port: port,
+                writer_host: host.clone(),
+                writer_port: *port,
+                link_up: false,
+                last_io: None,
+                offset: 0,
             }),
             None => ReplicationRole::Writer(WriterRole {
                 replid: new_master_replid(),
                 offset: 0,
                 clients: HashMap::new(),
                 write_queue: VecDeque::new(),
+                selected_db: None,
             }),
         };
 
         Self {
-            db: RwLock::new(Database::new()),
-            dir,
-            dbfilename,
-            stream_notify: Arc::new(Notify::new()),
+            dbs: RwLock::new(
+                (0..config.databases)
+                    .map(|_| {
+                        let mut db = Database::new();
+                        db.set_keyspace_events(config.notify_keyspace_events);
+                        db.set_eviction_policy(config.maxmemory_policy);
+                        db
+                    })
+                    .collect(),
+            ),
+            key_waiters: std::sync::Mutex::new(KeyWaiters::default()),
             transaction_store: Mutex::new(HashMap::new()),
+            watched_keys: Mutex::new(HashMap::new()),
             replication_role: RwLock::new(replication_role),
             wr_cmd_propagation_notify: Notify::new(),
             wr_read_client_offset_notify: Arc::new(Notify::new()),
-            subscriptions: RwLock::new(HashMap::new()),
-            subscription_notify: Notify::new(),
-            users: RwLock::new(HashMap::new()),
+            pubsub: PubSub::new(config.client_output_buffer_limit),
+            tracking: Mutex::new(Tracking::default()),
+            clients: Mutex::new(ClientRegistry::default()),
+            pause: watch::channel(None).0,
+            users: RwLock::new(BTreeMap::from([(
+                DEFAULT_USER.to_string(),
+                User::new_default(config.requirepass.as_deref()),
+            )])),
+            config: RwLock::new(config),
+            acl_log: Mutex::new(AclLog::default()),
+            script_control: Arc::new(ScriptControl::default()),
+            replication_tls,
+            scripts: Mutex::new(HashMap::new()),
+            functions: RwLock::new(FunctionRegistry::default()),
+            stats: Mutex::new(Stats::new(random_hex(40))),
+            net: Arc::new(NetCounters::default()),
         }
     }
 
     pub(crate) async fn init(&self, server_port: u16) -> Result<(), Error> {
+        self.stats.lock().await.tcp_port = server_port;
         self.reload_from_snapshot().await?;
 
-        if self.replication_role.read().await.is_reader() {
-            self.handle_replication_connection(server_port).await
-        } else {
-            Ok(())
+        if !self.replication_role.read().await.is_reader() {
+            return Ok(());
+        }
+
+        // A reader keeps connecting to its writer, whether the link failed or
+        // the writer closed it.
+        loop {
+            if let Err(err) = self.handle_replication_connection(server_port).await {
+                error!("Replication from the writer has failed: {}", err);
+            }
+            tokio::time::sleep(REPLICATION_RETRY_INTERVAL).await;
         }
     }
 
+    async fn snapshot_path(&self) -> std::path::PathBuf {
+        let config = self.config.read().await;
+        std::path::PathBuf::from(&config.dir).join(&config.dbfilename)
+    }
+
     async fn reload_from_snapshot(&self) -> Result<(), Error> {
-        let path = std::path::PathBuf::from(&self.dir).join(&self.dbfilename);
+        let path = self.snapshot_path().await;
         if !path.exists() {
             info!("No snapshot file found for sync");
             return Ok(());
         }
 
         let content = RdbFile::new(path).read()?;
+        self.load_snapshot(content).await
+    }
 
-        let mut db = self.db.write().await;
-        db.clear();
+    /// Replaces the keyspace and the function libraries with the snapshot.
+    async fn load_snapshot(&self, content: RdbContent) -> Result<(), Error> {
+        let mut dbs = self.dbs.write().await;
+        if let Some(db_index) = content.data.keys().find(|db_index| **db_index >= dbs.len()) {
+            return Err(format!(
+                "The snapshot uses DB {} but only {} databases are configured",
+                db_index,
+                dbs.len()
+            )
+            .into());
+        }
+        for db in dbs.iter_mut() {
+            db.clear();
+        }
         debug!("Import starts");
 
         for (db_index, data) in content.data {
-            assert!(db_index == 0); // For now.
             debug!("Importing to DB #{}", db_index);
+            let db = &mut dbs[db_index];
 
             for (key, (expiry_ms, value)) in data {
                 let has_expired = expiry_ms.map(|ms| ms < current_time_ms()).unwrap_or(false);
@@ -126,13 +289,77 @@ impl Engine {
 
                 match value {
                     RdbValue::Str(str) => db.set(key, str, expiry_ms)?,
+                    RdbValue::List(items) => {
+                        db.push_to_array(key, items)?;
+                    }
+                    RdbValue::SortedSet(members) => {
+                        let members = members
+                            .into_iter()
+                            .map(|(member, score)| (score, member))
+                            .collect();
+                        db.add_score_to_sorted_set(&key, &members)?;
+                    }
+                    // Without XDEL the last ID is always the one of the last entry.
+                    RdbValue::Stream(entries, _) => {
+                        for (id, kvpairs) in entries {
+                            db.stream_push(key.clone(), StreamEntryID::Full(id), kvpairs)?;
+                        }
+                    }
                 }
             }
+            // Loading is not a change clients are told about.
+            db.take_events();
+        }
+
+        let mut functions = self.functions.write().await;
+        functions.flush();
+        for code in content.functions {
+            functions.load(&code, true)?;
         }
 
         Ok(())
     }
 
+    /// Saves the keyspace and the function libraries.
+    async fn save(&self, dbs: &[Database]) -> Result<(), Error> {
+        let content = self.snapshot(dbs).await;
+        let path = self.snapshot_path().await;
+        RdbFile::new(path).write(&content)
+    }
+
+    /// The keyspace and the function libraries, as saved and as sent to
+    /// replicas on full resync.
+    async fn snapshot(&self, dbs: &[Database]) -> RdbContent {
+        let mut content = RdbContent::default();
+        content
+            .aux_fields
+            .push(("redis-ver".to_string(), REDIS_VERSION.to_string()));
+
+        for (db_index, db) in dbs.iter().enumerate() {
+            let entries = db.snapshot_entries();
+            if entries.is_empty() {
+                continue;
+            }
+            content.data.insert(
+                db_index,
+                entries
+                    .into_iter()
+                    .map(|(key, expiry_ms, value)| (key, (expiry_ms, value)))
+                    .collect(),
+            );
+        }
+
+        content.functions = self
+            .functions
+            .read()
+            .await
+            .libraries()
+            .map(|library| library.code.clone())
+            .collect();
+
+        content
+    }
+
     async fn handle_replication_connection(&self, server_port: u16) -> Result<(), Error> {
         let (writer_host, writer_port) = {
             let ReplicationRole::Reader(ref reader) = *self.replication_role.read().await else {
@@ -142,10 +369,8 @@ impl Engine {
         };
 
         let socket_addr = {
-            if let Ok(addr) =
-                format!("{}:{}", writer_host, writer_port).parse::<std::net::SocketAddr>()
-            {
-                addr
+            if let Ok(ip) = writer_host.parse::<std::net::IpAddr>() {
+                std::net::SocketAddr::new(ip, writer_port)
             } else {
                 let mut addrs = tokio::net::lookup_host((writer_host.as_str(), writer_port))
                     .await
@@ -159,26 +384,67 @@ impl Engine {
             }
         };
 
-        let mut stream = TcpSocket::new_v4()?
-            .connect(socket_addr)
+        let stream = Self::connect_to_writer(socket_addr)
             .await
             .context("connecting-to-writer")?;
-        let mut stream_reader = StreamReader::new(&mut stream);
+        let mut stream: Connection = match &self.replication_tls {
+            Some(connector) => {
+                let server_name =
+                    ServerName::try_from(writer_host.clone()).context("writer-tls-server-name")?;
+                Box::new(
+                    connector
+                        .connect(server_name, stream)
+                        .await
+                        .context("tls-handshake-with-writer")?,
+                )
+            }
+            None => Box::new(stream),
+        };
+        let mut stream_reader = StreamReader::new(&mut stream, RespParser::default());
 
-        self.replica_handshake(server_port, &mut stream_reader)
+        let offset = self
+            .replica_handshake(server_port, &mut stream_reader)
             .await?;
-        stream_reader.reset_byte_counter();
+        stream_reader.reset_byte_counter(offset);
+        if let ReplicationRole::Reader(ref mut reader) = *self.replication_role.write().await {
+            reader.offset = offset;
+        }
 
-        self.listen_for_replication_updates(&mut stream_reader)
-            .await?;
+        self.set_writer_link(true).await;
+        let result = self
+            .listen_for_replication_updates(&mut stream_reader)
+            .await;
+        self.set_writer_link(false).await;
 
-        Ok(())
+        result
+    }
+
+    /// Opens a socket of the address family of the writer, which may be
+    /// reached over IPv4 or IPv6.
+    async fn connect_to_writer(addr: std::net::SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.connect(addr).await
+    }
+
+    async fn set_writer_link(&self, up: bool) {
+        if let ReplicationRole::Reader(ref mut reader) = *self.replication_role.write().await {
+            reader.link_up = up;
+        }
     }
 
     async fn listen_for_replication_updates(
         &self,
         stream_reader: &mut StreamReader<'_>,
     ) -> Result<(), Error> {
+        // Writes replicated inside MULTI/EXEC are collected and applied at once.
+        let mut transaction: Option<Vec<Command>> = None;
+        // The writer selects the database of the writes it replicates.
+        let mut db_index = 0;
+
         loop {
             debug!("Start waiting for replication input");
             match stream_reader.read_resp_value_from_buf_reader(None).await? {
@@ -189,12 +455,35 @@ impl Engine {
                     if command.is_replconf() {
                         self.execute_and_reply(&command, None, stream_reader)
                             .await?;
+                    } else if command.is_multi() {
+                        transaction = Some(vec![]);
+                    } else if command.is_exec() {
+                        let commands = transaction.take().unwrap_or_default();
+                        let mut dbs = self.dbs.write().await;
+                        self.execute_batch(
+                            &mut dbs,
+                            &mut db_index,
+                            &commands,
+                            None,
+                            stream_reader.byte_count,
+                        )
+                        .await?;
+                    } else if let Some(commands) = transaction.as_mut() {
+                        commands.push(command);
+                    } else if let Command::Select(_) = command {
+                        Self::execute_on_dbs(&mut self.dbs.write().await, &mut db_index, &command);
                     } else {
-                        self.execute_only(&command, None, stream_reader.byte_count)
+                        self.execute_only(&command, None, db_index, stream_reader.byte_count, None)
                             .await?;
                     }
 
                     stream_reader.commit_byte_count();
+                    if let ReplicationRole::Reader(ref mut reader) =
+                        *self.replication_role.write().await
+                    {
+                        reader.offset = stream_reader.byte_count;
+                        reader.last_io = Some(std::time::Instant::now());
+                    }
                 }
                 None => {
                     debug!("Reader listening has ended due to stream closing");
@@ -204,11 +493,13 @@ impl Engine {
         }
     }
 
+    /// Runs the handshake up to the full resync, returning the replication
+    /// offset it starts from.
     async fn replica_handshake(
         &self,
         server_port: u16,
         stream_reader: &mut StreamReader<'_>,
-    ) -> Result<(), Error> {
+    ) -> Result<usize, Error> {
         Self::handshake_step(
             stream_reader,
             RespValue::Array(vec![RespValue::BulkString("PING".into())]),
@@ -253,54 +544,194 @@ impl Engine {
 
         let response = stream_reader.read_resp_value_from_buf_reader(None).await?;
         debug!("Handshake response: {:?}", response);
+        // FULLRESYNC <replid> <offset>: the writes after the snapshot count
+        // from the offset of the writer.
+        let offset = match &response {
+            Some(RespValue::SimpleString(full_resync)) => full_resync
+                .strip_prefix("FULLRESYNC ")
+                .and_then(|rest| rest.split(' ').nth(1))
+                .and_then(|offset| offset.parse().ok()),
+            _ => None,
+        }
+        .ok_or("Unexpected response to PSYNC")?;
 
         let response = stream_reader.read_bulk_bytes_from_tcp_stream(None).await?;
         debug!("Handshake final response: {} bytes", response.len());
 
-        // TODO: replace DB to `response`
-
-        Ok(())
+        self.load_snapshot(RdbFile::decode(&response)?).await?;
+        Ok(offset)
     }
 
     pub(crate) async fn execute(
         &self,
         command: &Command,
-        request_count: u64,
+        session: &mut Session,
         stream_reader: &mut StreamReader<'_>,
     ) -> Result<(), Error> {
-        if !command.is_exec() && !command.is_discard() && self.is_transaction(request_count).await {
-            if command.is_multi() {
-                stream_reader
-                    .get_mut()
-                    .write_all(
-                        &RespValue::SimpleString("ERR MULTI calls can not be nested".to_string())
-                            .serialize(),
-                    )
-                    .await
-                    .context("write-simple-value-back-to-stream")?;
-            } else {
+        let request_count = session.id;
+
+        if let Some(client) = self.clients.lock().await.get_mut(request_count) {
+            client.interact(command.acl_name());
+            client.db = session.db;
+            client.user = session.user.as_deref().unwrap_or(DEFAULT_USER).to_string();
+            client.blocked = command.is_blocking();
+            (client.query_buffer, client.query_buffer_free) = stream_reader.query_buffer();
+            client.output_buffer = stream_reader.output_buffer();
+        }
+
+        if let Some(denial) = self.check_permissions(command, session).await? {
+            if self.is_transaction(request_count).await {
+                self.abort_transaction(request_count).await;
+            }
+            self.stats.lock().await.record_rejected(command, &denial);
+            stream_reader
+                .get_mut()
+                .write_all(&RespValue::SimpleError(denial).serialize())
+                .await
+                .context("write-simple-value-back-to-stream")?;
+            return Ok(());
+        }
+
+        if self.script_control.is_busy()
+            && !matches!(command, Command::ScriptKill)
+            && !command.is_auth()
+            && !command.is_connection_control()
+        {
+            if self.is_transaction(request_count).await {
+                self.abort_transaction(request_count).await;
+            }
+            let err = "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.".to_string();
+            self.stats.lock().await.record_rejected(command, &err);
+            stream_reader
+                .get_mut()
+                .write_all(&RespValue::SimpleError(err).serialize())
+                .await
+                .context("write-simple-value-back-to-stream")?;
+            return Ok(());
+        }
+
+        self.wait_while_paused(command, session).await;
+        if session.is_killed() {
+            return Ok(());
+        }
+
+        if command.is_denyoom() && !self.perform_evictions().await {
+            if self.is_transaction(request_count).await {
+                self.abort_transaction(request_count).await;
+            }
+            let err = "OOM command not allowed when used memory > 'maxmemory'.".to_string();
+            self.stats.lock().await.record_rejected(command, &err);
+            stream_reader
+                .get_mut()
+                .write_all(&RespValue::SimpleError(err).serialize())
+                .await
+                .context("write-simple-value-back-to-stream")?;
+            return Ok(());
+        }
+
+        if !command.is_exec()
+            && !command.is_discard()
+            && !command.is_connection_control()
+            && self.is_transaction(request_count).await
+        {
+            let queue_error = match command {
+                Command::Multi => Some("ERR MULTI calls can not be nested".to_string()),
+                Command::Watch(_) => Some("ERR WATCH inside MULTI is not allowed".to_string()),
+                command
+                    if command.is_psync()
+                        || command.is_subscribe()
+                        || command.is_client()
+                        || command.is_auth() =>
                 {
-                    let mut transaction_store = self.transaction_store.lock().await;
-                    let transactions = transaction_store.get_mut(&request_count).unwrap();
-                    transactions.push(command.clone());
+                    self.abort_transaction(request_count).await;
+                    Some("ERR Command not allowed inside a transaction".to_string())
+                }
+                Command::Unknown(msg) => {
+                    self.abort_transaction(request_count).await;
+                    Some(format!("Unrecognized command: {}", msg))
+                }
+                _ => None,
+            };
+
+            let reply = match queue_error {
+                Some(err) => {
+                    self.stats.lock().await.record_rejected(command, &err);
+                    RespValue::SimpleError(err)
                 }
+                None => {
+                    {
+                        let mut transaction_store = self.transaction_store.lock().await;
+                        let transaction = transaction_store.get_mut(&request_count).unwrap();
+                        transaction.commands.push(command.clone());
+                    }
 
-                stream_reader
-                    .get_mut()
-                    .write_all(&RespValue::SimpleString("QUEUED".to_string()).serialize())
-                    .await
-                    .context("write-simple-value-back-to-stream")?;
-            }
+                    RespValue::SimpleString("QUEUED".to_string())
+                }
+            };
+
+            stream_reader
+                .get_mut()
+                .write_all(&reply.serialize())
+                .await
+                .context("write-simple-value-back-to-stream")?;
         } else if command.is_psync() {
-            self.handle_replica_connection(stream_reader, request_count, command)
+            self.stats
+                .lock()
+                .await
+                .record_call(command, Duration::ZERO, None);
+            self.handle_replica_connection(stream_reader, session, command)
                 .await?;
         } else if command.is_subscribe() {
             debug!("Subscribe by req {}", request_count);
-            self.subscribe(stream_reader, command, request_count)
-                .await?;
+            self.stats
+                .lock()
+                .await
+                .record_call(command, Duration::ZERO, None);
+            self.subscribe(stream_reader, command, session).await?;
         } else {
-            self.execute_and_reply(command, Some(request_count), stream_reader)
-                .await?;
+            let started = Instant::now();
+            let reply = if command.is_connection_control() {
+                self.connection_control(command, session).await
+            } else if command.is_client() {
+                self.client_command(command, session).await
+            } else if let Command::Auth(user, password) = command {
+                self.auth(session, user.as_deref(), password).await
+            } else if command.is_exec() {
+                self.exec(session, stream_reader.byte_count).await?
+            } else if let Command::Select(_) = command {
+                let mut dbs = self.dbs.write().await;
+                Self::execute_on_dbs(&mut dbs, &mut session.db, command)
+            } else {
+                self.execute_only(
+                    command,
+                    Some(session),
+                    session.db,
+                    stream_reader.byte_count,
+                    None,
+                )
+                .await?
+            };
+            self.record_call(command, started, &reply).await;
+
+            stream_reader
+                .get_mut()
+                .write_all(&reply.serialize())
+                .await
+                .context("write-simple-value-back-to-stream")?;
+        }
+
+        if command.is_blocking() {
+            if let Some(client) = self.clients.lock().await.get_mut(request_count) {
+                client.blocked = false;
+            }
+        }
+
+        // CLIENT CACHING covers the next command, or all of a transaction.
+        if session.caching.is_some()
+            && !matches!(command, Command::ClientCaching(_))
+            && !self.is_transaction(request_count).await
+        {
+            session.caching = None;
         }
 
         Ok(())
@@ -309,11 +740,12 @@ impl Engine {
     async fn execute_and_reply(
         &self,
         command: &Command,
-        request_count: Option<u64>,
+        session: Option<&Session>,
         stream_reader: &mut StreamReader<'_>,
     ) -> Result<(), Error> {
+        let db_index = session.map(|session| session.db).unwrap_or(0);
         let response_value = self
-            .execute_only(command, request_count, stream_reader.byte_count)
+            .execute_only(command, session, db_index, stream_reader.byte_count, None)
             .await?;
 
         debug!(
@@ -333,216 +765,255 @@ impl Engine {
         Ok(())
     }
 
+    /// Runs a command in the database at `db_index`, the selected one of the
+    /// client or of the replication link.
+    /// Runs a command. `held` are the databases when the caller already holds
+    /// them, as EXEC does; every command using the databases goes through
+    /// `databases` so it never locks them a second time.
     async fn execute_only(
         &self,
         command: &Command,
-        request_count: Option<u64>,
+        session: Option<&Session>,
+        db_index: usize,
         current_offset: usize,
+        held: Option<&mut [Database]>,
     ) -> Result<RespValue, Error> {
-        let value = match command {
-            Command::Ping => RespValue::SimpleString("PONG".to_string()),
-
-            Command::Echo(arg) => RespValue::BulkString(arg.clone()),
+        let mut guard = None;
+        let request_count = session.map(|session| session.id);
 
-            Command::Set(key, value, expiry) => {
-                match self.db.write().await.set(
-                    key.clone(),
-                    value.clone(),
-                    expiry.clone().map(|offset| offset + current_time_ms()),
-                ) {
-                    Ok(_) => RespValue::SimpleString("OK".into()),
-                    Err(err) => RespValue::SimpleError(err),
-                }
+        let value = match command {
+            Command::Blpop(keys, timeout_secs) => {
+                self.blocking_pop(session, db_index, keys, timeout_secs, ArrayDirection::Front)
+                    .await
             }
 
-            Command::Get(key) => match self.db.read().await.get(key) {
-                Ok(Some(v)) => RespValue::BulkString(v.clone()),
-                Ok(None) => RespValue::NullBulkString,
-                Err(err) => RespValue::SimpleError(err),
-            },
-
-            Command::Lrange(key, start, end) => {
-                match self.db.read().await.get_list_lrange(key, *start, *end) {
-                    Ok(array) => RespValue::Array(
-                        array
-                            .into_iter()
-                            .map(|elem| RespValue::BulkString(elem))
-                            .collect::<Vec<_>>(),
-                    ),
-                    Err(err) => RespValue::SimpleError(err),
-                }
+            Command::Brpop(keys, timeout_secs) => {
+                self.blocking_pop(session, db_index, keys, timeout_secs, ArrayDirection::Back)
+                    .await
             }
 
-            Command::Llen(key) => match self.db.read().await.list_length(key) {
-                Ok(n) => RespValue::Integer(n as i64),
-                Err(err) => RespValue::SimpleError(err),
-            },
+            Command::Xread(key_id_pairs, count, Some(blocking_ttl)) => {
+                self.blocking_stream_read(session, db_index, key_id_pairs, *count, *blocking_ttl)
+                    .await
+            }
 
-            Command::Rpush(key, values) => self.push(key, values, ArrayDirection::Back).await?,
+            command if command.is_keyspace() => {
+                let dbs = self.databases(held, &mut guard).await;
+                let value = Self::execute_on_dbs(dbs, &mut db_index.clone(), command);
+                if command.for_replication() {
+                    self.propagate(Some(db_index), vec![command.clone()]).await;
+                }
+                if let Some(session) = session {
+                    self.remember_tracked_keys(session, command).await;
+                }
+                self.publish_key_changes(dbs, request_count).await;
+                drop(guard);
 
-            Command::Lpush(key, values) => self.push(key, values, ArrayDirection::Front).await?,
+                self.wake_blocked_clients(command).await;
 
-            Command::Lpop(key) => self.pop(key, ArrayDirection::Front).await?,
+                value
+            }
 
-            Command::Rpop(key) => self.pop(key, ArrayDirection::Back).await?,
+            command if command.is_script() => {
+                let dbs = self.databases(held, &mut guard).await;
+                let script_run = self.eval(&mut dbs[db_index], command, session).await;
+                self.propagate_transaction(
+                    script_run
+                        .effects
+                        .iter()
+                        .map(|effect| (db_index, effect.clone()))
+                        .collect(),
+                )
+                .await;
+                self.publish_key_changes(dbs, request_count).await;
+                drop(guard);
 
-            Command::Lpopn(key, n) => self.pop_multi(key, n, ArrayDirection::Front).await?,
+                for effect in &script_run.effects {
+                    self.wake_blocked_clients(effect).await;
+                }
 
-            Command::Rpopn(key, n) => self.pop_multi(key, n, ArrayDirection::Back).await?,
+                script_run.reply
+            }
 
-            Command::Blpop(keys, timeout_secs) => {
-                self.blocking_pop(keys, timeout_secs, ArrayDirection::Front)
-                    .await?
+            Command::ScriptLoad(script) => {
+                let sha = script_sha1(script);
+                self.scripts
+                    .lock()
+                    .await
+                    .insert(sha.clone(), script.clone());
+                RespValue::BulkString(sha)
             }
 
-            Command::Brpop(keys, timeout_secs) => {
-                self.blocking_pop(keys, timeout_secs, ArrayDirection::Back)
-                    .await?
+            Command::ScriptExists(shas) => {
+                let scripts = self.scripts.lock().await;
+                RespValue::Array(
+                    shas.iter()
+                        .map(|sha| RespValue::Integer(scripts.contains_key(sha) as i64))
+                        .collect(),
+                )
             }
 
-            Command::Type(key) => {
-                RespValue::SimpleString(self.db.read().await.get_key_type_name(key).to_string())
+            Command::ScriptFlush => {
+                self.scripts.lock().await.clear();
+                RespValue::SimpleString("OK".to_string())
             }
 
-            Command::Xadd(key, id, entries) => {
-                match self
-                    .db
-                    .write()
-                    .await
-                    .stream_push(key.clone(), id.clone(), entries.clone())
-                {
-                    Ok(final_id) => {
-                        self.stream_notify.notify_one();
-                        RespValue::BulkString(final_id.to_string())
+            Command::ScriptKill => match self.script_control.kill() {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::FunctionLoad(code, replace) => {
+                let loaded = self.functions.write().await.load(code, *replace);
+                match loaded {
+                    Ok(library_name) => {
+                        self.propagate(None, vec![command.clone()]).await;
+                        RespValue::BulkString(library_name)
                     }
                     Err(err) => RespValue::SimpleError(err),
                 }
             }
 
-            Command::Xrange(key, start, end, count) => {
-                if *count == 0 {
-                    RespValue::NullBulkString
-                } else {
-                    let start = match start {
-                        RangeStreamEntryID::Fixed(v) => v,
-                        RangeStreamEntryID::Latest => {
-                            &self.db.read().await.resolve_latest_stream_id(key)?
-                        }
-                    };
-
-                    let end = match end {
-                        RangeStreamEntryID::Fixed(v) => v,
-                        RangeStreamEntryID::Latest => {
-                            &self.db.read().await.resolve_latest_stream_id(key)?
-                        }
-                    };
-
-                    match self
-                        .db
-                        .read()
-                        .await
-                        .stream_get_range(key, start, end, *count)
-                    {
-                        Ok(stream_entry) => Self::stream_to_resp(stream_entry),
-                        Err(err) => RespValue::SimpleError(err),
+            Command::FunctionDelete(library_name) => {
+                let deleted = self.functions.write().await.delete(library_name);
+                match deleted {
+                    Ok(_) => {
+                        self.propagate(None, vec![command.clone()]).await;
+                        RespValue::SimpleString("OK".to_string())
                     }
+                    Err(err) => RespValue::SimpleError(err),
                 }
             }
 
-            Command::Xread(key_id_pairs, count, blocking_ttl) => {
-                let end_ms = current_time_ms() + blocking_ttl.unwrap_or(0);
+            Command::FunctionFlush => {
+                self.functions.write().await.flush();
+                self.propagate(None, vec![command.clone()]).await;
+                RespValue::SimpleString("OK".to_string())
+            }
 
-                // Resolve any `Latest` ids here in the async context (await is allowed).
-                let mut resolved_key_id_pairs = vec![];
-                for (key, id) in key_id_pairs {
-                    let id = match id {
-                        RangeStreamEntryID::Fixed(v) => v,
-                        RangeStreamEntryID::Latest => {
-                            &self.db.read().await.resolve_latest_stream_id(&key)?
-                        }
-                    };
-                    resolved_key_id_pairs.push((key.clone(), id.clone()));
-                }
+            Command::FunctionList(pattern, with_code) => {
+                let matcher = pattern.as_ref().map(|pattern| PatternMatcher::new(pattern));
+                let functions = self.functions.read().await;
 
-                loop {
-                    match self
-                        .db
-                        .read()
-                        .await
-                        .stream_read_multi_from_id_exclusive(&resolved_key_id_pairs, *count)
-                    {
-                        Ok(result) => {
-                            if !result.is_empty() || blocking_ttl.is_none() {
-                                break RespValue::Array(
-                                    result
-                                        .into_iter()
-                                        .map(|(key, stream_entry)| {
+                RespValue::Array(
+                    functions
+                        .libraries()
+                        .filter(|library| {
+                            matcher
+                                .as_ref()
+                                .map(|matcher| matcher.is_match(&library.name))
+                                .unwrap_or(true)
+                        })
+                        .map(|library| {
+                            let mut fields = vec![
+                                RespValue::BulkString("library_name".into()),
+                                RespValue::BulkString(library.name.clone()),
+                                RespValue::BulkString("engine".into()),
+                                RespValue::BulkString("LUA".into()),
+                                RespValue::BulkString("functions".into()),
+                                RespValue::Array(
+                                    library
+                                        .functions
+                                        .iter()
+                                        .map(|function| {
                                             RespValue::Array(vec![
-                                                RespValue::BulkString(key),
-                                                Self::stream_to_resp(stream_entry),
+                                                RespValue::BulkString("name".into()),
+                                                RespValue::BulkString(function.name.clone()),
+                                                RespValue::BulkString("description".into()),
+                                                RespValue::NullBulkString,
+                                                RespValue::BulkString("flags".into()),
+                                                RespValue::Array(
+                                                    function
+                                                        .flags
+                                                        .iter()
+                                                        .map(|flag| {
+                                                            RespValue::BulkString(flag.clone())
+                                                        })
+                                                        .collect(),
+                                                ),
                                             ])
                                         })
-                                        .collect::<Vec<_>>(),
-                                );
+                                        .collect(),
+                                ),
+                            ];
+
+                            if *with_code {
+                                fields.push(RespValue::BulkString("library_code".into()));
+                                fields.push(RespValue::BulkString(library.code.clone()));
                             }
-                        }
-                        Err(err) => break RespValue::SimpleError(err),
-                    }
 
-                    let now_ms = current_time_ms();
-                    if end_ms <= now_ms {
-                        break RespValue::NullArray;
-                    }
-                    let ttl = end_ms - now_ms;
+                            RespValue::Array(fields)
+                        })
+                        .collect(),
+                )
+            }
 
-                    tokio::spawn({
-                        let notification = self.stream_notify.clone();
+            // Command arguments are UTF-8 strings here, so the payload, the RDB
+            // function records Redis dumps, is hex encoded. Only this server
+            // restores it, and it can't restore a payload dumped by Redis.
+            Command::FunctionDump => {
+                let codes = self
+                    .functions
+                    .read()
+                    .await
+                    .libraries()
+                    .map(|library| library.code.clone())
+                    .collect::<Vec<_>>();
+
+                RespValue::BulkString(
+                    dump_functions(&codes)
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect(),
+                )
+            }
 
-                        async move {
-                            tokio::time::sleep(Duration::from_millis(ttl as u64)).await;
-                            notification.notify_waiters();
+            Command::FunctionRestore(payload, policy) => {
+                let codes = (0..payload.len())
+                    .step_by(2)
+                    .map(|i| {
+                        payload
+                            .get(i..i + 2)
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|bytes| restore_functions(&bytes).ok());
+
+                match codes {
+                    Some(codes) => {
+                        let restored = self.functions.write().await.restore(&codes, *policy);
+                        match restored {
+                            Ok(_) => {
+                                self.propagate(None, vec![command.clone()]).await;
+                                RespValue::SimpleString("OK".to_string())
+                            }
+                            Err(err) => RespValue::SimpleError(err),
                         }
-                    });
-
-                    self.stream_notify.notified().await;
+                    }
+                    None => RespValue::SimpleError(
+                        "ERR payload version or checksum are wrong".to_string(),
+                    ),
                 }
             }
 
-            Command::Incr(key) => match self.db.write().await.incr(key) {
-                Ok(n) => RespValue::Integer(n),
-                Err(err) => RespValue::SimpleError(err),
-            },
+            Command::Save => {
+                self.save_command(self.databases(held, &mut guard).await)
+                    .await
+            }
+
+            Command::Ping => RespValue::SimpleString("PONG".to_string()),
+
+            Command::Echo(arg) => RespValue::BulkString(arg.clone()),
 
             Command::Multi => {
                 self.transaction_store
                     .lock()
                     .await
-                    .insert(request_count.unwrap(), vec![]);
+                    .insert(request_count.unwrap(), Transaction::default());
                 RespValue::SimpleString("OK".to_string())
             }
 
-            Command::Exec => {
-                let mut transaction_store = self.transaction_store.lock().await;
-
-                match transaction_store.remove(&request_count.unwrap()) {
-                    Some(commands) => {
-                        let mut subvalues = vec![];
-                        for command in commands {
-                            let subvalue = Box::pin(self.execute_only(
-                                &command,
-                                request_count,
-                                current_offset,
-                            ))
-                            .await?;
-
-                            subvalues.push(subvalue);
-                        }
-
-                        RespValue::Array(subvalues)
-                    }
-                    None => RespValue::SimpleError("ERR EXEC without MULTI".to_string()),
-                }
-            }
+            Command::Exec | Command::Select(_) => unreachable!("Handled above"),
 
             Command::Discard => {
                 if self.is_transaction(request_count.unwrap()).await {
@@ -550,25 +1021,37 @@ impl Engine {
                         let mut transaction_store = self.transaction_store.lock().await;
                         transaction_store.remove(&request_count.unwrap());
                     }
+                    let dbs = self.databases(held, &mut guard).await;
+                    self.unwatch_all(request_count.unwrap(), dbs).await;
                     RespValue::SimpleString("OK".to_string())
                 } else {
                     RespValue::SimpleError("ERR DISCARD without MULTI".to_string())
                 }
             }
 
-            Command::Info(sections) => {
-                let mut section_strs = String::new();
-                if sections.is_empty() {
-                    for section_name in INFO_SECTIONS {
-                        section_strs.push_str(&self.section_info(section_name).await);
-                    }
-                } else {
-                    for section_name in sections {
-                        section_strs.push_str(&self.section_info(section_name).await);
-                    }
+            Command::Watch(keys) => {
+                let dbs = self.databases(held, &mut guard).await;
+                let mut watched_keys = self.watched_keys.lock().await;
+                let client_watched_keys = watched_keys.entry(request_count.unwrap()).or_default();
+
+                for key in keys {
+                    client_watched_keys
+                        .entry((db_index, key.clone()))
+                        .or_insert_with(|| dbs[db_index].watch(key));
                 }
 
-                RespValue::BulkString(section_strs)
+                RespValue::SimpleString("OK".to_string())
+            }
+
+            Command::Unwatch => {
+                let dbs = self.databases(held, &mut guard).await;
+                self.unwatch_all(request_count.unwrap(), dbs).await;
+                RespValue::SimpleString("OK".to_string())
+            }
+
+            Command::Info(sections) => {
+                let dbs = self.databases(held, &mut guard).await;
+                RespValue::BulkString(self.info(sections, dbs).await)
             }
 
             Command::Replconf(args) => {
@@ -648,325 +1131,936 @@ impl Engine {
                 RespValue::Integer(up_to_date_replica_count)
             }
 
-            Command::GetConfig(params) => {
-                let mut values = vec![];
-
-                for param in params {
-                    let matcher = PatternMatcher::new(&param.to_lowercase());
+            Command::GetConfig(patterns) => RespValue::Array(
+                self.config
+                    .read()
+                    .await
+                    .get(patterns)
+                    .into_iter()
+                    .flat_map(|(name, value)| {
+                        [RespValue::BulkString(name), RespValue::BulkString(value)]
+                    })
+                    .collect(),
+            ),
 
-                    if matcher.is_match("dir") {
-                        values.push(RespValue::BulkString("dir".into()));
-                        values.push(RespValue::BulkString(self.dir.clone()));
-                    } else if matcher.is_match("dbfilename") {
-                        values.push(RespValue::BulkString("dbfilename".into()));
-                        values.push(RespValue::BulkString(self.dbfilename.clone()));
-                    } else {
-                        error!("Unrecognized get parameter: {}", param);
-                    }
+            Command::SetConfig(pairs) => {
+                let dbs = self.databases(held, &mut guard).await;
+                match self.set_config(dbs, pairs).await {
+                    Ok(_) => RespValue::SimpleString("OK".into()),
+                    Err(err) => RespValue::SimpleError(err),
                 }
-
-                RespValue::Array(values)
             }
 
-            Command::Keys(raw_pattern) => {
-                let matches = self.db.read().await.keys(raw_pattern);
-                RespValue::Array(
-                    matches
-                        .into_iter()
-                        .map(|elem| RespValue::BulkString(elem))
-                        .collect::<Vec<_>>(),
-                )
+            Command::ResetStatConfig => {
+                self.reset_stats(self.databases(held, &mut guard).await)
+                    .await;
+                RespValue::SimpleString("OK".into())
             }
 
-            Command::Subscribe(_) => unreachable!("Handled above"),
+            Command::RewriteConfig => match self.config.read().await.rewrite() {
+                Ok(_) => RespValue::SimpleString("OK".into()),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Subscribe(_)
+            | Command::Psubscribe(_)
+            | Command::Ssubscribe(_)
+            | Command::Quit
+            | Command::Reset => unreachable!("Handled above"),
 
-            Command::Unsubscribe(_) => {
+            Command::Unsubscribe(_) | Command::Punsubscribe(_) | Command::Sunsubscribe(_) => {
                 RespValue::SimpleError("ERR Cannot unsubscribe outside of a subscription".into())
             }
 
             Command::Publish(channel, message) => {
-                let client_count = self
-                    .subscription_add_message(channel, message.clone())
-                    .await;
+                let client_count = self.pubsub.publish(channel, message, false).await;
                 RespValue::Integer(client_count as i64)
             }
 
-            Command::Zadd(key, args) => {
-                match self.db.write().await.add_score_to_sorted_set(key, args) {
-                    Ok(added_count) => RespValue::Integer(added_count as i64),
-                    Err(err) => RespValue::SimpleError(err),
-                }
-            }
+            Command::PubsubChannels(pattern) => RespValue::Array(
+                self.pubsub
+                    .channels(pattern.as_deref(), false)
+                    .await
+                    .into_iter()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
 
-            Command::Zrank(key, member) => {
-                match self.db.read().await.sorted_set_rank(key, member) {
-                    Ok(Some(rank)) => RespValue::Integer(rank as i64),
-                    Ok(None) => RespValue::NullBulkString,
-                    Err(err) => RespValue::SimpleError(err),
-                }
+            Command::PubsubShardchannels(pattern) => RespValue::Array(
+                self.pubsub
+                    .channels(pattern.as_deref(), true)
+                    .await
+                    .into_iter()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+
+            Command::PubsubNumsub(channels) => {
+                Self::subscriber_counts_to_resp(self.pubsub.numsub(channels, false).await)
             }
 
-            Command::Zrange(key, start, end) => {
-                match self.db.read().await.sorted_set_range(key, *start, *end) {
-                    Ok(members) => RespValue::Array(
-                        members
-                            .into_iter()
-                            .map(|member| RespValue::BulkString(member))
-                            .collect(),
-                    ),
-                    Err(err) => RespValue::SimpleError(err),
-                }
+            Command::PubsubShardnumsub(channels) => {
+                Self::subscriber_counts_to_resp(self.pubsub.numsub(channels, true).await)
             }
 
-            Command::Zcard(key) => match self.db.read().await.sorted_set_len(key) {
-                Ok(len) => RespValue::Integer(len as i64),
-                Err(err) => RespValue::SimpleError(err),
-            },
+            Command::PubsubNumpat => RespValue::Integer(self.pubsub.numpat().await as i64),
 
-            Command::Zscore(key, member) => {
-                match self.db.read().await.sorted_set_member_score(key, member) {
-                    Ok(Some(score)) => RespValue::BulkString(score.to_string()),
-                    Ok(None) => RespValue::NullBulkString,
-                    Err(err) => RespValue::SimpleError(err),
-                }
+            Command::Spublish(channel, message) => {
+                let client_count = self.pubsub.publish(channel, message, true).await;
+                RespValue::Integer(client_count as i64)
             }
 
-            Command::Zrem(key, members) => match self
-                .db
-                .write()
+            Command::AclWhoami => RespValue::BulkString(
+                session
+                    .and_then(|session| session.user.clone())
+                    .unwrap_or(DEFAULT_USER.to_string()),
+            ),
+
+            Command::AclGetuser(user) => self
+                .users
+                .read()
                 .await
-                .sorted_set_remove_members(key, members.clone())
-            {
-                Ok(count) => RespValue::Integer(count as i64),
-                Err(err) => RespValue::SimpleError(err),
-            },
+                .get(user)
+                .map(|user| user.to_resp())
+                .unwrap_or(RespValue::NullBulkString),
+
+            Command::AclSetuser(name, rules) => {
+                let mut users = self.users.write().await;
+                // Rules are applied to a copy, so a bad rule leaves the user unchanged.
+                let mut user = users.get(name).cloned().unwrap_or_default();
+                let result = rules.iter().try_for_each(|rule| {
+                    user.apply_rule(rule).map_err(|reason| {
+                        format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, reason)
+                    })
+                });
 
-            Command::Geoadd(key, args) => {
-                match self.db.write().await.add_geo_to_sorted_set(key, args) {
-                    Ok(added_count) => RespValue::Integer(added_count as i64),
+                match result {
+                    Ok(()) => {
+                        users.insert(name.clone(), user);
+                        RespValue::SimpleString("OK".into())
+                    }
                     Err(err) => RespValue::SimpleError(err),
                 }
             }
 
-            Command::Geopos(key, members) => {
-                match self.db.read().await.sorted_set_geopos(key, members) {
-                    Ok(coords) => RespValue::Array(
-                        coords
-                            .iter()
-                            .map(|maybe_coord| {
-                                maybe_coord
-                                    .map(|(lon, lat)| {
-                                        RespValue::Array(vec![
-                                            RespValue::BulkString(lon.to_string()),
-                                            RespValue::BulkString(lat.to_string()),
-                                        ])
-                                    })
-                                    .unwrap_or(RespValue::NullArray)
-                            })
-                            .collect(),
-                    ),
-                    Err(err) => RespValue::SimpleError(err),
+            Command::AclDeluser(names) => {
+                if names.iter().any(|name| name == DEFAULT_USER) {
+                    RespValue::SimpleError("ERR The 'default' user cannot be removed".into())
+                } else {
+                    let mut users = self.users.write().await;
+                    let deleted = names
+                        .iter()
+                        .filter(|name| users.remove(name.as_str()).is_some())
+                        .count();
+                    RespValue::Integer(deleted as i64)
                 }
             }
 
-            Command::Geodist(key, member_lhs, member_rhs) => match self
-                .db
-                .read()
-                .await
-                .sorted_set_geodist(key, member_lhs, member_rhs)
-            {
-                Ok(Some(val)) => RespValue::BulkString(val.to_string()),
-                Ok(None) => RespValue::NullBulkString,
-                Err(err) => RespValue::SimpleError(err),
+            Command::AclList => RespValue::Array(
+                self.users
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(name, user)| RespValue::BulkString(user.describe(name)))
+                    .collect(),
+            ),
+
+            Command::AclUsers => RespValue::Array(
+                self.users
+                    .read()
+                    .await
+                    .keys()
+                    .map(|name| RespValue::BulkString(name.clone()))
+                    .collect(),
+            ),
+
+            Command::AclCat(None) => RespValue::Array(
+                acl::categories()
+                    .into_iter()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+
+            Command::AclCat(Some(category)) => match acl::commands_in_category(category) {
+                Some(commands) => {
+                    RespValue::Array(commands.into_iter().map(RespValue::BulkString).collect())
+                }
+                None => RespValue::SimpleError(format!("ERR Unknown category '{}'", category)),
             },
 
-            Command::Geosearch(key, coord, radius) => match self
-                .db
-                .read()
-                .await
-                .sorted_set_geo_search(key, coord.0, coord.1, *radius)
-            {
-                Ok(members) => RespValue::Array(
-                    members
-                        .into_iter()
-                        .map(|member| RespValue::BulkString(member))
-                        .collect(),
-                ),
+            Command::AclDryrun(name, command) => match self.users.read().await.get(name) {
+                Some(user) => match user.check(command) {
+                    Ok(()) => RespValue::SimpleString("OK".into()),
+                    Err(denial) => RespValue::BulkString(denial.to_dryrun_reply()),
+                },
+                None => RespValue::SimpleError(format!("ERR User '{}' not found", name)),
+            },
+
+            Command::AclLoad => match self.load_acl_file().await {
+                Ok(()) => RespValue::SimpleString("OK".into()),
                 Err(err) => RespValue::SimpleError(err),
             },
 
-            Command::AclWhoami => RespValue::BulkString("default".into()),
+            Command::AclSave => match self.save_acl_file().await {
+                Ok(()) => RespValue::SimpleString("OK".into()),
+                Err(err) => RespValue::SimpleError(err),
+            },
 
-            Command::AclGetuser(user) => RespValue::Array(vec![
-                RespValue::BulkString("flags".into()),
-                RespValue::Array(
-                    self.user_flags(user)
-                        .await
-                        .into_iter()
-                        .map(|elem| RespValue::BulkString(elem))
-                        .collect(),
-                ),
-                RespValue::BulkString("passwords".into()),
-                RespValue::Array(
-                    self.user_passwords(user)
-                        .await
-                        .into_iter()
-                        .map(|elem| RespValue::BulkString(elem))
-                        .collect(),
-                ),
-            ]),
+            Command::AclLog(count) => self.acl_log.lock().await.to_resp(*count),
 
-            Command::AclSetuser(user, password) => {
-                self.users
-                    .write()
-                    .await
-                    .insert(user.clone(), User::new(password.clone()));
+            Command::AclLogReset => {
+                self.acl_log.lock().await.reset();
                 RespValue::SimpleString("OK".into())
             }
 
+            Command::AclGenpass(bits) => {
+                RespValue::BulkString(random_hex(bits.unwrap_or(256).div_ceil(4)))
+            }
+
+            Command::Auth(_, _) => unreachable!("Handled above"),
+
             Command::Unknown(msg) => {
                 RespValue::SimpleError(format!("Unrecognized command: {}", msg))
             }
-        };
 
-        if command.for_replication() {
-            if self.replication_role.read().await.is_writer() {
-                self.replication_role
-                    .write()
-                    .await
-                    .writer_mut()
-                    .push_write_command(command.clone());
-                self.wr_cmd_propagation_notify.notify_waiters();
-            }
-        }
+            other => unreachable!("Keyspace command not handled: {:?}", other),
+        };
 
         Ok(value)
     }
 
-    async fn wait(&self, replica_count: usize, timeout_ms: u128) -> Result<i64, Error> {
-        let mut up_to_date_replicas = HashSet::new();
-        let writer_offset = self.replication_role.read().await.writer().offset;
-        let end_ms = current_time_ms() + timeout_ms;
+    /// Runs the queued commands. A SELECT among them stays in effect for the
+    /// client after EXEC.
+    async fn exec(&self, session: &mut Session, current_offset: usize) -> Result<RespValue, Error> {
+        let request_count = session.id;
+        let Some(transaction) = self.transaction_store.lock().await.remove(&request_count) else {
+            return Ok(RespValue::SimpleError("ERR EXEC without MULTI".to_string()));
+        };
 
-        debug!("WAIT#1 - start (expected offset: {})", writer_offset);
+        if transaction.aborted {
+            self.unwatch_all(request_count, &mut self.dbs.write().await)
+                .await;
+            return Ok(RespValue::SimpleError(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            ));
+        }
 
-        loop {
-            let mut need_client_notification = false;
+        // The database stays locked from the WATCH check until the batch is
+        // replicated, so no other client can interleave writes.
+        let mut dbs = self.dbs.write().await;
 
-            {
-                let ReplicationRole::Writer(ref mut writer) = *self.replication_role.write().await
-                else {
-                    return Err("Wait command on a non writer instance".into());
-                };
+        let watched_keys = self
+            .watched_keys
+            .lock()
+            .await
+            .remove(&request_count)
+            .unwrap_or_default();
+        let watched_key_modified = watched_keys
+            .iter()
+            .any(|((db_index, key), watch)| dbs[*db_index].is_modified_since(key, watch));
+        for (db_index, key) in watched_keys.keys() {
+            dbs[*db_index].unwatch(key);
+        }
 
-                debug!("WAIT#1 - Examining {} clients", writer.clients.len());
-                for (client_request_count, client_info) in writer.clients.iter_mut() {
-                    if client_info.offset >= writer_offset {
-                        up_to_date_replicas.insert(*client_request_count);
-                        debug!(
-                            "WAIT#1 - found client with sufficient offset ({})",
-                            client_info.offset
-                        );
-                    } else {
-                        if client_info.offset_update == ClientOffsetUpdate::Idle {
-                            client_info.offset_update = ClientOffsetUpdate::UpdateRequested;
-                            need_client_notification = true;
-                            debug!(
-                                "WAIT#1 - notify client with insufficient offset ({})",
-                                client_info.offset
-                            );
-                        } else {
-                            debug!(
-                                "WAIT#1 - client with insufficient offset [no notification] ({})",
-                                client_info.offset
-                            );
-                        }
+        if watched_key_modified {
+            return Ok(RespValue::NullArray);
+        }
+
+        let mut db_index = session.db;
+        let values = self
+            .execute_batch(
+                &mut dbs,
+                &mut db_index,
+                &transaction.commands,
+                Some(session),
+                current_offset,
+            )
+            .await?;
+        session.db = db_index;
+
+        Ok(RespValue::Array(values))
+    }
+
+    /// Runs commands one after the other on the locked databases and replicates
+    /// the writes among them wrapped in MULTI/EXEC. SELECT among the commands
+    /// changes `db_index` for the ones after it.
+    async fn execute_batch(
+        &self,
+        dbs: &mut [Database],
+        db_index: &mut usize,
+        commands: &Vec<Command>,
+        session: Option<&Session>,
+        current_offset: usize,
+    ) -> Result<Vec<RespValue>, Error> {
+        let mut values = vec![];
+        let mut writes = vec![];
+        for command in commands {
+            let started = Instant::now();
+            let value = match command {
+                command if command.is_script() => {
+                    let script_run = self.eval(&mut dbs[*db_index], command, session).await;
+                    writes.extend(
+                        script_run
+                            .effects
+                            .into_iter()
+                            .map(|effect| (*db_index, effect)),
+                    );
+                    script_run.reply
+                }
+                command if command.is_keyspace() => {
+                    if command.for_replication() {
+                        writes.push((*db_index, command.clone()));
+                    }
+                    if let Some(session) = session {
+                        self.remember_tracked_keys(session, command).await;
                     }
+                    Self::execute_on_dbs(dbs, db_index, command)
                 }
+                command => {
+                    Box::pin(self.execute_only(
+                        command,
+                        session,
+                        *db_index,
+                        current_offset,
+                        Some(dbs),
+                    ))
+                    .await?
+                }
+            };
+            // Commands replicated from the writer are not counted.
+            if session.is_some() {
+                self.record_call(command, started, &value).await;
             }
+            values.push(value);
+        }
+        self.publish_key_changes(dbs, session.map(|session| session.id))
+            .await;
 
-            debug!(
-                "WAIT#1 - current up to date client count = {}",
-                up_to_date_replicas.len()
-            );
+        // Woken clients only get to the database once the caller releases it.
+        for (_, command) in &writes {
+            self.wake_blocked_clients(command).await;
+        }
+        self.propagate_transaction(writes).await;
 
-            let current_ms = current_time_ms();
-            if up_to_date_replicas.len() < replica_count && current_ms < end_ms {
-                debug!("WAIT#1 - notifying writer propagators to send client requests");
-                if need_client_notification {
-                    self.wr_cmd_propagation_notify.notify_waiters();
-                }
+        Ok(values)
+    }
 
-                tokio::spawn({
-                    let wr_read_client_offset_notify = self.wr_read_client_offset_notify.clone();
-                    async move {
-                        tokio::time::sleep(Duration::from_millis((end_ms - current_ms) as u64))
-                            .await;
-                        wr_read_client_offset_notify.notify_one();
-                    }
-                });
+    /// The databases `held` by the caller, or else the ones locked into
+    /// `guard`.
+    async fn databases<'s, 'g>(
+        &'s self,
+        held: Option<&'g mut [Database]>,
+        guard: &'g mut Option<RwLockWriteGuard<'s, Vec<Database>>>,
+    ) -> &'g mut [Database] {
+        match held {
+            Some(dbs) => dbs,
+            None => guard.insert(self.dbs.write().await),
+        }
+    }
 
-                // Wait for readers to finish.
-                self.wr_read_client_offset_notify.notified().await;
-            } else {
-                debug!(
-                    "WAIT#1 - return (ms left: {})",
-                    end_ms as i64 - current_ms as i64
-                );
-                break Ok(up_to_date_replicas.len() as i64);
+    /// Runs SAVE on the locked databases.
+    async fn save_command(&self, dbs: &[Database]) -> RespValue {
+        let saved = self.save(dbs).await;
+        let mut stats = self.stats.lock().await;
+        stats.last_save_ok = saved.is_ok();
+        match saved {
+            Ok(_) => {
+                stats.dirty = 0;
+                stats.last_save_time = unix_time().as_secs();
+                RespValue::SimpleString("OK".to_string())
+            }
+            Err(err) => {
+                error!("Saving the snapshot has failed: {}", err);
+                RespValue::SimpleError("ERR saving the snapshot has failed".to_string())
             }
         }
     }
 
-    async fn subscribe(
+    /// Runs EVAL, EVALSHA or FCALL on the locked database. Every call of the
+    /// script is checked against the ACL user of the session, and the refused
+    /// ones are logged.
+    async fn eval(
         &self,
-        stream_reader: &mut StreamReader<'_>,
+        db: &mut Database,
         command: &Command,
-        request_count: u64,
-    ) -> Result<(), Error> {
-        let input_channels = match command {
-            Command::Subscribe(channels) => channels,
-            _ => panic!(),
+        session: Option<&Session>,
+    ) -> ScriptRun {
+        let name = session.and_then(|session| session.user.as_deref());
+        // Cloned, so no lock is held while the script runs and SCRIPT KILL
+        // can get through.
+        let user = match name {
+            Some(name) => self.users.read().await.get(name).cloned(),
+            None => None,
+        };
+        let env = ScriptEnv {
+            user: name.zip(user.as_ref()),
+            control: self.script_control.clone(),
+            busy_threshold: Duration::from_millis(self.config.read().await.busy_reply_threshold),
         };
 
-        for channel in input_channels {
-            let client_subs_len = self
-                .subscribe_client_to_channel(request_count, channel.clone())
-                .await;
+        let script_run = self.run_eval(db, command, &env).await;
 
-            let payload = RespValue::Array(vec![
-                RespValue::BulkString("subscribe".into()),
-                RespValue::BulkString(channel.clone()),
-                RespValue::Integer(client_subs_len as i64),
-            ]);
-            stream_reader
-                .get_mut()
-                .write_all(&payload.serialize())
-                .await?;
+        if let (Some(session), Some(name)) = (session, name) {
+            let mut acl_log = self.acl_log.lock().await;
+            for denial in &script_run.denials {
+                let (reason, object) = denial.log_reason_and_object();
+                acl_log.add(reason, "lua", object, name, &session.client_info());
+            }
         }
 
-        loop {
-            tokio::select! {
-                should_finish = self.subscription_handle_incoming_commands(stream_reader, request_count) => {
-                    if should_finish? {
-                        return Ok(());
-                    }
-                }
-                _ = self.subscription_notify.notified() => {
-                    self.subscription_handle_publishing(stream_reader, request_count).await?;
+        script_run
+    }
+
+    /// EVAL caches the script by its SHA1 so EVALSHA can call it later.
+    async fn run_eval(
+        &self,
+        db: &mut Database,
+        command: &Command,
+        env: &ScriptEnv<'_>,
+    ) -> ScriptRun {
+        let (script, keys, args) = match command {
+            Command::Eval(script, keys, args) => {
+                self.scripts
+                    .lock()
+                    .await
+                    .insert(script_sha1(script), script.clone());
+                (script.clone(), keys, args)
+            }
+            Command::Evalsha(sha, keys, args) => match self.scripts.lock().await.get(sha) {
+                Some(script) => (script.clone(), keys, args),
+                None => return ScriptRun::error("NOSCRIPT No matching script. Please use EVAL."),
+            },
+            Command::Fcall(name, keys, args) | Command::FcallRo(name, keys, args) => {
+                let functions = self.functions.read().await;
+                let Some((library, function)) = functions.find_function(name) else {
+                    return ScriptRun::error("ERR Function not found");
+                };
+
+                let read_only = function.is_read_only();
+                if matches!(command, Command::FcallRo(_, _, _)) && !read_only {
+                    return ScriptRun::error(
+                        "ERR Can not execute a script with write flag using *_ro command.",
+                    );
                 }
+
+                return run_blocking(|| {
+                    run_function(db, env, library, name, keys, args, read_only)
+                });
             }
+            other => unreachable!("Not a script command: {:?}", other),
+        };
+
+        run_blocking(|| run_script(db, env, &script, keys, args))
+    }
+
+    /// Replicates writes that have to be applied together wrapped in MULTI/EXEC,
+    /// each paired with the database it was made in.
+    async fn propagate_transaction(&self, writes: Vec<(usize, Command)>) {
+        let Some(&(first_db_index, _)) = writes.first() else {
+            return;
+        };
+
+        let mut current_db_index = first_db_index;
+        let mut commands = vec![Command::Multi];
+        for (db_index, command) in writes {
+            if db_index != current_db_index {
+                commands.push(Command::Select(db_index));
+                current_db_index = db_index;
+            }
+            commands.push(command);
         }
+        commands.push(Command::Exec);
+
+        self.propagate(Some(first_db_index), commands).await;
     }
 
-    fn stream_to_resp(stream_entry: StreamEntry) -> RespValue {
-        RespValue::Array(
-            stream_entry
-                .into_iter()
-                .map(|value| {
-                    RespValue::Array(vec![
-                        RespValue::BulkString(value.id.to_string()),
-                        RespValue::Array(
+    /// Replicates commands, first selecting `db_index` on the replicas unless
+    /// it is selected already. Commands outside the keyspace pass `None`.
+    async fn propagate(&self, db_index: Option<usize>, commands: Vec<Command>) {
+        if !self.replication_role.read().await.is_writer() {
+            return;
+        }
+
+        {
+            let mut replication_role = self.replication_role.write().await;
+            let writer = replication_role.writer_mut();
+            if let Some(db_index) = db_index {
+                if writer.selected_db != Some(db_index) {
+                    writer.push_write_command(Command::Select(db_index));
+                }
+            }
+            for command in commands {
+                writer.push_write_command(command);
+            }
+        }
+
+        self.wr_cmd_propagation_notify.notify_waiters();
+    }
+
+    async fn wake_blocked_clients(&self, command: &Command) {
+        match command {
+            Command::Rpush(key, _)
+            | Command::Lpush(key, _)
+            | Command::Xadd(key, _, _)
+            | Command::Move(key, _) => {
+                self.key_waiters.lock().unwrap().notify(key);
+            }
+            Command::Swapdb(_, _) => self.key_waiters.lock().unwrap().notify_all(),
+            _ => {}
+        }
+    }
+
+    /// Runs a keyspace command against the locked databases, with `db_index`
+    /// the selected one. SELECT changes it, other commands that span databases
+    /// are handled here and the rest go to the selected database.
+    pub(crate) fn execute_on_dbs(
+        dbs: &mut [Database],
+        db_index: &mut usize,
+        command: &Command,
+    ) -> RespValue {
+        let out_of_range = || RespValue::SimpleError("ERR DB index is out of range".into());
+
+        match command {
+            Command::Select(index) => {
+                if *index >= dbs.len() {
+                    return out_of_range();
+                }
+                *db_index = *index;
+                RespValue::SimpleString("OK".into())
+            }
+
+            Command::Move(key, dest_index) => {
+                if *dest_index >= dbs.len() {
+                    return out_of_range();
+                }
+                if *dest_index == *db_index {
+                    return RespValue::SimpleError(
+                        "ERR source and destination objects are the same".into(),
+                    );
+                }
+                let (source, dest) = Self::db_pair(dbs, *db_index, *dest_index);
+                source.expire_if_needed(key);
+                RespValue::Integer(source.move_key(key, dest) as i64)
+            }
+
+            Command::Swapdb(first, second) => {
+                if *first >= dbs.len() || *second >= dbs.len() {
+                    return out_of_range();
+                }
+                if first != second {
+                    let (first, second) = Self::db_pair(dbs, *first, *second);
+                    first.swap_entries(second);
+                }
+                RespValue::SimpleString("OK".into())
+            }
+
+            Command::Flushall(lazy) => {
+                for db in dbs.iter_mut() {
+                    if *lazy {
+                        db.clear_lazy();
+                    } else {
+                        db.clear();
+                    }
+                }
+                RespValue::SimpleString("OK".into())
+            }
+
+            command => Self::execute_on_db(&mut dbs[*db_index], command),
+        }
+    }
+
+    /// Borrows two distinct databases at once.
+    fn db_pair(
+        dbs: &mut [Database],
+        first: usize,
+        second: usize,
+    ) -> (&mut Database, &mut Database) {
+        if first < second {
+            let (head, tail) = dbs.split_at_mut(second);
+            (&mut head[first], &mut tail[0])
+        } else {
+            let (head, tail) = dbs.split_at_mut(first);
+            (&mut tail[0], &mut head[second])
+        }
+    }
+
+    /// Runs a command that reads or writes the keyspace against an already locked
+    /// database. Keeping this synchronous lets EXEC hold the lock for a whole batch.
+    pub(crate) fn execute_on_db(db: &mut Database, command: &Command) -> RespValue {
+        let inspects_usage = matches!(command, Command::ObjectFreq(_) | Command::ObjectIdletime(_));
+        for (key, access) in command.acl_keys() {
+            db.expire_if_needed(key);
+            if !inspects_usage {
+                db.record_access(key);
+            }
+            if access == KeyAccess::Read {
+                db.record_lookup(key);
+                if !db.is_alive(key) {
+                    db.notify(KeyspaceEvents::KEY_MISS, "keymiss", key);
+                }
+            }
+        }
+
+        match command {
+            Command::Set(key, value, expiry) => match db.set(
+                key.clone(),
+                value.clone(),
+                expiry.map(|offset| offset + current_time_ms()),
+            ) {
+                Ok(_) => RespValue::SimpleString("OK".into()),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Get(key) => match db.get(key) {
+                Ok(Some(v)) => RespValue::BulkString(v.clone()),
+                Ok(None) => RespValue::NullBulkString,
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Lrange(key, start, end) => match db.get_list_lrange(key, *start, *end) {
+                Ok(array) => RespValue::Array(
+                    array
+                        .into_iter()
+                        .map(|elem| RespValue::BulkString(elem))
+                        .collect::<Vec<_>>(),
+                ),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Llen(key) => match db.list_length(key) {
+                Ok(n) => RespValue::Integer(n as i64),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Rpush(key, values) => Self::push(db, key, values, ArrayDirection::Back),
+
+            Command::Lpush(key, values) => Self::push(db, key, values, ArrayDirection::Front),
+
+            Command::Lpop(key) => Self::pop(db, key, ArrayDirection::Front),
+
+            Command::Rpop(key) => Self::pop(db, key, ArrayDirection::Back),
+
+            Command::Lpopn(key, n) => Self::pop_multi(db, key, n, ArrayDirection::Front),
+
+            Command::Rpopn(key, n) => Self::pop_multi(db, key, n, ArrayDirection::Back),
+
+            // Blocking commands never block inside a transaction.
+            Command::Blpop(keys, _) => Self::pop_first_available(db, keys, &ArrayDirection::Front)
+                .unwrap_or(RespValue::NullArray),
+
+            Command::Brpop(keys, _) => Self::pop_first_available(db, keys, &ArrayDirection::Back)
+                .unwrap_or(RespValue::NullArray),
+
+            Command::Type(key) => RespValue::SimpleString(db.get_key_type_name(key).to_string()),
+
+            Command::Del(keys) => {
+                RespValue::Integer(keys.iter().filter(|key| db.delete(key)).count() as i64)
+            }
+
+            Command::ObjectFreq(key) => match db.object_freq(key) {
+                Ok(Some(frequency)) => RespValue::Integer(frequency as i64),
+                Ok(None) => RespValue::NullBulkString,
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::ObjectIdletime(key) => match db.object_idletime(key) {
+                Ok(Some(idle_secs)) => RespValue::Integer(idle_secs as i64),
+                Ok(None) => RespValue::NullBulkString,
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Xadd(key, id, entries) => {
+                match db.stream_push(key.clone(), id.clone(), entries.clone()) {
+                    Ok(final_id) => RespValue::BulkString(final_id.to_string()),
+                    Err(err) => RespValue::SimpleError(err),
+                }
+            }
+
+            Command::Xrange(key, start, end, count) => {
+                if *count == 0 {
+                    return RespValue::NullBulkString;
+                }
+
+                let start = match Self::resolve_range_stream_id(db, key, start) {
+                    Ok(start) => start,
+                    Err(err) => return RespValue::SimpleError(err),
+                };
+                let end = match Self::resolve_range_stream_id(db, key, end) {
+                    Ok(end) => end,
+                    Err(err) => return RespValue::SimpleError(err),
+                };
+
+                match db.stream_get_range(key, &start, &end, *count) {
+                    Ok(stream_entry) => Self::stream_to_resp(stream_entry),
+                    Err(err) => RespValue::SimpleError(err),
+                }
+            }
+
+            Command::Xread(key_id_pairs, count, _) => {
+                let mut resolved_key_id_pairs = vec![];
+                for (key, id) in key_id_pairs {
+                    match Self::resolve_range_stream_id(db, key, id) {
+                        Ok(id) => resolved_key_id_pairs.push((key.clone(), id)),
+                        Err(err) => return RespValue::SimpleError(err),
+                    }
+                }
+
+                Self::read_streams(db, &resolved_key_id_pairs, *count)
+                    .unwrap_or(RespValue::NullArray)
+            }
+
+            Command::Incr(key) => match db.incr(key) {
+                Ok(n) => RespValue::Integer(n),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Flushdb(lazy) => {
+                if *lazy {
+                    db.clear_lazy();
+                } else {
+                    db.clear();
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+
+            Command::Dbsize => RespValue::Integer(db.key_count() as i64),
+
+            Command::Keys(raw_pattern) => RespValue::Array(
+                db.keys(raw_pattern)
+                    .into_iter()
+                    .map(|elem| RespValue::BulkString(elem))
+                    .collect::<Vec<_>>(),
+            ),
+
+            Command::Zadd(key, args) => match db.add_score_to_sorted_set(key, args) {
+                Ok(added_count) => RespValue::Integer(added_count as i64),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Zrank(key, member) => match db.sorted_set_rank(key, member) {
+                Ok(Some(rank)) => RespValue::Integer(rank as i64),
+                Ok(None) => RespValue::NullBulkString,
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Zrange(key, start, end) => match db.sorted_set_range(key, *start, *end) {
+                Ok(members) => RespValue::Array(
+                    members
+                        .into_iter()
+                        .map(|member| RespValue::BulkString(member))
+                        .collect(),
+                ),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Zcard(key) => match db.sorted_set_len(key) {
+                Ok(len) => RespValue::Integer(len as i64),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Zscore(key, member) => match db.sorted_set_member_score(key, member) {
+                Ok(Some(score)) => RespValue::BulkString(score.to_string()),
+                Ok(None) => RespValue::NullBulkString,
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Zrem(key, members) => {
+                match db.sorted_set_remove_members(key, members.clone()) {
+                    Ok(count) => RespValue::Integer(count as i64),
+                    Err(err) => RespValue::SimpleError(err),
+                }
+            }
+
+            Command::Geoadd(key, args) => match db.add_geo_to_sorted_set(key, args) {
+                Ok(added_count) => RespValue::Integer(added_count as i64),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Geopos(key, members) => match db.sorted_set_geopos(key, members) {
+                Ok(coords) => RespValue::Array(
+                    coords
+                        .iter()
+                        .map(|maybe_coord| {
+                            maybe_coord
+                                .map(|(lon, lat)| {
+                                    RespValue::Array(vec![
+                                        RespValue::BulkString(lon.to_string()),
+                                        RespValue::BulkString(lat.to_string()),
+                                    ])
+                                })
+                                .unwrap_or(RespValue::NullArray)
+                        })
+                        .collect(),
+                ),
+                Err(err) => RespValue::SimpleError(err),
+            },
+
+            Command::Geodist(key, member_lhs, member_rhs) => {
+                match db.sorted_set_geodist(key, member_lhs, member_rhs) {
+                    Ok(Some(val)) => RespValue::BulkString(val.to_string()),
+                    Ok(None) => RespValue::NullBulkString,
+                    Err(err) => RespValue::SimpleError(err),
+                }
+            }
+
+            Command::Geosearch(key, coord, radius) => {
+                match db.sorted_set_geo_search(key, coord.0, coord.1, *radius) {
+                    Ok(members) => RespValue::Array(
+                        members
+                            .into_iter()
+                            .map(|member| RespValue::BulkString(member))
+                            .collect(),
+                    ),
+                    Err(err) => RespValue::SimpleError(err),
+                }
+            }
+
+            other => unreachable!("Not a keyspace command: {:?}", other),
+        }
+    }
+
+    async fn wait(&self, replica_count: usize, timeout_ms: u128) -> Result<i64, Error> {
+        let mut up_to_date_replicas = HashSet::new();
+        let writer_offset = self.replication_role.read().await.writer().offset;
+        let end_ms = current_time_ms() + timeout_ms;
+
+        debug!("WAIT#1 - start (expected offset: {})", writer_offset);
+
+        loop {
+            let mut need_client_notification = false;
+
+            {
+                let ReplicationRole::Writer(ref mut writer) = *self.replication_role.write().await
+                else {
+                    return Err("Wait command on a non writer instance".into());
+                };
+
+                debug!("WAIT#1 - Examining {} clients", writer.clients.len());
+                for (client_request_count, client_info) in writer.clients.iter_mut() {
+                    if client_info.offset >= writer_offset {
+                        up_to_date_replicas.insert(*client_request_count);
+                        debug!(
+                            "WAIT#1 - found client with sufficient offset ({})",
+                            client_info.offset
+                        );
+                    } else {
+                        if client_info.offset_update == ClientOffsetUpdate::Idle {
+                            client_info.offset_update = ClientOffsetUpdate::UpdateRequested;
+                            need_client_notification = true;
+                            debug!(
+                                "WAIT#1 - notify client with insufficient offset ({})",
+                                client_info.offset
+                            );
+                        } else {
+                            debug!(
+                                "WAIT#1 - client with insufficient offset [no notification] ({})",
+                                client_info.offset
+                            );
+                        }
+                    }
+                }
+            }
+
+            debug!(
+                "WAIT#1 - current up to date client count = {}",
+                up_to_date_replicas.len()
+            );
+
+            let current_ms = current_time_ms();
+            if up_to_date_replicas.len() < replica_count && current_ms < end_ms {
+                debug!("WAIT#1 - notifying writer propagators to send client requests");
+                if need_client_notification {
+                    self.wr_cmd_propagation_notify.notify_waiters();
+                }
+
+                tokio::spawn({
+                    let wr_read_client_offset_notify = self.wr_read_client_offset_notify.clone();
+                    async move {
+                        tokio::time::sleep(Duration::from_millis((end_ms - current_ms) as u64))
+                            .await;
+                        wr_read_client_offset_notify.notify_one();
+                    }
+                });
+
+                // Wait for readers to finish.
+                self.wr_read_client_offset_notify.notified().await;
+            } else {
+                debug!(
+                    "WAIT#1 - return (ms left: {})",
+                    end_ms as i64 - current_ms as i64
+                );
+                break Ok(up_to_date_replicas.len() as i64);
+            }
+        }
+    }
+
+    /// Serves the client in subscribed mode until it drops all its
+    /// subscriptions, resets or closes the connection. A client reading its
+    /// messages too slowly is disconnected.
+    async fn subscribe(
+        &self,
+        stream_reader: &mut StreamReader<'_>,
+        command: &Command,
+        session: &mut Session,
+    ) -> Result<(), Error> {
+        let mut subscription = self.pubsub.attach(session.id).await;
+        self.subscription_command(stream_reader, command, session.id)
+            .await?;
+        stream_reader.flush().await?;
+        let killed = session.killed.clone();
+
+        loop {
+            tokio::select! {
+                _ = Session::wait_killed(killed.clone()) => {
+                    session.closing = true;
+                    break;
+                }
+                should_finish = self.subscription_handle_incoming_commands(stream_reader, session) => {
+                    if should_finish? {
+                        break;
+                    }
+                }
+                payload = subscription.recv() => {
+                    let Some(payload) = payload else {
+                        session.closing = true;
+                        break;
+                    };
+                    stream_reader.get_mut().write_all(&payload).await?;
+                    while let Some(payload) = subscription.try_recv() {
+                        stream_reader.get_mut().write_all(&payload).await?;
+                    }
+                    stream_reader.flush().await?;
+                }
+            }
+        }
+
+        self.pubsub.detach(session.id).await;
+        stream_reader.flush().await
+    }
+
+    /// Flattens channels and their subscriber counts, as PUBSUB NUMSUB replies.
+    fn subscriber_counts_to_resp(counts: Vec<(String, usize)>) -> RespValue {
+        RespValue::Array(
+            counts
+                .into_iter()
+                .flat_map(|(channel, count)| {
+                    [
+                        RespValue::BulkString(channel),
+                        RespValue::Integer(count as i64),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    fn stream_to_resp(stream_entry: StreamEntry) -> RespValue {
+        RespValue::Array(
+            stream_entry
+                .into_iter()
+                .map(|value| {
+                    RespValue::Array(vec![
+                        RespValue::BulkString(value.id.to_string()),
+                        RespValue::Array(
                             value
                                 .kvpairs
                                 .into_iter()
@@ -984,105 +2078,748 @@ impl Engine {
         )
     }
 
-    async fn push(
-        &self,
-        key: &String,
-        values: &Vec<String>,
-        dir: ArrayDirection,
-    ) -> Result<RespValue, Error> {
-        let result = match dir {
-            ArrayDirection::Back => self
-                .db
-                .write()
-                .await
-                .push_to_array(key.clone(), values.clone()),
-            ArrayDirection::Front => self
-                .db
-                .write()
-                .await
-                .insert_to_array(key.clone(), values.clone()),
+    fn push(
+        db: &mut Database,
+        key: &String,
+        values: &Vec<String>,
+        dir: ArrayDirection,
+    ) -> RespValue {
+        let result = match dir {
+            ArrayDirection::Back => db.push_to_array(key.clone(), values.clone()),
+            ArrayDirection::Front => db.insert_to_array(key.clone(), values.clone()),
+        };
+        match result {
+            Ok(count) => RespValue::Integer(count as i64),
+            Err(err) => RespValue::SimpleError(err),
+        }
+    }
+
+    fn pop(db: &mut Database, key: &String, dir: ArrayDirection) -> RespValue {
+        let result = match dir {
+            ArrayDirection::Back => db.list_pop_one_back(key),
+            ArrayDirection::Front => db.list_pop_one_front(key),
+        };
+        match result {
+            Ok(Some(v)) => RespValue::BulkString(v),
+            Ok(None) => RespValue::NullBulkString,
+            Err(err) => RespValue::SimpleError(err),
+        }
+    }
+
+    fn pop_multi(db: &mut Database, key: &String, n: &usize, dir: ArrayDirection) -> RespValue {
+        let result = match dir {
+            ArrayDirection::Back => db.list_pop_multi_back(key, *n),
+            ArrayDirection::Front => db.list_pop_multi_front(key, *n),
+        };
+        match result {
+            Ok(Some(elems)) => RespValue::Array(
+                elems
+                    .into_iter()
+                    .map(|e| RespValue::BulkString(e))
+                    .collect(),
+            ),
+            Ok(None) => RespValue::NullBulkString,
+            Err(err) => RespValue::SimpleError(err),
+        }
+    }
+
+    /// Pops from the first non-empty list, or returns `None` if all are empty.
+    fn pop_first_available(
+        db: &mut Database,
+        keys: &Vec<String>,
+        dir: &ArrayDirection,
+    ) -> Option<RespValue> {
+        for key in keys {
+            db.expire_if_needed(key);
+            let result = match dir {
+                ArrayDirection::Back => db.list_pop_one_back(key),
+                ArrayDirection::Front => db.list_pop_one_front(key),
+            };
+            match result {
+                Ok(Some(v)) => {
+                    return Some(RespValue::Array(vec![
+                        RespValue::BulkString(key.clone()),
+                        RespValue::BulkString(v),
+                    ]))
+                }
+                Ok(None) => {}
+                Err(err) => return Some(RespValue::SimpleError(err)),
+            }
+        }
+
+        None
+    }
+
+    /// Publishes the keyspace events the databases recorded and invalidates
+    /// the keys tracking clients read. Called with the databases still locked,
+    /// so messages go out in the order of the writes. `writer` is the client
+    /// that made them, if any.
+    async fn publish_key_changes(&self, dbs: &mut [Database], writer: Option<u64>) {
+        let mut modified_keys = vec![];
+        let mut flushed = false;
+        for (db_index, db) in dbs.iter_mut().enumerate() {
+            for event in db.take_events() {
+                self.pubsub
+                    .notify_keyspace_event(db.keyspace_events(), db_index, &event)
+                    .await;
+            }
+            if let Some(modifications) = db.take_modifications() {
+                modified_keys.extend(modifications.keys);
+                flushed |= modifications.flushed;
+            }
+        }
+
+        if modified_keys.is_empty() && !flushed {
+            return;
+        }
+        let invalidations = {
+            let mut tracking = self.tracking.lock().await;
+            if flushed {
+                tracking.invalidate_all()
+            } else {
+                tracking.invalidate_keys(&modified_keys, writer)
+            }
+        };
+        for (target, keys) in invalidations {
+            let message = match keys {
+                Some(keys) => {
+                    RespValue::Array(keys.into_iter().map(RespValue::BulkString).collect())
+                }
+                None => RespValue::NullBulkString,
+            };
+            self.pubsub
+                .send_to(target, INVALIDATE_CHANNEL, message)
+                .await;
+        }
+    }
+
+    /// Remembers the keys a read-only command of a tracking client read.
+    async fn remember_tracked_keys(&self, session: &Session, command: &Command) {
+        if !session.tracking {
+            return;
+        }
+        let keys = command.acl_keys();
+        if keys.iter().any(|(_, access)| *access != KeyAccess::Read) {
+            return;
+        }
+
+        self.tracking.lock().await.remember_keys(
+            session.id,
+            session.caching,
+            keys.into_iter().map(|(key, _)| key),
+        );
+    }
+
+    /// Removes a bounded number of expired keys from every database, so keys
+    /// nobody reads again still go away and publish their `expired` event.
+    pub(crate) async fn active_expire_cycle(&self) {
+        const KEYS_PER_CYCLE: usize = 200;
+
+        // Keys must not change under a CLIENT PAUSE.
+        if self
+            .pause
+            .borrow()
+            .is_some_and(|pause| pause.until > Instant::now())
+        {
+            return;
+        }
+
+        let mut dbs = self.dbs.write().await;
+        let mut removed = 0;
+        for db in dbs.iter_mut() {
+            removed += db.remove_expired_keys(KEYS_PER_CYCLE);
+        }
+        if removed > 0 {
+            debug!("Removed {} expired keys", removed);
+            self.publish_key_changes(&mut dbs, None).await;
+        }
+    }
+
+    fn resolve_range_stream_id(
+        db: &Database,
+        key: &str,
+        id: &RangeStreamEntryID,
+    ) -> Result<CompleteStreamEntryID, String> {
+        match id {
+            RangeStreamEntryID::Fixed(v) => Ok(v.clone()),
+            RangeStreamEntryID::Latest => db.resolve_latest_stream_id(key),
+        }
+    }
+
+    /// Reads entries after the given IDs, or returns `None` if there are none yet.
+    fn read_streams(
+        db: &Database,
+        key_id_pairs: &Vec<(String, CompleteStreamEntryID)>,
+        count: usize,
+    ) -> Option<RespValue> {
+        match db.stream_read_multi_from_id_exclusive(key_id_pairs, count) {
+            Ok(result) if result.is_empty() => None,
+            Ok(result) => Some(RespValue::Array(
+                result
+                    .into_iter()
+                    .map(|(key, stream_entry)| {
+                        RespValue::Array(vec![
+                            RespValue::BulkString(key),
+                            Self::stream_to_resp(stream_entry),
+                        ])
+                    })
+                    .collect::<Vec<_>>(),
+            )),
+            Err(err) => Some(RespValue::SimpleError(err)),
+        }
+    }
+
+    async fn blocking_pop(
+        &self,
+        session: Option<&Session>,
+        db_index: usize,
+        keys: &Vec<String>,
+        timeout_secs: &f64,
+        dir: ArrayDirection,
+    ) -> RespValue {
+        let deadline = Self::blocking_deadline(Some(Duration::from_secs_f64(*timeout_secs)));
+        let wait = KeyWait::new(&self.key_waiters, keys);
+
+        let value = loop {
+            let mut dbs = self.dbs.write().await;
+            let popped = Self::pop_first_available(&mut dbs[db_index], keys, &dir);
+            self.publish_key_changes(&mut dbs, session.map(|session| session.id))
+                .await;
+            drop(dbs);
+            if let Some(value) = popped {
+                break value;
+            }
+
+            if !Self::wait_for_key_write(wait.notify(), deadline, session).await {
+                break RespValue::NullArray;
+            }
+        };
+
+        value
+    }
+
+    async fn blocking_stream_read(
+        &self,
+        session: Option<&Session>,
+        db_index: usize,
+        key_id_pairs: &Vec<(String, RangeStreamEntryID)>,
+        count: usize,
+        blocking_ttl_ms: u128,
+    ) -> RespValue {
+        // Resolve any `Latest` ids before blocking, so `$` means entries added
+        // after this command arrived.
+        let mut resolved_key_id_pairs = vec![];
+        {
+            let dbs = self.dbs.read().await;
+            for (key, id) in key_id_pairs {
+                match Self::resolve_range_stream_id(&dbs[db_index], key, id) {
+                    Ok(id) => resolved_key_id_pairs.push((key.clone(), id)),
+                    Err(err) => return RespValue::SimpleError(err),
+                }
+            }
+        }
+
+        let keys = key_id_pairs
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let deadline = Self::blocking_deadline(Some(Duration::from_millis(blocking_ttl_ms as u64)));
+        let wait = KeyWait::new(&self.key_waiters, &keys);
+
+        let value = loop {
+            let read = Self::read_streams(
+                &self.dbs.read().await[db_index],
+                &resolved_key_id_pairs,
+                count,
+            );
+            if let Some(value) = read {
+                break value;
+            }
+
+            if !Self::wait_for_key_write(wait.notify(), deadline, session).await {
+                break RespValue::NullArray;
+            }
+        };
+
+        value
+    }
+
+    /// Zero or a missing timeout blocks forever, as in Redis.
+    fn blocking_deadline(timeout: Option<Duration>) -> Option<Instant> {
+        timeout
+            .filter(|timeout| !timeout.is_zero())
+            .and_then(|timeout| Instant::now().checked_add(timeout))
+    }
+
+    /// Waits for a write to a key, giving false on timeout or when the client
+    /// was killed meanwhile.
+    async fn wait_for_key_write(
+        notify: &Notify,
+        deadline: Option<Instant>,
+        session: Option<&Session>,
+    ) -> bool {
+        let killed = async {
+            match session {
+                Some(session) => session.killed().await,
+                None => std::future::pending().await,
+            }
         };
-        match result {
-            Ok(count) => {
-                self.stream_notify.notify_one();
-                Ok(RespValue::Integer(count as i64))
+        let written = async {
+            match deadline {
+                Some(deadline) => timeout_at(deadline, notify.notified()).await.is_ok(),
+                None => {
+                    notify.notified().await;
+                    true
+                }
             }
-            Err(err) => Ok(RespValue::SimpleError(err)),
+        };
+
+        tokio::select! {
+            written = written => written,
+            _ = killed => false,
         }
     }
 
-    async fn pop(&self, key: &String, dir: ArrayDirection) -> Result<RespValue, Error> {
-        let result = match dir {
-            ArrayDirection::Back => self.db.write().await.list_pop_one_back(key),
-            ArrayDirection::Front => self.db.write().await.list_pop_one_front(key),
+    /// Holds the command back while CLIENT PAUSE covers it, until the pause
+    /// ends or the client is killed. CLIENT UNPAUSE always goes through, or
+    /// a pause of all commands could not be lifted.
+    async fn wait_while_paused(&self, command: &Command, session: &Session) {
+        let mut pause = self.pause.subscribe();
+        if pause.borrow_and_update().is_none() || matches!(command, Command::ClientUnpause) {
+            return;
+        }
+        let may_write = command.may_write()
+            || (command.is_exec()
+                && self
+                    .transaction_store
+                    .lock()
+                    .await
+                    .get(&session.id)
+                    .is_some_and(|transaction| {
+                        transaction
+                            .commands
+                            .iter()
+                            .any(|command| command.may_write())
+                    }));
+
+        loop {
+            let until = match *pause.borrow_and_update() {
+                Some(current) if current.holds(may_write) => current.until,
+                _ => return,
+            };
+            tokio::select! {
+                _ = sleep_until(until) => {}
+                _ = pause.changed() => {}
+                _ = session.killed() => return,
+            }
+        }
+    }
+
+    /// Flags the open transaction of the client, if any, so EXEC discards it.
+    pub(crate) async fn abort_transaction(&self, request_count: u64) {
+        if let Some(transaction) = self.transaction_store.lock().await.get_mut(&request_count) {
+            transaction.aborted = true;
+        }
+    }
+
+    /// Drops all per-connection state once the client disconnects.
+    pub(crate) async fn disconnect(&self, request_count: u64) {
+        self.transaction_store.lock().await.remove(&request_count);
+        self.unwatch_all(request_count, &mut self.dbs.write().await)
+            .await;
+        self.pubsub.detach(request_count).await;
+        self.disable_tracking(request_count).await;
+        self.clients.lock().await.remove(request_count);
+    }
+
+    async fn record_call(&self, command: &Command, started: Instant, reply: &RespValue) {
+        let error = match reply {
+            RespValue::SimpleError(err) => Some(err.as_str()),
+            _ => None,
         };
-        match result {
-            Ok(Some(v)) => return Ok(RespValue::BulkString(v)),
-            Ok(None) => return Ok(RespValue::NullBulkString),
-            Err(err) => Ok(RespValue::SimpleError(err)),
+        self.stats
+            .lock()
+            .await
+            .record_call(command, started.elapsed(), error);
+    }
+
+    /// Counts an error reply to a request that was refused before it ran, or
+    /// didn't parse into a command.
+    pub(crate) async fn record_rejected(&self, command: Option<&Command>, err: &str) {
+        let mut stats = self.stats.lock().await;
+        match command {
+            Some(command) => stats.record_rejected(command, err),
+            None => stats.record_error(err),
+        }
+    }
+
+    /// Counts a connection refused by protected mode.
+    pub(crate) async fn record_rejected_connection(&self) {
+        self.stats.lock().await.rejected_connections += 1;
+    }
+
+    pub(crate) fn net_counters(&self) -> Arc<NetCounters> {
+        self.net.clone()
+    }
+
+    /// Samples the instantaneous metrics of INFO stats.
+    pub(crate) async fn sample_stats(&self) {
+        self.stats.lock().await.sample(&self.net);
+    }
+
+    async fn reset_stats(&self, dbs: &mut [Database]) {
+        for db in dbs.iter_mut() {
+            db.reset_stats();
         }
+        self.stats.lock().await.reset();
+        self.net.reset();
     }
 
-    async fn pop_multi(
+    /// Applies CONFIG SET, all parameters or none, and puts the changed
+    /// settings to use on the databases locked by the caller.
+    async fn set_config(
         &self,
-        key: &String,
-        n: &usize,
-        dir: ArrayDirection,
-    ) -> Result<RespValue, Error> {
-        let result = match dir {
-            ArrayDirection::Back => self.db.write().await.list_pop_multi_back(key, *n),
-            ArrayDirection::Front => self.db.write().await.list_pop_multi_front(key, *n),
+        dbs: &mut [Database],
+        pairs: &[(String, String)],
+    ) -> Result<(), String> {
+        let mut config = self.config.write().await;
+        let previous = config.clone();
+        config.set_at_runtime(pairs)?;
+
+        if config.notify_keyspace_events != previous.notify_keyspace_events {
+            for db in dbs.iter_mut() {
+                db.set_keyspace_events(config.notify_keyspace_events);
+            }
+        }
+        if config.maxmemory_policy != previous.maxmemory_policy {
+            for db in dbs.iter_mut() {
+                db.set_eviction_policy(config.maxmemory_policy);
+            }
+        }
+        if config.client_output_buffer_limit != previous.client_output_buffer_limit {
+            self.pubsub.set_limit(config.client_output_buffer_limit);
+        }
+        if config.requirepass != previous.requirepass {
+            let mut users = self.users.write().await;
+            if let Some(user) = users.get_mut(DEFAULT_USER) {
+                let password_rule = match &config.requirepass {
+                    Some(password) => format!(">{}", password),
+                    None => "nopass".to_string(),
+                };
+                for rule in ["resetpass", &password_rule] {
+                    user.apply_rule(rule).expect("valid-default-user-rule");
+                }
+            }
+        }
+
+        if config.maxmemory != previous.maxmemory
+            || config.maxmemory_policy != previous.maxmemory_policy
+        {
+            self.evict_keys(dbs, config.maxmemory, config.maxmemory_samples)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Evicts keys as `maxmemory-policy` picks them until the used memory is
+    /// back under `maxmemory`. Returns whether it is. Replicas leave eviction
+    /// to their writer, whose DELs they receive.
+    async fn perform_evictions(&self) -> bool {
+        let (maxmemory, samples) = {
+            let config = self.config.read().await;
+            (config.maxmemory, config.maxmemory_samples)
         };
-        match result {
-            Ok(Some(elems)) => Ok(RespValue::Array(
-                elems
-                    .into_iter()
-                    .map(|e| RespValue::BulkString(e))
-                    .collect(),
-            )),
-            Ok(None) => return Ok(RespValue::NullBulkString),
-            Err(err) => Ok(RespValue::SimpleError(err)),
+        if maxmemory == 0 {
+            return true;
         }
+
+        let mut dbs = self.dbs.write().await;
+        self.evict_keys(&mut dbs, maxmemory, samples).await
     }
 
-    async fn blocking_pop(
-        &self,
-        keys: &Vec<String>,
-        timeout_secs: &f64,
-        dir: ArrayDirection,
-    ) -> Result<RespValue, Error> {
-        let now_secs = current_time_secs_f64();
-        let end_secs = now_secs + timeout_secs;
+    /// Evicts keys from the locked databases, see `perform_evictions`.
+    async fn evict_keys(&self, dbs: &mut [Database], maxmemory: usize, samples: usize) -> bool {
+        if maxmemory == 0 || !self.replication_role.read().await.is_writer() {
+            return true;
+        }
 
-        loop {
-            for key in keys {
-                let result = match dir {
-                    ArrayDirection::Back => self.db.write().await.list_pop_one_back(key)?,
-                    ArrayDirection::Front => self.db.write().await.list_pop_one_front(key)?,
-                };
-                if let Some(v) = result {
-                    return Ok(RespValue::Array(vec![
-                        RespValue::BulkString(key.clone()),
-                        RespValue::BulkString(v),
-                    ]));
+        let mut used_memory = dbs.iter_mut().map(|db| db.used_memory()).sum::<usize>();
+        let mut evicted = vec![];
+        while used_memory > maxmemory {
+            let candidate = dbs
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(db_index, db)| {
+                    let (key, score) = db.eviction_candidate(samples)?;
+                    Some((db_index, key, score))
+                })
+                .max_by_key(|(_, _, score)| *score);
+            let Some((db_index, key, _)) = candidate else {
+                break;
+            };
+            used_memory -= dbs[db_index].evict(&key);
+            evicted.push((db_index, key));
+        }
+
+        if !evicted.is_empty() {
+            debug!("Evicted {} keys", evicted.len());
+            self.publish_key_changes(dbs, None).await;
+            for (db_index, key) in evicted {
+                self.propagate(Some(db_index), vec![Command::Del(vec![key])])
+                    .await;
+            }
+        }
+
+        used_memory <= maxmemory
+    }
+
+    /// Disconnects the clients idle for longer than `timeout`. Replicas,
+    /// subscribers and clients waiting in a blocking command are left alone.
+    pub(crate) async fn disconnect_idle_clients(&self) {
+        let timeout = self.config.read().await.timeout;
+        if timeout == 0 {
+            return;
+        }
+
+        let idle = self
+            .client_views()
+            .await
+            .into_iter()
+            .filter(|view| view.idle >= timeout)
+            .filter(|view| view.client_type() == ClientType::Normal && !view.blocked)
+            .map(|view| view.id)
+            .collect::<Vec<_>>();
+        let clients = self.clients.lock().await;
+        for id in idle {
+            debug!("Closing idle client {}", id);
+            clients.kill(id);
+        }
+    }
+
+    pub(crate) async fn proto_max_bulk_len(&self) -> usize {
+        self.config.read().await.proto_max_bulk_len
+    }
+
+    /// Snapshot of the connected clients, with the state other registries
+    /// keep about them.
+    async fn client_views(&self) -> Vec<ClientView> {
+        let mut views = self.clients.lock().await.views();
+        for view in views.iter_mut() {
+            if let Some((channels, patterns, shard_channels, queued)) =
+                self.pubsub.subscriber_info(view.id).await
+            {
+                view.subscriptions = channels;
+                view.pattern_subscriptions = patterns;
+                view.shard_subscriptions = shard_channels;
+                view.output_memory = queued;
+            }
+        }
+        {
+            let transaction_store = self.transaction_store.lock().await;
+            for view in views.iter_mut() {
+                view.multi = transaction_store
+                    .get(&view.id)
+                    .map(|transaction| transaction.commands.len());
+            }
+        }
+        let tracking = self.tracking.lock().await;
+        for view in views.iter_mut() {
+            view.tracking = tracking.options(view.id).map(|options| options.redirect);
+        }
+
+        views
+    }
+
+    /// Runs a CLIENT subcommand for the connection of the session.
+    async fn client_command(&self, command: &Command, session: &mut Session) -> RespValue {
+        match command {
+            Command::ClientId => RespValue::Integer(session.id as i64),
+
+            Command::ClientList(client_type, ids) => {
+                let lines = self
+                    .client_views()
+                    .await
+                    .into_iter()
+                    .filter(|view| client_type.is_none_or(|t| view.client_type() == t))
+                    .filter(|view| ids.is_empty() || ids.contains(&view.id))
+                    .map(|view| format!("{}\n", view))
+                    .collect::<String>();
+                RespValue::BulkString(lines)
+            }
+
+            Command::ClientInfo => {
+                let view = self
+                    .client_views()
+                    .await
+                    .into_iter()
+                    .find(|view| view.id == session.id)
+                    .expect("The client is registered");
+                RespValue::BulkString(format!("{}\n", view))
+            }
+
+            Command::ClientSetname(name) => {
+                if let Err(err) = validate_client_name(name) {
+                    return RespValue::SimpleError(err);
+                }
+                if let Some(client) = self.clients.lock().await.get_mut(session.id) {
+                    client.name = name.clone();
+                }
+                RespValue::SimpleString("OK".into())
+            }
+
+            Command::ClientGetname => {
+                let name = self
+                    .clients
+                    .lock()
+                    .await
+                    .get_mut(session.id)
+                    .map(|client| client.name.clone())
+                    .unwrap_or_default();
+                if name.is_empty() {
+                    RespValue::NullBulkString
+                } else {
+                    RespValue::BulkString(name)
+                }
+            }
+
+            Command::ClientKill(filter) => {
+                if let Some(user) = &filter.user {
+                    if !self.users.read().await.contains_key(user) {
+                        return RespValue::SimpleError(format!("ERR No such user '{}'", user));
+                    }
+                }
+
+                let killed = self
+                    .client_views()
+                    .await
+                    .into_iter()
+                    .filter(|view| filter.matches(view, session.id))
+                    .map(|view| view.id)
+                    .collect::<Vec<_>>();
+                let clients = self.clients.lock().await;
+                for id in &killed {
+                    // The client killing itself still gets the reply.
+                    if *id == session.id {
+                        session.closing = true;
+                    } else {
+                        clients.kill(*id);
+                    }
+                }
+
+                match (filter.legacy, killed.len()) {
+                    (true, 0) => RespValue::SimpleError("ERR No such client".into()),
+                    (true, _) => RespValue::SimpleString("OK".into()),
+                    (false, count) => RespValue::Integer(count as i64),
+                }
+            }
+
+            Command::ClientPause(timeout_ms, mode) => {
+                let until = Instant::now() + Duration::from_millis(*timeout_ms);
+                // A shorter pause doesn't cut the current one short.
+                self.pause.send_modify(|pause| {
+                    let until = match pause {
+                        Some(current) if current.until > until => current.until,
+                        _ => until,
+                    };
+                    *pause = Some(Pause { until, mode: *mode });
+                });
+                RespValue::SimpleString("OK".into())
+            }
+
+            Command::ClientUnpause => {
+                self.pause.send_replace(None);
+                RespValue::SimpleString("OK".into())
+            }
+
+            Command::ClientNoEvict(on) => {
+                if let Some(client) = self.clients.lock().await.get_mut(session.id) {
+                    client.no_evict = *on;
                 }
+                RespValue::SimpleString("OK".into())
             }
 
-            let ttl = end_secs - current_time_secs_f64();
-            if ttl <= 0.0 {
-                return Ok(RespValue::NullArray);
+            Command::ClientTracking(true, options) => {
+                if let Some(redirect) = options.redirect {
+                    if !self.clients.lock().await.contains(redirect) {
+                        return RespValue::SimpleError(
+                            "ERR The client ID you want redirect to does not exist".into(),
+                        );
+                    }
+                }
+                match self.enable_tracking(session.id, options.clone()).await {
+                    Ok(_) => {
+                        session.tracking = true;
+                        RespValue::SimpleString("OK".into())
+                    }
+                    Err(err) => RespValue::SimpleError(err),
+                }
             }
 
-            tokio::spawn({
-                let stream_notify = self.stream_notify.clone();
+            Command::ClientTracking(false, _) => {
+                self.disable_tracking(session.id).await;
+                session.tracking = false;
+                session.caching = None;
+                RespValue::SimpleString("OK".into())
+            }
 
-                async move {
-                    tokio::time::sleep(Duration::from_secs_f64(ttl)).await;
-                    stream_notify.notify_waiters();
+            Command::ClientCaching(yes) => {
+                let tracking = self.tracking.lock().await;
+                let Some(options) = tracking.options(session.id) else {
+                    return RespValue::SimpleError("ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled".into());
+                };
+                if *yes && !options.optin {
+                    return RespValue::SimpleError("ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.".into());
+                }
+                if !*yes && !options.optout {
+                    return RespValue::SimpleError("ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.".into());
                 }
-            });
+                session.caching = Some(*yes);
+                RespValue::SimpleString("OK".into())
+            }
+
+            other => unreachable!("Not a CLIENT command: {:?}", other),
+        }
+    }
+
+    /// Turns tracking on with the options. The databases only record the
+    /// keys they modify while some client tracks keys.
+    async fn enable_tracking(
+        &self,
+        request_count: u64,
+        options: TrackingOptions,
+    ) -> Result<(), String> {
+        let mut dbs = self.dbs.write().await;
+        let mut tracking = self.tracking.lock().await;
+        tracking.enable(request_count, options)?;
+        for db in dbs.iter_mut() {
+            db.set_tracking(true);
+        }
+
+        Ok(())
+    }
+
+    async fn disable_tracking(&self, request_count: u64) {
+        if self.tracking.lock().await.options(request_count).is_none() {
+            return;
+        }
+
+        let mut dbs = self.dbs.write().await;
+        let mut tracking = self.tracking.lock().await;
+        tracking.disable(request_count);
+        for db in dbs.iter_mut() {
+            db.set_tracking(!tracking.is_empty());
+        }
+    }
+
+    /// Drops the watches of the client, on the databases locked by the caller.
+    async fn unwatch_all(&self, request_count: u64, dbs: &mut [Database]) {
+        let client_watched_keys = self.watched_keys.lock().await.remove(&request_count);
 
-            self.stream_notify.notified().await;
+        if let Some(client_watched_keys) = client_watched_keys {
+            for (db_index, key) in client_watched_keys.keys() {
+                dbs[*db_index].unwatch(key);
+            }
         }
     }
 
@@ -1093,18 +2830,287 @@ impl Engine {
             .contains_key(&request_count)
     }
 
-    async fn section_info(&self, section: &str) -> String {
-        match section {
-            "replication" => match *self.replication_role.read().await {
-                ReplicationRole::Writer(ref role) => {
-                    let writer_offset = self.replication_role.read().await.writer().offset;
-                    format!("# Replication\r\nrole:master\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n\r\n", role.replid, writer_offset)
-                }
-                ReplicationRole::Reader(ref _role) => {
-                    "# Replication\r\nrole:slave\r\n\r\n".to_string()
+    /// Text of INFO for the requested sections, or the default ones, with the
+    /// databases locked by the caller.
+    async fn info(&self, sections: &[String], dbs: &mut [Database]) -> String {
+        let requested = sections
+            .iter()
+            .map(|section| section.to_lowercase())
+            .collect::<HashSet<_>>();
+        let everything = requested.contains("all") || requested.contains("everything");
+        let default = requested.is_empty() || requested.contains("default");
+
+        let mut shown = vec![];
+        for (i, section) in INFO_SECTIONS.into_iter().enumerate() {
+            if everything || (default && i < DEFAULT_INFO_SECTIONS) || requested.contains(section) {
+                shown.push(self.section_info(section, dbs).await);
+            }
+        }
+
+        shown.join("\r\n")
+    }
+
+    async fn section_info(&self, section: &str, dbs: &mut [Database]) -> String {
+        let (title, lines) = match section {
+            "server" => ("Server", info_lines(&self.server_info().await)),
+            "clients" => ("Clients", info_lines(&self.clients_info().await)),
+            "memory" => ("Memory", info_lines(&self.memory_info(dbs).await)),
+            "persistence" => {
+                let stats = self.stats.lock().await;
+                let fields = vec![
+                    ("loading", "0".to_string()),
+                    ("async_loading", "0".to_string()),
+                    ("rdb_changes_since_last_save", stats.dirty.to_string()),
+                    ("rdb_bgsave_in_progress", "0".to_string()),
+                    ("rdb_last_save_time", stats.last_save_time.to_string()),
+                    (
+                        "rdb_last_bgsave_status",
+                        if stats.last_save_ok { "ok" } else { "err" }.to_string(),
+                    ),
+                    ("aof_enabled", "0".to_string()),
+                    ("aof_rewrite_in_progress", "0".to_string()),
+                ];
+                ("Persistence", info_lines(&fields))
+            }
+            "stats" => ("Stats", info_lines(&self.stats_info(dbs).await)),
+            "replication" => ("Replication", info_lines(&self.replication_info().await)),
+            "cpu" => {
+                let (system, user) = cpu_time();
+                let fields = vec![
+                    ("used_cpu_sys", format!("{:.6}", system)),
+                    ("used_cpu_user", format!("{:.6}", user)),
+                    ("used_cpu_sys_children", format!("{:.6}", 0.0)),
+                    ("used_cpu_user_children", format!("{:.6}", 0.0)),
+                ];
+                ("CPU", info_lines(&fields))
+            }
+            "errorstats" => ("Errorstats", self.stats.lock().await.errorstats()),
+            "commandstats" => ("Commandstats", self.stats.lock().await.commandstats()),
+            "latencystats" => ("Latencystats", self.stats.lock().await.latencystats()),
+            "keyspace" => {
+                let fields = dbs
+                    .iter()
+                    .enumerate()
+                    .map(|(db_index, db)| (db_index, db.keyspace_info()))
+                    .filter(|(_, (keys, _, _))| *keys > 0)
+                    .map(|(db_index, (keys, expires, avg_ttl))| {
+                        (
+                            format!("db{}", db_index),
+                            format!("keys={},expires={},avg_ttl={}", keys, expires, avg_ttl),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                ("Keyspace", info_lines(&fields))
+            }
+            _ => return String::new(),
+        };
+
+        format!("# {}\r\n{}", title, lines)
+    }
+
+    async fn server_info(&self) -> Vec<(&'static str, String)> {
+        let stats = self.stats.lock().await;
+        let uptime = stats.started.elapsed().as_secs();
+        let executable = std::env::current_exe()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+
+        vec![
+            ("redis_version", REDIS_VERSION.to_string()),
+            ("redis_mode", "standalone".to_string()),
+            (
+                "os",
+                format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+            ),
+            ("arch_bits", usize::BITS.to_string()),
+            ("process_id", std::process::id().to_string()),
+            ("run_id", stats.run_id.clone()),
+            ("tcp_port", stats.tcp_port.to_string()),
+            ("server_time_usec", unix_time().as_micros().to_string()),
+            ("uptime_in_seconds", uptime.to_string()),
+            ("uptime_in_days", (uptime / 86400).to_string()),
+            ("executable", executable),
+            (
+                "config_file",
+                self.config.read().await.file.clone().unwrap_or_default(),
+            ),
+        ]
+    }
+
+    async fn clients_info(&self) -> Vec<(&'static str, String)> {
+        let views = self.client_views().await;
+        let count = |filter: fn(&ClientView) -> bool| {
+            views.iter().filter(|view| filter(view)).count().to_string()
+        };
+        let max_input_buffer = views.iter().map(|view| view.query_buffer).max();
+        let max_output_buffer = views.iter().map(|view| view.output_buffer).max();
+
+        let watched_keys = self.watched_keys.lock().await;
+        let total_watched_keys = watched_keys
+            .values()
+            .flat_map(|keys| keys.keys())
+            .collect::<HashSet<_>>()
+            .len();
+
+        vec![
+            ("connected_clients", count(|view| !view.replica)),
+            (
+                "client_recent_max_input_buffer",
+                max_input_buffer.unwrap_or(0).to_string(),
+            ),
+            (
+                "client_recent_max_output_buffer",
+                max_output_buffer.unwrap_or(0).to_string(),
+            ),
+            ("blocked_clients", count(|view| view.blocked)),
+            ("tracking_clients", count(|view| view.tracking.is_some())),
+            (
+                "pubsub_clients",
+                count(|view| view.client_type() == ClientType::Pubsub),
+            ),
+            ("watching_clients", watched_keys.len().to_string()),
+            ("total_watched_keys", total_watched_keys.to_string()),
+        ]
+    }
+
+    async fn memory_info(&self, dbs: &mut [Database]) -> Vec<(&'static str, String)> {
+        let (maxmemory, maxmemory_policy) = {
+            let config = self.config.read().await;
+            (config.maxmemory, config.maxmemory_policy)
+        };
+        let used_memory = dbs.iter_mut().map(|db| db.used_memory()).sum::<usize>();
+        let rss = resident_memory();
+        let peak_memory = {
+            let mut stats = self.stats.lock().await;
+            stats.peak_memory = stats.peak_memory.max(used_memory);
+            stats.peak_memory
+        };
+
+        vec![
+            ("used_memory", used_memory.to_string()),
+            ("used_memory_human", bytes_to_human(used_memory)),
+            ("used_memory_rss", rss.to_string()),
+            ("used_memory_rss_human", bytes_to_human(rss)),
+            ("used_memory_peak", peak_memory.to_string()),
+            ("used_memory_peak_human", bytes_to_human(peak_memory)),
+            ("maxmemory", maxmemory.to_string()),
+            ("maxmemory_human", bytes_to_human(maxmemory)),
+            ("maxmemory_policy", maxmemory_policy.to_string()),
+            ("mem_allocator", "libc".to_string()),
+        ]
+    }
+
+    async fn stats_info(&self, dbs: &[Database]) -> Vec<(&'static str, String)> {
+        let db_stats = dbs.iter().map(|db| db.stats()).collect::<Vec<_>>();
+        let sum = |field: fn(&DbStats) -> u64| db_stats.iter().map(field).sum::<u64>().to_string();
+        let pubsub_channels = self.pubsub.channels(None, false).await.len();
+        let pubsub_patterns = self.pubsub.numpat().await;
+        let pubsub_shardchannels = self.pubsub.channels(None, true).await.len();
+
+        let stats = self.stats.lock().await;
+        let (input_kbps, output_kbps) = stats.net_kbps();
+        vec![
+            (
+                "total_connections_received",
+                stats.total_connections_received.to_string(),
+            ),
+            (
+                "total_commands_processed",
+                stats.total_commands_processed.to_string(),
+            ),
+            ("instantaneous_ops_per_sec", stats.ops_per_sec().to_string()),
+            (
+                "total_net_input_bytes",
+                self.net.input.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "total_net_output_bytes",
+                self.net.output.load(Ordering::Relaxed).to_string(),
+            ),
+            ("instantaneous_input_kbps", format!("{:.2}", input_kbps)),
+            ("instantaneous_output_kbps", format!("{:.2}", output_kbps)),
+            (
+                "rejected_connections",
+                stats.rejected_connections.to_string(),
+            ),
+            ("expired_keys", sum(|stats| stats.expired_keys)),
+            ("evicted_keys", sum(|stats| stats.evicted_keys)),
+            ("keyspace_hits", sum(|stats| stats.keyspace_hits)),
+            ("keyspace_misses", sum(|stats| stats.keyspace_misses)),
+            ("pubsub_channels", pubsub_channels.to_string()),
+            ("pubsub_patterns", pubsub_patterns.to_string()),
+            ("pubsub_shardchannels", pubsub_shardchannels.to_string()),
+            ("total_error_replies", stats.total_error_replies.to_string()),
+        ]
+    }
+
+    async fn replication_info(&self) -> Vec<(String, String)> {
+        let replica_addrs = self
+            .clients
+            .lock()
+            .await
+            .views()
+            .into_iter()
+            .filter(|view| view.replica)
+            .map(|view| (view.id, view.addr))
+            .collect::<BTreeMap<_, _>>();
+
+        let field = |name: &str, value: String| (name.to_string(), value);
+        match *self.replication_role.read().await {
+            ReplicationRole::Writer(ref writer) => {
+                let mut fields = vec![
+                    field("role", "master".into()),
+                    field("connected_slaves", replica_addrs.len().to_string()),
+                ];
+                for (i, (id, addr)) in replica_addrs.iter().enumerate() {
+                    let Some(client_info) = writer.clients.get(id) else {
+                        continue;
+                    };
+                    let (ip, port) = addr.rsplit_once(':').unwrap_or((addr, "0"));
+                    let port = client_info
+                        .port
+                        .map_or(port.to_string(), |port| port.to_string());
+                    fields.push((
+                        format!("slave{}", i),
+                        format!(
+                            "ip={},port={},state=online,offset={},lag={}",
+                            ip.trim_start_matches('[').trim_end_matches(']'),
+                            port,
+                            client_info.offset,
+                            client_info.last_ack.elapsed().as_secs()
+                        ),
+                    ));
                 }
-            },
-            _ => String::new(),
+                fields.extend([
+                    field("master_failover_state", "no-failover".into()),
+                    field("master_replid", writer.replid.clone()),
+                    field("master_repl_offset", writer.offset.to_string()),
+                    field("second_repl_offset", "-1".into()),
+                ]);
+                fields
+            }
+            ReplicationRole::Reader(ref reader) => vec![
+                field("role", "slave".into()),
+                field("master_host", reader.writer_host.clone()),
+                field("master_port", reader.writer_port.to_string()),
+                field(
+                    "master_link_status",
+                    if reader.link_up { "up" } else { "down" }.into(),
+                ),
+                field(
+                    "master_last_io_seconds_ago",
+                    reader
+                        .last_io
+                        .map_or(-1, |last_io| last_io.elapsed().as_secs() as i64)
+                        .to_string(),
+                ),
+                field("master_sync_in_progress", "0".into()),
+                field("slave_read_repl_offset", reader.offset.to_string()),
+                field("slave_repl_offset", reader.offset.to_string()),
+                field("slave_priority", "100".into()),
+                field("slave_read_only", "1".into()),
+                field("connected_slaves", "0".into()),
+            ],
         }
     }
 
@@ -1132,10 +3138,11 @@ impl Engine {
     async fn handle_replica_connection(
         &self,
         stream_reader: &mut StreamReader<'_>,
-        request_count: u64,
+        session: &Session,
         command: &Command,
     ) -> Result<(), Error> {
-        let Command::Psync(_replication_id, offset) = command else {
+        let request_count = session.id;
+        let Command::Psync(_replication_id, _offset) = command else {
             unreachable!()
         };
 
@@ -1151,46 +3158,34 @@ impl Engine {
             return Ok(());
         }
 
-        let writer_replid;
+        // The snapshot is taken and the replica registered under the same lock,
+        // so every write is either in the snapshot or sent to the replica after
+        // it, never both.
+        let dbs = self.dbs.read().await;
+        let snapshot = RdbFile::encode(&self.snapshot(&dbs).await);
 
-        {
+        let full_resync = {
             let ReplicationRole::Writer(ref mut writer) = *self.replication_role.write().await
             else {
                 unreachable!()
             };
-            writer_replid = writer.replid.clone();
-
-            let client_info = writer
-                .clients
-                .entry(request_count)
-                .or_insert(ClientInfo::new());
-
-            if *offset >= 0 {
-                client_info.offset = *offset as usize;
-            } else {
-                debug!("Ignoring negative psync offset");
-            }
+            writer.add_synced_client(request_count);
+            format!("FULLRESYNC {} {}", writer.replid, writer.offset)
+        };
+        drop(dbs);
+        if let Some(client) = self.clients.lock().await.get_mut(request_count) {
+            client.replica = true;
         }
 
-        let fake_rdb_file_bytes_str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-        let fake_rdb_file_bytes = (0..fake_rdb_file_bytes_str.len() / 2)
-            .into_iter()
-            .map(|i| {
-                u8::from_str_radix(&fake_rdb_file_bytes_str[(i * 2)..=(i * 2) + 1], 16).unwrap()
-            })
-            .collect::<Vec<_>>();
-
         stream_reader
             .get_mut()
-            .write_all(
-                &RespValue::SimpleString(format!("FULLRESYNC {} 0", writer_replid)).serialize(),
-            )
+            .write_all(&RespValue::SimpleString(full_resync).serialize())
             .await
             .context("write-simple-value-back-to-stream")?;
 
         stream_reader
             .get_mut()
-            .write_all(&RespValue::BulkBytes(fake_rdb_file_bytes).serialize())
+            .write_all(&RespValue::BulkBytes(snapshot).serialize())
             .await
             .context("write-simple-value-back-to-stream")?;
 
@@ -1219,8 +3214,20 @@ impl Engine {
 
             if write_commands.is_empty() && !client_offset_update_request {
                 debug!("Wait for write events to send to readers");
-                self.wr_cmd_propagation_notify.notified().await;
-                continue;
+                stream_reader.flush().await?;
+                tokio::select! {
+                    _ = self.wr_cmd_propagation_notify.notified() => continue,
+                    _ = session.killed() => {
+                        // WAIT must not count on a replica that is gone.
+                        self.replication_role
+                            .write()
+                            .await
+                            .writer_mut()
+                            .clients
+                            .remove(&request_count);
+                        return Ok(());
+                    }
+                }
             }
 
             for command in write_commands {
@@ -1261,8 +3268,10 @@ impl Engine {
                             Some(Ok(command)) => {
                                 self.execute_only(
                                     &command,
-                                    Some(request_count),
+                                    Some(session),
+                                    session.db,
                                     stream_reader.byte_count,
+                                    None,
                                 )
                                 .await?;
                             }
@@ -1289,181 +3298,796 @@ impl Engine {
         }
     }
 
-    async fn subscribe_client_to_channel(&self, request_count: u64, channel: String) -> usize {
-        let mut subs = self.subscriptions.write().await;
-        let client_subs = subs.entry(request_count).or_default();
-        client_subs.entry(channel).or_default();
-
-        client_subs.len()
-    }
-
-    async fn unsubscribe_client_from_channel(&self, request_count: u64, channel: &String) -> usize {
-        let mut subs = self.subscriptions.write().await;
-        let client_subs = subs.entry(request_count).or_default();
-        client_subs.remove(channel);
-
-        client_subs.len()
-    }
-
-    async fn subscription_add_message(&self, channel: &String, message: String) -> usize {
-        let mut count = 0;
-        let mut subs = self.subscriptions.write().await;
-
-        for (_, client_subs) in subs.iter_mut() {
-            if !client_subs.contains_key(channel) {
-                continue;
-            }
+    /// Runs a (P|S)SUBSCRIBE or (P|S)UNSUBSCRIBE command, giving whether the
+    /// client has no subscriptions left.
+    async fn subscription_command(
+        &self,
+        stream_reader: &mut StreamReader<'_>,
+        command: &Command,
+        request_count: u64,
+    ) -> Result<bool, Error> {
+        let (kind, names, subscribing) = match command {
+            Command::Subscribe(names) => (SubscriptionKind::Channel, names, true),
+            Command::Unsubscribe(names) => (SubscriptionKind::Channel, names, false),
+            Command::Psubscribe(names) => (SubscriptionKind::Pattern, names, true),
+            Command::Punsubscribe(names) => (SubscriptionKind::Pattern, names, false),
+            Command::Ssubscribe(names) => (SubscriptionKind::ShardChannel, names, true),
+            Command::Sunsubscribe(names) => (SubscriptionKind::ShardChannel, names, false),
+            _ => unreachable!("Not a subscription command"),
+        };
 
-            client_subs
-                .get_mut(channel)
-                .map(|messages| messages.push_back(message.clone()));
+        let (replies, is_empty) = self
+            .pubsub
+            .update(request_count, kind, names, subscribing)
+            .await;
 
-            count += 1;
+        for reply in replies {
+            stream_reader
+                .get_mut()
+                .write_all(&reply.serialize())
+                .await?;
         }
 
-        self.subscription_notify.notify_waiters();
-
-        count
+        Ok(is_empty)
     }
 
     async fn subscription_handle_incoming_commands(
         &self,
         stream_reader: &mut StreamReader<'_>,
-        request_count: u64,
+        session: &mut Session,
     ) -> Result<bool /* should the sub finish */, Error> {
         let incoming = stream_reader
-            .read_resp_value_from_buf_reader(Some(request_count))
+            .read_resp_value_from_buf_reader(Some(session.id))
             .await?;
 
-        match incoming {
-            Some(resp_value) => match CommandParser::parse(resp_value) {
-                Ok(command) => match command {
-                    Command::Subscribe(more_channels) => {
-                        for channel in more_channels {
-                            let client_subs_len = self
-                                .subscribe_client_to_channel(request_count, channel.clone())
-                                .await;
-
-                            let payload = RespValue::Array(vec![
-                                RespValue::BulkString("subscribe".into()),
-                                RespValue::BulkString(channel),
-                                RespValue::Integer(client_subs_len as i64),
-                            ]);
-                            stream_reader
-                                .get_mut()
-                                .write_all(&payload.serialize())
-                                .await?;
-                        }
-                    }
-                    Command::Unsubscribe(channels_to_remove) => {
-                        for channel in channels_to_remove {
-                            let client_subs_len = self
-                                .unsubscribe_client_from_channel(request_count, &channel)
-                                .await;
-
-                            let payload = RespValue::Array(vec![
-                                RespValue::BulkString("unsubscribe".into()),
-                                RespValue::BulkString(channel),
-                                RespValue::Integer(client_subs_len as i64),
-                            ]);
-                            stream_reader
-                                .get_mut()
-                                .write_all(&payload.serialize())
-                                .await?;
-                        }
-                    }
-                    Command::Ping => {
-                        let payload = RespValue::Array(vec![
-                            RespValue::BulkString("pong".into()),
-                            RespValue::BulkString("".into()),
-                        ]);
-                        stream_reader
-                            .get_mut()
-                            .write_all(&payload.serialize())
-                            .await?;
-                    }
-                    other => {
-                        warn!("Unexpected command inside subscription: {:?}", other);
-                        stream_reader
-                                .get_mut()
-                                .write_all(
-                                    &RespValue::SimpleError(
-                                        format!("ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context ", other.short_name().to_lowercase()),
-                                    )
-                                    .serialize(),
-                                )
-                                .await?;
-                    }
-                },
-                Err(err) => {
-                    stream_reader
+        let Some(resp_value) = incoming else {
+            debug!("Subscription ended its TCP stream for req {}", session.id);
+            return Ok(true);
+        };
+
+        let command = match CommandParser::parse(resp_value) {
+            Ok(command) => command,
+            Err(err) => {
+                stream_reader
+                    .get_mut()
+                    .write_all(&RespValue::SimpleError(err).serialize())
+                    .await?;
+                return Ok(false);
+            }
+        };
+
+        if let Some(denial) = self.check_permissions(&command, session).await? {
+            stream_reader
+                .get_mut()
+                .write_all(&RespValue::SimpleError(denial).serialize())
+                .await?;
+            return Ok(false);
+        }
+
+        match command {
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::Psubscribe(_)
+            | Command::Punsubscribe(_)
+            | Command::Ssubscribe(_)
+            | Command::Sunsubscribe(_) => {
+                self.subscription_command(stream_reader, &command, session.id)
+                    .await
+            }
+            Command::Ping => {
+                let payload = RespValue::Array(vec![
+                    RespValue::BulkString("pong".into()),
+                    RespValue::BulkString("".into()),
+                ]);
+                stream_reader
+                    .get_mut()
+                    .write_all(&payload.serialize())
+                    .await?;
+                Ok(false)
+            }
+            Command::Quit | Command::Reset => {
+                let reply = self.connection_control(&command, session).await;
+                stream_reader
+                    .get_mut()
+                    .write_all(&reply.serialize())
+                    .await?;
+                Ok(true)
+            }
+            other => {
+                warn!("Unexpected command inside subscription: {:?}", other);
+                stream_reader
                         .get_mut()
-                        .write_all(&RespValue::SimpleError(err).serialize())
+                        .write_all(
+                            &RespValue::SimpleError(
+                                format!("ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context ", other.short_name().to_lowercase()),
+                            )
+                            .serialize(),
+                        )
                         .await?;
-                }
-            },
-            None => {
-                debug!(
-                    "Subscription ended its TCP stream for req {}",
-                    request_count
-                );
-                return Ok(true);
+                Ok(false)
             }
         }
+    }
+
+    /// Runs QUIT, which closes the connection after the reply, or RESET,
+    /// which brings the connection back to the state of a new one.
+    async fn connection_control(&self, command: &Command, session: &mut Session) -> RespValue {
+        if let Command::Quit = command {
+            session.closing = true;
+            return RespValue::SimpleString("OK".into());
+        }
+
+        self.transaction_store.lock().await.remove(&session.id);
+        self.unwatch_all(session.id, &mut self.dbs.write().await)
+            .await;
+        self.pubsub.detach(session.id).await;
+        self.disable_tracking(session.id).await;
+        session.tracking = false;
+        session.caching = None;
+        if let Some(client) = self.clients.lock().await.get_mut(session.id) {
+            client.name.clear();
+            client.no_evict = false;
+        }
+
+        let default_user_is_nopass = self
+            .users
+            .read()
+            .await
+            .get(DEFAULT_USER)
+            .map(|user| user.is_enabled() && user.is_nopass())
+            .unwrap_or(false);
+        session.user = default_user_is_nopass.then(|| DEFAULT_USER.to_string());
+        session.db = 0;
 
-        Ok(false)
+        RespValue::SimpleString("RESET".into())
     }
 
-    async fn subscription_handle_publishing(
+    /// Checks the command against the ACL user of the session, giving the
+    /// error to reply with when it is refused. AUTH is always allowed.
+    async fn check_permissions(
         &self,
-        stream_reader: &mut StreamReader<'_>,
-        request_count: u64,
-    ) -> Result<(), Error> {
-        let mut channel_messages: HashMap<String, Vec<String>> = HashMap::new();
-
+        command: &Command,
+        session: &Session,
+    ) -> Result<Option<String>, Error> {
+        if command.is_auth()
+            || command.is_connection_control()
+            || matches!(command, Command::Unknown(_))
         {
-            let mut subs = self.subscriptions.write().await;
-            let client_subs = subs
-                .get_mut(&request_count)
-                .expect("Cannot find client subs");
+            return Ok(None);
+        }
+        let Some(name) = session.user.as_deref() else {
+            return Ok(None);
+        };
+
+        let denial = match self.users.read().await.get(name) {
+            Some(user) => user.check(command).err(),
+            // The connection of a deleted user is closed.
+            None => return Err(format!("User {} has been deleted", name).into()),
+        };
+
+        let Some(denial) = denial else {
+            return Ok(None);
+        };
+
+        let context = if self.is_transaction(session.id).await {
+            "multi"
+        } else {
+            "toplevel"
+        };
+        let (reason, object) = denial.log_reason_and_object();
+        self.acl_log
+            .lock()
+            .await
+            .add(reason, context, object, name, &session.client_info());
+
+        Ok(Some(denial.to_error(name)))
+    }
+
+    pub(crate) async fn has_aclfile(&self) -> bool {
+        self.config.read().await.aclfile.is_some()
+    }
 
-            for (channel, stored_messages) in client_subs {
-                channel_messages.insert(channel.clone(), stored_messages.drain(..).collect());
+    /// Replaces all users with the ones in the ACL file. Nothing changes when
+    /// the file has an error. The default user is kept when the file does not
+    /// define it.
+    pub(crate) async fn load_acl_file(&self) -> Result<(), String> {
+        let Some(path) = self.config.read().await.aclfile.clone() else {
+            return Err(NO_ACLFILE_ERROR.to_string());
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| format!("ERR Error loading ACLs, opening file '{}': {}", path, err))?;
+        let mut loaded = acl::parse_acl_file(&path, &content)?;
+
+        let mut users = self.users.write().await;
+        if !loaded.contains_key(DEFAULT_USER) {
+            if let Some(default_user) = users.get(DEFAULT_USER) {
+                loaded.insert(DEFAULT_USER.to_string(), default_user.clone());
             }
         }
+        *users = loaded;
 
-        for (channel, messages) in channel_messages {
-            for message in messages {
-                let payload = RespValue::Array(vec![
-                    RespValue::BulkString("message".into()),
-                    RespValue::BulkString(channel.clone()),
-                    RespValue::BulkString(message),
-                ]);
-                stream_reader
-                    .get_mut()
-                    .write_all(&payload.serialize())
-                    .await?;
+        Ok(())
+    }
+
+    async fn save_acl_file(&self) -> Result<(), String> {
+        let Some(path) = self.config.read().await.aclfile.clone() else {
+            return Err(NO_ACLFILE_ERROR.to_string());
+        };
+
+        let content = acl::format_acl_file(&*self.users.read().await);
+
+        // Written next to the target and renamed, so the file is never half written.
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, &path))
+            .map_err(|err| format!("ERR There was an error trying to save the ACLs: {}", err))
+    }
+
+    pub(crate) async fn is_protected(&self) -> bool {
+        self.config.read().await.protected_mode
+            && self
+                .users
+                .read()
+                .await
+                .get(DEFAULT_USER)
+                .map(|user| user.is_nopass())
+                .unwrap_or(false)
+    }
+
+    /// A new connection is authenticated as the user named by its client
+    /// certificate if that user exists and is enabled. Otherwise it is the
+    /// default user, unless that user has a password or is disabled.
+    pub(crate) async fn new_session(
+        &self,
+        id: u64,
+        addr: String,
+        laddr: String,
+        cert_user: Option<&str>,
+    ) -> Session {
+        let users = self.users.read().await;
+
+        let cert_user = cert_user.filter(|name| {
+            users
+                .get(*name)
+                .map(|user| user.is_enabled())
+                .unwrap_or(false)
+        });
+        let default_user_is_nopass = users
+            .get(DEFAULT_USER)
+            .map(|user| user.is_enabled() && user.is_nopass())
+            .unwrap_or(false);
+
+        let user = match cert_user {
+            Some(name) => Some(name.to_string()),
+            None => default_user_is_nopass.then(|| DEFAULT_USER.to_string()),
+        };
+
+        self.stats.lock().await.total_connections_received += 1;
+        let killed = self.clients.lock().await.register(
+            id,
+            addr.clone(),
+            laddr,
+            user.as_deref().unwrap_or(DEFAULT_USER).to_string(),
+        );
+
+        Session {
+            id,
+            user,
+            addr,
+            db: 0,
+            closing: false,
+            tracking: false,
+            caching: None,
+            killed,
+        }
+    }
+
+    async fn auth(&self, session: &mut Session, user: Option<&str>, password: &str) -> RespValue {
+        let users = self.users.read().await;
+
+        let default_user_is_nopass = users
+            .get(DEFAULT_USER)
+            .map(|user| user.is_nopass())
+            .unwrap_or(false);
+        if user.is_none() && default_user_is_nopass {
+            return RespValue::SimpleError(
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string(),
+            );
+        }
+        let user = user.unwrap_or(DEFAULT_USER);
+
+        match users.get(user) {
+            Some(stored_user)
+                if stored_user.is_enabled() && stored_user.check_password(password) =>
+            {
+                session.user = Some(user.to_string());
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => {
+                self.acl_log.lock().await.add(
+                    "auth",
+                    "toplevel",
+                    "AUTH",
+                    user,
+                    &session.client_info(),
+                );
+                RespValue::SimpleError(
+                    "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                )
             }
         }
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{
+        commands::Command,
+        common::ReplicationRole,
+        config::Config,
+        engine::Engine,
+        rdb::RdbFile,
+        resp::{RespParser, RespValue},
+        server::Server,
+    };
+
+    fn engine() -> Arc<Engine> {
+        Arc::new(Engine::new(
+            Config {
+                dir: std::env::temp_dir().to_string_lossy().into(),
+                dbfilename: format!("engine-test-{}.rdb", std::process::id()),
+                ..Default::default()
+            },
+            None,
+        ))
     }
 
-    async fn user_flags(&self, user: &str) -> Vec<String> {
-        self.users
-            .read()
+    /// A connection to the engine over an in-memory stream.
+    struct TestClient {
+        stream: DuplexStream,
+        replies: BytesMut,
+        parser: RespParser,
+    }
+
+    impl TestClient {
+        async fn connect(engine: &Arc<Engine>, id: u64) -> Self {
+            let session = engine
+                .new_session(id, format!("client-{}", id), "server".into(), None)
+                .await;
+            let (stream, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(Server::handle_request(
+                Box::new(server),
+                RespParser::default(),
+                engine.clone(),
+                session,
+            ));
+
+            Self {
+                stream,
+                replies: BytesMut::new(),
+                parser: RespParser::default(),
+            }
+        }
+
+        /// Sends the command and waits for its reply, failing if the server
+        /// stops responding.
+        async fn call(&mut self, args: &[&str]) -> RespValue {
+            let request = RespValue::Array(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(arg.to_string()))
+                    .collect(),
+            );
+            self.stream.write_all(&request.serialize()).await.unwrap();
+
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Some((reply, _)) = self.parser.parse(&mut self.replies).unwrap() {
+                        return reply;
+                    }
+                    assert!(self.stream.read_buf(&mut self.replies).await.unwrap() > 0);
+                }
+            })
             .await
-            .get(user)
-            .map(|_user| vec![])
-            .unwrap_or(vec!["nopass".into()])
+            .expect("The server stopped responding")
+        }
     }
 
-    async fn user_passwords(&self, user: &str) -> Vec<String> {
-        self.users
-            .read()
+    fn ok() -> RespValue {
+        RespValue::SimpleString("OK".into())
+    }
+
+    fn queued() -> RespValue {
+        RespValue::SimpleString("QUEUED".into())
+    }
+
+    #[tokio::test]
+    async fn test_exec_aborts_after_queue_error() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(ok(), client.call(&["MULTI"]).await);
+        assert_eq!(queued(), client.call(&["SET", "a", "1"]).await);
+        assert!(matches!(
+            client.call(&["SET", "b"]).await,
+            RespValue::SimpleError(_)
+        ));
+        assert_eq!(
+            RespValue::SimpleError(
+                "EXECABORT Transaction discarded because of previous errors.".into()
+            ),
+            client.call(&["EXEC"]).await
+        );
+        assert_eq!(RespValue::NullBulkString, client.call(&["GET", "a"]).await);
+        assert_eq!(
+            RespValue::SimpleError("ERR EXEC without MULTI".into()),
+            client.call(&["EXEC"]).await
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_exec_is_atomic() {
+        const INCRS: usize = 20;
+
+        let engine = engine();
+        let mut writer = TestClient::connect(&engine, 1).await;
+        let mut reader = TestClient::connect(&engine, 2).await;
+
+        let reads = tokio::spawn(async move {
+            let mut seen = vec![];
+            for _ in 0..200 {
+                if let RespValue::BulkString(n) = reader.call(&["GET", "n"]).await {
+                    seen.push(n.parse::<usize>().unwrap());
+                }
+            }
+            seen
+        });
+        for round in 1..=20 {
+            assert_eq!(ok(), writer.call(&["MULTI"]).await);
+            for _ in 0..INCRS {
+                assert_eq!(queued(), writer.call(&["INCR", "n"]).await);
+            }
+            let RespValue::Array(replies) = writer.call(&["EXEC"]).await else {
+                panic!("EXEC did not run");
+            };
+            assert_eq!(
+                RespValue::Integer((round * INCRS) as i64),
+                replies[INCRS - 1]
+            );
+        }
+
+        for n in reads.await.unwrap() {
+            assert_eq!(0, n % INCRS, "Saw {} in the middle of a transaction", n);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exec_is_replicated_as_one_unit() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(ok(), client.call(&["MULTI"]).await);
+        for command in [&["SET", "k", "v"][..], &["GET", "k"], &["INCR", "n"]] {
+            assert_eq!(queued(), client.call(command).await);
+        }
+        client.call(&["EXEC"]).await;
+
+        let ReplicationRole::Writer(writer) = &*engine.replication_role.read().await else {
+            unreachable!();
+        };
+        let replicated = writer
+            .write_queue
+            .iter()
+            .map(|command| command.into_resp())
+            .collect::<Vec<_>>();
+        let expected = [
+            Command::Select(0),
+            Command::Multi,
+            Command::Set("k".into(), "v".into(), None),
+            Command::Incr("n".into()),
+            Command::Exec,
+        ]
+        .iter()
+        .map(|command| command.into_resp())
+        .collect::<Vec<_>>();
+        assert_eq!(expected, replicated);
+    }
+
+    #[tokio::test]
+    async fn test_exec_runs_commands_using_the_databases() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(ok(), client.call(&["MULTI"]).await);
+        assert_eq!(queued(), client.call(&["SET", "k", "v"]).await);
+        assert_eq!(queued(), client.call(&["SAVE"]).await);
+        assert_eq!(
+            RespValue::Array(vec![ok(), ok()]),
+            client.call(&["EXEC"]).await
+        );
+        assert_eq!(
+            RespValue::BulkString("v".into()),
+            client.call(&["GET", "k"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_info_in_transaction() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(ok(), client.call(&["MULTI"]).await);
+        assert_eq!(queued(), client.call(&["SET", "k", "v"]).await);
+        assert_eq!(queued(), client.call(&["INFO"]).await);
+        assert_eq!(queued(), client.call(&["INFO", "keyspace"]).await);
+        let RespValue::Array(replies) = client.call(&["EXEC"]).await else {
+            panic!("EXEC did not run");
+        };
+        assert_eq!(3, replies.len());
+        assert!(matches!(&replies[1], RespValue::BulkString(info) if info.contains("# Memory")));
+        assert_eq!(
+            RespValue::BulkString("# Keyspace\r\ndb0:keys=1,expires=0,avg_ttl=0\r\n".into()),
+            replies[2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_in_transaction() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(ok(), client.call(&["SET", "k", "v"]).await);
+        client.call(&["GET", "k"]).await;
+
+        assert_eq!(ok(), client.call(&["MULTI"]).await);
+        assert_eq!(queued(), client.call(&["CONFIG", "RESETSTAT"]).await);
+        assert_eq!(
+            queued(),
+            client
+                .call(&["CONFIG", "SET", "maxmemory-policy", "allkeys-lru"])
+                .await
+        );
+        assert_eq!(
+            RespValue::Array(vec![ok(), ok()]),
+            client.call(&["EXEC"]).await
+        );
+
+        let RespValue::BulkString(info) = client.call(&["INFO", "stats"]).await else {
+            panic!("INFO did not reply");
+        };
+        assert!(info.contains("keyspace_hits:0\r\n"), "{}", info);
+        assert_eq!(
+            RespValue::Array(vec![
+                RespValue::BulkString("maxmemory-policy".into()),
+                RespValue::BulkString("allkeys-lru".into()),
+            ]),
+            client.call(&["CONFIG", "GET", "maxmemory-policy"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_rejects_bad_timeouts() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(
+            RespValue::SimpleError("ERR timeout is negative".into()),
+            client.call(&["BLPOP", "k", "-1"]).await
+        );
+        for timeout in ["inf", "nan", "1e300", "soon"] {
+            assert_eq!(
+                RespValue::SimpleError("ERR timeout is not a float or out of range".into()),
+                client.call(&["BRPOP", "k", timeout]).await
+            );
+        }
+        assert_eq!(
+            RespValue::NullArray,
+            client.call(&["BLPOP", "k", "0.01"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_script_calls_are_checked_against_the_acl() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(
+            ok(),
+            client
+                .call(&[
+                    "ACL",
+                    "SETUSER",
+                    "restricted",
+                    "on",
+                    "nopass",
+                    "+@all",
+                    "~public:*"
+                ])
+                .await
+        );
+        assert_eq!(ok(), client.call(&["AUTH", "restricted", "any"]).await);
+
+        assert_eq!(
+            RespValue::SimpleError("NOPERM No permissions to access a key".into()),
+            client
+                .call(&["EVAL", "return redis.call('SET', 'secret', 'v')", "0"])
+                .await
+        );
+
+        let RespValue::Array(entries) = client.call(&["ACL", "LOG", "1"]).await else {
+            panic!("ACL LOG did not reply");
+        };
+        let RespValue::Array(entry) = &entries[0] else {
+            panic!("Not an ACL LOG entry");
+        };
+        assert_eq!(
+            [
+                RespValue::BulkString("reason".into()),
+                RespValue::BulkString("key".into()),
+                RespValue::BulkString("context".into()),
+                RespValue::BulkString("lua".into()),
+                RespValue::BulkString("object".into()),
+                RespValue::BulkString("secret".into()),
+                RespValue::BulkString("username".into()),
+                RespValue::BulkString("restricted".into()),
+            ],
+            entry[2..10]
+        );
+
+        assert_eq!(ok(), client.call(&["AUTH", "default", "any"]).await);
+        assert_eq!(
+            RespValue::NullBulkString,
+            client.call(&["GET", "secret"]).await
+        );
+    }
+
+    // A single worker, which the looping script must not keep from the other
+    // clients.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_busy_script_is_killed() {
+        let engine = engine();
+        let mut script_client = TestClient::connect(&engine, 1).await;
+        let mut client = TestClient::connect(&engine, 2).await;
+
+        assert_eq!(
+            ok(),
+            client
+                .call(&["CONFIG", "SET", "lua-time-limit", "10"])
+                .await
+        );
+        let script = tokio::spawn(async move {
+            script_client
+                .call(&["EVAL", "while true do end", "0"])
+                .await
+        });
+
+        let busy = RespValue::SimpleError(
+            "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."
+                .into(),
+        );
+        while client.call(&["PING"]).await != busy {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(busy, client.call(&["GET", "k"]).await);
+
+        assert_eq!(ok(), client.call(&["SCRIPT", "KILL"]).await);
+        assert_eq!(
+            RespValue::SimpleError("ERR Script killed by user with SCRIPT KILL...".into()),
+            script.await.unwrap()
+        );
+        assert_eq!(RespValue::NullBulkString, client.call(&["GET", "k"]).await);
+        assert_eq!(
+            RespValue::SimpleError("NOTBUSY No scripts in execution right now.".into()),
+            client.call(&["SCRIPT", "KILL"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_carries_every_type_and_the_libraries() {
+        let writer = engine();
+        let mut client = TestClient::connect(&writer, 1).await;
+        assert_eq!(ok(), client.call(&["SET", "s", "v"]).await);
+        client.call(&["RPUSH", "l", "a", "b"]).await;
+        client.call(&["ZADD", "z", "2.5", "m"]).await;
+        client.call(&["XADD", "x", "1-1", "a", "1"]).await;
+        client
+            .call(&["XADD", "x", "1700000000000-2", "b", "2", "c", "3"])
+            .await;
+        client
+            .call(&[
+                "FUNCTION",
+                "LOAD",
+                "#!lua name=lib\nredis.register_function('f', function() return 1 end)",
+            ])
+            .await;
+
+        let snapshot = writer.snapshot(&writer.dbs.read().await).await;
+        let reader = engine();
+        reader
+            .load_snapshot(RdbFile::decode(&RdbFile::encode(&snapshot)).unwrap())
             .await
-            .get(user)
-            .map(|user| vec![user.password_hash()])
-            .unwrap_or(vec![])
+            .unwrap();
+
+        let mut client = TestClient::connect(&reader, 2).await;
+        assert_eq!(
+            RespValue::BulkString("v".into()),
+            client.call(&["GET", "s"]).await
+        );
+        assert_eq!(
+            RespValue::Array(vec![
+                RespValue::BulkString("a".into()),
+                RespValue::BulkString("b".into()),
+            ]),
+            client.call(&["LRANGE", "l", "0", "-1"]).await
+        );
+        assert_eq!(
+            RespValue::BulkString("2.5".into()),
+            client.call(&["ZSCORE", "z", "m"]).await
+        );
+        assert_eq!(
+            RespValue::Integer(1),
+            client.call(&["FCALL", "f", "0"]).await
+        );
+        assert_eq!(
+            RespValue::Array(vec![
+                RespValue::Array(vec![
+                    RespValue::BulkString("1-1".into()),
+                    RespValue::Array(vec![
+                        RespValue::BulkString("a".into()),
+                        RespValue::BulkString("1".into()),
+                    ]),
+                ]),
+                RespValue::Array(vec![
+                    RespValue::BulkString("1700000000000-2".into()),
+                    RespValue::Array(vec![
+                        RespValue::BulkString("b".into()),
+                        RespValue::BulkString("2".into()),
+                        RespValue::BulkString("c".into()),
+                        RespValue::BulkString("3".into()),
+                    ]),
+                ]),
+            ]),
+            client.call(&["XRANGE", "x", "-", "+"]).await
+        );
+        assert_eq!(
+            RespValue::BulkString("1700000000000-3".into()),
+            client
+                .call(&["XADD", "x", "1700000000000-*", "d", "4"])
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_writer_of_either_family() {
+        for local in ["127.0.0.1:0", "[::1]:0"] {
+            // Hosts without IPv6 can't run the second half.
+            let Ok(listener) = tokio::net::TcpListener::bind(local).await else {
+                continue;
+            };
+            let addr = listener.local_addr().unwrap();
+
+            let stream = Engine::connect_to_writer(addr).await.unwrap();
+            assert_eq!(addr, stream.peer_addr().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unwatch_in_transaction() {
+        let engine = engine();
+        let mut client = TestClient::connect(&engine, 1).await;
+
+        assert_eq!(ok(), client.call(&["WATCH", "k"]).await);
+        assert_eq!(ok(), client.call(&["MULTI"]).await);
+        assert_eq!(queued(), client.call(&["UNWATCH"]).await);
+        assert_eq!(queued(), client.call(&["PING"]).await);
+        assert_eq!(
+            RespValue::Array(vec![ok(), RespValue::SimpleString("PONG".into())]),
+            client.call(&["EXEC"]).await
+        );
     }
 }
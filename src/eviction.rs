@@ -0,0 +1,181 @@
+use std::fmt;
+
+use rand::Rng;
+
+use crate::common::current_time_ms;
+
+/// Counter of new keys, so they are not evicted before they had a chance to
+/// be used again.
+const LFU_INIT_VAL: u8 = 5;
+/// How hard the counter gets to increment: with 10, a million accesses reach
+/// the 255 maximum, as `lfu-log-factor 10` does.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// The counter is decremented once per idle period of this length, as
+/// `lfu-decay-time 1` does.
+const LFU_DECAY_TIME_MS: u128 = 60 * 1000;
+
+/// `maxmemory-policy`: which keys go once `maxmemory` is reached. The
+/// `volatile` policies only evict keys with an expiry.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum EvictionPolicy {
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    const NAMES: [(&'static str, EvictionPolicy); 8] = [
+        ("noeviction", EvictionPolicy::NoEviction),
+        ("allkeys-lru", EvictionPolicy::AllKeysLru),
+        ("allkeys-lfu", EvictionPolicy::AllKeysLfu),
+        ("allkeys-random", EvictionPolicy::AllKeysRandom),
+        ("volatile-lru", EvictionPolicy::VolatileLru),
+        ("volatile-lfu", EvictionPolicy::VolatileLfu),
+        ("volatile-random", EvictionPolicy::VolatileRandom),
+        ("volatile-ttl", EvictionPolicy::VolatileTtl),
+    ];
+
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        Self::NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(raw))
+            .map(|(_, policy)| *policy)
+            .ok_or_else(|| "argument(s) must be one of the following: volatile-lru, volatile-lfu, volatile-random, volatile-ttl, allkeys-lru, allkeys-lfu, allkeys-random, noeviction".to_string())
+    }
+
+    pub(crate) fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru
+                | EvictionPolicy::VolatileLfu
+                | EvictionPolicy::VolatileRandom
+                | EvictionPolicy::VolatileTtl
+        )
+    }
+
+    pub(crate) fn is_lfu(self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu
+        )
+    }
+
+    /// How much the policy wants a key gone, higher first. `None` under
+    /// noeviction.
+    pub(crate) fn score(self, usage: &KeyUsage, expiry_ms: Option<u128>) -> Option<u128> {
+        let now = current_time_ms();
+        match self {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => Some(usage.idle_ms(now)),
+            EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => {
+                Some((u8::MAX - usage.frequency(now)) as u128)
+            }
+            EvictionPolicy::AllKeysRandom | EvictionPolicy::VolatileRandom => {
+                Some(rand::rng().random())
+            }
+            // Sooner deadlines first.
+            EvictionPolicy::VolatileTtl => expiry_ms.map(|expiry_ms| u128::MAX - expiry_ms),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = Self::NAMES
+            .iter()
+            .find(|(_, policy)| policy == self)
+            .expect("Every policy has a name");
+        write!(f, "{}", name)
+    }
+}
+
+/// When a key was last accessed and how often, for OBJECT IDLETIME and FREQ
+/// and for the LRU and LFU policies.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeyUsage {
+    last_access_ms: u128,
+    /// Logarithmic access counter, decremented as the key stays idle.
+    counter: u8,
+}
+
+impl KeyUsage {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_access_ms: current_time_ms(),
+            counter: LFU_INIT_VAL,
+        }
+    }
+
+    /// Records an access to the key.
+    pub(crate) fn touch(&mut self) {
+        let now = current_time_ms();
+        self.counter = Self::increment(self.frequency(now));
+        self.last_access_ms = now;
+    }
+
+    pub(crate) fn idle_ms(&self, now: u128) -> u128 {
+        now.saturating_sub(self.last_access_ms)
+    }
+
+    /// The access counter, after the decay for the time the key stayed idle.
+    pub(crate) fn frequency(&self, now: u128) -> u8 {
+        let periods = self.idle_ms(now) / LFU_DECAY_TIME_MS;
+        self.counter
+            .saturating_sub(periods.min(u8::MAX as u128) as u8)
+    }
+
+    /// Increments the counter with a probability falling as it grows, so it
+    /// counts up to millions of accesses in a byte.
+    fn increment(counter: u8) -> u8 {
+        if counter == u8::MAX {
+            return counter;
+        }
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let probability = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        if rand::rng().random::<f64>() < probability {
+            counter + 1
+        } else {
+            counter
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::eviction::{EvictionPolicy, KeyUsage, LFU_DECAY_TIME_MS, LFU_INIT_VAL};
+
+    #[test]
+    fn test_policy_names() {
+        for (name, policy) in EvictionPolicy::NAMES {
+            assert_eq!(Ok(policy), EvictionPolicy::parse(&name.to_uppercase()));
+            assert_eq!(name, policy.to_string());
+        }
+        assert!(EvictionPolicy::parse("allkeys").is_err());
+        assert!(EvictionPolicy::VolatileTtl.is_volatile());
+        assert!(!EvictionPolicy::AllKeysLfu.is_volatile());
+    }
+
+    #[test]
+    fn test_lfu_counter_grows_slowly_and_decays() {
+        let mut usage = KeyUsage::new();
+        assert_eq!(LFU_INIT_VAL, usage.frequency(usage.last_access_ms));
+
+        for _ in 0..1000 {
+            usage.touch();
+        }
+        let now = usage.last_access_ms;
+        let frequency = usage.frequency(now);
+        assert!(frequency > LFU_INIT_VAL + 5, "{}", frequency);
+        assert!(frequency < 40, "{}", frequency);
+
+        assert_eq!(frequency - 3, usage.frequency(now + 3 * LFU_DECAY_TIME_MS));
+        assert_eq!(0, usage.frequency(now + 1000 * LFU_DECAY_TIME_MS));
+        assert_eq!(3000, usage.idle_ms(now + 3000));
+    }
+}
@@ -2,48 +2,56 @@ extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
+mod acl;
+mod clients;
 mod command_parser;
 mod commands;
 mod common;
+mod config;
 mod database;
 mod engine;
+mod eviction;
 mod network;
+mod pubsub;
 mod rdb;
 mod resp;
+mod scripting;
 mod server;
+mod stats;
+mod tls;
+mod tracking;
 
 use log::info;
 
-use crate::{common::Error, server::*};
+use crate::{common::Error, config::Config, server::*};
 use clap::Parser;
 
+/// The config file and options, as in `redis-server /etc/redis.conf --port
+/// 6380`. Options override the config file, whose settings override the
+/// defaults.
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value_t = 6379)]
-    port: u16,
-
-    #[arg(long)]
-    replicaof: Option<String>,
-
-    #[arg(long, default_value_t = String::from("/tmp/redis-files"))]
-    dir: String,
-
-    #[arg(long, default_value_t = String::from("dump.rdb"))]
-    dbfilename: String,
+    /// redis.conf file to load, followed by `--<directive> <value>` options
+    /// for any directive of the file.
+    #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+    args: Vec<String>,
 }
 
 impl Args {
-    fn parsed_replica_of(&self) -> Option<(String, u16)> {
-        self.replicaof.clone().map(|raw| {
-            let parts = raw.split(' ').collect::<Vec<_>>();
-            if parts.len() != 2 {
-                panic!("Invalid replica of argument");
+    /// The defaults, updated by the config file and then by the options.
+    fn config(&self) -> Result<Config, Error> {
+        let mut config = Config::default();
+        let options = match self.args.split_first() {
+            Some((path, options)) if !path.starts_with('-') => {
+                config.load_file(path)?;
+                options
             }
+            _ => &self.args[..],
+        };
+        config.load_args(options)?;
 
-            let replica_port = u16::from_str_radix(parts[1], 10).expect("port from argument");
-            (parts[0].to_string(), replica_port)
-        })
+        Ok(config)
     }
 }
 
@@ -54,14 +62,14 @@ async fn main() -> Result<(), Error> {
 
     info!("Peter-Redis starting");
 
-    let args = Args::parse();
-
-    let server = Server::new(
-        args.port,
-        args.parsed_replica_of(),
-        args.dir,
-        args.dbfilename,
-    );
+    let config = match Args::parse().config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let server = Server::new(config)?;
     server.run().await?;
 
     info!("Peter-Redis ending");
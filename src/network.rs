@@ -1,163 +1,200 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
 use anyhow::Context;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
-    net::TcpStream,
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+
+use crate::{
+    common::Error,
+    resp::{RespParser, RespValue},
+    stats::NetCounters,
 };
 
-use crate::{common::Error, resp::RespValue};
+/// A client or replication stream, plain TCP or TLS.
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub(crate) type Connection = Box<dyn AsyncStream>;
+
+/// A client connection counting the bytes it moves, for INFO stats.
+pub(crate) struct CountedStream {
+    inner: Connection,
+    counters: Arc<NetCounters>,
+}
+
+impl CountedStream {
+    pub(crate) fn new(inner: Connection, counters: Arc<NetCounters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.counters.add_input(buf.filled().len() - before);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.counters.add_output(written);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Size reserved in the read buffer before each read from the peer.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// A malformed request; the client gets the error before being disconnected.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct ProtocolError(pub(crate) String);
 
+/// Reads requests and buffers replies of one connection. It is kept for the
+/// whole connection, so bytes of pipelined requests already read stay in the
+/// buffer. Replies are flushed only when more input has to be read from the
+/// peer, which sends the replies of a pipelined batch in a single write.
 pub(crate) struct StreamReader<'a> {
-    buf_reader: BufReader<&'a mut TcpStream>,
+    stream: BufWriter<&'a mut Connection>,
+    buf: BytesMut,
+    parser: RespParser,
     uncommitted_byte_count: usize,
     pub(crate) byte_count: usize,
 }
 
 impl<'a> StreamReader<'a> {
-    pub(crate) fn new(stream: &'a mut TcpStream) -> Self {
+    pub(crate) fn new(stream: &'a mut Connection, parser: RespParser) -> Self {
         Self {
-            buf_reader: BufReader::new(stream),
+            stream: BufWriter::new(stream),
+            buf: BytesMut::with_capacity(READ_CHUNK),
+            parser,
             uncommitted_byte_count: 0,
             byte_count: 0,
         }
     }
 
-    pub(crate) fn get_mut(&mut self) -> &mut TcpStream {
-        self.buf_reader.get_mut()
+    /// The buffered writer of replies.
+    pub(crate) fn get_mut(&mut self) -> &mut BufWriter<&'a mut Connection> {
+        &mut self.stream
     }
 
-    pub(crate) fn reset_byte_counter(&mut self) {
-        self.uncommitted_byte_count = 0;
-        self.byte_count = 0;
+    /// Sends the buffered replies. Needed before waiting on anything other
+    /// than the peer's input, e.g. blocking commands or pub/sub messages.
+    pub(crate) async fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush().await.context("flush-replies")?;
+        Ok(())
+    }
+
+    /// Bytes of requests read but not parsed yet, and the room left for more.
+    pub(crate) fn query_buffer(&self) -> (usize, usize) {
+        (self.buf.len(), self.buf.capacity() - self.buf.len())
+    }
+
+    /// Bytes of replies not sent yet.
+    pub(crate) fn output_buffer(&self) -> usize {
+        self.stream.buffer().len()
+    }
+
+    pub(crate) fn reset_byte_counter(&mut self, offset: usize) {
+        self.uncommitted_byte_count = offset;
+        self.byte_count = offset;
     }
 
     pub(crate) fn commit_byte_count(&mut self) {
         self.byte_count = self.uncommitted_byte_count;
     }
 
+    /// Next value sent by the peer, `None` once it closed the connection.
     pub(crate) async fn read_resp_value_from_buf_reader(
         &mut self,
         request_count: Option<u64>,
     ) -> Result<Option<RespValue>, Error> {
-        self.read_resp_value(request_count).await
-    }
-
-    async fn read_resp_value(
-        &mut self,
-        request_count: Option<u64>,
-    ) -> Result<Option<RespValue>, Error> {
-        let line = self.read_line_from_tcp_stream(request_count).await?;
-
-        if line.is_empty() {
-            return Ok(None);
-        } else if line.starts_with("+") {
-            return Ok(Some(RespValue::SimpleString(line[1..].trim().to_string())));
-        } else if line.starts_with("$") {
-            let bulk_str_len =
-                usize::from_str_radix(&line[1..].trim(), 10).context("parse-bulk-str-len")?;
-
-            let next_line = self.read_line_from_tcp_stream(request_count).await?;
-
-            if next_line.trim().len() != bulk_str_len {
-                return Err(format!(
-                    "Bulk string len mismatch. Expected {}, got {}. Bulk string: {}",
-                    bulk_str_len,
-                    next_line.len(),
-                    &next_line
-                )
-                .into());
+        loop {
+            if let Some((value, len)) = self.parser.parse(&mut self.buf).map_err(ProtocolError)? {
+                self.uncommitted_byte_count += len;
+                debug!(
+                    "Incoming {} bytes from TcpStream (RC: {:?}): {:?}",
+                    len, request_count, value
+                );
+                return Ok(Some(value));
             }
 
-            return Ok(Some(RespValue::BulkString(next_line.trim().to_string())));
-        } else if line.starts_with("*") {
-            let array_len =
-                usize::from_str_radix(&line[1..].trim(), 10).context("parse-array-len")?;
-            let mut items = vec![];
-
-            for _ in 0..array_len {
-                match Box::pin(self.read_resp_value(request_count)).await? {
-                    Some(item) => items.push(item),
-                    None => return Err("Missing array item".into()),
-                }
+            if !self.fill_buf(request_count).await? {
+                return Ok(None);
             }
-
-            return Ok(Some(RespValue::Array(items)));
-        } else if line.starts_with(":") {
-            let v = i64::from_str_radix(&line[1..].trim(), 10).context("parse-array-len")?;
-            return Ok(Some(RespValue::Integer(v)));
-        } else if line.starts_with(",") {
-            let v = line[1..].trim().parse::<f64>().expect("parsing-double");
-            return Ok(Some(RespValue::Double(v)));
         }
-
-        Err(format!("Unexpected incoming RESP string from connection: {}", line).into())
-    }
-
-    async fn read_line_from_tcp_stream(
-        &mut self,
-        request_count: Option<u64>,
-    ) -> Result<String, Error> {
-        let mut buf = String::new();
-        self.buf_reader.read_line(&mut buf).await.context(format!(
-            "reading-line-from-tcpstream-req{:?}",
-            request_count
-        ))?;
-        self.uncommitted_byte_count += buf.len();
-
-        debug!(
-            "Incoming {} bytes from TcpStream [{}] (RC: {:?})",
-            buf.len(),
-            buf.trim_end(),
-            request_count
-        );
-
-        Ok(buf)
     }
 
+    /// Reads the RDB payload of a full resync.
     pub(crate) async fn read_bulk_bytes_from_tcp_stream(
         &mut self,
         request_count: Option<u64>,
     ) -> Result<Vec<u8>, Error> {
-        let mut size_raw = String::new();
-        self.buf_reader
-            .read_line(&mut size_raw)
-            .await
-            .context("read-bulk-bytes-size")?;
-        self.uncommitted_byte_count += size_raw.len();
-
-        let size_raw = size_raw.trim_end();
-
-        if !size_raw.starts_with("$") {
-            let next = self.read_line_from_tcp_stream(request_count).await?;
-            return Err(format!(
-                "Invalid start of size for bulk bytes. Got: {} (Next: {}) (RC: {:?})",
-                size_raw, next, request_count
-            )
-            .into());
+        loop {
+            if let Some((bytes, len)) = self
+                .parser
+                .parse_bulk_bytes(&mut self.buf)
+                .map_err(ProtocolError)?
+            {
+                self.uncommitted_byte_count += len;
+                debug!(
+                    "Incoming {} bytes from TcpStream (RC: {:?})",
+                    bytes.len(),
+                    request_count
+                );
+                return Ok(bytes);
+            }
+
+            if !self.fill_buf(request_count).await? {
+                return Err("Connection closed before the bulk bytes were complete".into());
+            }
         }
+    }
 
-        let size = usize::from_str_radix(&size_raw[1..], 10).context("convert-size-to-usize")?;
+    /// Reads more bytes from the peer, sending the pending replies first.
+    /// Returns false when the peer closed the connection.
+    async fn fill_buf(&mut self, request_count: Option<u64>) -> Result<bool, Error> {
+        self.flush().await?;
 
-        let mut buf = Vec::with_capacity(size);
-        buf.resize(size, 0);
-        let read_size = self
-            .buf_reader
-            .read_exact(&mut buf[0..size])
+        self.buf.reserve(READ_CHUNK);
+        let read = self
+            .stream
+            .read_buf(&mut self.buf)
             .await
-            .context(format!("reading-bulk-bytes-with-len-{}", size))?;
-        self.uncommitted_byte_count += read_size;
+            .context(format!("reading-from-tcpstream-req{:?}", request_count))?;
 
-        if read_size != size {
-            return Err(
-                format!("Read size mismatch. Read {}. Expected {}.", read_size, size).into(),
-            );
+        if read == 0 && !self.buf.is_empty() {
+            return Err("Connection closed in the middle of a request".into());
         }
-
-        debug!(
-            "Incoming {} bytes from TcpStream (RC: {:?})",
-            buf.len(),
-            request_count
-        );
-
-        Ok(buf)
+        Ok(read > 0)
     }
 }
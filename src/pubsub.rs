@@ -0,0 +1,965 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        RwLock,
+    },
+    time::Instant,
+};
+
+use crate::{
+    common::{parse_memory_size, split_args, PatternMatcher},
+    resp::RespValue,
+};
+
+/// The three kinds of pub/sub subscriptions, each with its own commands.
+#[derive(Clone, Copy)]
+pub(crate) enum SubscriptionKind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+
+impl SubscriptionKind {
+    /// Names of the subscribe and unsubscribe commands, used in their replies.
+    fn command_names(self) -> (&'static str, &'static str) {
+        match self {
+            SubscriptionKind::Channel => ("subscribe", "unsubscribe"),
+            SubscriptionKind::Pattern => ("psubscribe", "punsubscribe"),
+            SubscriptionKind::ShardChannel => ("ssubscribe", "sunsubscribe"),
+        }
+    }
+}
+
+/// `client-output-buffer-limit` of the pubsub class. A subscriber whose
+/// undelivered messages go over `hard` bytes, or stay over `soft` bytes for
+/// `soft_duration`, is disconnected. Zero disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct OutputBufferLimit {
+    pub(crate) hard: usize,
+    pub(crate) soft: usize,
+    pub(crate) soft_duration: Duration,
+}
+
+impl Default for OutputBufferLimit {
+    fn default() -> Self {
+        Self {
+            hard: 32 * 1024 * 1024,
+            soft: 8 * 1024 * 1024,
+            soft_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+impl OutputBufferLimit {
+    /// Parses `<class> <hard> <soft> <soft seconds>` groups as in redis.conf
+    /// and gives the limit of the pubsub class, or `current` when no group
+    /// sets it. The normal and replica classes are accepted, but their clients
+    /// have no output buffer limit here.
+    pub(crate) fn parse_pubsub(raw: &str, current: Self) -> Result<Self, String> {
+        let args = split_args(raw.as_bytes())?;
+        if args.is_empty() || args.len() % 4 != 0 {
+            return Err("Wrong number of arguments in buffer limit configuration.".into());
+        }
+
+        let mut pubsub = current;
+        for group in args.chunks(4) {
+            let invalid =
+                || "Error in hard, soft or soft_seconds setting in buffer limit configuration.";
+            let limit = Self {
+                hard: parse_memory_size(&group[1]).map_err(|_| invalid())?,
+                soft: parse_memory_size(&group[2]).map_err(|_| invalid())?,
+                soft_duration: Duration::from_secs(group[3].parse().map_err(|_| invalid())?),
+            };
+
+            match group[0].to_lowercase().as_str() {
+                "pubsub" => pubsub = limit,
+                "normal" | "replica" | "slave" => {}
+                _ => {
+                    return Err(
+                        "Invalid client class specified in buffer limit configuration.".into(),
+                    )
+                }
+            }
+        }
+
+        Ok(pubsub)
+    }
+}
+
+impl fmt::Display for OutputBufferLimit {
+    /// Formats all classes as CONFIG GET shows them, with the fixed limits of
+    /// the normal and replica classes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "normal 0 0 0 slave 268435456 67108864 60 pubsub {} {} {}",
+            self.hard,
+            self.soft,
+            self.soft_duration.as_secs()
+        )
+    }
+}
+
+/// Classes of keyspace events to publish, as the flags of
+/// `notify-keyspace-events`. Nothing is published unless K or E is set too.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    pub(crate) const KEYSPACE: Self = Self(1 << 0);
+    pub(crate) const KEYEVENT: Self = Self(1 << 1);
+    pub(crate) const GENERIC: Self = Self(1 << 2);
+    pub(crate) const STRING: Self = Self(1 << 3);
+    pub(crate) const LIST: Self = Self(1 << 4);
+    pub(crate) const SET: Self = Self(1 << 5);
+    pub(crate) const HASH: Self = Self(1 << 6);
+    pub(crate) const ZSET: Self = Self(1 << 7);
+    pub(crate) const EXPIRED: Self = Self(1 << 8);
+    pub(crate) const EVICTED: Self = Self(1 << 9);
+    pub(crate) const STREAM: Self = Self(1 << 10);
+    pub(crate) const KEY_MISS: Self = Self(1 << 11);
+    pub(crate) const MODULE: Self = Self(1 << 12);
+    pub(crate) const NEW: Self = Self(1 << 13);
+    /// What `A` stands for. Key misses and new keys are left out, as in Redis.
+    const ALL: Self = Self(
+        Self::GENERIC.0
+            | Self::STRING.0
+            | Self::LIST.0
+            | Self::SET.0
+            | Self::HASH.0
+            | Self::ZSET.0
+            | Self::EXPIRED.0
+            | Self::EVICTED.0
+            | Self::STREAM.0
+            | Self::MODULE.0,
+    );
+    /// Flag characters, in the order Redis prints them.
+    const FLAGS: [(char, Self); 14] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('d', Self::MODULE),
+        ('K', Self::KEYSPACE),
+        ('E', Self::KEYEVENT),
+        ('m', Self::KEY_MISS),
+        ('n', Self::NEW),
+    ];
+
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        let mut events = Self::default();
+        for flag in raw.chars() {
+            let class = match flag {
+                'A' => Self::ALL,
+                _ => Self::FLAGS
+                    .iter()
+                    .find(|(c, _)| *c == flag)
+                    .map(|(_, class)| *class)
+                    .ok_or("Invalid event class character. Use 'Ag$lshzxeKEtmdn'.")?,
+            };
+            events.0 |= class.0;
+        }
+        Ok(events)
+    }
+
+    fn contains(self, class: Self) -> bool {
+        self.0 & class.0 == class.0
+    }
+
+    /// Whether events of the class get published.
+    pub(crate) fn wants(self, class: Self) -> bool {
+        (self.contains(Self::KEYSPACE) || self.contains(Self::KEYEVENT)) && self.0 & class.0 != 0
+    }
+}
+
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let all = self.contains(Self::ALL);
+        if all {
+            write!(f, "A")?;
+        }
+        for (flag, class) in Self::FLAGS {
+            if self.contains(class) && !(all && Self::ALL.contains(class)) {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A change to a key, published to the keyspace and keyevent channels.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeyspaceEvent {
+    pub(crate) class: KeyspaceEvents,
+    pub(crate) name: &'static str,
+    pub(crate) key: String,
+}
+
+/// Bytes published to a subscriber that it didn't take yet, shared by the
+/// publishers and the subscriber.
+#[derive(Default)]
+struct OutputBuffer {
+    queued: AtomicUsize,
+    /// Since when `queued` is over the soft limit.
+    over_soft_limit_since: std::sync::Mutex<Option<Instant>>,
+    /// Set once the subscriber went over its limit, it gets disconnected.
+    closed: AtomicBool,
+}
+
+impl OutputBuffer {
+    /// Accounts `len` more queued bytes, giving false when that breaks the
+    /// limit.
+    fn reserve(&self, len: usize, limit: &OutputBufferLimit) -> bool {
+        let queued = self.queued.fetch_add(len, Ordering::AcqRel) + len;
+        if limit.hard > 0 && queued > limit.hard {
+            return false;
+        }
+
+        let mut over_soft_limit_since = self.over_soft_limit_since.lock().unwrap();
+        if limit.soft > 0 && queued > limit.soft {
+            let since = *over_soft_limit_since.get_or_insert_with(Instant::now);
+            since.elapsed() < limit.soft_duration
+        } else {
+            *over_soft_limit_since = None;
+            true
+        }
+    }
+}
+
+/// The receiving end of a subscriber, held by its connection.
+pub(crate) struct Subscription {
+    receiver: UnboundedReceiver<Bytes>,
+    output: Arc<OutputBuffer>,
+}
+
+impl Subscription {
+    /// Waits for the next serialized message. `None` means the subscriber
+    /// went over its output buffer limit and must be disconnected.
+    pub(crate) async fn recv(&mut self) -> Option<Bytes> {
+        let payload = self.receiver.recv().await;
+        self.take(payload)
+    }
+
+    /// Takes a message that is already queued, if any.
+    pub(crate) fn try_recv(&mut self) -> Option<Bytes> {
+        let payload = self.receiver.try_recv().ok();
+        self.take(payload)
+    }
+
+    fn take(&self, payload: Option<Bytes>) -> Option<Bytes> {
+        if self.output.closed.load(Ordering::Acquire) {
+            return None;
+        }
+        let payload = payload?;
+        self.output
+            .queued
+            .fetch_sub(payload.len(), Ordering::AcqRel);
+        Some(payload)
+    }
+}
+
+/// A client in subscribed mode.
+struct Subscriber {
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    shard_channels: HashSet<String>,
+    sender: UnboundedSender<Bytes>,
+    output: Arc<OutputBuffer>,
+}
+
+impl Subscriber {
+    fn of_kind(&mut self, kind: SubscriptionKind) -> &mut HashSet<String> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    /// The count (un)subscribe replies carry. Shard channels are counted on
+    /// their own, as in Redis.
+    fn count(&self, kind: SubscriptionKind) -> usize {
+        match kind {
+            SubscriptionKind::ShardChannel => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.patterns.is_empty() && self.shard_channels.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    //                      vvv--request-count
+    subscribers: HashMap<u64, Subscriber>,
+    // Fan-out indexes, from each channel or pattern to its subscribers.
+    channels: HashMap<String, HashSet<u64>>,
+    patterns: HashMap<String, HashSet<u64>>,
+    shard_channels: HashMap<String, HashSet<u64>>,
+}
+
+impl Registry {
+    fn index(&mut self, kind: SubscriptionKind) -> &mut HashMap<String, HashSet<u64>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    fn unindex(&mut self, kind: SubscriptionKind, name: &str, request_count: u64) {
+        let index = self.index(kind);
+        if let Some(subscribers) = index.get_mut(name) {
+            subscribers.remove(&request_count);
+            if subscribers.is_empty() {
+                index.remove(name);
+            }
+        }
+    }
+
+    fn remove(&mut self, request_count: u64) {
+        let Some(subscriber) = self.subscribers.remove(&request_count) else {
+            return;
+        };
+
+        for channel in &subscriber.channels {
+            self.unindex(SubscriptionKind::Channel, channel, request_count);
+        }
+        for pattern in &subscriber.patterns {
+            self.unindex(SubscriptionKind::Pattern, pattern, request_count);
+        }
+        for channel in &subscriber.shard_channels {
+            self.unindex(SubscriptionKind::ShardChannel, channel, request_count);
+        }
+    }
+}
+
+/// Subscriptions of all clients, delivering published messages to each
+/// subscriber through its own queue.
+pub(crate) struct PubSub {
+    limit: std::sync::Mutex<OutputBufferLimit>,
+    registry: RwLock<Registry>,
+}
+
+impl PubSub {
+    pub(crate) fn new(limit: OutputBufferLimit) -> Self {
+        Self {
+            limit: std::sync::Mutex::new(limit),
+            registry: RwLock::new(Registry::default()),
+        }
+    }
+
+    /// Changes the output buffer limit, checked from the next delivery on.
+    pub(crate) fn set_limit(&self, limit: OutputBufferLimit) {
+        *self.limit.lock().unwrap() = limit;
+    }
+
+    /// Makes the client a subscriber, with no subscriptions yet.
+    pub(crate) async fn attach(&self, request_count: u64) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let output = Arc::new(OutputBuffer::default());
+
+        let mut registry = self.registry.write().await;
+        registry.remove(request_count);
+        registry.subscribers.insert(
+            request_count,
+            Subscriber {
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+                shard_channels: HashSet::new(),
+                sender,
+                output: output.clone(),
+            },
+        );
+
+        Subscription { receiver, output }
+    }
+
+    /// Drops the client and all its subscriptions.
+    pub(crate) async fn detach(&self, request_count: u64) {
+        self.registry.write().await.remove(request_count);
+    }
+
+    /// Subscribes the client to, or unsubscribes it from, the names of the
+    /// given kind. Unsubscribing without names drops every subscription of
+    /// the kind. Gives the replies and whether no subscriptions are left.
+    pub(crate) async fn update(
+        &self,
+        request_count: u64,
+        kind: SubscriptionKind,
+        names: &[String],
+        subscribing: bool,
+    ) -> (Vec<RespValue>, bool) {
+        let mut registry = self.registry.write().await;
+        let Some(subscriber) = registry.subscribers.get_mut(&request_count) else {
+            return (vec![], true);
+        };
+        let (subscribe_name, unsubscribe_name) = kind.command_names();
+        let reply_name = if subscribing {
+            subscribe_name
+        } else {
+            unsubscribe_name
+        };
+
+        let mut names = names.to_vec();
+        let mut replies = vec![];
+        if names.is_empty() && !subscribing {
+            names = subscriber.of_kind(kind).iter().cloned().collect();
+            names.sort();
+            if names.is_empty() {
+                replies.push(RespValue::Array(vec![
+                    RespValue::BulkString(unsubscribe_name.into()),
+                    RespValue::NullBulkString,
+                    RespValue::Integer(subscriber.count(kind) as i64),
+                ]));
+            }
+        }
+
+        let mut indexed = vec![];
+        let mut unindexed = vec![];
+        for name in names {
+            if subscribing {
+                if subscriber.of_kind(kind).insert(name.clone()) {
+                    indexed.push(name.clone());
+                }
+            } else if subscriber.of_kind(kind).remove(&name) {
+                unindexed.push(name.clone());
+            }
+            replies.push(RespValue::Array(vec![
+                RespValue::BulkString(reply_name.into()),
+                RespValue::BulkString(name),
+                RespValue::Integer(subscriber.count(kind) as i64),
+            ]));
+        }
+        let is_empty = subscriber.is_empty();
+
+        for name in indexed {
+            registry
+                .index(kind)
+                .entry(name)
+                .or_default()
+                .insert(request_count);
+        }
+        for name in unindexed {
+            registry.unindex(kind, &name, request_count);
+        }
+
+        (replies, is_empty)
+    }
+
+    /// Channels with at least one subscriber, matching the pattern if given.
+    pub(crate) async fn channels(&self, pattern: Option<&str>, shard: bool) -> Vec<String> {
+        let registry = self.registry.read().await;
+        let index = if shard {
+            &registry.shard_channels
+        } else {
+            &registry.channels
+        };
+        let matcher = pattern.map(PatternMatcher::new);
+
+        let mut channels = index
+            .keys()
+            .filter(|channel| matcher.as_ref().is_none_or(|m| m.is_match(channel)))
+            .cloned()
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    /// Number of subscribers of each channel, patterns not counted.
+    pub(crate) async fn numsub(&self, channels: &[String], shard: bool) -> Vec<(String, usize)> {
+        let registry = self.registry.read().await;
+        let index = if shard {
+            &registry.shard_channels
+        } else {
+            &registry.channels
+        };
+
+        channels
+            .iter()
+            .map(|channel| {
+                let count = index
+                    .get(channel)
+                    .map_or(0, |subscribers| subscribers.len());
+                (channel.clone(), count)
+            })
+            .collect()
+    }
+
+    /// Number of distinct patterns subscribed to.
+    pub(crate) async fn numpat(&self) -> usize {
+        self.registry.read().await.patterns.len()
+    }
+
+    /// Channel, pattern and shard channel subscription counts of a client,
+    /// with the bytes queued for it, while it is in subscribed mode.
+    pub(crate) async fn subscriber_info(
+        &self,
+        request_count: u64,
+    ) -> Option<(usize, usize, usize, usize)> {
+        let registry = self.registry.read().await;
+        let subscriber = registry.subscribers.get(&request_count)?;
+        Some((
+            subscriber.channels.len(),
+            subscriber.patterns.len(),
+            subscriber.shard_channels.len(),
+            subscriber.output.queued.load(Ordering::Acquire),
+        ))
+    }
+
+    /// Publishes the event to `__keyspace@<db>__:<key>` and to
+    /// `__keyevent@<db>__:<event>`, as the K and E flags ask.
+    pub(crate) async fn notify_keyspace_event(
+        &self,
+        events: KeyspaceEvents,
+        db_index: usize,
+        event: &KeyspaceEvent,
+    ) {
+        if events.contains(KeyspaceEvents::KEYSPACE) {
+            let channel = format!("__keyspace@{}__:{}", db_index, event.key);
+            self.publish(&channel, event.name, false).await;
+        }
+        if events.contains(KeyspaceEvents::KEYEVENT) {
+            let channel = format!("__keyevent@{}__:{}", db_index, event.name);
+            self.publish(&channel, &event.key, false).await;
+        }
+    }
+
+    /// Queues a message on the channel for one subscriber only, as tracking
+    /// redirects do. Returns whether the client is subscribed to anything.
+    pub(crate) async fn send_to(
+        &self,
+        request_count: u64,
+        channel: &str,
+        message: RespValue,
+    ) -> bool {
+        let payload = Bytes::from(
+            RespValue::Array(vec![
+                RespValue::BulkString("message".into()),
+                RespValue::BulkString(channel.into()),
+                message,
+            ])
+            .serialize(),
+        );
+
+        let limit = *self.limit.lock().unwrap();
+        {
+            let registry = self.registry.read().await;
+            let Some(subscriber) = registry.subscribers.get(&request_count) else {
+                return false;
+            };
+            if subscriber.output.reserve(payload.len(), &limit) {
+                return subscriber.sender.send(payload).is_ok();
+            }
+            subscriber.output.closed.store(true, Ordering::Release);
+        }
+
+        warn!(
+            "Client {} closed for overcoming of output buffer limits",
+            request_count
+        );
+        self.registry.write().await.remove(request_count);
+        false
+    }
+
+    /// Queues the message for the subscribers of the channel and of the
+    /// patterns matching it, or for the subscribers of the shard channel.
+    /// Subscribers going over their output buffer limit are dropped. Gives
+    /// the number of deliveries.
+    pub(crate) async fn publish(&self, channel: &str, message: &str, shard: bool) -> usize {
+        let mut count = 0;
+        let mut laggards = vec![];
+        let limit = *self.limit.lock().unwrap();
+
+        {
+            let registry = self.registry.read().await;
+            let mut deliver = |request_count: &u64, payload: &Bytes| {
+                let Some(subscriber) = registry.subscribers.get(request_count) else {
+                    return;
+                };
+                if !subscriber.output.reserve(payload.len(), &limit) {
+                    subscriber.output.closed.store(true, Ordering::Release);
+                    laggards.push(*request_count);
+                } else if subscriber.sender.send(payload.clone()).is_ok() {
+                    count += 1;
+                }
+            };
+
+            let (index, kind) = if shard {
+                (&registry.shard_channels, "smessage")
+            } else {
+                (&registry.channels, "message")
+            };
+            if let Some(subscribers) = index.get(channel) {
+                let payload = Bytes::from(
+                    RespValue::Array(vec![
+                        RespValue::BulkString(kind.into()),
+                        RespValue::BulkString(channel.into()),
+                        RespValue::BulkString(message.into()),
+                    ])
+                    .serialize(),
+                );
+                for request_count in subscribers {
+                    deliver(request_count, &payload);
+                }
+            }
+
+            if !shard {
+                for (pattern, subscribers) in &registry.patterns {
+                    if !PatternMatcher::new(pattern).is_match(channel) {
+                        continue;
+                    }
+                    let payload = Bytes::from(
+                        RespValue::Array(vec![
+                            RespValue::BulkString("pmessage".into()),
+                            RespValue::BulkString(pattern.clone()),
+                            RespValue::BulkString(channel.into()),
+                            RespValue::BulkString(message.into()),
+                        ])
+                        .serialize(),
+                    );
+                    for request_count in subscribers {
+                        deliver(request_count, &payload);
+                    }
+                }
+            }
+        }
+
+        if !laggards.is_empty() {
+            let mut registry = self.registry.write().await;
+            for request_count in laggards {
+                warn!(
+                    "Client {} closed for overcoming of output buffer limits",
+                    request_count
+                );
+                registry.remove(request_count);
+            }
+        }
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    fn message(kind: &str, parts: &[&str]) -> Bytes {
+        let mut items = vec![RespValue::BulkString(kind.into())];
+        items.extend(
+            parts
+                .iter()
+                .map(|part| RespValue::BulkString(part.to_string())),
+        );
+        Bytes::from(RespValue::Array(items).serialize())
+    }
+
+    #[test]
+    fn test_parse_output_buffer_limit() {
+        assert_eq!(
+            OutputBufferLimit {
+                hard: 1024,
+                soft: 1000,
+                soft_duration: Duration::from_secs(5),
+            },
+            OutputBufferLimit::parse_pubsub("normal 0 0 0 pubsub 1kb 1k 5", Default::default())
+                .unwrap()
+        );
+        let current = OutputBufferLimit {
+            hard: 0,
+            soft: 0,
+            soft_duration: Duration::ZERO,
+        };
+        assert_eq!(
+            current,
+            OutputBufferLimit::parse_pubsub("replica 256mb 64mb 60", current).unwrap()
+        );
+        assert_eq!(
+            OutputBufferLimit::default(),
+            OutputBufferLimit::parse_pubsub(&OutputBufferLimit::default().to_string(), current)
+                .unwrap()
+        );
+        let parse = |raw| OutputBufferLimit::parse_pubsub(raw, Default::default());
+        assert!(parse("pubsub 1mb 1mb").is_err());
+        assert!(parse("other 1mb 1mb 1").is_err());
+        assert!(parse("pubsub 1mb x 1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_to_channels_patterns_and_shards() {
+        let pubsub = PubSub::new(OutputBufferLimit::default());
+        let mut first = pubsub.attach(1).await;
+        let mut second = pubsub.attach(2).await;
+        let channels = vec!["news".to_string()];
+        pubsub
+            .update(1, SubscriptionKind::Channel, &channels, true)
+            .await;
+        pubsub
+            .update(1, SubscriptionKind::Pattern, &["n*".to_string()], true)
+            .await;
+        pubsub
+            .update(2, SubscriptionKind::ShardChannel, &channels, true)
+            .await;
+
+        assert_eq!(2, pubsub.publish("news", "a", false).await);
+        assert_eq!(1, pubsub.publish("news", "b", true).await);
+        assert_eq!(0, pubsub.publish("sports", "c", false).await);
+
+        assert_eq!(Some(message("message", &["news", "a"])), first.try_recv());
+        assert_eq!(
+            Some(message("pmessage", &["n*", "news", "a"])),
+            first.try_recv()
+        );
+        assert_eq!(None, first.try_recv());
+        assert_eq!(Some(message("smessage", &["news", "b"])), second.try_recv());
+
+        let (_, is_empty) = pubsub
+            .update(1, SubscriptionKind::Channel, &[], false)
+            .await;
+        assert!(!is_empty);
+        let (replies, is_empty) = pubsub
+            .update(1, SubscriptionKind::Pattern, &[], false)
+            .await;
+        assert!(is_empty);
+        assert_eq!(1, replies.len());
+        assert_eq!(0, pubsub.publish("news", "d", false).await);
+
+        pubsub.detach(2).await;
+        assert_eq!(0, pubsub.publish("news", "e", true).await);
+        let registry = pubsub.registry.read().await;
+        assert!(registry.channels.is_empty() && registry.patterns.is_empty());
+        assert!(registry.shard_channels.is_empty());
+    }
+
+    #[test]
+    fn test_parse_keyspace_events() {
+        let parse = |raw: &str| KeyspaceEvents::parse(raw).unwrap();
+
+        assert_eq!("", parse("").to_string());
+        assert_eq!("AKE", parse("KEA").to_string());
+        assert_eq!("AKEmn", parse("nmg$AKE").to_string());
+        assert_eq!("g$xK", parse("Kx$g").to_string());
+        assert!(KeyspaceEvents::parse("Kq").is_err());
+
+        assert!(parse("Kx").wants(KeyspaceEvents::EXPIRED));
+        assert!(!parse("Kx").wants(KeyspaceEvents::GENERIC));
+        assert!(!parse("A").wants(KeyspaceEvents::EXPIRED));
+        assert!(!parse("EA").wants(KeyspaceEvents::KEY_MISS));
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_event_channels() {
+        let pubsub = PubSub::new(OutputBufferLimit::default());
+        let mut subscription = pubsub.attach(1).await;
+        pubsub
+            .update(
+                1,
+                SubscriptionKind::Pattern,
+                &["__key*__:*".to_string()],
+                true,
+            )
+            .await;
+
+        let event = KeyspaceEvent {
+            class: KeyspaceEvents::STRING,
+            name: "set",
+            key: "k".into(),
+        };
+        pubsub
+            .notify_keyspace_event(KeyspaceEvents::parse("KE$").unwrap(), 3, &event)
+            .await;
+        pubsub
+            .notify_keyspace_event(KeyspaceEvents::parse("E$").unwrap(), 0, &event)
+            .await;
+
+        assert_eq!(
+            Some(message(
+                "pmessage",
+                &["__key*__:*", "__keyspace@3__:k", "set"]
+            )),
+            subscription.try_recv()
+        );
+        assert_eq!(
+            Some(message(
+                "pmessage",
+                &["__key*__:*", "__keyevent@3__:set", "k"]
+            )),
+            subscription.try_recv()
+        );
+        assert_eq!(
+            Some(message(
+                "pmessage",
+                &["__key*__:*", "__keyevent@0__:set", "k"]
+            )),
+            subscription.try_recv()
+        );
+        assert_eq!(None, subscription.try_recv());
+    }
+
+    #[tokio::test]
+    async fn test_introspection() {
+        let pubsub = PubSub::new(OutputBufferLimit::default());
+        let _first = pubsub.attach(1).await;
+        let _second = pubsub.attach(2).await;
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        pubsub
+            .update(1, SubscriptionKind::Channel, &names(&["a.1", "b"]), true)
+            .await;
+        pubsub
+            .update(2, SubscriptionKind::Channel, &names(&["a.1"]), true)
+            .await;
+        pubsub
+            .update(2, SubscriptionKind::Pattern, &names(&["a.*", "b*"]), true)
+            .await;
+        pubsub
+            .update(1, SubscriptionKind::Pattern, &names(&["a.*"]), true)
+            .await;
+        pubsub
+            .update(1, SubscriptionKind::ShardChannel, &names(&["s"]), true)
+            .await;
+
+        assert_eq!(names(&["a.1", "b"]), pubsub.channels(None, false).await);
+        assert_eq!(names(&["a.1"]), pubsub.channels(Some("a.*"), false).await);
+        assert_eq!(names(&["s"]), pubsub.channels(None, true).await);
+        assert_eq!(
+            vec![("a.1".to_string(), 2), ("b".into(), 1), ("s".into(), 0)],
+            pubsub.numsub(&names(&["a.1", "b", "s"]), false).await
+        );
+        assert_eq!(
+            vec![("s".to_string(), 1)],
+            pubsub.numsub(&names(&["s"]), true).await
+        );
+        assert_eq!(2, pubsub.numpat().await);
+
+        // Publishing to a channel nobody listens to leaves no trace.
+        assert_eq!(0, pubsub.publish("nobody", "x", false).await);
+        assert_eq!(0, pubsub.publish("nobody", "x", true).await);
+        assert!(pubsub.channels(Some("nobody"), false).await.is_empty());
+
+        pubsub.detach(2).await;
+        assert_eq!(1, pubsub.numpat().await);
+        assert_eq!(
+            vec![("a.1".to_string(), 1)],
+            pubsub.numsub(&names(&["a.1"]), false).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_laggard_is_disconnected() {
+        let pubsub = PubSub::new(OutputBufferLimit {
+            hard: 1000,
+            soft: 0,
+            soft_duration: Duration::ZERO,
+        });
+        let mut lagging = pubsub.attach(1).await;
+        let mut reading = pubsub.attach(2).await;
+        for request_count in [1, 2] {
+            pubsub
+                .update(
+                    request_count,
+                    SubscriptionKind::Channel,
+                    &["c".into()],
+                    true,
+                )
+                .await;
+        }
+
+        let message = "x".repeat(100);
+        for _ in 0..20 {
+            pubsub.publish("c", &message, false).await;
+            assert!(reading.try_recv().is_some());
+        }
+
+        assert_eq!(None, lagging.recv().await);
+        assert!(!pubsub.registry.read().await.subscribers.contains_key(&1));
+        assert_eq!(1, pubsub.publish("c", &message, false).await);
+    }
+
+    #[tokio::test]
+    async fn test_soft_limit_tolerates_short_bursts() {
+        let pubsub = PubSub::new(OutputBufferLimit {
+            hard: 0,
+            soft: 100,
+            soft_duration: Duration::from_secs(60),
+        });
+        let mut subscription = pubsub.attach(1).await;
+        pubsub
+            .update(1, SubscriptionKind::Channel, &["c".into()], true)
+            .await;
+
+        for _ in 0..10 {
+            assert_eq!(1, pubsub.publish("c", &"x".repeat(50), false).await);
+        }
+        for _ in 0..10 {
+            assert!(subscription.try_recv().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_thousands_of_subscribers() {
+        const SUBSCRIBERS: u64 = 5_000;
+        const MESSAGES: usize = 50;
+
+        let pubsub = Arc::new(PubSub::new(OutputBufferLimit::default()));
+        let mut readers = JoinSet::new();
+        for request_count in 0..SUBSCRIBERS {
+            let mut subscription = pubsub.attach(request_count).await;
+            let (kind, name) = if request_count % 2 == 0 {
+                (SubscriptionKind::Channel, "load")
+            } else {
+                (SubscriptionKind::Pattern, "lo*")
+            };
+            pubsub
+                .update(request_count, kind, &[name.to_string()], true)
+                .await;
+
+            readers.spawn(async move {
+                let mut received = 0;
+                while received < MESSAGES {
+                    subscription.recv().await.expect("Subscriber was dropped");
+                    received += 1;
+                }
+                received
+            });
+        }
+
+        let publisher = tokio::spawn({
+            let pubsub = pubsub.clone();
+            async move {
+                for i in 0..MESSAGES {
+                    assert_eq!(
+                        SUBSCRIBERS as usize,
+                        pubsub.publish("load", &i.to_string(), false).await
+                    );
+                }
+            }
+        });
+
+        publisher.await.unwrap();
+        let mut total = 0;
+        while let Some(received) = readers.join_next().await {
+            total += received.unwrap();
+        }
+        assert_eq!(SUBSCRIBERS as usize * MESSAGES, total);
+    }
+}
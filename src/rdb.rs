@@ -1,11 +1,20 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufReader, Read},
+    io::{BufReader, Cursor, Read},
     path::{Path, PathBuf},
 };
 
-use crate::common::Error;
+use crate::common::{CompleteStreamEntryID, Error, KeyValuePair};
+
+const RDB_VERSION: u16 = 11;
+const OPCODE_FUNCTION: u8 = 0xF5;
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
 
 #[derive(Debug)]
 enum Length {
@@ -14,7 +23,7 @@ enum Length {
 }
 
 struct RecordingReader {
-    reader: BufReader<File>,
+    reader: Box<dyn Read>,
     memory: Vec<u8>,
     peeked: Vec<u8>,
 }
@@ -22,12 +31,15 @@ struct RecordingReader {
 impl RecordingReader {
     fn new<P: AsRef<Path>>(filepath: P) -> Result<Self, Error> {
         let file = File::open(filepath)?;
-        let reader = BufReader::new(file);
-        Ok(Self {
+        Ok(Self::from_reader(Box::new(BufReader::new(file))))
+    }
+
+    fn from_reader(reader: Box<dyn Read>) -> Self {
+        Self {
             reader,
             memory: vec![],
             peeked: vec![],
-        })
+        }
     }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), std::io::Error> {
@@ -84,9 +96,15 @@ impl RecordingReader {
 
 pub(crate) type AuxKeyValuePair = (String, String);
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub(crate) enum RdbValue {
     Str(String),
+    List(Vec<String>),
+    SortedSet(Vec<(String /* Member */, f64 /* Score */)>),
+    Stream(
+        Vec<(CompleteStreamEntryID, Vec<KeyValuePair>)>,
+        CompleteStreamEntryID, /* Last ID */
+    ),
 }
 
 #[derive(Debug, Default)]
@@ -97,6 +115,7 @@ pub(crate) struct RdbContent {
     pub(crate) hash_table_size: Option<usize>,
     pub(crate) expiry_hash_table_size: Option<usize>,
     pub(crate) data: HashMap<usize, HashMap<String, (Option<u128> /* Expiry */, RdbValue)>>,
+    pub(crate) functions: Vec<String /* Library code */>,
 }
 
 impl RdbContent {
@@ -116,7 +135,17 @@ impl RdbFile {
     }
 
     pub(crate) fn read(&self) -> Result<RdbContent, Error> {
-        let mut reader = RecordingReader::new(&self.filepath)?;
+        Self::read_from(RecordingReader::new(&self.filepath)?)
+    }
+
+    /// Reads a snapshot received in memory, as a replica does on full resync.
+    pub(crate) fn decode(bytes: &[u8]) -> Result<RdbContent, Error> {
+        Self::read_from(RecordingReader::from_reader(Box::new(Cursor::new(
+            bytes.to_vec(),
+        ))))
+    }
+
+    fn read_from(mut reader: RecordingReader) -> Result<RdbContent, Error> {
         let mut content = RdbContent::default();
 
         let mut general_buffer: [u8; 64] = [0; 64];
@@ -150,6 +179,12 @@ impl RdbFile {
                     reader.consume(1)?; // Header.
                     Self::read_aux_section(&mut reader, &mut content)?;
                 }
+                OPCODE_FUNCTION => {
+                    reader.consume(1)?; // Header.
+                    content
+                        .functions
+                        .push(Self::read_variable_len_str(&mut reader)?);
+                }
                 header => {
                     let expiry_ms = match header {
                         0xFD => {
@@ -174,6 +209,93 @@ impl RdbFile {
         Ok(content)
     }
 
+    /// Writes the snapshot to a temporary file first and then moves it in place,
+    /// so a failing save never leaves a truncated file behind.
+    pub(crate) fn write(&self, content: &RdbContent) -> Result<(), Error> {
+        let tmp_filepath = self.filepath.with_extension("tmp");
+        std::fs::write(&tmp_filepath, Self::encode(content))?;
+        std::fs::rename(&tmp_filepath, &self.filepath)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn encode(content: &RdbContent) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(b"REDIS");
+        out.extend_from_slice(format!("{:04}", RDB_VERSION).as_bytes());
+
+        for (key, value) in &content.aux_fields {
+            out.push(0xFA);
+            write_string(&mut out, key);
+            write_string(&mut out, value);
+        }
+
+        for code in &content.functions {
+            out.push(OPCODE_FUNCTION);
+            write_string(&mut out, code);
+        }
+
+        let mut db_indexes = content.data.keys().collect::<Vec<_>>();
+        db_indexes.sort();
+
+        for db_index in db_indexes {
+            let data = &content.data[db_index];
+
+            out.push(0xFE);
+            write_length(&mut out, *db_index);
+
+            out.push(0xFB);
+            write_length(&mut out, data.len());
+            write_length(
+                &mut out,
+                data.values().filter(|(expiry, _)| expiry.is_some()).count(),
+            );
+
+            for (key, (expiry_ms, value)) in data {
+                if let Some(expiry_ms) = expiry_ms {
+                    out.push(0xFC);
+                    out.extend_from_slice(&(*expiry_ms as u64).to_le_bytes());
+                }
+
+                match value {
+                    RdbValue::Str(str) => {
+                        out.push(TYPE_STRING);
+                        write_string(&mut out, key);
+                        write_string(&mut out, str);
+                    }
+                    RdbValue::List(items) => {
+                        out.push(TYPE_LIST);
+                        write_string(&mut out, key);
+                        write_length(&mut out, items.len());
+                        for item in items {
+                            write_string(&mut out, item);
+                        }
+                    }
+                    RdbValue::SortedSet(members) => {
+                        out.push(TYPE_ZSET_2);
+                        write_string(&mut out, key);
+                        write_length(&mut out, members.len());
+                        for (member, score) in members {
+                            write_string(&mut out, member);
+                            out.extend_from_slice(&score.to_le_bytes());
+                        }
+                    }
+                    RdbValue::Stream(entries, last_id) => {
+                        out.push(TYPE_STREAM_LISTPACKS);
+                        write_string(&mut out, key);
+                        write_stream(&mut out, entries, last_id);
+                    }
+                }
+            }
+        }
+
+        out.push(0xFF);
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+
+        out
+    }
+
     fn read_db_section(
         reader: &mut RecordingReader,
         content: &mut RdbContent,
@@ -202,8 +324,27 @@ impl RdbFile {
         let key = Self::read_variable_len_str(reader)?;
 
         let value = match value_type {
-            0 => RdbValue::Str(Self::read_variable_len_str(reader)?),
-            1 => unimplemented!("List Encoding"),
+            TYPE_STRING => RdbValue::Str(Self::read_variable_len_str(reader)?),
+            TYPE_LIST => {
+                let len = Self::read_number(reader)?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(Self::read_variable_len_str(reader)?);
+                }
+                RdbValue::List(items)
+            }
+            TYPE_ZSET_2 => {
+                let len = Self::read_number(reader)?;
+                let mut members = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let member = Self::read_variable_len_str(reader)?;
+                    let mut score = [0u8; 8];
+                    reader.read_exact(&mut score)?;
+                    members.push((member, f64::from_le_bytes(score)));
+                }
+                RdbValue::SortedSet(members)
+            }
+            TYPE_STREAM_LISTPACKS => Self::read_stream(reader)?,
             2 => unimplemented!("Set Encoding"),
             3 => unimplemented!("Sorted Set Encoding"),
             4 => unimplemented!("Hash Encoding"),
@@ -220,6 +361,62 @@ impl RdbFile {
         Ok(())
     }
 
+    /// Reads a stream without consumer groups. Its entries are kept in listpack
+    /// nodes, each keyed by the master ID the IDs of its entries are relative to.
+    fn read_stream(reader: &mut RecordingReader) -> Result<RdbValue, Error> {
+        let mut entries = vec![];
+        for _ in 0..Self::read_number(reader)? {
+            let master_id = Self::read_variable_len_bytes(reader)?;
+            if master_id.len() != 16 {
+                return Err("Invalid stream node key".into());
+            }
+            let master_ms = u64::from_be_bytes(master_id[0..8].try_into()?);
+            let master_seq = u64::from_be_bytes(master_id[8..16].try_into()?);
+
+            let mut items = decode_listpack(&Self::read_variable_len_bytes(reader)?)?.into_iter();
+            let count = next_int(&mut items)?;
+            let deleted = next_int(&mut items)?;
+            let master_fields = (0..next_int(&mut items)?)
+                .map(|_| next_str(&mut items))
+                .collect::<Result<Vec<_>, Error>>()?;
+            next_int(&mut items)?; // Master entry terminator.
+
+            for _ in 0..count + deleted {
+                let flags = next_int(&mut items)?;
+                let id = CompleteStreamEntryID(
+                    (master_ms + next_int(&mut items)? as u64) as u128,
+                    (master_seq + next_int(&mut items)? as u64) as usize,
+                );
+                let kvpairs = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                    master_fields
+                        .iter()
+                        .map(|field| Ok((field.clone(), next_str(&mut items)?)))
+                        .collect::<Result<Vec<_>, Error>>()?
+                } else {
+                    (0..next_int(&mut items)?)
+                        .map(|_| Ok((next_str(&mut items)?, next_str(&mut items)?)))
+                        .collect::<Result<Vec<_>, Error>>()?
+                };
+                next_int(&mut items)?; // Item count of the entry.
+
+                if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                    entries.push((id, kvpairs));
+                }
+            }
+        }
+
+        Self::read_number(reader)?; // Length.
+        let last_id = CompleteStreamEntryID(
+            Self::read_number(reader)? as u128,
+            Self::read_number(reader)?,
+        );
+        if Self::read_number(reader)? != 0 {
+            return Err("Stream consumer groups are not supported".into());
+        }
+
+        Ok(RdbValue::Stream(entries, last_id))
+    }
+
     fn read_resize_db(reader: &mut RecordingReader, content: &mut RdbContent) -> Result<(), Error> {
         match Self::read_length(reader)? {
             Length::Number(v) => content.hash_table_size = Some(v as usize),
@@ -235,7 +432,7 @@ impl RdbFile {
     }
 
     fn is_header(byte: u8) -> bool {
-        byte >= 0xfa
+        byte >= 0xfa || byte == OPCODE_FUNCTION
     }
 
     fn read_aux_section(
@@ -267,10 +464,14 @@ impl RdbFile {
                 let rhs = buf[0] as usize;
                 Ok(Length::Number(lhs + rhs))
             }
+            0b10 if buf[0] == 0x81 => {
+                reader.read_exact(&mut buf[0..8])?;
+                Ok(Length::Number(u64::from_be_bytes(buf) as usize))
+            }
             0b10 => {
                 reader.read_exact(&mut buf[0..4])?;
                 Ok(Length::Number(
-                    u32::from_le_bytes(buf[0..3].try_into()?) as usize
+                    u32::from_be_bytes(buf[0..4].try_into()?) as usize
                 ))
             }
             0b11 => match buf[0] & 0b0011_1111 {
@@ -284,38 +485,47 @@ impl RdbFile {
         }
     }
 
+    fn read_number(reader: &mut RecordingReader) -> Result<usize, Error> {
+        match Self::read_length(reader)? {
+            Length::Number(n) => Ok(n),
+            other => Err(format!("Expected a length, got {:?}", other).into()),
+        }
+    }
+
     fn read_variable_len_str(reader: &mut RecordingReader) -> Result<String, Error> {
+        Ok(String::from_utf8(Self::read_variable_len_bytes(reader)?)?)
+    }
+
+    fn read_variable_len_bytes(reader: &mut RecordingReader) -> Result<Vec<u8>, Error> {
         match Self::read_length(reader)? {
-            Length::Number(len) => Self::read_string_of_len(reader, len),
+            Length::Number(len) => Self::read_bytes_of_len(reader, len),
             Length::String(bitsize) => {
                 let mut buf = [0u8; 4];
-                match bitsize {
+                let number = match bitsize {
                     1 => {
                         reader.read_exact(&mut buf[0..1])?;
-                        let number = i8::from_le_bytes(buf[0..1].try_into()?);
-                        Ok(number.to_string())
+                        i8::from_le_bytes(buf[0..1].try_into()?) as i32
                     }
                     2 => {
                         reader.read_exact(&mut buf[0..2])?;
-                        let number = i16::from_le_bytes(buf[0..2].try_into()?);
-                        Ok(number.to_string())
+                        i16::from_le_bytes(buf[0..2].try_into()?) as i32
                     }
                     4 => {
                         reader.read_exact(&mut buf[0..4])?;
-                        let number = i32::from_le_bytes(buf[0..4].try_into()?);
-                        Ok(number.to_string())
+                        i32::from_le_bytes(buf[0..4].try_into()?)
                     }
                     other => panic!("Unexpected string number bit length: {}", other),
-                }
+                };
+                Ok(number.to_string().into_bytes())
             }
         }
     }
 
-    fn read_string_of_len(reader: &mut RecordingReader, len: usize) -> Result<String, Error> {
+    fn read_bytes_of_len(reader: &mut RecordingReader, len: usize) -> Result<Vec<u8>, Error> {
         let mut buf = Vec::with_capacity(len);
         buf.resize(len, 0u8);
         reader.read_exact(&mut buf[0..len])?;
-        Ok(String::from_utf8(buf)?)
+        Ok(buf)
     }
 
     fn read_eof(reader: &mut RecordingReader) -> Result<(), Error> {
@@ -323,10 +533,7 @@ impl RdbFile {
         reader.read_exact_no_memory(&mut buf)?;
         let expected_checksum = u64::from_le_bytes(buf);
 
-        let crc = crc::Crc::<u64>::new(&crc::CRC_64_REDIS);
-        let mut crc_digest = crc.digest();
-        crc_digest.update(&reader.memory);
-        let actual_checksum = crc_digest.finalize();
+        let actual_checksum = crc64(&reader.memory);
 
         if expected_checksum == actual_checksum {
             Ok(())
@@ -336,16 +543,271 @@ impl RdbFile {
     }
 }
 
+/// Serializes libraries the way FUNCTION DUMP does in Redis: function records,
+/// then the RDB version and a CRC64 of everything before it.
+pub(crate) fn dump_functions(codes: &[String]) -> Vec<u8> {
+    let mut out = vec![];
+    for code in codes {
+        out.push(OPCODE_FUNCTION);
+        write_string(&mut out, code);
+    }
+
+    out.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let checksum = crc64(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+
+    out
+}
+
+pub(crate) fn restore_functions(payload: &[u8]) -> Result<Vec<String>, Error> {
+    if payload.len() < 10 {
+        return Err("Payload is too short".into());
+    }
+
+    let (versioned, checksum) = payload.split_at(payload.len() - 8);
+    if crc64(versioned) != u64::from_le_bytes(checksum.try_into()?) {
+        return Err("Payload checksum mismatch".into());
+    }
+
+    let (body, version) = versioned.split_at(versioned.len() - 2);
+    if u16::from_le_bytes(version.try_into()?) > RDB_VERSION {
+        return Err("Payload version is not supported".into());
+    }
+
+    let mut reader = RecordingReader::from_reader(Box::new(Cursor::new(body.to_vec())));
+    let mut codes = vec![];
+    while reader.memory.len() < body.len() {
+        let mut opcode = [0u8; 1];
+        reader.read_exact(&mut opcode)?;
+        if opcode[0] != OPCODE_FUNCTION {
+            return Err(format!("Unexpected opcode in payload: {}", opcode[0]).into());
+        }
+
+        codes.push(RdbFile::read_variable_len_str(&mut reader)?);
+    }
+
+    Ok(codes)
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0b0100_0000 | (len >> 8) as u8);
+        out.push((len & 0xFF) as u8);
+    } else if len <= u32::MAX as usize {
+        out.push(0b1000_0000);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, str: &str) {
+    write_bytes(out, str.as_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_length(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+/// Writes each entry in a listpack node of its own, with the entry ID as the
+/// master ID and the entry fields as the master fields.
+fn write_stream(
+    out: &mut Vec<u8>,
+    entries: &[(CompleteStreamEntryID, Vec<KeyValuePair>)],
+    last_id: &CompleteStreamEntryID,
+) {
+    write_length(out, entries.len());
+    for (id, kvpairs) in entries {
+        let mut master_id = (id.0 as u64).to_be_bytes().to_vec();
+        master_id.extend_from_slice(&(id.1 as u64).to_be_bytes());
+        write_bytes(out, &master_id);
+
+        let fields = kvpairs.len() as i64;
+        let mut items = vec![
+            ListpackItem::Int(1), // Count.
+            ListpackItem::Int(0), // Deleted.
+            ListpackItem::Int(fields),
+        ];
+        items.extend(kvpairs.iter().map(|(field, _)| ListpackItem::Str(field)));
+        items.extend([
+            ListpackItem::Int(0), // Master entry terminator.
+            ListpackItem::Int(STREAM_ITEM_FLAG_SAMEFIELDS),
+            ListpackItem::Int(0), // Milliseconds from the master ID.
+            ListpackItem::Int(0), // Sequence from the master ID.
+        ]);
+        items.extend(kvpairs.iter().map(|(_, value)| ListpackItem::Str(value)));
+        items.push(ListpackItem::Int(fields + 3));
+        write_bytes(out, &encode_listpack(&items));
+    }
+
+    write_length(out, entries.len());
+    write_length(out, last_id.0 as usize);
+    write_length(out, last_id.1);
+    write_length(out, 0); // Consumer groups.
+}
+
+enum ListpackItem<'a> {
+    Int(i64),
+    Str(&'a str),
+}
+
+fn encode_listpack(items: &[ListpackItem]) -> Vec<u8> {
+    let mut body = vec![];
+    for item in items {
+        let start = body.len();
+        match item {
+            ListpackItem::Int(number @ 0..=127) => body.push(*number as u8),
+            ListpackItem::Int(number) => {
+                body.push(0xF4);
+                body.extend_from_slice(&number.to_le_bytes());
+            }
+            ListpackItem::Str(str) => {
+                let len = str.len();
+                if len < 1 << 6 {
+                    body.push(0b1000_0000 | len as u8);
+                } else if len < 1 << 12 {
+                    body.push(0b1110_0000 | (len >> 8) as u8);
+                    body.push((len & 0xFF) as u8);
+                } else {
+                    body.push(0xF0);
+                    body.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                body.extend_from_slice(str.as_bytes());
+            }
+        }
+
+        // The entry length follows it in 7 bit groups, most significant
+        // first, so the listpack can be walked backwards.
+        let len = body.len() - start;
+        let backlen_size = listpack_backlen_size(len);
+        for i in (0..backlen_size).rev() {
+            let group = ((len >> (7 * i)) & 0x7F) as u8;
+            body.push(if i == backlen_size - 1 {
+                group
+            } else {
+                group | 0x80
+            });
+        }
+    }
+
+    let mut out = ((body.len() + 7) as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&(items.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    out.append(&mut body);
+    out.push(0xFF);
+
+    out
+}
+
+/// The items of a listpack, with the integers in their decimal form.
+fn decode_listpack(bytes: &[u8]) -> Result<Vec<String>, Error> {
+    let slice = |start: usize, len: usize| {
+        bytes
+            .get(start..start + len)
+            .ok_or_else(|| Error::from("Truncated listpack"))
+    };
+
+    let mut items = vec![];
+    let mut pos = 6; // Total bytes and item count.
+    loop {
+        let encoding = slice(pos, 1)?[0];
+        let (item, len) = match encoding {
+            0xFF => return Ok(items),
+            0x00..=0x7F => (encoding.to_string(), 1),
+            0x80..=0xBF => {
+                let str_len = (encoding & 0x3F) as usize;
+                (
+                    String::from_utf8(slice(pos + 1, str_len)?.to_vec())?,
+                    1 + str_len,
+                )
+            }
+            0xC0..=0xDF => {
+                let number = ((encoding as i64 & 0x1F) << 8) | slice(pos + 1, 1)?[0] as i64;
+                let number = if number >= 1 << 12 {
+                    number - (1 << 13)
+                } else {
+                    number
+                };
+                (number.to_string(), 2)
+            }
+            0xE0..=0xEF => {
+                let str_len = ((encoding & 0x0F) as usize) << 8 | slice(pos + 1, 1)?[0] as usize;
+                (
+                    String::from_utf8(slice(pos + 2, str_len)?.to_vec())?,
+                    2 + str_len,
+                )
+            }
+            0xF0 => {
+                let str_len = u32::from_le_bytes(slice(pos + 1, 4)?.try_into()?) as usize;
+                (
+                    String::from_utf8(slice(pos + 5, str_len)?.to_vec())?,
+                    5 + str_len,
+                )
+            }
+            0xF1 => {
+                let number = i16::from_le_bytes(slice(pos + 1, 2)?.try_into()?);
+                (number.to_string(), 3)
+            }
+            0xF2 => {
+                let raw = slice(pos + 1, 3)?;
+                let number = i32::from_le_bytes([0, raw[0], raw[1], raw[2]]) >> 8;
+                (number.to_string(), 4)
+            }
+            0xF3 => {
+                let number = i32::from_le_bytes(slice(pos + 1, 4)?.try_into()?);
+                (number.to_string(), 5)
+            }
+            0xF4 => {
+                let number = i64::from_le_bytes(slice(pos + 1, 8)?.try_into()?);
+                (number.to_string(), 9)
+            }
+            other => return Err(format!("Unexpected listpack encoding: {:#x}", other).into()),
+        };
+
+        items.push(item);
+        pos += len + listpack_backlen_size(len);
+    }
+}
+
+fn listpack_backlen_size(len: usize) -> usize {
+    let mut size = 1;
+    while len >> (7 * size) > 0 {
+        size += 1;
+    }
+    size
+}
+
+fn next_str(items: &mut impl Iterator<Item = String>) -> Result<String, Error> {
+    items.next().ok_or_else(|| "Truncated stream node".into())
+}
+
+fn next_int(items: &mut impl Iterator<Item = String>) -> Result<i64, Error> {
+    Ok(next_str(items)?.parse()?)
+}
+
+fn crc64(bytes: &[u8]) -> u64 {
+    let crc = crc::Crc::<u64>::new(&crc::CRC_64_REDIS);
+    let mut crc_digest = crc.digest();
+    crc_digest.update(bytes);
+    crc_digest.finalize()
+}
+
 #[cfg(test)]
 mod test {
-    use std::io::Write;
+    use std::{io::Write, path::PathBuf};
 
-    use crate::rdb::{RdbFile, RecordingReader};
+    use crate::common::CompleteStreamEntryID;
+    use crate::rdb::{
+        decode_listpack, dump_functions, restore_functions, RdbContent, RdbFile, RdbValue,
+        RecordingReader,
+    };
 
     #[test]
     fn test_reading_empty() {
-        create_empty_rdb_file();
-        let rdb = RdbFile::new("/tmp/rdb".into());
+        let rdb = RdbFile::new(create_empty_rdb_file("reading-empty"));
 
         let content = rdb.read().unwrap();
         dbg!(content);
@@ -353,8 +815,7 @@ mod test {
 
     #[test]
     fn test_peeking() {
-        create_empty_rdb_file();
-        let mut reader = RecordingReader::new("/tmp/rdb").unwrap();
+        let mut reader = RecordingReader::new(create_empty_rdb_file("peeking")).unwrap();
 
         let mut buf = [0u8; 2];
 
@@ -375,9 +836,119 @@ mod test {
         assert_eq!(vec![0x49, 0x53], buf);
     }
 
+    #[test]
+    fn test_write_and_read_back() {
+        let mut content = RdbContent::default();
+        content
+            .aux_fields
+            .push(("redis-ver".into(), "7.2.0".into()));
+        content.functions.push("#!lua name=lib\nreturn 1".into());
+        content.functions.push("x".repeat(20_000));
+        content.data.insert(
+            0,
+            [
+                ("plain".to_string(), (None, RdbValue::Str("value".into()))),
+                (
+                    "expiring".to_string(),
+                    (Some(1_900_000_000_000), RdbValue::Str("y".repeat(100))),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        content.data.insert(
+            3,
+            [
+                ("other".to_string(), (None, RdbValue::Str("db".into()))),
+                (
+                    "list".to_string(),
+                    (None, RdbValue::List(vec!["a".into(), "b".repeat(100)])),
+                ),
+                (
+                    "zset".to_string(),
+                    (
+                        Some(1_900_000_000_000),
+                        RdbValue::SortedSet(vec![("m".into(), -1.5), ("n".into(), f64::INFINITY)]),
+                    ),
+                ),
+                (
+                    "stream".to_string(),
+                    (
+                        None,
+                        RdbValue::Stream(
+                            vec![
+                                (
+                                    CompleteStreamEntryID(1, 0),
+                                    vec![("f".into(), "v".into()), ("n".into(), "1".repeat(5000))],
+                                ),
+                                (
+                                    CompleteStreamEntryID(1_700_000_000_000, 7),
+                                    vec![("g".into(), "w".repeat(100))],
+                                ),
+                            ],
+                            CompleteStreamEntryID(1_700_000_000_000, 7),
+                        ),
+                    ),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let rdb = RdbFile::new(temp_path("write-and-read-back"));
+        rdb.write(&content).unwrap();
+        let read = rdb.read().unwrap();
+
+        assert_eq!(Some(11), read.version);
+        assert_eq!(content.aux_fields, read.aux_fields);
+        assert_eq!(content.functions, read.functions);
+
+        let data = &read.data[&0];
+        assert_eq!(2, data.len());
+        for db_index in [0, 3] {
+            assert_eq!(content.data[&db_index], read.data[&db_index]);
+        }
+
+        let decoded = RdbFile::decode(&RdbFile::encode(&content)).unwrap();
+        assert_eq!(content.data[&3], decoded.data[&3]);
+    }
+
+    #[test]
+    fn test_function_dump_and_restore() {
+        let codes = vec!["#!lua name=a\n".to_string(), "#!lua name=b\n".repeat(10)];
+        let payload = dump_functions(&codes);
+        assert_eq!(codes, restore_functions(&payload).unwrap());
+
+        assert!(restore_functions(&dump_functions(&[])).unwrap().is_empty());
+
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 1;
+        assert!(restore_functions(&corrupted).is_err());
+        assert!(restore_functions(&payload[0..5]).is_err());
+    }
+
+    #[test]
+    fn test_decoding_listpack_encodings() {
+        // As written by Redis: a 7 bit and a 13 bit integer, a 6 bit string
+        // and a 16 bit integer.
+        let listpack = [
+            0x14, 0, 0, 0, 4, 0, 0x05, 0x01, 0xDF, 0xFF, 0x02, 0x82, b'h', b'i', 0x03, 0xF1, 0x00,
+            0x80, 0x03, 0xFF,
+        ];
+        assert_eq!(
+            vec!["5", "-1", "hi", "-32768"],
+            decode_listpack(&listpack).unwrap()
+        );
+        assert!(decode_listpack(&listpack[..10]).is_err());
+    }
+
     // ---
 
-    fn create_empty_rdb_file() {
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rdb-{}-{}", name, std::process::id()))
+    }
+
+    fn create_empty_rdb_file(name: &str) -> PathBuf {
         let fake_rdb_file_bytes_str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
         let fake_rdb_file_bytes = (0..fake_rdb_file_bytes_str.len() / 2)
             .into_iter()
@@ -386,7 +957,9 @@ mod test {
             })
             .collect::<Vec<_>>();
 
-        let mut file = std::fs::File::create("/tmp/rdb").unwrap();
+        let path = temp_path(name);
+        let mut file = std::fs::File::create(&path).unwrap();
         file.write_all(&fake_rdb_file_bytes).unwrap();
+        path
     }
 }
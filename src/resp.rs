@@ -1,3 +1,16 @@
+use std::io::Write;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::common::split_args;
+
+/// Default of `proto-max-bulk-len`.
+pub(crate) const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+const MAX_NESTING_DEPTH: usize = 32;
+/// Longest line accepted for headers and simple values.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum RespValue {
     SimpleString(String),
@@ -13,40 +26,37 @@ pub(crate) enum RespValue {
 
 impl RespValue {
     pub(crate) fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+
+    /// Appends the RESP encoding to `out`, without intermediate buffers for
+    /// nested values.
+    pub(crate) fn encode(&self, out: &mut impl BufMut) {
         match self {
-            Self::SimpleString(s) => format!("+{}\r\n", s).as_bytes().to_vec(),
-            Self::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).as_bytes().to_vec(),
+            Self::SimpleString(s) => put_line(out, b'+', s.as_bytes()),
+            Self::BulkString(s) => {
+                put_length(out, b'$', s.len() as i64);
+                out.put_slice(s.as_bytes());
+                out.put_slice(b"\r\n");
+            }
             Self::Array(list) => {
-                let mut prefix = format!("*{}\r\n", list.len())
-                    .as_bytes()
-                    .into_iter()
-                    .cloned()
-                    .collect::<Vec<_>>();
-                let mut suffix = list
-                    .iter()
-                    .flat_map(|elem| elem.serialize())
-                    .collect::<Vec<_>>();
-
-                prefix.append(&mut suffix);
-                prefix
+                put_length(out, b'*', list.len() as i64);
+                for elem in list {
+                    elem.encode(out);
+                }
             }
-            Self::NullBulkString => "$-1\r\n".as_bytes().to_vec(),
-            Self::Integer(n) => format!(":{}\r\n", n).as_bytes().to_vec(),
-            Self::SimpleError(s) => format!("-{}\r\n", s).as_bytes().to_vec(),
-            Self::NullArray => "*-1\r\n".as_bytes().to_vec(),
+            Self::NullBulkString => out.put_slice(b"$-1\r\n"),
+            Self::Integer(n) => put_length(out, b':', *n),
+            Self::SimpleError(s) => put_line(out, b'-', s.as_bytes()),
+            Self::NullArray => out.put_slice(b"*-1\r\n"),
+            // Used for the RDB payload, which has no trailing CRLF.
             Self::BulkBytes(bytes) => {
-                let len = bytes.len();
-                let mut out = format!("${}\r\n", len)
-                    .as_bytes()
-                    .into_iter()
-                    .cloned()
-                    .collect::<Vec<_>>();
-
-                out.append(&mut bytes.clone());
-
-                out
+                put_length(out, b'$', bytes.len() as i64);
+                out.put_slice(bytes);
             }
-            Self::Double(n) => format!(",{}\r\n", n).as_bytes().to_vec(),
+            Self::Double(n) => put_line(out, b',', n.to_string().as_bytes()),
         }
     }
 
@@ -65,9 +75,273 @@ impl RespValue {
     }
 }
 
+fn put_line(out: &mut impl BufMut, prefix: u8, line: &[u8]) {
+    out.put_u8(prefix);
+    out.put_slice(line);
+    out.put_slice(b"\r\n");
+}
+
+fn put_length(out: &mut impl BufMut, prefix: u8, n: i64) {
+    let mut digits = [0u8; 20];
+    let mut cursor = &mut digits[..];
+    write!(cursor, "{}", n).expect("i64 fits 20 bytes");
+    let len = 20 - cursor.len();
+    put_line(out, prefix, &digits[..len]);
+}
+
+/// Incremental RESP parser working on the bytes read so far. A frame is only
+/// decoded once it is complete. The scan of an incomplete frame resumes where
+/// it stopped when more bytes arrive, so large pipelined or nested frames are
+/// not rescanned from the start after every read.
+#[derive(Debug)]
+pub(crate) struct RespParser {
+    max_bulk_len: usize,
+    /// Scanned bytes of the incomplete frame.
+    scanned: usize,
+    /// Remaining items of the arrays the scan is inside of.
+    open_arrays: Vec<usize>,
+}
+
+impl Default for RespParser {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROTO_MAX_BULK_LEN)
+    }
+}
+
+impl RespParser {
+    pub(crate) fn new(max_bulk_len: usize) -> Self {
+        Self {
+            max_bulk_len,
+            scanned: 0,
+            open_arrays: vec![],
+        }
+    }
+
+    /// Takes the next complete value off the front of `buf` together with its
+    /// length in bytes. `None` means more bytes are needed.
+    pub(crate) fn parse(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<(RespValue, usize)>, String> {
+        let Some(len) = self.scan(buf)? else {
+            return Ok(None);
+        };
+
+        let frame = buf.split_to(len);
+        let mut pos = 0;
+        while let Some((line, next)) = find_line(&frame, pos)? {
+            if !line.trim_ascii().is_empty() {
+                break;
+            }
+            pos = next;
+        }
+
+        let value = match frame[pos] {
+            b'*' | b'$' | b'+' | b'-' | b':' | b',' => decode(&frame, &mut pos)?,
+            _ => decode_inline(&frame[pos..])?,
+        };
+        Ok(Some((value, len)))
+    }
+
+    /// Takes a `$<len>\r\n<bytes>` payload without trailing CRLF, as the RDB
+    /// file sent on a full resync.
+    pub(crate) fn parse_bulk_bytes(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<(Vec<u8>, usize)>, String> {
+        let Some((header, start)) = find_line(buf, 0)? else {
+            return Ok(None);
+        };
+        if header.first() != Some(&b'$') {
+            return Err(format!(
+                "Invalid start of size for bulk bytes. Got: {}",
+                String::from_utf8_lossy(header)
+            ));
+        }
+        let size = self
+            .bulk_len(&header[1..])?
+            .ok_or("Protocol error: invalid bulk length")?;
+        if buf.len() < start + size {
+            return Ok(None);
+        }
+
+        let mut frame = buf.split_to(start + size);
+        Ok(Some((frame.split_off(start).to_vec(), start + size)))
+    }
+
+    /// Finds the end of the frame at the front of `buf`.
+    fn scan(&mut self, buf: &[u8]) -> Result<Option<usize>, String> {
+        loop {
+            let Some((line, next)) = find_line(buf, self.scanned)? else {
+                return Ok(None);
+            };
+
+            let end = match line.first() {
+                Some(b'*') => match parse_length(&line[1..], "multibulk")? {
+                    None | Some(0) => next,
+                    Some(len) if len > MAX_MULTIBULK_LEN => {
+                        return Err("Protocol error: invalid multibulk length".into())
+                    }
+                    Some(len) => {
+                        if self.open_arrays.len() >= MAX_NESTING_DEPTH {
+                            return Err("Protocol error: too deeply nested array".into());
+                        }
+                        self.open_arrays.push(len);
+                        self.scanned = next;
+                        continue;
+                    }
+                },
+                Some(b'$') => match self.bulk_len(&line[1..])? {
+                    None => next,
+                    Some(len) => {
+                        if buf.len() < next + len + 2 {
+                            return Ok(None);
+                        }
+                        if &buf[next + len..next + len + 2] != b"\r\n" {
+                            return Err(format!(
+                                "Bulk string is not terminated by CRLF. Expected len: {}",
+                                len
+                            ));
+                        }
+                        next + len + 2
+                    }
+                },
+                Some(b'+' | b'-' | b':' | b',') => next,
+                // Inline command. Blank lines between commands are skipped.
+                _ if self.open_arrays.is_empty() => {
+                    if line.trim_ascii().is_empty() {
+                        self.scanned = next;
+                        continue;
+                    }
+                    next
+                }
+                _ => {
+                    return Err(format!(
+                        "Unexpected incoming RESP string from connection: {}",
+                        String::from_utf8_lossy(line)
+                    ))
+                }
+            };
+            self.scanned = end;
+
+            // The value is complete; close the arrays it completes.
+            loop {
+                match self.open_arrays.last_mut() {
+                    None => {
+                        self.scanned = 0;
+                        return Ok(Some(end));
+                    }
+                    Some(remaining) if *remaining > 1 => {
+                        *remaining -= 1;
+                        break;
+                    }
+                    Some(_) => {
+                        self.open_arrays.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    fn bulk_len(&self, raw: &[u8]) -> Result<Option<usize>, String> {
+        match parse_length(raw, "bulk")? {
+            Some(len) if len > self.max_bulk_len => {
+                Err("Protocol error: invalid bulk length".into())
+            }
+            len => Ok(len),
+        }
+    }
+}
+
+/// The line starting at `pos` without its line break, and the position after
+/// it. Bare `\n` line breaks are accepted too.
+fn find_line(buf: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>, String> {
+    let rest = &buf[pos..];
+    match rest.iter().position(|b| *b == b'\n') {
+        Some(newline) => {
+            let line = &rest[..newline];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            Ok(Some((line, pos + newline + 1)))
+        }
+        None if rest.len() > MAX_LINE_LEN => Err("Protocol error: too big inline request".into()),
+        None => Ok(None),
+    }
+}
+
+/// Length of a `*` or `$` header, `None` for the null value (-1).
+fn parse_length(raw: &[u8], kind: &str) -> Result<Option<usize>, String> {
+    let invalid = || format!("Protocol error: invalid {} length", kind);
+    let raw = std::str::from_utf8(raw).map_err(|_| invalid())?;
+    match raw.trim().parse::<i64>().map_err(|_| invalid())? {
+        -1 => Ok(None),
+        len if len < 0 => Err(invalid()),
+        len => Ok(Some(len as usize)),
+    }
+}
+
+/// Turns an inline command like `SET key "a b"` into the array of bulk
+/// strings clients send.
+fn decode_inline(frame: &[u8]) -> Result<RespValue, String> {
+    let (line, _) = find_line(frame, 0)?.ok_or("Incomplete RESP frame")?;
+    let args = split_args(line).map_err(|err| format!("Protocol error: {} in request", err))?;
+    Ok(RespValue::Array(
+        args.into_iter().map(RespValue::BulkString).collect(),
+    ))
+}
+
+/// Decodes a value of a frame `scan` found complete.
+fn decode(frame: &[u8], pos: &mut usize) -> Result<RespValue, String> {
+    let (line, next) = find_line(frame, *pos)?.ok_or("Incomplete RESP frame")?;
+    *pos = next;
+
+    let text = || {
+        std::str::from_utf8(&line[1..])
+            .map(|s| s.to_string())
+            .map_err(|_| "RESP line is not valid UTF-8".to_string())
+    };
+
+    match line[0] {
+        b'+' => Ok(RespValue::SimpleString(text()?)),
+        b'-' => Ok(RespValue::SimpleError(text()?)),
+        b':' => text()?
+            .trim()
+            .parse::<i64>()
+            .map(RespValue::Integer)
+            .map_err(|_| "Protocol error: invalid integer".to_string()),
+        b',' => text()?
+            .trim()
+            .parse::<f64>()
+            .map(RespValue::Double)
+            .map_err(|_| "Protocol error: invalid double".to_string()),
+        b'$' => match parse_length(&line[1..], "bulk")? {
+            None => Ok(RespValue::NullBulkString),
+            Some(len) => {
+                let bytes = frame[next..next + len].to_vec();
+                *pos = next + len + 2;
+                String::from_utf8(bytes)
+                    .map(RespValue::BulkString)
+                    .map_err(|_| "Bulk string is not valid UTF-8".to_string())
+            }
+        },
+        _ => match parse_length(&line[1..], "multibulk")? {
+            None => Ok(RespValue::NullArray),
+            Some(len) => {
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(decode(frame, pos)?);
+                }
+                Ok(RespValue::Array(items))
+            }
+        },
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::resp::RespValue;
+    use bytes::BytesMut;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::resp::{RespParser, RespValue, MAX_NESTING_DEPTH};
 
     #[test]
     fn test_simple_string() {
@@ -119,4 +393,171 @@ mod test {
             RespValue::SimpleError("ERR Bad code".to_string()).serialize()
         );
     }
+
+    fn parse_all(parser: &mut RespParser, buf: &mut BytesMut) -> Vec<RespValue> {
+        let mut values = vec![];
+        while let Some((value, _)) = parser.parse(buf).unwrap() {
+            values.push(value);
+        }
+        values
+    }
+
+    #[test]
+    fn test_parse_partial_frames() {
+        let mut parser = RespParser::default();
+        let mut buf = BytesMut::new();
+        let input = b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n:5\r\n";
+
+        let mut values = vec![];
+        for byte in input {
+            buf.extend_from_slice(&[*byte]);
+            values.extend(parse_all(&mut parser, &mut buf));
+        }
+
+        assert_eq!(
+            vec![
+                RespValue::Array(vec![
+                    RespValue::BulkString("ECHO".into()),
+                    RespValue::BulkString("hi".into()),
+                ]),
+                RespValue::Integer(5),
+            ],
+            values
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_rejects_malicious_lengths() {
+        let rejected: [&[u8]; 5] = [
+            b"$1000\r\n",
+            b"*99999999999\r\n",
+            b"$-5\r\n",
+            b"*abc\r\n",
+            b"*1\r\n!unknown\r\n",
+        ];
+        for input in rejected {
+            let mut parser = RespParser::new(100);
+            assert!(parser.parse(&mut BytesMut::from(input)).is_err());
+        }
+
+        let nested = "*1\r\n".repeat(MAX_NESTING_DEPTH + 1);
+        let mut parser = RespParser::default();
+        assert!(parser
+            .parse(&mut BytesMut::from(nested.as_bytes()))
+            .is_err());
+
+        let mut parser = RespParser::default();
+        let long_line = vec![b'+'; 128 * 1024];
+        assert!(parser.parse(&mut BytesMut::from(&long_line[..])).is_err());
+    }
+
+    #[test]
+    fn test_parse_bulk_bytes_without_crlf() {
+        let mut parser = RespParser::default();
+        let mut buf = BytesMut::from(&b"$3\r\nab"[..]);
+        assert_eq!(None, parser.parse_bulk_bytes(&mut buf).unwrap());
+
+        buf.extend_from_slice(b"c+OK\r\n");
+        assert_eq!(
+            Some((b"abc".to_vec(), 7)),
+            parser.parse_bulk_bytes(&mut buf).unwrap()
+        );
+        assert_eq!(
+            vec![RespValue::SimpleString("OK".into())],
+            parse_all(&mut parser, &mut buf)
+        );
+    }
+
+    fn random_text(rng: &mut StdRng) -> String {
+        let len = rng.random_range(0..12);
+        (0..len)
+            .map(|_| rng.random_range(b'a'..=b'z') as char)
+            .collect()
+    }
+
+    fn random_value(rng: &mut StdRng, depth: usize) -> RespValue {
+        match rng.random_range(0..if depth < 4 { 8 } else { 7 }) {
+            0 => RespValue::SimpleString(random_text(rng)),
+            // Bulk strings may contain line breaks.
+            1 => RespValue::BulkString(format!("{}\r\n{}", random_text(rng), random_text(rng))),
+            2 => RespValue::NullBulkString,
+            3 => RespValue::NullArray,
+            4 => RespValue::Integer(rng.random()),
+            5 => RespValue::SimpleError(format!("ERR {}", random_text(rng))),
+            6 => RespValue::Double(rng.random_range(-1e9..1e9)),
+            _ => RespValue::Array(
+                (0..rng.random_range(0..5))
+                    .map(|_| random_value(rng, depth + 1))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_parse_fuzz_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+
+        for _ in 0..500 {
+            let values = (0..rng.random_range(1..6))
+                .map(|_| random_value(&mut rng, 0))
+                .collect::<Vec<_>>();
+            let encoded = values
+                .iter()
+                .flat_map(|value| value.serialize())
+                .collect::<Vec<_>>();
+
+            // Feed the bytes in random chunks, as they arrive from a socket.
+            let mut parser = RespParser::default();
+            let mut buf = BytesMut::new();
+            let mut parsed = vec![];
+            let mut pos = 0;
+            while pos < encoded.len() {
+                let end = (pos + rng.random_range(1..16)).min(encoded.len());
+                buf.extend_from_slice(&encoded[pos..end]);
+                pos = end;
+                parsed.extend(parse_all(&mut parser, &mut buf));
+            }
+            assert_eq!(values, parsed);
+            assert!(buf.is_empty());
+
+            // Corrupted input must fail cleanly or parse into something.
+            let mut corrupted = encoded.clone();
+            for _ in 0..3 {
+                let index = rng.random_range(0..corrupted.len());
+                corrupted[index] = rng.random();
+            }
+            let mut parser = RespParser::default();
+            let mut buf = BytesMut::from(&corrupted[..]);
+            while let Ok(Some(_)) = parser.parse(&mut buf) {}
+        }
+    }
+
+    #[test]
+    fn test_parse_inline_commands() {
+        let mut parser = RespParser::default();
+        let mut buf = BytesMut::from(&b"PING\r\n\r\nSET key \"a b\"\n*1\r\n$4\r\nPING\r\n"[..]);
+
+        let command = |args: &[&str]| {
+            RespValue::Array(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(arg.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            vec![
+                command(&["PING"]),
+                command(&["SET", "key", "a b"]),
+                command(&["PING"]),
+            ],
+            parse_all(&mut parser, &mut buf)
+        );
+
+        let mut buf = BytesMut::from(&b"GET \"key\r\n"[..]);
+        assert_eq!(
+            Err("Protocol error: unbalanced quotes in request".to_string()),
+            parser.parse(&mut buf)
+        );
+    }
 }
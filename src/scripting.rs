@@ -0,0 +1,950 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+
+use crate::{
+    acl::{AclDenial, User},
+    command_parser::CommandParser,
+    commands::Command,
+    database::Database,
+    engine::Engine,
+    resp::RespValue,
+};
+
+/// Lua instructions run between two checks of the time limit and of SCRIPT
+/// KILL.
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+
+const KILLED_ERROR: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+const FUNCTION_FLAGS: [&str; 5] = [
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// Outcome of a script: its reply and the writes it made. Replicas receive the
+/// writes instead of the script, so they never have to run Lua themselves.
+pub(crate) struct ScriptRun {
+    pub(crate) reply: RespValue,
+    pub(crate) effects: Vec<Command>,
+    /// Calls the ACL refused, for the ACL LOG.
+    pub(crate) denials: Vec<AclDenial>,
+}
+
+impl ScriptRun {
+    pub(crate) fn error(message: &str) -> Self {
+        Self {
+            reply: RespValue::SimpleError(message.to_string()),
+            effects: vec![],
+            denials: vec![],
+        }
+    }
+}
+
+#[derive(Default)]
+struct ScriptState {
+    running: bool,
+    /// Set once the script ran past `busy-reply-threshold`.
+    busy: bool,
+    wrote: bool,
+    killed: bool,
+}
+
+/// State of the running script, shared with the other clients so they get
+/// BUSY once it runs too long and can stop it with SCRIPT KILL.
+#[derive(Default)]
+pub(crate) struct ScriptControl {
+    state: Mutex<ScriptState>,
+}
+
+impl ScriptControl {
+    pub(crate) fn is_busy(&self) -> bool {
+        self.state.lock().unwrap().busy
+    }
+
+    /// Asks the running script to stop. A script that has written can't be
+    /// stopped, or the write would be left half done.
+    pub(crate) fn kill(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.running {
+            return Err("NOTBUSY No scripts in execution right now.".into());
+        }
+        if state.wrote {
+            return Err("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.".into());
+        }
+        state.killed = true;
+        Ok(())
+    }
+
+    fn start(&self) {
+        *self.state.lock().unwrap() = ScriptState {
+            running: true,
+            ..Default::default()
+        };
+    }
+
+    /// Returns whether the script was killed.
+    fn finish(&self) -> bool {
+        std::mem::take(&mut *self.state.lock().unwrap()).killed
+    }
+
+    /// Called by the Lua hook: marks the script busy past the deadline and
+    /// aborts it once killed.
+    fn check(&self, deadline: Option<Instant>) -> mlua::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.busy && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            state.busy = true;
+        }
+        if state.killed {
+            return Err(mlua::Error::RuntimeError(KILLED_ERROR.into()));
+        }
+        Ok(())
+    }
+
+    /// Records a write about to be made, refused once the script is killed.
+    fn record_write(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.wrote = !state.killed;
+        state.wrote
+    }
+}
+
+/// What a script runs with besides its database.
+pub(crate) struct ScriptEnv<'a> {
+    /// The name and the ACL user of the client, `None` when no checks apply.
+    pub(crate) user: Option<(&'a str, &'a User)>,
+    pub(crate) control: Arc<ScriptControl>,
+    /// Time after which the other clients get BUSY.
+    pub(crate) busy_threshold: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LibraryFunction {
+    pub(crate) name: String,
+    pub(crate) flags: Vec<String>,
+}
+
+impl LibraryFunction {
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FunctionLibrary {
+    pub(crate) name: String,
+    pub(crate) code: String,
+    pub(crate) functions: Vec<LibraryFunction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FunctionRestorePolicy {
+    Append,
+    Replace,
+    Flush,
+}
+
+/// Libraries loaded by FUNCTION LOAD. Function names are unique across all
+/// libraries, so FCALL only needs the function name.
+#[derive(Default, Clone)]
+pub(crate) struct FunctionRegistry {
+    libraries: BTreeMap<String, FunctionLibrary>,
+}
+
+impl FunctionRegistry {
+    pub(crate) fn load(&mut self, code: &str, replace: bool) -> Result<String, String> {
+        let library = load_library(code)?;
+
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(format!("ERR Library '{}' already exists", library.name));
+        }
+
+        for other in self.libraries.values() {
+            if other.name == library.name {
+                continue;
+            }
+            for function in &library.functions {
+                if other.functions.iter().any(|f| f.name == function.name) {
+                    return Err(format!("ERR Function {} already exists", function.name));
+                }
+            }
+        }
+
+        let name = library.name.clone();
+        self.libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    pub(crate) fn delete(&mut self, name: &str) -> Result<(), String> {
+        self.libraries
+            .remove(name)
+            .map(|_| ())
+            .ok_or("ERR Library not found".to_string())
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.libraries.clear();
+    }
+
+    pub(crate) fn libraries(&self) -> impl Iterator<Item = &FunctionLibrary> {
+        self.libraries.values()
+    }
+
+    pub(crate) fn find_function(&self, name: &str) -> Option<(&FunctionLibrary, &LibraryFunction)> {
+        self.libraries.values().find_map(|library| {
+            library
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .map(|function| (library, function))
+        })
+    }
+
+    /// Loads the libraries of a FUNCTION DUMP payload. Either all of them are
+    /// loaded or, on the first error, none.
+    pub(crate) fn restore(
+        &mut self,
+        codes: &[String],
+        policy: FunctionRestorePolicy,
+    ) -> Result<(), String> {
+        let mut registry = match policy {
+            FunctionRestorePolicy::Flush => FunctionRegistry::default(),
+            _ => self.clone(),
+        };
+
+        for code in codes {
+            registry.load(code, policy == FunctionRestorePolicy::Replace)?;
+        }
+
+        *self = registry;
+        Ok(())
+    }
+}
+
+pub(crate) fn script_sha1(script: &str) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+/// Runs a script against an already locked database. Every `redis.call` goes
+/// through `Engine::execute_on_db`, so the whole script is atomic.
+pub(crate) fn run_script(
+    db: &mut Database,
+    env: &ScriptEnv,
+    script: &str,
+    keys: &[String],
+    args: &[String],
+) -> ScriptRun {
+    run_lua(db, env, false, |lua| {
+        lua.globals().set("KEYS", keys.to_vec())?;
+        lua.globals().set("ARGV", args.to_vec())?;
+
+        lua.load(script)
+            .set_name("user_script")
+            .into_function()?
+            .call::<_, Value>(())
+    })
+}
+
+/// Runs a library function. The library code is executed first to register its
+/// functions, then the callback is called with the keys and the arguments.
+pub(crate) fn run_function(
+    db: &mut Database,
+    env: &ScriptEnv,
+    library: &FunctionLibrary,
+    name: &str,
+    keys: &[String],
+    args: &[String],
+    read_only: bool,
+) -> ScriptRun {
+    run_lua(db, env, read_only, |lua| {
+        lua.load(library_body(&library.code))
+            .set_name(format!("@user_function:{}", library.name))
+            .exec()?;
+
+        let functions = lua.named_registry_value::<Table>("functions")?;
+        let function = functions.get::<_, Table>(name)?;
+        function
+            .get::<_, Function>("callback")?
+            .call::<_, Value>((keys.to_vec(), args.to_vec()))
+    })
+}
+
+/// Validates the `#!lua name=<library>` header and registers the functions of
+/// the library, without running any of them.
+fn load_library(code: &str) -> Result<FunctionLibrary, String> {
+    let name = parse_library_header(code)?;
+
+    let functions = (|| -> mlua::Result<Vec<LibraryFunction>> {
+        let lua = new_lua()?;
+        lua.load(library_body(code))
+            .set_name(format!("@user_function:{}", name))
+            .exec()?;
+
+        let mut functions = vec![];
+        let registered = lua.named_registry_value::<Table>("functions")?;
+        for pair in registered.pairs::<String, Table>() {
+            let (name, function) = pair?;
+            functions.push(LibraryFunction {
+                name,
+                flags: function.get("flags")?,
+            });
+        }
+        functions.sort_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+
+        Ok(functions)
+    })()
+    .map_err(|err| match err {
+        mlua::Error::SyntaxError { message, .. } => {
+            format!("ERR Error compiling function: {}", message)
+        }
+        err => match callback_error(&err) {
+            Some(message) => message,
+            None => format!("ERR Error registering functions: {}", err),
+        },
+    })?;
+
+    if functions.is_empty() {
+        return Err("ERR No functions registered".into());
+    }
+
+    Ok(FunctionLibrary {
+        name,
+        code: code.to_string(),
+        functions,
+    })
+}
+
+fn parse_library_header(code: &str) -> Result<String, String> {
+    let header = code.lines().next().unwrap_or_default();
+    let Some(header) = header.strip_prefix("#!") else {
+        return Err("ERR Missing library metadata".into());
+    };
+
+    let mut parts = header.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+
+    let Some(name) = name else {
+        return Err("ERR Library name was not given".into());
+    };
+    if !is_valid_name(&name) {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".into());
+    }
+
+    Ok(name)
+}
+
+/// The library code without its header. The header line is kept empty, so line
+/// numbers in error messages still match.
+fn library_body(code: &str) -> String {
+    match code.find('\n') {
+        Some(i) => code[i..].to_string(),
+        None => String::new(),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A Lua state with the `redis` helpers that need no database access.
+fn new_lua() -> mlua::Result<Lua> {
+    // No io, os or debug library: scripts only reach the outside via `redis`.
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+        LuaOptions::new(),
+    )?;
+
+    lua.set_named_registry_value("functions", lua.create_table()?)?;
+
+    let redis = lua.create_table()?;
+
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, message: String| reply_table(lua, "err", message))?,
+    )?;
+
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, message: String| reply_table(lua, "ok", message))?,
+    )?;
+
+    redis.set(
+        "register_function",
+        lua.create_function(|lua, args: Variadic<Value>| register_function(lua, args))?,
+    )?;
+
+    lua.globals().set("redis", redis)?;
+
+    Ok(lua)
+}
+
+/// Accepts both `redis.register_function(name, callback)` and the table form
+/// with `function_name`, `callback` and `flags`.
+fn register_function(lua: &Lua, args: Variadic<Value>) -> mlua::Result<()> {
+    let (name, callback, flags) = match args.as_slice() {
+        [Value::String(name), Value::Function(callback)] => (
+            name.to_str()?.to_string(),
+            callback.clone(),
+            Vec::<String>::new(),
+        ),
+        [Value::Table(table)] => (
+            table.get::<_, String>("function_name")?,
+            table.get::<_, Function>("callback")?,
+            table
+                .get::<_, Option<Vec<String>>>("flags")?
+                .unwrap_or_default(),
+        ),
+        _ => {
+            return Err(mlua::Error::RuntimeError(
+                "ERR wrong number of arguments to redis.register_function".into(),
+            ))
+        }
+    };
+
+    if !is_valid_name(&name) {
+        return Err(mlua::Error::RuntimeError("ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".into()));
+    }
+    if let Some(flag) = flags
+        .iter()
+        .find(|flag| !FUNCTION_FLAGS.contains(&flag.as_str()))
+    {
+        return Err(mlua::Error::RuntimeError(format!(
+            "ERR unknown flag given: {}",
+            flag
+        )));
+    }
+
+    let functions = lua.named_registry_value::<Table>("functions")?;
+    if functions.contains_key(name.as_str())? {
+        return Err(mlua::Error::RuntimeError(
+            "ERR Function already exists in the library".into(),
+        ));
+    }
+
+    let function = lua.create_table()?;
+    function.set("callback", callback)?;
+    function.set("flags", flags)?;
+    functions.set(name, function)
+}
+
+fn run_lua(
+    db: &mut Database,
+    env: &ScriptEnv,
+    read_only: bool,
+    entry: impl FnOnce(&Lua) -> mlua::Result<Value<'_>>,
+) -> ScriptRun {
+    let mut effects = vec![];
+    let mut denials = vec![];
+    let state = RefCell::new((db, &mut effects, &mut denials));
+
+    env.control.start();
+    let result = new_lua().and_then(|lua| {
+        let control = env.control.clone();
+        let deadline = Instant::now().checked_add(env.busy_threshold);
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| control.check(deadline),
+        );
+
+        lua.scope(|scope| {
+            let redis = lua.globals().get::<_, Table>("redis")?;
+
+            redis.set(
+                "call",
+                scope.create_function(|lua, args: Variadic<Value>| {
+                    let (ref mut db, ref mut effects, ref mut denials) = *state.borrow_mut();
+                    match call(db, effects, denials, env, read_only, args) {
+                        // Raised as a Lua error, it aborts the script unless caught.
+                        RespValue::SimpleError(err) => Err(mlua::Error::RuntimeError(err)),
+                        reply => resp_to_lua(lua, reply),
+                    }
+                })?,
+            )?;
+
+            redis.set(
+                "pcall",
+                scope.create_function(|lua, args: Variadic<Value>| {
+                    let (ref mut db, ref mut effects, ref mut denials) = *state.borrow_mut();
+                    resp_to_lua(lua, call(db, effects, denials, env, read_only, args))
+                })?,
+            )?;
+
+            lua_to_resp(entry(&lua)?)
+        })
+    });
+
+    let reply = match result {
+        _ if env.control.finish() => RespValue::SimpleError(KILLED_ERROR.into()),
+        Ok(reply) => reply,
+        Err(err) => RespValue::SimpleError(error_message(&err)),
+    };
+
+    ScriptRun {
+        reply,
+        effects,
+        denials,
+    }
+}
+
+fn call(
+    db: &mut Database,
+    effects: &mut Vec<Command>,
+    denials: &mut Vec<AclDenial>,
+    env: &ScriptEnv,
+    read_only: bool,
+    args: Variadic<Value>,
+) -> RespValue {
+    if args.is_empty() {
+        return RespValue::SimpleError(
+            "ERR Please specify at least one argument for this redis lib call".into(),
+        );
+    }
+
+    let mut items = vec![];
+    for arg in args.iter() {
+        match arg {
+            Value::String(s) => items.push(RespValue::BulkString(s.to_string_lossy().into())),
+            Value::Integer(n) => items.push(RespValue::BulkString(n.to_string())),
+            Value::Number(n) => items.push(RespValue::BulkString(n.to_string())),
+            _ => {
+                return RespValue::SimpleError(
+                    "ERR Lua redis lib command arguments must be strings or integers".into(),
+                )
+            }
+        }
+    }
+
+    let command = match CommandParser::parse(RespValue::Array(items)) {
+        Ok(Command::Unknown(_)) => {
+            return RespValue::SimpleError("ERR Unknown Redis command called from script".into())
+        }
+        Ok(command) => command,
+        Err(err) => return RespValue::SimpleError(err),
+    };
+
+    if !command.is_keyspace() || command.spans_databases() {
+        return RespValue::SimpleError("ERR This Redis command is not allowed from script".into());
+    }
+    if let Some((name, user)) = env.user {
+        if let Err(denial) = user.check(&command) {
+            let err = denial.to_error(name);
+            denials.push(denial);
+            return RespValue::SimpleError(err);
+        }
+    }
+    if read_only && command.for_replication() {
+        return RespValue::SimpleError(
+            "ERR Write commands are not allowed from read-only scripts.".into(),
+        );
+    }
+
+    if command.for_replication() && !env.control.record_write() {
+        return RespValue::SimpleError(KILLED_ERROR.into());
+    }
+
+    let reply = Engine::execute_on_db(db, &command);
+    if command.for_replication() && !matches!(reply, RespValue::SimpleError(_)) {
+        effects.push(command);
+    }
+
+    reply
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, message: String) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, message)?;
+    Ok(table)
+}
+
+fn resp_to_lua(lua: &Lua, value: RespValue) -> mlua::Result<Value<'_>> {
+    Ok(match value {
+        RespValue::Integer(n) => Value::Integer(n),
+        RespValue::Double(n) => Value::Number(n),
+        RespValue::BulkString(s) => Value::String(lua.create_string(&s)?),
+        RespValue::BulkBytes(bytes) => Value::String(lua.create_string(&bytes)?),
+        RespValue::NullBulkString | RespValue::NullArray => Value::Boolean(false),
+        RespValue::SimpleString(s) => Value::Table(reply_table(lua, "ok", s)?),
+        RespValue::SimpleError(s) => Value::Table(reply_table(lua, "err", s)?),
+        RespValue::Array(items) => {
+            let table = lua.create_table()?;
+            for item in items {
+                table.raw_push(resp_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+fn lua_to_resp(value: Value) -> mlua::Result<RespValue> {
+    Ok(match value {
+        Value::Boolean(true) => RespValue::Integer(1),
+        Value::Integer(n) => RespValue::Integer(n),
+        // Lua numbers are truncated to integers, like in Redis.
+        Value::Number(n) => RespValue::Integer(n as i64),
+        Value::String(s) => RespValue::BulkString(s.to_string_lossy().into()),
+        Value::Table(table) => {
+            if let Some(err) = table.get::<_, Option<String>>("err")? {
+                RespValue::SimpleError(err)
+            } else if let Some(ok) = table.get::<_, Option<String>>("ok")? {
+                RespValue::SimpleString(ok)
+            } else {
+                // Like in Redis, the array ends at the first nil.
+                let mut items = vec![];
+                for item in table.sequence_values::<Value>() {
+                    items.push(lua_to_resp(item?)?);
+                }
+                RespValue::Array(items)
+            }
+        }
+        _ => RespValue::NullBulkString,
+    })
+}
+
+/// Errors raised by our own Rust callbacks are already Redis errors.
+fn callback_error(err: &mlua::Error) -> Option<String> {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => match cause.as_ref() {
+            mlua::Error::RuntimeError(message) => Some(message.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn error_message(err: &mlua::Error) -> String {
+    if let Some(message) = callback_error(err) {
+        return message;
+    }
+
+    match err {
+        mlua::Error::SyntaxError { message, .. } => {
+            format!("ERR Error compiling script: {}", message)
+        }
+        mlua::Error::CallbackError { cause, .. } => {
+            format!("ERR Error running script: {}", cause)
+        }
+        mlua::Error::RuntimeError(message) => format!("ERR Error running script: {}", message),
+        other => format!("ERR Error running script: {}", other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        acl::{AclDenial, User},
+        database::Database,
+        resp::RespValue,
+        scripting::*,
+    };
+
+    const LIBRARY: &str = "#!lua name=counters
+local function incr(keys, args)
+  return redis.call('INCR', keys[1])
+end
+redis.register_function('incr', incr)
+redis.register_function{
+  function_name = 'peek',
+  callback = function(keys, args) return redis.call('GET', keys[1]) end,
+  flags = {'no-writes'},
+}
+redis.register_function{
+  function_name = 'sneaky',
+  callback = function(keys, args) return redis.call('SET', keys[1], '0') end,
+  flags = {'no-writes'},
+}";
+
+    fn env() -> ScriptEnv<'static> {
+        ScriptEnv {
+            user: None,
+            control: Arc::new(ScriptControl::default()),
+            busy_threshold: Duration::from_secs(5),
+        }
+    }
+
+    fn run(db: &mut Database, script: &str, keys: &[&str], args: &[&str]) -> ScriptRun {
+        let keys = keys.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let args = args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        run_script(db, &env(), script, &keys, &args)
+    }
+
+    #[test]
+    fn test_script_sha1() {
+        assert_eq!(
+            "e0e1f9fabfc9d4800c877a703b823ac0578ff8db",
+            script_sha1("return 1")
+        );
+    }
+
+    #[test]
+    fn test_script_reply_conversion() {
+        let mut db = Database::new();
+
+        assert_eq!(
+            RespValue::Array(vec![
+                RespValue::Integer(1),
+                RespValue::Integer(2),
+                RespValue::BulkString("three".into()),
+                RespValue::Integer(1),
+            ]),
+            run(&mut db, "return {1, 2.9, 'three', true, nil, 5}", &[], &[]).reply
+        );
+        assert_eq!(
+            RespValue::NullBulkString,
+            run(&mut db, "return false", &[], &[]).reply
+        );
+        assert_eq!(
+            RespValue::SimpleString("FINE".into()),
+            run(&mut db, "return redis.status_reply('FINE')", &[], &[]).reply
+        );
+        assert_eq!(
+            RespValue::SimpleError("MY error".into()),
+            run(&mut db, "return redis.error_reply('MY error')", &[], &[]).reply
+        );
+    }
+
+    #[test]
+    fn test_script_calls_and_effects() {
+        let mut db = Database::new();
+
+        let script_run = run(
+            &mut db,
+            "redis.call('SET', KEYS[1], ARGV[1]); return redis.call('INCR', KEYS[1])",
+            &["counter"],
+            &["41"],
+        );
+        assert_eq!(RespValue::Integer(42), script_run.reply);
+        assert_eq!(
+            vec!["set", "incr"],
+            script_run
+                .effects
+                .iter()
+                .map(|command| command.short_name())
+                .collect::<Vec<_>>()
+        );
+
+        let script_run = run(&mut db, "return redis.call('GET', 'counter')", &[], &[]);
+        assert_eq!(RespValue::BulkString("42".into()), script_run.reply);
+        assert!(script_run.effects.is_empty());
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let mut db = Database::new();
+        db.set("list".into(), "value".into(), None).unwrap();
+
+        let RespValue::SimpleError(err) = run(&mut db, "return +", &[], &[]).reply else {
+            panic!("compile error expected");
+        };
+        assert!(err.starts_with("ERR Error compiling script"));
+
+        let RespValue::SimpleError(err) = run(&mut db, "error('boom')", &[], &[]).reply else {
+            panic!("runtime error expected");
+        };
+        assert!(err.starts_with("ERR Error running script"));
+
+        let RespValue::SimpleError(err) =
+            run(&mut db, "return redis.call('INCR', 'list')", &[], &[]).reply
+        else {
+            panic!("call error expected");
+        };
+        assert!(err.starts_with("ERR value is not an integer"));
+
+        assert_eq!(
+            RespValue::SimpleError("ERR This Redis command is not allowed from script".into()),
+            run(&mut db, "return redis.pcall('MULTI')", &[], &[]).reply
+        );
+        assert_eq!(
+            RespValue::SimpleError("ERR Unknown Redis command called from script".into()),
+            run(&mut db, "return redis.call('NOPE')", &[], &[]).reply
+        );
+    }
+
+    #[test]
+    fn test_script_calls_are_checked_against_the_user() {
+        let mut db = Database::new();
+        let mut user = User::default();
+        for rule in ["on", "nopass", "+@all", "~public:*"] {
+            user.apply_rule(rule).unwrap();
+        }
+        let env = ScriptEnv {
+            user: Some(("restricted", &user)),
+            ..env()
+        };
+
+        let script_run = run_script(
+            &mut db,
+            &env,
+            "redis.call('SET', 'public:a', '1'); return redis.call('SET', 'secret', '1')",
+            &[],
+            &[],
+        );
+        assert_eq!(
+            RespValue::SimpleError("NOPERM No permissions to access a key".into()),
+            script_run.reply
+        );
+        assert_eq!(vec![AclDenial::Key("secret".into())], script_run.denials);
+        assert_eq!(1, script_run.effects.len());
+        assert_eq!(None, db.get(&"secret".to_string()).unwrap());
+
+        let script_run = run_script(
+            &mut db,
+            &env,
+            "return redis.pcall('GET', 'secret')",
+            &[],
+            &[],
+        );
+        assert_eq!(
+            RespValue::SimpleError("NOPERM No permissions to access a key".into()),
+            script_run.reply
+        );
+    }
+
+    #[test]
+    fn test_script_kill() {
+        let mut db = Database::new();
+        let env = ScriptEnv {
+            busy_threshold: Duration::ZERO,
+            ..env()
+        };
+        let control = env.control.clone();
+
+        let script_run = std::thread::scope(|scope| {
+            let script = scope.spawn(|| run_script(&mut db, &env, "while true do end", &[], &[]));
+            while !control.is_busy() {
+                std::thread::yield_now();
+            }
+            assert_eq!(Ok(()), control.kill());
+            script.join().unwrap()
+        });
+        assert_eq!(
+            RespValue::SimpleError(KILLED_ERROR.into()),
+            script_run.reply
+        );
+        assert!(!control.is_busy());
+        assert!(control.kill().unwrap_err().starts_with("NOTBUSY"));
+
+        control.start();
+        assert!(control.record_write());
+        assert!(control.kill().unwrap_err().starts_with("UNKILLABLE"));
+        assert!(!control.finish());
+    }
+
+    #[test]
+    fn test_function_library_loading() {
+        let mut registry = FunctionRegistry::default();
+        assert_eq!(Ok("counters".to_string()), registry.load(LIBRARY, false));
+
+        let (library, function) = registry.find_function("peek").unwrap();
+        assert_eq!("counters", library.name);
+        assert!(function.is_read_only());
+        assert!(!registry.find_function("incr").unwrap().1.is_read_only());
+        assert!(registry.find_function("nope").is_none());
+
+        assert_eq!(
+            Err("ERR Library 'counters' already exists".to_string()),
+            registry.load(LIBRARY, false)
+        );
+        assert!(registry.load(LIBRARY, true).is_ok());
+        assert_eq!(
+            Err("ERR Function incr already exists".to_string()),
+            registry.load(
+                "#!lua name=other\nredis.register_function('incr', function() end)",
+                false
+            )
+        );
+
+        assert_eq!(
+            Err("ERR Missing library metadata".to_string()),
+            registry.load("return 1", false)
+        );
+        assert_eq!(
+            Err("ERR Engine 'python' not found".to_string()),
+            registry.load("#!python name=x\n", false)
+        );
+        assert_eq!(
+            Err("ERR No functions registered".to_string()),
+            registry.load("#!lua name=empty\nlocal x = 1", false)
+        );
+        assert_eq!(
+            Err("ERR unknown flag given: fast".to_string()),
+            registry.load(
+                "#!lua name=flags\nredis.register_function{function_name='f', callback=function() end, flags={'fast'}}",
+                false
+            )
+        );
+
+        assert_eq!(Ok(()), registry.delete("counters"));
+        assert_eq!(
+            Err("ERR Library not found".to_string()),
+            registry.delete("counters")
+        );
+    }
+
+    #[test]
+    fn test_function_restore_is_all_or_nothing() {
+        let mut registry = FunctionRegistry::default();
+        registry.load(LIBRARY, false).unwrap();
+
+        let other = "#!lua name=other\nredis.register_function('other', function() end)";
+        let codes = vec![other.to_string(), LIBRARY.to_string()];
+
+        assert!(registry
+            .restore(&codes, FunctionRestorePolicy::Append)
+            .is_err());
+        assert!(registry.find_function("other").is_none());
+
+        assert!(registry
+            .restore(&codes, FunctionRestorePolicy::Replace)
+            .is_ok());
+        assert!(registry.find_function("other").is_some());
+
+        assert!(registry
+            .restore(&codes[0..1], FunctionRestorePolicy::Flush)
+            .is_ok());
+        assert!(registry.find_function("incr").is_none());
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let mut db = Database::new();
+        let mut registry = FunctionRegistry::default();
+        registry.load(LIBRARY, false).unwrap();
+        let keys = vec!["counter".to_string()];
+
+        let (library, _) = registry.find_function("incr").unwrap();
+        let function_run = run_function(&mut db, &env(), library, "incr", &keys, &[], false);
+        assert_eq!(RespValue::Integer(1), function_run.reply);
+        assert_eq!(1, function_run.effects.len());
+
+        let function_run = run_function(&mut db, &env(), library, "peek", &keys, &[], true);
+        assert_eq!(RespValue::BulkString("1".into()), function_run.reply);
+
+        let function_run = run_function(&mut db, &env(), library, "sneaky", &keys, &[], true);
+        assert_eq!(
+            RespValue::SimpleError(
+                "ERR Write commands are not allowed from read-only scripts.".into()
+            ),
+            function_run.reply
+        );
+        assert!(function_run.effects.is_empty());
+    }
+}
@@ -1,60 +1,271 @@
-use std::{cell::Cell, sync::Arc};
+use std::{
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::Context;
+use socket2::{Domain, Socket, Type};
 use tokio::{
     io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UnixListener},
+    task::JoinSet,
 };
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
-    command_parser::CommandParser, common::Error, engine::Engine, network::StreamReader,
-    resp::RespValue,
+    command_parser::CommandParser,
+    common::Error,
+    config::Config,
+    engine::{Engine, Session},
+    network::{Connection, CountedStream, ProtocolError, StreamReader},
+    resp::{RespParser, RespValue},
+    tls::{peer_common_name, TlsConfig},
 };
 
+const PROTECTED_MODE_ERROR: &str = "DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.";
+const LISTEN_BACKLOG: i32 = 511;
+/// How often the background cycle runs, as `hz 10` does. It removes expired
+/// keys nobody reads again and samples the instantaneous metrics of INFO.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the server accepts clients.
+pub(crate) struct NetworkConfig {
+    /// Addresses to listen on. `*` and `::*` stand for all IPv4 and IPv6
+    /// interfaces, and a leading `-` marks an address that may be unavailable.
+    pub(crate) bind: Vec<String>,
+    /// Port of the plain TCP listeners, 0 disables them.
+    pub(crate) port: u16,
+    pub(crate) unixsocket: Option<String>,
+    pub(crate) unixsocket_perm: Option<u32>,
+    pub(crate) tls: Option<TlsConfig>,
+}
+
 pub(crate) struct Server {
     engine: Arc<Engine>,
-    request_counter: Cell<u64>,
-    port: u16,
+    request_counter: Arc<AtomicU64>,
+    network: NetworkConfig,
 }
 
 impl Server {
-    pub(crate) fn new(
-        port: u16,
-        replica_of: Option<(String, u16)>,
-        dir: String,
-        dbfilename: String,
-    ) -> Self {
-        Self {
-            engine: Arc::new(Engine::new(replica_of, dir, dbfilename)),
-            request_counter: Cell::new(0),
-            port,
-        }
+    pub(crate) fn new(config: Config) -> Result<Self, Error> {
+        let network = config.network_config()?;
+        let replication_tls = match &network.tls {
+            Some(tls) if tls.replication => Some(tls.connector()?),
+            _ => None,
+        };
+
+        Ok(Self {
+            engine: Arc::new(Engine::new(config, replication_tls)),
+            request_counter: Arc::new(AtomicU64::new(1)),
+            network,
+        })
     }
 
     pub(crate) async fn run(&self) -> Result<(), Error> {
+        if self.engine.has_aclfile().await {
+            self.engine.load_acl_file().await?;
+        }
+
+        let tls = self
+            .network
+            .tls
+            .as_ref()
+            .and_then(|tls| tls.port.map(|tls_port| (tls, tls_port)));
+        let tls_acceptor = tls.map(|(tls, _)| tls.acceptor()).transpose()?;
+        let cn_auth = tls
+            .map(|(tls, _)| tls.auth_clients_user_cn)
+            .unwrap_or(false);
+
+        let mut listeners = JoinSet::new();
+
+        for addr in &self.network.bind {
+            let mut ports = vec![];
+            if self.network.port != 0 {
+                ports.push((self.network.port, None));
+            }
+            if let Some((_, tls_port)) = tls {
+                ports.push((tls_port, tls_acceptor.clone()));
+            }
+
+            for (port, acceptor) in ports {
+                let listener = match Self::bind_tcp(addr, port) {
+                    Ok(listener) => listener,
+                    Err(err) if addr.starts_with('-') => {
+                        warn!("Skipping unavailable bind address {}: {}", addr, err);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                info!("Listening on {}", listener.local_addr()?);
+
+                listeners.spawn(Self::listen_tcp(
+                    listener,
+                    acceptor,
+                    cn_auth,
+                    self.engine.clone(),
+                    self.request_counter.clone(),
+                ));
+            }
+        }
+
+        if let Some(path) = &self.network.unixsocket {
+            let listener = Self::bind_unix(path, self.network.unixsocket_perm)?;
+            info!("Listening on unix socket {}", path);
+
+            listeners.spawn(Self::listen_unix(
+                listener,
+                path.clone(),
+                self.engine.clone(),
+                self.request_counter.clone(),
+            ));
+        }
+
+        if listeners.is_empty() {
+            return Err("No listener could be started".into());
+        }
+
         tokio::spawn({
             let engine = self.engine.clone();
-            let port = self.port;
+            // The port replicas announce to their writer.
+            let port = match (self.network.port, tls) {
+                (0, Some((_, tls_port))) => tls_port,
+                (port, _) => port,
+            };
             async move {
-                engine.init(port).await.unwrap();
+                if let Err(err) = engine.init(port).await {
+                    error!("Loading the snapshot has failed: {}", err);
+                }
             }
         });
 
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port))
-            .await
-            .context("tcp-bind")?;
+        tokio::spawn({
+            let engine = self.engine.clone();
+            async move {
+                let mut interval = tokio::time::interval(CRON_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    engine.active_expire_cycle().await;
+                    engine.disconnect_idle_clients().await;
+                    engine.sample_stats().await;
+                }
+            }
+        });
+
+        while let Some(result) = listeners.join_next().await {
+            result.context("listener-task")??;
+        }
+
+        Ok(())
+    }
+
+    fn bind_tcp(addr: &str, port: u16) -> Result<TcpListener, Error> {
+        let host = match addr.trim_start_matches('-') {
+            "*" => "0.0.0.0",
+            "::*" => "::",
+            host => host,
+        };
+        let socket_addr = SocketAddr::new(
+            host.parse()
+                .context(format!("parse-bind-address-{}", addr))?,
+            port,
+        );
+
+        let socket = Socket::new(Domain::for_address(socket_addr), Type::STREAM, None)?;
+        // IPv6 listeners leave IPv4 to their own listeners, so `* ::*` can bind both.
+        if socket_addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket
+            .bind(&socket_addr.into())
+            .context(format!("tcp-bind-{}", socket_addr))?;
+        socket.listen(LISTEN_BACKLOG)?;
+
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
+    fn bind_unix(path: &str, perm: Option<u32>) -> Result<UnixListener, Error> {
+        // A socket file left behind by a previous run would fail the bind.
+        if std::fs::metadata(path).is_ok() {
+            std::fs::remove_file(path).context("remove-stale-unixsocket")?;
+        }
+
+        let listener = UnixListener::bind(path).context("unixsocket-bind")?;
+        if let Some(perm) = perm {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(perm))
+                .context("unixsocket-permissions")?;
+        }
+
+        Ok(listener)
+    }
 
+    async fn listen_tcp(
+        listener: TcpListener,
+        acceptor: Option<TlsAcceptor>,
+        cn_auth: bool,
+        engine: Arc<Engine>,
+        request_counter: Arc<AtomicU64>,
+    ) -> Result<(), Error> {
         loop {
-            let (stream, _) = listener.accept().await.context("accept-tcp-connection")?;
+            let (stream, peer) = listener.accept().await.context("accept-tcp-connection")?;
+            let request_count = request_counter.fetch_add(1, Ordering::Relaxed);
+
+            tokio::spawn({
+                let engine = engine.clone();
+                let acceptor = acceptor.clone();
 
-            let request_count = self.request_counter.take();
-            self.request_counter.set(request_count + 1);
+                async move {
+                    let result = Self::handle_tcp_connection(
+                        stream,
+                        peer,
+                        acceptor,
+                        cn_auth,
+                        engine.clone(),
+                        request_count,
+                    )
+                    .await;
+                    engine.disconnect(request_count).await;
+
+                    match result {
+                        Ok(_) => debug!("Request completed"),
+                        Err(err) => error!("Request has failed with reason: {:#?}", err),
+                    }
+                }
+            });
+        }
+    }
+
+    async fn listen_unix(
+        listener: UnixListener,
+        path: String,
+        engine: Arc<Engine>,
+        request_counter: Arc<AtomicU64>,
+    ) -> Result<(), Error> {
+        loop {
+            let (stream, _) = listener.accept().await.context("accept-unix-connection")?;
+            let request_count = request_counter.fetch_add(1, Ordering::Relaxed);
+            let addr = format!("{}:0", path);
 
             tokio::spawn({
-                let engine = self.engine.clone();
+                let engine = engine.clone();
 
                 async move {
-                    match Self::handle_request(stream, engine, request_count).await {
+                    let session = engine
+                        .new_session(request_count, addr.clone(), addr, None)
+                        .await;
+                    let parser = RespParser::new(engine.proto_max_bulk_len().await);
+                    let result =
+                        Self::handle_request(Box::new(stream), parser, engine.clone(), session)
+                            .await;
+                    engine.disconnect(request_count).await;
+
+                    match result {
                         Ok(_) => debug!("Request completed"),
                         Err(err) => error!("Request has failed with reason: {:#?}", err),
                     }
@@ -63,36 +274,187 @@ impl Server {
         }
     }
 
-    async fn handle_request(
-        mut stream: TcpStream,
+    /// Runs the TLS handshake when the connection came in on the TLS port.
+    /// The common name of a verified client certificate is used as the user
+    /// when certificate authentication is enabled. In protected mode, clients
+    /// from other hosts are refused.
+    async fn handle_tcp_connection(
+        stream: TcpStream,
+        peer: SocketAddr,
+        acceptor: Option<TlsAcceptor>,
+        cn_auth: bool,
         engine: Arc<Engine>,
         request_count: u64,
     ) -> Result<(), Error> {
+        let laddr = stream
+            .local_addr()
+            .map(|laddr| laddr.to_string())
+            .unwrap_or_default();
+        let (mut stream, cert_user): (Connection, Option<String>) = match acceptor {
+            Some(acceptor) => {
+                let stream = acceptor.accept(stream).await.context("tls-handshake")?;
+                let cert_user = cn_auth.then(|| peer_common_name(&stream)).flatten();
+                (Box::new(stream), cert_user)
+            }
+            None => (Box::new(stream), None),
+        };
+
+        if !peer.ip().to_canonical().is_loopback() && engine.is_protected().await {
+            engine.record_rejected_connection().await;
+            stream
+                .write_all(&RespValue::SimpleError(PROTECTED_MODE_ERROR.into()).serialize())
+                .await
+                .context("write-protected-mode-error")?;
+            return Ok(());
+        }
+
+        let session = engine
+            .new_session(request_count, peer.to_string(), laddr, cert_user.as_deref())
+            .await;
+        let parser = RespParser::new(engine.proto_max_bulk_len().await);
+        Self::handle_request(stream, parser, engine, session).await
+    }
+
+    pub(crate) async fn handle_request(
+        stream: Connection,
+        parser: RespParser,
+        engine: Arc<Engine>,
+        mut session: Session,
+    ) -> Result<(), Error> {
+        let mut stream: Connection = Box::new(CountedStream::new(stream, engine.net_counters()));
+        let request_count = session.id;
+        let mut stream_reader = StreamReader::new(&mut stream, parser);
+        let killed = session.killed.clone();
+
         loop {
-            let mut stream_reader = StreamReader::new(&mut stream);
-            match stream_reader
-                .read_resp_value_from_buf_reader(Some(request_count))
-                .await?
-            {
-                Some(input) => match CommandParser::parse(input) {
-                    Ok(command) => {
-                        debug!("Received command: {:?}", command);
-                        // TODO: get rid of passing raw stream. Use buf reader everywhere.
-                        engine
-                            .execute(&command, request_count, &mut stream_reader)
-                            .await?;
-                    }
-                    Err(err) => {
-                        stream
+            let input = tokio::select! {
+                biased;
+                // Replies still buffered for a killed client are dropped.
+                _ = Session::wait_killed(killed.clone()) => return Ok(()),
+                input = stream_reader.read_resp_value_from_buf_reader(Some(request_count)) => input,
+            };
+            let input = match input {
+                Ok(Some(input)) => input,
+                Ok(None) => break,
+                Err(err) => match err.downcast::<ProtocolError>() {
+                    Ok(err) => {
+                        let err = format!("ERR {}", err);
+                        engine.abort_transaction(request_count).await;
+                        engine.record_rejected(None, &err).await;
+                        stream_reader
+                            .get_mut()
                             .write_all(&RespValue::SimpleError(err).serialize())
                             .await
-                            .context("write-simple-value-back-to-stream")?;
+                            .context("write-protocol-error")?;
+                        break;
                     }
+                    Err(err) => return Err(err),
                 },
-                None => break,
+            };
+
+            match CommandParser::parse(input) {
+                Ok(command)
+                    if session.user.is_none()
+                        && !command.is_auth()
+                        && !command.is_connection_control() =>
+                {
+                    let err = "NOAUTH Authentication required.".to_string();
+                    engine.record_rejected(Some(&command), &err).await;
+                    stream_reader
+                        .get_mut()
+                        .write_all(&RespValue::SimpleError(err).serialize())
+                        .await
+                        .context("write-simple-value-back-to-stream")?;
+                }
+                Ok(command) => {
+                    debug!("Received command: {:?}", command);
+                    // Replies of the earlier pipelined commands must not wait
+                    // for this one.
+                    if command.is_blocking() {
+                        stream_reader.flush().await?;
+                    }
+                    engine
+                        .execute(&command, &mut session, &mut stream_reader)
+                        .await?;
+                    if session.closing {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    engine.abort_transaction(request_count).await;
+                    engine.record_rejected(None, &err).await;
+                    stream_reader
+                        .get_mut()
+                        .write_all(&RespValue::SimpleError(err).serialize())
+                        .await
+                        .context("write-simple-value-back-to-stream")?;
+                }
             }
         }
 
-        Ok(())
+        // The peer may only have closed its writing half.
+        stream_reader.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_ipv4_and_ipv6_on_same_port() {
+        let v4 = Server::bind_tcp("127.0.0.1", 0).unwrap();
+        let port = v4.local_addr().unwrap().port();
+
+        let v6 = Server::bind_tcp("-::1", port).unwrap();
+        assert_eq!(port, v6.local_addr().unwrap().port());
+        assert!(v6.local_addr().unwrap().is_ipv6());
+
+        assert!(Server::bind_tcp("not-an-address", port).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies_in_order() {
+        const COMMANDS: usize = 10_000;
+
+        let engine = Arc::new(Engine::new(
+            Config {
+                dir: ".".into(),
+                dbfilename: "pipeline-test.rdb".into(),
+                ..Default::default()
+            },
+            None,
+        ));
+        let session = engine
+            .new_session(1, "pipeline".into(), "pipeline".into(), None)
+            .await;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server_task = tokio::spawn(Server::handle_request(
+            Box::new(server),
+            RespParser::default(),
+            engine,
+            session,
+        ));
+
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let writer = tokio::spawn(async move {
+            let request = (0..COMMANDS)
+                .map(|i| format!("*2\r\n$4\r\nECHO\r\n${}\r\n{}\r\n", i.to_string().len(), i))
+                .collect::<String>();
+            client_write.write_all(request.as_bytes()).await.unwrap();
+            client_write.shutdown().await.unwrap();
+        });
+
+        let mut replies = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut client_read, &mut replies)
+            .await
+            .unwrap();
+        writer.await.unwrap();
+        server_task.await.unwrap().unwrap();
+
+        let expected = (0..COMMANDS)
+            .map(|i| format!("${}\r\n{}\r\n", i.to_string().len(), i))
+            .collect::<String>();
+        assert_eq!(expected, replies);
     }
 }
@@ -0,0 +1,441 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::time::Instant;
+
+use crate::commands::Command;
+
+/// Percentiles shown by INFO latencystats, as `latency-tracking-info-percentiles`
+/// defaults to.
+const LATENCY_PERCENTILES: [f64; 3] = [50.0, 99.0, 99.9];
+
+/// Samples kept for the instantaneous metrics, one per background cycle.
+const METRIC_SAMPLES: usize = 16;
+
+/// Bytes read from and written to client connections.
+#[derive(Default)]
+pub(crate) struct NetCounters {
+    pub(crate) input: AtomicU64,
+    pub(crate) output: AtomicU64,
+}
+
+impl NetCounters {
+    pub(crate) fn add_input(&self, bytes: usize) {
+        self.input.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_output(&self, bytes: usize) {
+        self.output.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        self.input.store(0, Ordering::Relaxed);
+        self.output.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Latencies in microseconds, in buckets about 12% wide: exact below 16, then
+/// eight buckets per power of two.
+#[derive(Default)]
+pub(crate) struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+}
+
+impl LatencyHistogram {
+    fn bucket(usec: u64) -> usize {
+        if usec < 16 {
+            return usec as usize;
+        }
+        let exp = 63 - usec.leading_zeros() as usize;
+        let mantissa = (usec >> (exp - 3)) as usize & 0b111;
+        16 + (exp - 4) * 8 + mantissa
+    }
+
+    /// Lowest latency that falls in the bucket.
+    fn bucket_value(bucket: usize) -> u64 {
+        if bucket < 16 {
+            return bucket as u64;
+        }
+        let exp = (bucket - 16) / 8 + 4;
+        let mantissa = ((bucket - 16) % 8) as u64;
+        (8 + mantissa) << (exp - 3)
+    }
+
+    pub(crate) fn record(&mut self, usec: u64) {
+        let bucket = Self::bucket(usec);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+    }
+
+    pub(crate) fn percentile(&self, percentile: f64) -> u64 {
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::bucket_value(bucket);
+            }
+        }
+        0
+    }
+}
+
+/// Calls of one command, for INFO commandstats and latencystats.
+#[derive(Default)]
+pub(crate) struct CommandStats {
+    calls: u64,
+    usec: u64,
+    /// Refused before running, e.g. by ACL rules or inside MULTI.
+    rejected_calls: u64,
+    /// Ran but replied with an error.
+    failed_calls: u64,
+    latency: LatencyHistogram,
+}
+
+/// Rate of a counter per second, averaged over the last samples.
+#[derive(Default)]
+struct InstantaneousMetric {
+    samples: [f64; METRIC_SAMPLES],
+    index: usize,
+    last: Option<(Instant, u64)>,
+}
+
+impl InstantaneousMetric {
+    fn sample(&mut self, now: Instant, value: u64) {
+        if let Some((time, last_value)) = self.last {
+            let elapsed = now.duration_since(time).as_secs_f64();
+            if elapsed > 0.0 {
+                self.samples[self.index] = value.saturating_sub(last_value) as f64 / elapsed;
+                self.index = (self.index + 1) % METRIC_SAMPLES;
+            }
+        }
+        self.last = Some((now, value));
+    }
+
+    fn rate(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / METRIC_SAMPLES as f64
+    }
+}
+
+/// Counters of the server since it started, behind INFO.
+pub(crate) struct Stats {
+    pub(crate) started: Instant,
+    /// Random id of this run of the server.
+    pub(crate) run_id: String,
+    /// Port the server announces, once the listeners are up.
+    pub(crate) tcp_port: u16,
+    pub(crate) total_connections_received: u64,
+    /// Connections refused by protected mode.
+    pub(crate) rejected_connections: u64,
+    pub(crate) total_commands_processed: u64,
+    pub(crate) total_error_replies: u64,
+    /// Writes since the last successful SAVE.
+    pub(crate) dirty: u64,
+    /// Unix time of the last SAVE, or of the start.
+    pub(crate) last_save_time: u64,
+    pub(crate) last_save_ok: bool,
+    pub(crate) peak_memory: usize,
+    commands: BTreeMap<String, CommandStats>,
+    /// Error replies by their first word, e.g. `ERR` or `WRONGTYPE`.
+    errors: BTreeMap<String, u64>,
+    ops_per_sec: InstantaneousMetric,
+    input_per_sec: InstantaneousMetric,
+    output_per_sec: InstantaneousMetric,
+}
+
+impl Stats {
+    pub(crate) fn new(run_id: String) -> Self {
+        Self {
+            started: Instant::now(),
+            run_id,
+            tcp_port: 0,
+            total_connections_received: 0,
+            rejected_connections: 0,
+            total_commands_processed: 0,
+            total_error_replies: 0,
+            dirty: 0,
+            last_save_time: unix_time().as_secs(),
+            last_save_ok: true,
+            peak_memory: 0,
+            commands: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            ops_per_sec: InstantaneousMetric::default(),
+            input_per_sec: InstantaneousMetric::default(),
+            output_per_sec: InstantaneousMetric::default(),
+        }
+    }
+
+    /// Clears the counters as CONFIG RESETSTAT does. What describes the run
+    /// and the persistence state is kept.
+    pub(crate) fn reset(&mut self) {
+        *self = Self {
+            started: self.started,
+            run_id: std::mem::take(&mut self.run_id),
+            tcp_port: self.tcp_port,
+            dirty: self.dirty,
+            last_save_time: self.last_save_time,
+            last_save_ok: self.last_save_ok,
+            ..Self::new(String::new())
+        };
+    }
+
+    /// Records a command that ran, with the error it replied with if any.
+    pub(crate) fn record_call(
+        &mut self,
+        command: &Command,
+        duration: Duration,
+        error: Option<&str>,
+    ) {
+        // Unknown commands only show up in errorstats.
+        if let Command::Unknown(_) = command {
+            if let Some(err) = error {
+                self.record_error(err);
+            }
+            return;
+        }
+
+        let usec = duration.as_micros() as u64;
+        let stats = self.commands.entry(command.acl_name()).or_default();
+        stats.calls += 1;
+        stats.usec += usec;
+        stats.latency.record(usec);
+        self.total_commands_processed += 1;
+
+        if let Some(err) = error {
+            stats.failed_calls += 1;
+            self.record_error(err);
+        } else if command.for_replication() {
+            self.dirty += 1;
+        }
+    }
+
+    /// Records a command refused before it ran.
+    pub(crate) fn record_rejected(&mut self, command: &Command, err: &str) {
+        if let Command::Unknown(_) = command {
+            self.record_error(err);
+            return;
+        }
+        self.commands
+            .entry(command.acl_name())
+            .or_default()
+            .rejected_calls += 1;
+        self.record_error(err);
+    }
+
+    /// Records an error reply, also for requests that didn't parse.
+    pub(crate) fn record_error(&mut self, err: &str) {
+        *self.errors.entry(error_code(err).to_string()).or_default() += 1;
+        self.total_error_replies += 1;
+    }
+
+    /// Takes a sample of the instantaneous metrics.
+    pub(crate) fn sample(&mut self, net: &NetCounters) {
+        let now = Instant::now();
+        self.ops_per_sec.sample(now, self.total_commands_processed);
+        self.input_per_sec
+            .sample(now, net.input.load(Ordering::Relaxed));
+        self.output_per_sec
+            .sample(now, net.output.load(Ordering::Relaxed));
+    }
+
+    pub(crate) fn ops_per_sec(&self) -> u64 {
+        self.ops_per_sec.rate().round() as u64
+    }
+
+    /// Input and output rates in KB per second.
+    pub(crate) fn net_kbps(&self) -> (f64, f64) {
+        (
+            self.input_per_sec.rate() / 1024.0,
+            self.output_per_sec.rate() / 1024.0,
+        )
+    }
+
+    pub(crate) fn commandstats(&self) -> String {
+        let mut out = String::new();
+        for (name, stats) in &self.commands {
+            let usec_per_call = match stats.calls {
+                0 => 0.0,
+                calls => stats.usec as f64 / calls as f64,
+            };
+            let _ = write!(
+                out,
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+                name,
+                stats.calls,
+                stats.usec,
+                usec_per_call,
+                stats.rejected_calls,
+                stats.failed_calls
+            );
+        }
+        out
+    }
+
+    pub(crate) fn errorstats(&self) -> String {
+        self.errors
+            .iter()
+            .map(|(code, count)| format!("errorstat_{}:count={}\r\n", code, count))
+            .collect()
+    }
+
+    pub(crate) fn latencystats(&self) -> String {
+        let mut out = String::new();
+        for (name, stats) in &self.commands {
+            if stats.latency.count == 0 {
+                continue;
+            }
+            let percentiles = LATENCY_PERCENTILES
+                .iter()
+                .map(|percentile| {
+                    format!(
+                        "p{}={:.3}",
+                        percentile,
+                        stats.latency.percentile(*percentile) as f64
+                    )
+                })
+                .collect::<Vec<_>>();
+            let _ = write!(
+                out,
+                "latency_percentiles_usec_{}:{}\r\n",
+                name,
+                percentiles.join(",")
+            );
+        }
+        out
+    }
+}
+
+/// First word of an error reply, as INFO errorstats groups them.
+fn error_code(err: &str) -> &str {
+    err.split(' ').next().unwrap_or(err)
+}
+
+pub(crate) fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+}
+
+/// Resident set size of the process in bytes, 0 where procfs is missing.
+pub(crate) fn resident_memory() -> usize {
+    const PAGE_SIZE: usize = 4096;
+
+    std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<usize>().ok())
+        .map_or(0, |pages| pages * PAGE_SIZE)
+}
+
+/// System and user CPU time of the process in seconds, 0 where procfs is
+/// missing.
+pub(crate) fn cpu_time() -> (f64, f64) {
+    const CLOCK_TICKS: f64 = 100.0;
+
+    let ticks = std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| {
+            // Fields after the command name, which may contain spaces.
+            let fields = stat
+                .rsplit_once(')')?
+                .1
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            let user = fields.get(11)?.parse::<u64>().ok()?;
+            let system = fields.get(12)?.parse::<u64>().ok()?;
+            Some((system, user))
+        })
+        .unwrap_or_default();
+
+    (ticks.0 as f64 / CLOCK_TICKS, ticks.1 as f64 / CLOCK_TICKS)
+}
+
+/// Memory size as INFO shows it next to the byte count, e.g. `1.50M`.
+pub(crate) fn bytes_to_human(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    for unit in &UNITS[..UNITS.len() - 1] {
+        if size < 1024.0 {
+            return format!("{:.2}{}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.2}{}", size, UNITS[UNITS.len() - 1])
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        commands::Command,
+        stats::{bytes_to_human, LatencyHistogram, Stats},
+    };
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        for usec in 1..=100 {
+            histogram.record(usec);
+        }
+        histogram.record(10_000);
+
+        assert_eq!(48, histogram.percentile(50.0));
+        assert_eq!(96, histogram.percentile(99.0));
+        assert_eq!(9216, histogram.percentile(99.9));
+        assert_eq!(0, LatencyHistogram::default().percentile(50.0));
+    }
+
+    #[test]
+    fn test_command_and_error_stats() {
+        let mut stats = Stats::new("run".into());
+        let get = Command::Get("k".into());
+        let set = Command::Set("k".into(), "v".into(), None);
+        stats.record_call(&get, Duration::from_micros(3), None);
+        stats.record_call(
+            &get,
+            Duration::from_micros(5),
+            Some("WRONGTYPE Operation against a key"),
+        );
+        stats.record_call(&set, Duration::from_micros(2), None);
+        stats.record_rejected(&set, "NOPERM User has no permissions");
+        stats.record_error("ERR unknown command 'foo'");
+
+        assert_eq!(
+            "cmdstat_get:calls=2,usec=8,usec_per_call=4.00,rejected_calls=0,failed_calls=1\r\n\
+             cmdstat_set:calls=1,usec=2,usec_per_call=2.00,rejected_calls=1,failed_calls=0\r\n",
+            stats.commandstats()
+        );
+        assert_eq!(
+            "errorstat_ERR:count=1\r\nerrorstat_NOPERM:count=1\r\nerrorstat_WRONGTYPE:count=1\r\n",
+            stats.errorstats()
+        );
+        assert_eq!(
+            "latency_percentiles_usec_get:p50=3.000,p99=5.000,p99.9=5.000\r\n\
+             latency_percentiles_usec_set:p50=2.000,p99=2.000,p99.9=2.000\r\n",
+            stats.latencystats()
+        );
+        assert_eq!(3, stats.total_commands_processed);
+        assert_eq!(3, stats.total_error_replies);
+        assert_eq!(1, stats.dirty);
+    }
+
+    #[test]
+    fn test_bytes_to_human() {
+        assert_eq!("0B", bytes_to_human(0));
+        assert_eq!("1023B", bytes_to_human(1023));
+        assert_eq!("1.50K", bytes_to_human(1536));
+        assert_eq!("2.00G", bytes_to_human(2 << 30));
+    }
+}
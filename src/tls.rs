@@ -0,0 +1,236 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use anyhow::Context;
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::common::Error;
+
+/// Whether TLS clients must present a certificate signed by the CA.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TlsAuthClients {
+    No,
+    Optional,
+    Yes,
+}
+
+impl TlsAuthClients {
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        match raw.to_lowercase().as_str() {
+            "no" => Ok(TlsAuthClients::No),
+            "optional" => Ok(TlsAuthClients::Optional),
+            "yes" => Ok(TlsAuthClients::Yes),
+            _ => Err(format!(
+                "Invalid tls-auth-clients value '{}', expected yes, no or optional",
+                raw
+            )),
+        }
+    }
+}
+
+pub(crate) struct TlsConfig {
+    /// Port of the TLS listener, `None` when only replication uses TLS.
+    pub(crate) port: Option<u16>,
+    pub(crate) cert_file: String,
+    pub(crate) key_file: String,
+    pub(crate) ca_cert_file: Option<String>,
+    pub(crate) auth_clients: TlsAuthClients,
+    /// Authenticates clients as the ACL user named by their certificate CN.
+    pub(crate) auth_clients_user_cn: bool,
+    /// Connects to the writer over TLS.
+    pub(crate) replication: bool,
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path).context(format!("open-cert-{}", path))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .context(format!("parse-cert-{}", path))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path).context(format!("open-key-{}", path))?);
+    rustls_pemfile::private_key(&mut reader)
+        .context(format!("parse-key-{}", path))?
+        .ok_or_else(|| format!("No private key found in {}", path).into())
+}
+
+fn load_root_store(path: &str) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).context("add-ca-cert")?;
+    }
+    Ok(roots)
+}
+
+impl TlsConfig {
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let builder = match (self.auth_clients, &self.ca_cert_file) {
+            (TlsAuthClients::No, _) => ServerConfig::builder().with_no_client_auth(),
+            (auth_clients, Some(ca_cert_file)) => {
+                let verifier =
+                    WebPkiClientVerifier::builder(Arc::new(load_root_store(ca_cert_file)?));
+                let verifier = if auth_clients == TlsAuthClients::Optional {
+                    verifier.allow_unauthenticated().build()
+                } else {
+                    verifier.build()
+                }
+                .context("build-client-verifier")?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            (_, None) => {
+                return Err("Client certificate authentication requires a CA certificate".into())
+            }
+        };
+
+        let config = builder
+            .with_single_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)
+            .context("tls-server-config")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Connector of the replication link, presenting the server certificate
+    /// as the client certificate.
+    pub(crate) fn connector(&self) -> Result<TlsConnector, Error> {
+        let Some(ca_cert_file) = &self.ca_cert_file else {
+            return Err("TLS replication requires a CA certificate".into());
+        };
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(load_root_store(ca_cert_file)?)
+            .with_client_auth_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)
+            .context("tls-client-config")?;
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+/// Common name of the verified certificate the client presented, if any.
+pub(crate) fn peer_common_name(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let cert = stream.get_ref().1.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(|cn| cn.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, Issuer, KeyPair};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    use super::*;
+
+    /// Writes a CA, a server certificate and a client certificate with CN
+    /// `alice` into a fresh directory.
+    fn generate_certs(dir: &std::path::Path) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "test-ca");
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &issuer)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "alice");
+        let client_cert = client_params.signed_by(&client_key, &issuer).unwrap();
+
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("ca.crt"), ca_cert.pem()).unwrap();
+        std::fs::write(dir.join("server.crt"), server_cert.pem()).unwrap();
+        std::fs::write(dir.join("server.key"), server_key.serialize_pem()).unwrap();
+        std::fs::write(dir.join("client.crt"), client_cert.pem()).unwrap();
+        std::fs::write(dir.join("client.key"), client_key.serialize_pem()).unwrap();
+    }
+
+    fn config(dir: &std::path::Path, prefix: &str, auth_clients: TlsAuthClients) -> TlsConfig {
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        TlsConfig {
+            port: None,
+            cert_file: path(&format!("{}.crt", prefix)),
+            key_file: path(&format!("{}.key", prefix)),
+            ca_cert_file: Some(path("ca.crt")),
+            auth_clients,
+            auth_clients_user_cn: true,
+            replication: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_handshake() {
+        let dir = std::env::temp_dir().join(format!("tls-test-{}", std::process::id()));
+        generate_certs(&dir);
+
+        let acceptor = config(&dir, "server", TlsAuthClients::Yes)
+            .acceptor()
+            .unwrap();
+        let connector = config(&dir, "client", TlsAuthClients::Yes)
+            .connector()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let common_name = peer_common_name(&stream);
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            common_name
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("127.0.0.1").unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+        stream.write_all(b"PING").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(b"PING", &buf);
+        assert_eq!(Some("alice".to_string()), server.await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_auth_requires_ca() {
+        let dir = std::env::temp_dir().join(format!("tls-test-ca-{}", std::process::id()));
+        generate_certs(&dir);
+
+        let mut config = config(&dir, "server", TlsAuthClients::Yes);
+        config.ca_cert_file = None;
+        assert!(config.acceptor().is_err());
+
+        config.auth_clients = TlsAuthClients::No;
+        assert!(config.acceptor().is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
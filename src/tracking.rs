@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+
+/// Channel redirected invalidation messages are published on, for clients
+/// speaking RESP2.
+pub(crate) const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Options of CLIENT TRACKING ON.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TrackingOptions {
+    /// Client the invalidation messages are sent to.
+    pub(crate) redirect: Option<u64>,
+    /// Invalidate every key under the prefixes instead of the keys read.
+    pub(crate) bcast: bool,
+    pub(crate) prefixes: Vec<String>,
+    /// Only track keys read right after CLIENT CACHING YES.
+    pub(crate) optin: bool,
+    /// Track keys read unless right after CLIENT CACHING NO.
+    pub(crate) optout: bool,
+    /// Skip invalidations of keys the client wrote itself.
+    pub(crate) noloop: bool,
+}
+
+/// Invalidations to deliver: the redirect target, with the keys that changed
+/// or `None` when the keyspace was flushed.
+pub(crate) type Invalidations = Vec<(u64, Option<Vec<String>>)>;
+
+/// Clients with tracking enabled and the keys they may have cached, for
+/// server-assisted client-side caching.
+#[derive(Default)]
+pub(crate) struct Tracking {
+    clients: HashMap<u64, TrackingOptions>,
+    /// Clients that read each key, for the modes other than BCAST. Clients
+    /// that stopped tracking are only dropped when the key is invalidated.
+    table: HashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    /// Whether any client tracks keys.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub(crate) fn options(&self, request_count: u64) -> Option<&TrackingOptions> {
+        self.clients.get(&request_count)
+    }
+
+    /// Turns tracking on, or updates the options when it is on already. The
+    /// mode can't be switched without turning tracking off first.
+    pub(crate) fn enable(
+        &mut self,
+        request_count: u64,
+        mut options: TrackingOptions,
+    ) -> Result<(), String> {
+        let current = self.clients.get(&request_count);
+
+        if !options.bcast && !options.prefixes.is_empty() {
+            return Err("ERR PREFIX option requires BCAST mode to be enabled".into());
+        }
+        if current.is_some_and(|current| current.bcast != options.bcast) {
+            return Err("ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".into());
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return Err("ERR OPTIN and OPTOUT are not compatible with BCAST".into());
+        }
+        if options.optin && options.optout {
+            return Err("ERR You can't use both OPTIN and OPTOUT".into());
+        }
+        if current.is_some_and(|current| {
+            (options.optin && current.optout) || (options.optout && current.optin)
+        }) {
+            return Err("ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".into());
+        }
+
+        if options.bcast {
+            let existing = current.map(|current| &current.prefixes[..]).unwrap_or(&[]);
+            check_prefix_collisions(existing, &options.prefixes)?;
+
+            // BCAST without a prefix gets every key.
+            if options.prefixes.is_empty() {
+                options.prefixes.push(String::new());
+            }
+            for prefix in existing.iter().rev() {
+                if !options.prefixes.contains(prefix) {
+                    options.prefixes.insert(0, prefix.clone());
+                }
+            }
+        }
+
+        self.clients.insert(request_count, options);
+        Ok(())
+    }
+
+    pub(crate) fn disable(&mut self, request_count: u64) {
+        self.clients.remove(&request_count);
+    }
+
+    /// Remembers the keys read by a command of the client, unless its mode
+    /// says otherwise. `caching` is what CLIENT CACHING set for the command.
+    pub(crate) fn remember_keys<'a>(
+        &mut self,
+        request_count: u64,
+        caching: Option<bool>,
+        keys: impl Iterator<Item = &'a str>,
+    ) {
+        let Some(options) = self.clients.get(&request_count) else {
+            return;
+        };
+        if options.bcast
+            || (options.optin && caching != Some(true))
+            || (options.optout && caching == Some(false))
+        {
+            return;
+        }
+
+        for key in keys {
+            self.table
+                .entry(key.to_string())
+                .or_default()
+                .insert(request_count);
+        }
+    }
+
+    /// Collects the invalidations for keys written by `writer`, grouped by the
+    /// client that receives them.
+    pub(crate) fn invalidate_keys(
+        &mut self,
+        keys: &[String],
+        writer: Option<u64>,
+    ) -> Invalidations {
+        let mut invalidated: HashMap<u64, Vec<String>> = HashMap::new();
+        let mut seen = HashSet::new();
+
+        for key in keys {
+            if !seen.insert(key) {
+                continue;
+            }
+
+            let readers = self.table.remove(key).unwrap_or_default();
+            let recipients = self.clients.iter().filter(|(request_count, options)| {
+                let interested = if options.bcast {
+                    options
+                        .prefixes
+                        .iter()
+                        .any(|prefix| key.starts_with(prefix.as_str()))
+                } else {
+                    readers.contains(request_count)
+                };
+                interested && !(options.noloop && writer == Some(**request_count))
+            });
+
+            for (_, options) in recipients {
+                if let Some(target) = options.redirect {
+                    let target_keys = invalidated.entry(target).or_default();
+                    if !target_keys.contains(key) {
+                        target_keys.push(key.clone());
+                    }
+                }
+            }
+        }
+
+        invalidated
+            .into_iter()
+            .map(|(target, keys)| (target, Some(keys)))
+            .collect()
+    }
+
+    /// Invalidates everything for every tracking client, after a flush.
+    pub(crate) fn invalidate_all(&mut self) -> Invalidations {
+        self.table.clear();
+
+        self.clients
+            .values()
+            .filter_map(|options| options.redirect)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|target| (target, None))
+            .collect()
+    }
+}
+
+/// Prefixes of a client must not overlap, or a key would be invalidated
+/// twice.
+fn check_prefix_collisions(existing: &[String], prefixes: &[String]) -> Result<(), String> {
+    let overlaps = |a: &str, b: &str| a.starts_with(b) || b.starts_with(a);
+
+    for (i, prefix) in prefixes.iter().enumerate() {
+        if let Some(other) = existing
+            .iter()
+            .find(|other| *other != prefix && overlaps(prefix, other))
+        {
+            return Err(format!("ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.", prefix, other));
+        }
+        if let Some(other) = prefixes[i + 1..]
+            .iter()
+            .find(|other| overlaps(prefix, other))
+        {
+            return Err(format!("ERR Prefix '{}' overlaps with another provided prefix '{}'. Prefixes for a single client must not overlap.", prefix, other));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tracking::{Tracking, TrackingOptions};
+
+    fn redirect_to(target: u64) -> TrackingOptions {
+        TrackingOptions {
+            redirect: Some(target),
+            ..Default::default()
+        }
+    }
+
+    fn keys(raw: &[&str]) -> Vec<String> {
+        raw.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_mode_invalidates_keys_read_once() {
+        let mut tracking = Tracking::default();
+        tracking.enable(1, redirect_to(10)).unwrap();
+        tracking.remember_keys(1, None, ["a", "b"].into_iter());
+        tracking.remember_keys(2, None, ["c"].into_iter());
+
+        assert_eq!(
+            vec![(10, Some(keys(&["a"])))],
+            tracking.invalidate_keys(&keys(&["a", "a", "c"]), None)
+        );
+        assert!(tracking.invalidate_keys(&keys(&["a"]), None).is_empty());
+
+        tracking.disable(1);
+        assert!(tracking.is_empty());
+        assert!(tracking.invalidate_keys(&keys(&["b"]), None).is_empty());
+    }
+
+    #[test]
+    fn test_opt_modes_and_noloop() {
+        let mut tracking = Tracking::default();
+        tracking
+            .enable(
+                1,
+                TrackingOptions {
+                    optin: true,
+                    noloop: true,
+                    ..redirect_to(10)
+                },
+            )
+            .unwrap();
+        tracking.remember_keys(1, None, ["a"].into_iter());
+        tracking.remember_keys(1, Some(true), ["b"].into_iter());
+
+        assert!(tracking.invalidate_keys(&keys(&["b"]), Some(1)).is_empty());
+        tracking.remember_keys(1, Some(true), ["b"].into_iter());
+        assert_eq!(
+            vec![(10, Some(keys(&["b"])))],
+            tracking.invalidate_keys(&keys(&["a", "b"]), Some(2))
+        );
+
+        assert_eq!(
+            Err("ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".into()),
+            tracking.enable(1, TrackingOptions { optout: true, ..Default::default() })
+        );
+    }
+
+    #[test]
+    fn test_bcast_prefixes() {
+        let mut tracking = Tracking::default();
+        let bcast = |prefixes: &[&str]| TrackingOptions {
+            bcast: true,
+            prefixes: keys(prefixes),
+            ..redirect_to(10)
+        };
+
+        assert!(tracking.enable(1, bcast(&["user:", "us"])).is_err());
+        assert!(tracking
+            .enable(
+                1,
+                TrackingOptions {
+                    prefixes: keys(&["a"]),
+                    ..Default::default()
+                }
+            )
+            .is_err());
+        tracking.enable(1, bcast(&["user:"])).unwrap();
+        tracking.enable(1, bcast(&["post:"])).unwrap();
+        assert!(tracking.enable(1, bcast(&["user:1"])).is_err());
+        assert_eq!(
+            &keys(&["user:", "post:"]),
+            &tracking.options(1).unwrap().prefixes
+        );
+
+        assert_eq!(
+            vec![(10, Some(keys(&["user:1", "post:2"])))],
+            tracking.invalidate_keys(&keys(&["user:1", "other", "post:2"]), None)
+        );
+
+        tracking.enable(2, bcast(&[])).unwrap();
+        let mut invalidations = tracking.invalidate_all();
+        invalidations.sort();
+        assert_eq!(vec![(10, None)], invalidations);
+    }
+}